@@ -0,0 +1,60 @@
+#![no_main]
+
+use hf_codegen::compiler::{verify_encoding, CompilerSettings, HfCompiler};
+use hf_codegen::fuzz::Program;
+use hf_codegen::interpreter::try_interpret;
+use hf_codegen::optimizer;
+use hf_codegen::target::Target;
+use libfuzzer_sys::fuzz_target;
+
+const TAPE_SIZE: usize = 0x1000;
+/// Bounds the interpreter, as generated loops may never terminate.
+const FUEL: u64 = 0x10000;
+
+fuzz_target!(|input: (Program, bool)| {
+    let (Program(ir), optimize) = input;
+    let settings = || CompilerSettings {
+        optimization_level: optimize as u8,
+        ..Default::default()
+    };
+    let mut compiler = HfCompiler::new(Target::native(), settings());
+
+    let code = compiler
+        .compile_to_bytecode(ir.clone())
+        .expect("failed to compile well-formed IR");
+    // whatever we emit has to decode back into valid instructions, up to the
+    // jump tables at the end
+    let code_end = compiler
+        .stats()
+        .absolute_references
+        .first()
+        .map_or(code.len(), |reference| reference.offset);
+    verify_encoding(&code[..code_end], 64).expect("emitted an invalid instruction");
+
+    compiler
+        .compile_to_object_file(ir.clone(), "fuzz.hf")
+        .expect("failed to compile well-formed IR to an object file")
+        .write()
+        .expect("failed to write the object file");
+
+    // optimising must not change what an input-free program does. Programs
+    // the interpreter can't finish, e.g. because they call externals, are
+    // skipped. Optimised programs never take more steps, so they finish too.
+    let mut tape = [0; TAPE_SIZE];
+    if let Ok(output) = try_interpret(&ir, &mut tape, &[], Some(FUEL)) {
+        let optimized = optimizer::optimize(
+            ir,
+            &CompilerSettings {
+                optimization_level: optimizer::MAX_OPTIMIZATION_LEVEL,
+                sse2: true,
+                ..Default::default()
+            },
+        );
+        let mut optimized_tape = [0; TAPE_SIZE];
+        assert_eq!(
+            try_interpret(&optimized, &mut optimized_tape, &[], Some(FUEL)),
+            Ok(output)
+        );
+        assert_eq!(tape, optimized_tape);
+    }
+});
@@ -0,0 +1,264 @@
+//! Dynamically linked ELF executables, see
+//! [`super::HfCompiler::compile_to_dynamic_executable`].
+//!
+//! The file is laid out by hand, with no section headers, as only the loader
+//! reads it: a read-only segment with the headers, the name of the
+//! interpreter and the dynamic symbols and relocations, an executable one
+//! with the code, and a writable one with the dynamic section, the GOT and,
+//! zero-filled by the loader, the tape and the stack.
+
+use alloc::vec::Vec;
+
+use object::elf;
+use object::write::elf::{FileHeader, ProgramHeader, Rel, Sym, Writer};
+use object::Endianness;
+
+use super::{AbsoluteReference, CallableCode, DynamicLinking};
+
+/// Where the executable is loaded, the usual image base of executables.
+const IMAGE_BASE: u64 = 0x40_0000;
+const PAGE_SIZE: usize = 0x1000;
+const PROGRAM_HEADERS: u32 = 7;
+
+/// The external `_start` ends the program with, which also flushes what
+/// libc's stdio buffered.
+const EXIT: &str = "exit";
+
+/// `jmp qword ptr [rip + disp32]`, without the displacement.
+const JMP_INDIRECT: [u8; 2] = [0xff, 0x25];
+const STUB_SIZE: usize = 6;
+/// The size of `_start`, see [`write_dynamic_executable`].
+const START_SIZE: usize = 28;
+
+/// Lays out `callable`, which was compiled for `base_address`, as an x86-64
+/// executable that `linking.interpreter` loads along with `linking.needed`,
+/// resolving every external through the GOT before the program starts.
+pub(crate) fn write_dynamic_executable(
+    callable: CallableCode,
+    absolute_references: &[AbsoluteReference],
+    base_address: u64,
+    linking: &DynamicLinking,
+    tape_size: usize,
+    stack_size: usize,
+) -> Vec<u8> {
+    let mut externals = callable
+        .external_calls
+        .iter()
+        .map(|(name, _)| name.as_str())
+        .collect::<Vec<_>>();
+    if !externals.contains(&EXIT) {
+        externals.push(EXIT);
+    }
+
+    let mut buffer = Vec::new();
+    let mut writer = Writer::new(Endianness::Little, true, &mut buffer);
+
+    writer.reserve_file_header();
+    writer.reserve_program_headers(PROGRAM_HEADERS);
+    let interp_offset = writer.reserve(linking.interpreter.len() + 1, 1);
+    let needed = linking
+        .needed
+        .iter()
+        .map(|library| writer.add_dynamic_string(library.as_bytes()))
+        .collect::<Vec<_>>();
+    writer.reserve_null_dynamic_symbol_index();
+    let symbols = externals
+        .iter()
+        .map(|name| {
+            writer.reserve_dynamic_symbol_index();
+            writer.add_dynamic_string(name.as_bytes())
+        })
+        .collect::<Vec<_>>();
+    let symbol_count = writer.dynamic_symbol_count();
+    let hash_offset = writer.reserve_hash(1, symbol_count);
+    let dynsym_offset = writer.reserve_dynsym();
+    let dynstr_offset = writer.reserve_dynstr();
+    let dynstr_size = writer.dynstr_len();
+    let rela_offset = writer.reserve_relocations(externals.len(), true);
+    let headers_size = writer.reserved_len();
+
+    let code_start = START_SIZE;
+    let stubs = code_start + callable.code.len();
+    let text_size = stubs + externals.len() * STUB_SIZE;
+    let text_offset = writer.reserve(text_size, PAGE_SIZE);
+
+    let dynamic_count = linking.needed.len() + 9;
+    writer.reserve_until(writer.reserved_len().next_multiple_of(PAGE_SIZE));
+    let dynamic_offset = writer.reserve_dynamic(dynamic_count);
+    let got_offset = writer.reserve(externals.len() * 8, 8);
+    let data_size = writer.reserved_len() - dynamic_offset;
+    let tape_offset = writer.reserved_len().next_multiple_of(16);
+    let stack_offset = (tape_offset + tape_size).next_multiple_of(16);
+    let memory_size = stack_offset + stack_size - dynamic_offset;
+
+    let address = |offset: usize| IMAGE_BASE + offset as u64;
+    let got_slot = |i: usize| got_offset + i * 8;
+    let stub = |i: usize| text_offset + stubs + i * STUB_SIZE;
+    let rel32 = |from: usize, to: usize| (to as i64 - from as i64) as i32;
+
+    // _start:
+    // lea rdi, [rip + tape]
+    // lea rsi, [rip + stack - 1]      ; the empty stack, see `EntryPointers`
+    // call entry
+    // xor edi, edi
+    // call exit
+    // ud2
+    let mut text = Vec::with_capacity(text_size);
+    text.extend_from_slice(&[0x48, 0x8d, 0x3d]);
+    text.extend_from_slice(&rel32(text_offset + 7, tape_offset).to_le_bytes());
+    text.extend_from_slice(&[0x48, 0x8d, 0x35]);
+    text.extend_from_slice(&rel32(text_offset + 14, stack_offset - 1).to_le_bytes());
+    text.push(0xe8);
+    let entry = text_offset + code_start + callable.entry;
+    text.extend_from_slice(&rel32(text_offset + 19, entry).to_le_bytes());
+    text.extend_from_slice(&[0x31, 0xff]);
+    text.push(0xe8);
+    let exit = externals.iter().position(|&name| name == EXIT).unwrap();
+    text.extend_from_slice(&rel32(text_offset + 26, stub(exit)).to_le_bytes());
+    text.extend_from_slice(&[0x0f, 0x0b]);
+    debug_assert_eq!(text.len(), START_SIZE);
+
+    text.extend_from_slice(&callable.code);
+    let delta = address(text_offset + code_start).wrapping_sub(base_address);
+    for reference in absolute_references {
+        let bytes = &mut text[code_start + reference.offset..][..8];
+        let relocated = u64::from_le_bytes(bytes.try_into().unwrap()).wrapping_add(delta);
+        bytes.copy_from_slice(&relocated.to_le_bytes());
+    }
+    for (i, (_, calls)) in callable.external_calls.iter().enumerate() {
+        for &call in calls {
+            let call = code_start + call;
+            let displacement = rel32(text_offset + call + 5, stub(i));
+            text[call + 1..][..4].copy_from_slice(&displacement.to_le_bytes());
+        }
+    }
+    for i in 0..externals.len() {
+        text.extend_from_slice(&JMP_INDIRECT);
+        text.extend_from_slice(&rel32(stub(i) + STUB_SIZE, got_slot(i)).to_le_bytes());
+    }
+
+    writer
+        .write_file_header(&FileHeader {
+            os_abi: elf::ELFOSABI_NONE,
+            abi_version: 0,
+            e_type: elf::ET_EXEC,
+            e_machine: elf::EM_X86_64,
+            e_entry: address(text_offset),
+            e_flags: 0,
+        })
+        .expect("the buffer is a Vec");
+    writer.write_align_program_headers();
+    let program_headers_offset = writer.len();
+    let segment =
+        |p_type, p_flags, offset: usize, file_size: usize, memory_size: usize| ProgramHeader {
+            p_type,
+            p_flags,
+            p_offset: offset as u64,
+            p_vaddr: address(offset),
+            p_paddr: address(offset),
+            p_filesz: file_size as u64,
+            p_memsz: memory_size as u64,
+            p_align: if p_type == elf::PT_LOAD {
+                PAGE_SIZE as u64
+            } else {
+                8
+            },
+        };
+    let program_headers_size = PROGRAM_HEADERS as usize * 56;
+    for header in [
+        segment(
+            elf::PT_PHDR,
+            elf::PF_R,
+            program_headers_offset,
+            program_headers_size,
+            program_headers_size,
+        ),
+        segment(
+            elf::PT_INTERP,
+            elf::PF_R,
+            interp_offset,
+            linking.interpreter.len() + 1,
+            linking.interpreter.len() + 1,
+        ),
+        segment(elf::PT_LOAD, elf::PF_R, 0, headers_size, headers_size),
+        segment(
+            elf::PT_LOAD,
+            elf::PF_R | elf::PF_X,
+            text_offset,
+            text_size,
+            text_size,
+        ),
+        segment(
+            elf::PT_LOAD,
+            elf::PF_R | elf::PF_W,
+            dynamic_offset,
+            data_size,
+            memory_size,
+        ),
+        segment(
+            elf::PT_DYNAMIC,
+            elf::PF_R | elf::PF_W,
+            dynamic_offset,
+            got_offset - dynamic_offset,
+            got_offset - dynamic_offset,
+        ),
+        segment(elf::PT_GNU_STACK, elf::PF_R | elf::PF_W, 0, 0, 0),
+    ] {
+        writer.write_program_header(&header);
+    }
+
+    writer.write(linking.interpreter.as_bytes());
+    writer.write(&[0]);
+    // the null symbol has no name to hash
+    writer.write_hash(1, symbol_count, |i| {
+        let name = externals.get((i as usize).checked_sub(1)?)?;
+        Some(elf::hash(name.as_bytes()))
+    });
+    writer.write_null_dynamic_symbol();
+    for &name in &symbols {
+        writer.write_dynamic_symbol(&Sym {
+            name: Some(name),
+            section: None,
+            st_info: (elf::STB_GLOBAL << 4) | elf::STT_FUNC,
+            st_other: elf::STV_DEFAULT,
+            st_shndx: elf::SHN_UNDEF,
+            st_value: 0,
+            st_size: 0,
+        });
+    }
+    writer.write_dynstr();
+    writer.write_align_relocation();
+    for i in 0..externals.len() {
+        writer.write_relocation(
+            true,
+            &Rel {
+                r_offset: address(got_slot(i)),
+                r_sym: i as u32 + 1,
+                r_type: elf::R_X86_64_GLOB_DAT,
+                r_addend: 0,
+            },
+        );
+    }
+
+    writer.pad_until(text_offset);
+    writer.write(&text);
+
+    writer.pad_until(dynamic_offset);
+    for library in needed {
+        writer.write_dynamic_string(elf::DT_NEEDED, library);
+    }
+    writer.write_dynamic(elf::DT_HASH, address(hash_offset));
+    writer.write_dynamic(elf::DT_SYMTAB, address(dynsym_offset));
+    writer.write_dynamic(elf::DT_SYMENT, 24);
+    writer.write_dynamic(elf::DT_STRTAB, address(dynstr_offset));
+    writer.write_dynamic(elf::DT_STRSZ, dynstr_size as u64);
+    writer.write_dynamic(elf::DT_RELA, address(rela_offset));
+    writer.write_dynamic(elf::DT_RELASZ, (externals.len() * 24) as u64);
+    writer.write_dynamic(elf::DT_RELAENT, 24);
+    writer.write_dynamic(elf::DT_NULL, 0);
+    writer.pad_until(got_offset);
+    writer.write(&vec![0; externals.len() * 8]);
+    debug_assert_eq!(writer.len(), writer.reserved_len());
+
+    buffer
+}
@@ -0,0 +1,218 @@
+//! Running compiled code in the process that compiled it, see
+//! [`super::HfCompiler::jit_compile`].
+//!
+//! Memory is mapped with Linux system calls made directly, as there is no
+//! libc to go through.
+
+#[cfg(not(all(target_os = "linux", target_arch = "x86_64")))]
+compile_error!("the `jit` feature needs x86-64 Linux");
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::{AbsoluteReference, CallableCode, CompilerError, CompilerErrorKind};
+
+const PAGE_SIZE: usize = 4096;
+
+const SYS_MMAP: usize = 9;
+const SYS_MPROTECT: usize = 10;
+const SYS_MUNMAP: usize = 11;
+
+const PROT_READ: usize = 1;
+const PROT_WRITE: usize = 2;
+const PROT_EXEC: usize = 4;
+const MAP_PRIVATE: usize = 0x02;
+const MAP_ANONYMOUS: usize = 0x20;
+
+/// `jmp qword ptr [rip + disp32]`, without the displacement.
+const JMP_INDIRECT: [u8; 2] = [0xff, 0x25];
+const STUB_SIZE: usize = 6;
+
+/// Compiled code mapped into memory, ready to be called as often as needed.
+/// The memory is unmapped when it is dropped.
+///
+/// Every external the code calls, which includes the `read` and `write` of
+/// I/O, see [`super::RuntimeAbi`], has to be bound to a function with
+/// [`JitFunction::bind`] before the code is called. Calls go through a stub
+/// that jumps to the address it was bound to, which is kept apart from the
+/// code, so the code itself is never writable once it is mapped.
+#[derive(Debug)]
+pub struct JitFunction {
+    memory: *mut u8,
+    length: usize,
+    entry: usize,
+    /// The name of every external, the offset of the slot holding its
+    /// address, and whether it was bound.
+    externals: Vec<(String, usize, bool)>,
+    stack: Vec<u8>,
+}
+
+impl JitFunction {
+    /// Maps `code`, which was compiled for `base_address`, with its absolute
+    /// references relocated to where it ends up, followed by a stub for every
+    /// external and, on pages of their own, the addresses the stubs jump to.
+    pub(crate) fn new(
+        code: CallableCode,
+        absolute_references: &[AbsoluteReference],
+        base_address: u64,
+        stack_size: usize,
+    ) -> Result<Self, CompilerError> {
+        let stubs = code.code.len();
+        let code_length = page_align(stubs + code.external_calls.len() * STUB_SIZE);
+        let length = code_length + page_align(code.external_calls.len() * 8);
+        let memory = map(length)?;
+        let mut jit = Self {
+            memory,
+            length,
+            entry: code.entry,
+            externals: Vec::new(),
+            stack: vec![0; stack_size],
+        };
+
+        // SAFETY: the mapping is `length` bytes, readable and writable
+        let bytes = unsafe { core::slice::from_raw_parts_mut(memory, length) };
+        bytes[..stubs].copy_from_slice(&code.code);
+        let delta = (memory as u64).wrapping_sub(base_address);
+        for reference in absolute_references {
+            let address = &mut bytes[reference.offset..][..8];
+            let relocated = u64::from_le_bytes(address.try_into().unwrap()).wrapping_add(delta);
+            address.copy_from_slice(&relocated.to_le_bytes());
+        }
+        for (i, (name, calls)) in code.external_calls.into_iter().enumerate() {
+            let stub = stubs + i * STUB_SIZE;
+            let slot = code_length + i * 8;
+            bytes[stub..][..2].copy_from_slice(&JMP_INDIRECT);
+            let displacement = (slot - (stub + STUB_SIZE)) as i32;
+            bytes[stub + 2..][..4].copy_from_slice(&displacement.to_le_bytes());
+            for call in calls {
+                let displacement = stub as i32 - (call + 5) as i32;
+                bytes[call + 1..][..4].copy_from_slice(&displacement.to_le_bytes());
+            }
+            jit.externals.push((name, slot, false));
+        }
+
+        protect(memory, code_length, PROT_READ | PROT_EXEC)?;
+        Ok(jit)
+    }
+
+    /// Points every call to the external `name` at `function`, which is
+    /// called with the C calling convention and the arguments described at
+    /// [`crate::ir::Clobbers`]. Can be done again to rebind it, even between
+    /// calls of the code.
+    ///
+    /// Fails with [`CompilerErrorKind::FunctionNotFound`] if the code doesn't
+    /// call `name`.
+    pub fn bind(&mut self, name: &str, function: *const ()) -> Result<(), CompilerError> {
+        let Some((_, slot, bound)) = self
+            .externals
+            .iter_mut()
+            .find(|(external, _, _)| external == name)
+        else {
+            return Err(CompilerError {
+                kind: CompilerErrorKind::FunctionNotFound(name.into()),
+                span: None,
+            });
+        };
+        // SAFETY: the slots are on the readable and writable pages at the end
+        // of the mapping
+        unsafe {
+            self.memory
+                .add(*slot)
+                .cast::<usize>()
+                .write(function as usize)
+        };
+        *bound = true;
+        Ok(())
+    }
+
+    /// Runs the code on `tape`, starting at its first cell, with the stack
+    /// empty, and returns the index of the cell it ends on. Nothing carries
+    /// over from one call to the next but what the code leaves on the tape.
+    ///
+    /// Fails with [`CompilerErrorKind::UnboundExternal`] if an external the
+    /// code calls isn't bound, see [`JitFunction::bind`].
+    ///
+    /// # Safety
+    ///
+    /// The code only stays within `tape` and its stack if the program does,
+    /// nothing checks that it does. It may also loop forever. Bound functions
+    /// have to be callable the way the code calls them.
+    pub unsafe fn call(&mut self, tape: &mut [u8]) -> Result<usize, CompilerError> {
+        if let Some((name, _, _)) = self.externals.iter().find(|(_, _, bound)| !bound) {
+            return Err(CompilerError {
+                kind: CompilerErrorKind::UnboundExternal(name.clone()),
+                span: None,
+            });
+        }
+        // SAFETY: the entry point is a System V function taking the tape and
+        // the stack, see `Compiler::emit_entry_wrapper`
+        let entry: extern "sysv64" fn(*mut u8, *mut u8) -> *mut u8 =
+            unsafe { core::mem::transmute(self.memory.add(self.entry)) };
+        // r9 points at the top of the stack, which is the byte before it when
+        // it is empty
+        let end = entry(tape.as_mut_ptr(), self.stack.as_mut_ptr().wrapping_sub(1));
+        Ok((end as usize).wrapping_sub(tape.as_ptr() as usize))
+    }
+}
+
+impl Drop for JitFunction {
+    fn drop(&mut self) {
+        // SAFETY: the mapping is ours, and nothing refers to it anymore
+        unsafe { syscall(SYS_MUNMAP, [self.memory as usize, self.length, 0, 0, 0, 0]) };
+    }
+}
+
+fn page_align(length: usize) -> usize {
+    length.next_multiple_of(PAGE_SIZE)
+}
+
+fn map(length: usize) -> Result<*mut u8, CompilerError> {
+    let flags = MAP_PRIVATE | MAP_ANONYMOUS;
+    // SAFETY: an anonymous mapping doesn't touch any existing memory
+    let address = unsafe {
+        syscall(
+            SYS_MMAP,
+            [0, length, PROT_READ | PROT_WRITE, flags, usize::MAX, 0],
+        )
+    };
+    check(address).map(|address| address as *mut u8)
+}
+
+fn protect(memory: *mut u8, length: usize, protection: usize) -> Result<(), CompilerError> {
+    // SAFETY: only changes the protection of the mapping, which nothing
+    // borrows
+    check(unsafe { syscall(SYS_MPROTECT, [memory as usize, length, protection, 0, 0, 0]) })
+        .map(|_| ())
+}
+
+/// The result of a system call, which returns the negated errno on failure.
+fn check(result: isize) -> Result<usize, CompilerError> {
+    match result {
+        -4095..=-1 => Err(CompilerError {
+            kind: CompilerErrorKind::JitMapFailed(-result as i32),
+            span: None,
+        }),
+        _ => Ok(result as usize),
+    }
+}
+
+unsafe fn syscall(number: usize, [a, b, c, d, e, f]: [usize; 6]) -> isize {
+    let result;
+    // SAFETY: up to the caller, the kernel only clobbers rcx and r11
+    unsafe {
+        core::arch::asm!(
+            "syscall",
+            inlateout("rax") number as isize => result,
+            in("rdi") a,
+            in("rsi") b,
+            in("rdx") c,
+            in("r10") d,
+            in("r8") e,
+            in("r9") f,
+            lateout("rcx") _,
+            lateout("r11") _,
+            options(nostack),
+        )
+    };
+    result
+}
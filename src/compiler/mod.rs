@@ -1,92 +1,1780 @@
 use alloc::boxed::Box;
 use alloc::string::String;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
 
 use thiserror_no_std::Error;
 
-use crate::ir::IrNode;
-use crate::target::{Arch, Target};
+use crate::ir::{IrNode, IrOp, Span};
+use crate::optimizer;
+use crate::target::{Arch, CallingConvention, Target};
+use crate::Map;
 
+mod executable;
+#[cfg(feature = "jit")]
+mod jit;
 mod x86;
 #[cfg(test)]
 mod x86_64_tests;
 
+#[cfg(feature = "jit")]
+pub use jit::JitFunction;
+
 #[derive(Debug, Error)]
 pub struct CompilerError {
     pub kind: CompilerErrorKind,
     pub span: Option<crate::ir::Span>,
 }
 
+impl core::fmt::Display for CompilerError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self.span {
+            Some(span) => write!(
+                f,
+                "{}:{}: {}",
+                span.location.0 + 1,
+                span.location.1 + 1,
+                self.kind
+            ),
+            None => write!(f, "{}", self.kind),
+        }
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum CompilerErrorKind {
     #[error("unknown error: {0}")]
     Unknown(String),
     #[error("assembler error: {0}")]
     AssemblerError(String),
-    #[error("move left/right too large, can at most move 0x7FFFFFFF bytes at a time: {0:x}")]
-    MoveTooLarge(u32),
+    #[error("failed to encode `{instruction}` for {op}: {message}")]
+    EncodingFailed {
+        op: &'static str,
+        instruction: String,
+        message: String,
+    },
+    /// A move, or an access to cells that far from the current one, beyond
+    /// what the displacement of an instruction of the target reaches. 64-bit
+    /// code has no limit on moves.
+    #[error("move left/right too large, can at most move {limit:#x} bytes at a time: {count:#x}")]
+    MoveTooLarge { count: usize, limit: usize },
+    #[error("can at most read or write 0xFFFFFFFF bytes at a time: {0:x}")]
+    IoTooLarge(usize),
+    #[error("generated code exceeds the limit of {0} bytes")]
+    CodeSizeLimitExceeded(usize),
     #[error("function not found: '{0}'")]
     FunctionNotFound(String),
+    #[error("expected a Function, found {0}")]
+    NotAFunction(&'static str),
+    #[error("{0} is not supported by this backend")]
+    UnsupportedOp(&'static str),
+    #[error("`{0}` is reserved for the entry point that `auto_entry` generates")]
+    ReservedFunctionName(String),
+    #[error("{0} outside of a function, but there is no entry point to put it in as `auto_entry` is disabled")]
+    CodeOutsideFunction(&'static str),
+    #[error("the instruction at {0:#x} clobbers the cell pointer register")]
+    CellPointerClobbered(usize),
+    #[error("the instruction at {0:#x} clobbers a callee-saved register")]
+    CalleeSavedClobbered(usize),
+    #[error("the instruction at {0:#x} is reached with the direction flag set")]
+    DirectionFlagSet(usize),
+    #[error("invalid or truncated instruction at {0:#x}")]
+    InvalidEncoding(usize),
+    #[error("the displacement of the instruction at {0:#x} runs past the end of the code")]
+    DisplacementOutOfBounds(usize),
+    #[error("{0} needs to know where the tape is, which only object files with a static tape do")]
+    RequiresStaticTape(&'static str),
+    #[error(
+        "{0} needs a stack region, which only object files with a `stack_overflow` policy have"
+    )]
+    RequiresStackRegion(&'static str),
+    #[error("cell {index} is outside of the static tape of {size} cells")]
+    SeekOutOfTape { index: usize, size: usize },
+    #[error("{0} refers to an external symbol, which only object files can")]
+    RequiresObjectFile(&'static str),
+    #[error("the functions take {size:#x} bytes, more than fit in front of the entry offset {offset:#x}")]
+    EntryOffsetTooSmall { offset: usize, size: usize },
+    #[error("{0:?} can't be the scratch register, the calling convention has callers expect it to be preserved")]
+    CalleeSavedScratchRegister(ScratchRegister),
+    #[error("{0:?} can't be both the {1} and the {2} register of the register ABI")]
+    RegisterConflict(PointerRegister, &'static str, &'static str),
+    #[error("{0:?} can't be the base register, externals are free to clobber it")]
+    CallerSavedBaseRegister(PointerRegister),
+    #[error("{0} only supports x86-64 with the System V calling convention")]
+    UnsupportedTarget(&'static str),
+    #[error("failed to map memory for the JIT, errno {0}")]
+    JitMapFailed(i32),
+    #[error("the code calls `{0}`, which was never bound to a function")]
+    UnboundExternal(String),
+
+    #[error("failed to relocate `{symbol}` with a {relocation} relocation: {message}")]
+    RelocationFailed {
+        symbol: String,
+        relocation: String,
+        message: String,
+    },
+}
+
+/// Checks that `bytes` decode into valid `bitness`-bit instructions, all the way
+/// to the end. Debug builds run this on the code of every compilation, after
+/// relocated bytes have been zeroed, but without any jump tables after it.
+///
+/// # Panics
+///
+/// If `bitness` isn't 16, 32 or 64.
+pub fn verify_encoding(bytes: &[u8], bitness: u32) -> Result<(), CompilerError> {
+    let decoder = iced_x86::Decoder::new(bitness, bytes, iced_x86::DecoderOptions::NONE);
+    // a truncated instruction at the end decodes as an invalid one too
+    match decoder
+        .into_iter()
+        .find(|instruction| instruction.is_invalid())
+    {
+        Some(instruction) => Err(CompilerError {
+            kind: CompilerErrorKind::InvalidEncoding(instruction.ip() as usize),
+            span: None,
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Checks that nothing in `bytes` clobbers the cell pointer in the 64-bit
+/// register `cell` unless it is saved with a `push` and restored with a `pop`
+/// around it. Debug builds run this on the code of every compilation that
+/// keeps the cell pointer in a register, like [`verify_encoding`].
+///
+/// Outside of a save, `cell` may only be written the ways the backend does:
+/// moved to another cell with a 64-bit `add`, `sub` or `lea`, set up on entry
+/// with a `mov` from the first argument register, `rdi` or `rcx`, reloaded
+/// with a `mov` from a rip-relative slot, or popped from the stack where it
+/// was saved, or where an address computed from it with a `lea` was pushed,
+/// like the end of a buffer. Inside of a save, it may be used for anything,
+/// like passing an argument. `clobbering_calls` are the offsets of the calls
+/// that may clobber `cell`: if it is caller-saved, every call to an external,
+/// except for those that promise to preserve the cell pointer. Each of them
+/// has to be inside of a save. Pushes and pops are matched in the order they
+/// are laid out in, so a save has to be a straight line of code.
+///
+/// # Panics
+///
+/// If `bitness` isn't 16, 32 or 64.
+pub(crate) fn verify_cell_pointer(
+    bytes: &[u8],
+    bitness: u32,
+    cell: iced_x86::Register,
+    clobbering_calls: &[usize],
+) -> Result<(), CompilerError> {
+    use iced_x86::{Mnemonic, OpKind, Register};
+
+    /// What a `push` left on the stack, as far as the cell pointer goes.
+    #[derive(Clone, Copy, PartialEq)]
+    enum Slot {
+        /// The cell pointer itself, which is saved until it is popped.
+        Saved,
+        /// An address computed from the cell pointer.
+        Derived,
+        Other,
+    }
+
+    let decoder = iced_x86::Decoder::new(bitness, bytes, iced_x86::DecoderOptions::NONE);
+    let mut stack = Vec::new();
+    // the registers holding an address computed from the cell pointer
+    let mut derived = Vec::new();
+    for instruction in decoder {
+        let offset = instruction.ip() as usize;
+        let saved = stack.contains(&Slot::Saved);
+        let written = (instruction.op_count() > 0
+            && instruction.op0_kind() == OpKind::Register
+            && !matches!(
+                instruction.mnemonic(),
+                Mnemonic::Cmp | Mnemonic::Test | Mnemonic::Push
+            ))
+        .then(|| full_register(instruction.op0_register()));
+        let popped = match instruction.mnemonic() {
+            Mnemonic::Pop => stack.pop(),
+            _ => None,
+        };
+
+        let sanctioned = instruction.op0_register() == cell
+            && match instruction.mnemonic() {
+                Mnemonic::Add | Mnemonic::Sub | Mnemonic::Lea => true,
+                Mnemonic::Mov => match instruction.op1_kind() {
+                    OpKind::Register => {
+                        matches!(instruction.op1_register(), Register::RDI | Register::RCX)
+                    }
+                    OpKind::Memory => instruction.is_ip_rel_memory_operand(),
+                    _ => false,
+                },
+                Mnemonic::Pop => matches!(popped, Some(Slot::Saved | Slot::Derived)),
+                _ => false,
+            };
+        let clobbered = if clobbering_calls.contains(&offset) {
+            !saved
+        } else {
+            written == Some(cell) && !saved && !sanctioned
+        };
+        if clobbered {
+            return Err(CompilerError {
+                kind: CompilerErrorKind::CellPointerClobbered(offset),
+                span: None,
+            });
+        }
+
+        if instruction.mnemonic() == Mnemonic::Push {
+            stack.push(match instruction.op0_kind() {
+                OpKind::Register if instruction.op0_register() == cell => Slot::Saved,
+                OpKind::Register if derived.contains(&instruction.op0_register()) => Slot::Derived,
+                _ => Slot::Other,
+            });
+        }
+        if let Some(register) = written {
+            derived.retain(|&other| other != register);
+            if instruction.mnemonic() == Mnemonic::Lea
+                && instruction.memory_base() == cell
+                && register != cell
+            {
+                derived.push(register);
+            }
+        }
+        // nothing a call returns in a register is derived from the cell pointer
+        if matches!(instruction.mnemonic(), Mnemonic::Call | Mnemonic::Syscall) {
+            derived.clear();
+        }
+    }
+    Ok(())
+}
+
+/// Checks that nothing in `bytes` writes to rbx, rbp or r12 to r15, which are
+/// callee-saved in both calling conventions, other than the 64-bit registers
+/// it `owns`. Debug builds run this on the code of every compilation, like
+/// [`verify_encoding`], with the pointer registers of
+/// [`CompilerSettings::register_abi`] as the ones it owns.
+///
+/// The generated code sticks to caller-saved registers, even to marshal the
+/// arguments of externals, so functions need no frame to preserve anything
+/// for their callers. Only the wrappers of entry points save the pointer
+/// registers the code owns. Code that needs more registers has to save them
+/// itself.
+///
+/// # Panics
+///
+/// If `bitness` isn't 16, 32 or 64.
+pub(crate) fn verify_callee_saved(
+    bytes: &[u8],
+    bitness: u32,
+    owns: &[iced_x86::Register],
+) -> Result<(), CompilerError> {
+    use iced_x86::Register::{
+        BH, BL, BP, BPL, BX, EBP, EBX, R12, R12D, R12L, R12W, R13, R13D, R13L, R13W, R14, R14D,
+        R14L, R14W, R15, R15D, R15L, R15W, RBP, RBX,
+    };
+    use iced_x86::{Mnemonic, OpKind, Register};
+
+    const CALLEE_SAVED: [Register; 25] = [
+        RBX, EBX, BX, BL, BH, RBP, EBP, BP, BPL, R12, R12D, R12W, R12L, R13, R13D, R13W, R13L, R14,
+        R14D, R14W, R14L, R15, R15D, R15W, R15L,
+    ];
+    let decoder = iced_x86::Decoder::new(bitness, bytes, iced_x86::DecoderOptions::NONE);
+    for instruction in decoder {
+        let writes_callee_saved = instruction.op_count() > 0
+            && instruction.op0_kind() == OpKind::Register
+            && CALLEE_SAVED.contains(&instruction.op0_register())
+            && !owns.contains(&full_register(instruction.op0_register()))
+            && !matches!(
+                instruction.mnemonic(),
+                Mnemonic::Cmp | Mnemonic::Test | Mnemonic::Push
+            );
+        if writes_callee_saved {
+            return Err(CompilerError {
+                kind: CompilerErrorKind::CalleeSavedClobbered(instruction.ip() as usize),
+                span: None,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// The 64-bit general purpose register `register` is a part of, or `register`
+/// itself if it isn't one, without iced-x86's instruction info to look it up.
+pub(crate) fn full_register(register: iced_x86::Register) -> iced_x86::Register {
+    use iced_x86::Register::*;
+
+    match register {
+        AL | AH | AX | EAX => RAX,
+        CL | CH | CX | ECX => RCX,
+        DL | DH | DX | EDX => RDX,
+        BL | BH | BX | EBX => RBX,
+        SPL | SP | ESP => RSP,
+        BPL | BP | EBP => RBP,
+        SIL | SI | ESI => RSI,
+        DIL | DI | EDI => RDI,
+        R8L | R8W | R8D => R8,
+        R9L | R9W | R9D => R9,
+        R10L | R10W | R10D => R10,
+        R11L | R11W | R11D => R11,
+        R12L | R12W | R12D => R12,
+        R13L | R13W | R13D => R13,
+        R14L | R14W | R14D => R14,
+        R15L | R15W | R15D => R15,
+        _ => register,
+    }
+}
+
+/// Checks that the direction flag is clear at every `call` and `ret` in
+/// `bytes`. Debug builds run this on the code of every compilation, like
+/// [`verify_encoding`].
+///
+/// Both calling conventions have DF clear on entry to and return from every
+/// function, and the generated code assumes so too, so string instructions
+/// like `rep movsb` run forwards unless the lowering sets DF. A lowering that
+/// sets it with `std` has to clear it again with `cld` before the next call or
+/// the end of the function, in a straight line of code, as the instructions
+/// are checked in the order they are laid out in.
+///
+/// # Panics
+///
+/// If `bitness` isn't 16, 32 or 64.
+pub(crate) fn verify_direction_flag(bytes: &[u8], bitness: u32) -> Result<(), CompilerError> {
+    use iced_x86::Mnemonic;
+
+    let decoder = iced_x86::Decoder::new(bitness, bytes, iced_x86::DecoderOptions::NONE);
+    let mut set = false;
+    for instruction in decoder {
+        match instruction.mnemonic() {
+            Mnemonic::Std => set = true,
+            Mnemonic::Cld => set = false,
+            Mnemonic::Call | Mnemonic::Ret if set => {
+                return Err(CompilerError {
+                    kind: CompilerErrorKind::DirectionFlagSet(instruction.ip() as usize),
+                    span: None,
+                })
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// A likely mistake in the program, which doesn't keep it from compiling.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompilerWarning {
+    pub kind: CompilerWarningKind,
+    pub span: crate::ir::Span,
+}
+
+impl core::fmt::Display for CompilerWarning {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{}:{}: warning: {}",
+            self.span.location.0 + 1,
+            self.span.location.1 + 1,
+            self.kind
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum CompilerWarningKind {
+    /// A loop that only does arithmetic on the cell, by a net amount that is
+    /// even. It can't reach zero from an odd value, so it never ends for one.
+    #[error("loop adds {0:#x} to the cell per iteration, which is even, so it never ends for odd values")]
+    SuspiciousLoop(u8),
+    /// A loop whose cell is always zero when it is reached, usually a
+    /// comment. Optimisation removes it.
+    #[error("loop never runs, the cell is always zero here")]
+    DeadLoop,
+}
+
+/// An object file, along with the warnings found while compiling it.
+#[derive(Debug)]
+pub struct CompileOutput {
+    pub object: object::write::Object<'static>,
+    pub warnings: Vec<CompilerWarning>,
+}
+
+/// A COFF object, along with the functions it imports, see
+/// [`HfCompiler::compile_to_coff_with_imports`].
+#[derive(Debug)]
+pub struct CoffOutput {
+    pub object: object::write::Object<'static>,
+    /// Sorted by name.
+    pub imports: Vec<Import>,
+}
+
+/// An external function of a COFF object, with what a linker needs to put it
+/// in an import library, like an `EXPORTS` line of a `.def` file does.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Import {
+    /// The name the code calls, which is also the name the DLL exports.
+    pub name: String,
+    /// The undefined symbol the object references, after
+    /// [`CompilerSettings::symbol_mangling`]. With
+    /// [`ExternalCalls::IndirectGot`], this is the `__imp_` pointer of the
+    /// function rather than the function itself.
+    pub symbol: String,
+    pub lookup: ImportLookup,
+}
+
+/// How the loader finds an [`Import`] in the export table of its DLL.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportLookup {
+    /// By its name.
+    Name,
+    /// By its ordinal, see [`CompilerSettings::import_ordinals`].
+    Ordinal(u16),
+}
+
+/// Bytecode along with what it was compiled for, so it can be checked before
+/// it is loaded or linked. Object files say so in their header already.
+#[derive(Debug, Clone)]
+pub struct CompiledArtifact {
+    pub code: Vec<u8>,
+    pub target: Target,
+    /// 32 or 64, following from the architecture of the target.
+    pub bitness: u32,
+    /// Address execution starts at, which is where the code is loaded, at
+    /// [`CompilerSettings::base_address`], plus the
+    /// [`CompilerSettings::entry_offset`] if there is one.
+    pub entry: u64,
+}
+
+/// Statistics about a compilation.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CompilationStats {
+    /// Size of the generated code in bytes.
+    pub code_size: usize,
+    /// How many nodes of each kind were lowered and how much code they
+    /// produced, keyed by [`IrOp::name`].
+    pub ops: Map<&'static str, OpStats>,
+    /// Addresses in the code that have to be relocated if it is loaded
+    /// anywhere else than it was compiled for, in the order they appear.
+    pub absolute_references: Vec<AbsoluteReference>,
+    /// The name and offset in the code of every [`IrOp::DebugMarker`], in the
+    /// order they appear.
+    pub markers: Vec<(String, usize)>,
+    /// The span of the node every stretch of code from the given offset on is
+    /// emitted for, until the next entry, in the order they appear. Nodes
+    /// that emit nothing have no entry. A loop or function is split around
+    /// the code of its body.
+    pub spans: Vec<(Span, usize)>,
+    /// The top-level functions whose code [`HfCompiler::compile_incremental`]
+    /// reused from the previous compilation, in the order they are laid out.
+    /// Empty for any other kind of compilation.
+    pub reused_functions: Vec<String>,
+}
+
+impl CompilationStats {
+    /// The offset of the first instruction emitted for the node at `span`,
+    /// which is where a breakpoint on it goes, or `None` if it emitted no
+    /// code.
+    pub fn offset_for_span(&self, span: &Span) -> Option<u64> {
+        self.spans
+            .iter()
+            .filter(|(owner, _)| owner == span)
+            .map(|&(_, offset)| offset as u64)
+            .min()
+    }
+
+    /// Whether the code runs wherever it is loaded, without relocating
+    /// anything but calls to externals. See [`Self::absolute_references`]
+    /// for what keeps it from being so.
+    pub fn is_position_independent(&self) -> bool {
+        self.absolute_references.is_empty()
+    }
+}
 
-    #[error("relocation failed: '{0}'")]
-    RelocationFailed(String),
+/// What compiling a program to an object file would emit, see
+/// [`HfCompiler::plan`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CompilePlan {
+    /// The code lowered for each node that emits any, in the order it is laid
+    /// out. Like in [`CompilationStats::spans`], a loop or function is split
+    /// around the code of its body.
+    pub nodes: Vec<PlannedNode>,
+    /// An upper bound on the size of the code in bytes. Only branches can come
+    /// out smaller, see [`PlannedNode::max_size`].
+    pub max_code_size: usize,
+    /// The name of every external function the code calls and how many times,
+    /// sorted by name. Each call is a relocation in the object file.
+    pub external_calls: Vec<(String, usize)>,
+    /// The names of the top-level functions, including `_start`, in the order
+    /// they are laid out.
+    pub functions: Vec<String>,
+    /// The warnings compiling the program would produce.
+    pub warnings: Vec<CompilerWarning>,
+}
+
+/// The code of one node in a [`CompilePlan`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlannedNode {
+    /// The [`IrOp::name`] of the node.
+    pub op: &'static str,
+    pub span: Span,
+    /// The instructions as iced-x86 has them before assembly, when branch
+    /// targets and rip-relative operands are labels rather than addresses.
+    pub instructions: Vec<iced_x86::Instruction>,
+    /// How many bytes the instructions take at most. Branches are counted at
+    /// their near form, while the assembler uses the short form for targets
+    /// within 128 bytes.
+    pub max_size: usize,
+}
+
+/// An absolute, 64-bit address of a function, or of a `Switch` arm, in a jump
+/// table. In object files each one is an `R_X86_64_64` relocation in
+/// `.rodata`, which a shared object can only have in read-only memory with
+/// text relocations. There are none with [`CompilerSettings::pic`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct AbsoluteReference {
+    /// Where the address is, from the start of the generated code. Object
+    /// files keep the jump tables apart from the code, one after the other,
+    /// so there it is from the start of the first table.
+    pub offset: usize,
+    /// The function whose address it is, or the arm, like `case 3` or
+    /// `default`.
+    pub target: String,
+    /// The node that needs the address, like an `IndirectCall` or a `Switch`.
+    pub span: crate::ir::Span,
+}
+
+/// Totals for one kind of [`IrOp`] in [`CompilationStats`].
+///
+/// The bytes of a `Function` or `Condition` are only those of the function
+/// or loop itself, like the `ret` or the jumps. The code of its body is
+/// counted for the nodes in the body.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OpStats {
+    pub count: usize,
+    pub bytes: usize,
+}
+
+/// Bytecode with the top-level code wrapped in a C-callable function, which
+/// runs the program on the tape and stack it is passed, for running the code
+/// without linking it.
+pub(crate) struct CallableCode {
+    pub code: Vec<u8>,
+    /// Offset of the C-callable function.
+    pub entry: usize,
+    /// Every external the code calls, with the offset of each `call rel32`.
+    pub external_calls: Vec<(String, Vec<usize>)>,
 }
 
 pub(crate) trait CompilerTrait {
     fn compile_to_bytecode(&mut self, ast: Vec<IrNode>) -> Result<Vec<u8>, CompilerError>;
+    fn compile_stream(
+        &mut self,
+        ir: &mut dyn Iterator<Item = IrNode>,
+    ) -> Result<Vec<u8>, CompilerError>;
+    fn compile_incremental(&mut self, ast: Vec<IrNode>) -> Result<Vec<u8>, CompilerError>;
+    fn compile_function(&mut self, func: IrNode) -> Result<(Vec<u8>, usize), CompilerError>;
+    fn compile_to_callable(&mut self, ast: Vec<IrNode>) -> Result<CallableCode, CompilerError>;
     fn compile_to_object_file(
         &mut self,
         ast: Vec<IrNode>,
         filename: &str,
-    ) -> Result<object::write::Object, CompilerError>;
+    ) -> Result<object::write::Object<'static>, CompilerError>;
+    fn compile_to_object_file_with_externals(
+        &mut self,
+        ast: Vec<IrNode>,
+        filename: &str,
+        externals: &[String],
+    ) -> Result<object::write::Object<'static>, CompilerError>;
+    fn compile_into_object(
+        &mut self,
+        ast: Vec<IrNode>,
+        obj: &mut object::write::Object,
+        text_section: object::write::SectionId,
+    ) -> Result<(), CompilerError>;
+    fn plan(&mut self, ast: Vec<IrNode>) -> Result<CompilePlan, CompilerError>;
+    #[cfg(feature = "disasm")]
+    fn compile_to_text(&mut self, ast: Vec<IrNode>) -> Result<String, CompilerError>;
+    fn stats(&self) -> &CompilationStats;
+    fn warnings(&self) -> &[CompilerWarning];
+    fn dump_scopes(&self) -> String;
+    fn is_pure(&self) -> bool;
+    fn imports(&self) -> Vec<Import>;
+    fn settings(&self) -> &CompilerSettings;
+    fn settings_mut(&mut self) -> &mut CompilerSettings;
 }
 
 pub struct HfCompiler {
     compiler: Box<dyn CompilerTrait>,
+    target: Target,
+    bitness: u32,
+    base_address: u64,
+    entry_offset: Option<usize>,
 }
 
 impl HfCompiler {
     pub fn new(target: Target, compiler_settings: CompilerSettings) -> Self {
-        let compiler = match target.arch {
-            Arch::X86 => Box::new(x86::Compiler::new(
-                32,
-                compiler_settings,
-                target.calling_convention,
-            )),
-            Arch::X86_64 => Box::new(x86::Compiler::new(
-                64,
-                compiler_settings,
-                target.calling_convention,
-            )),
+        let bitness = match target.arch {
+            Arch::X86 => 32,
+            Arch::X86_64 => 64,
             _ => unimplemented!(),
         };
+        let base_address = compiler_settings.base_address;
+        let entry_offset = compiler_settings.entry_offset;
+        let compiler = Box::new(x86::Compiler::new(
+            bitness,
+            compiler_settings,
+            target.calling_convention,
+        ));
 
-        Self { compiler }
+        Self {
+            compiler,
+            target,
+            bitness,
+            base_address,
+            entry_offset,
+        }
+    }
+
+    /// The target the compiler generates code for.
+    pub fn target(&self) -> &Target {
+        &self.target
     }
 
     pub fn compile_to_bytecode(&mut self, ast: Vec<IrNode>) -> Result<Vec<u8>, CompilerError> {
         self.compiler.compile_to_bytecode(ast)
     }
 
+    /// Like [`HfCompiler::compile_to_bytecode`], but says what the code is for.
+    pub fn compile_to_artifact(
+        &mut self,
+        ast: Vec<IrNode>,
+    ) -> Result<CompiledArtifact, CompilerError> {
+        Ok(CompiledArtifact {
+            code: self.compiler.compile_to_bytecode(ast)?,
+            target: self.target.clone(),
+            bitness: self.bitness,
+            entry: self.base_address + self.entry_offset.unwrap_or(0) as u64,
+        })
+    }
+
+    /// Compiles to bytecode like [`HfCompiler::compile_to_bytecode`] and lays
+    /// it out as a listing, with a line for the code of every node that emits
+    /// any, in the order it is laid out:
+    ///
+    /// ```text
+    /// 00000000-00000004  41 80 00 01              1:1          +
+    /// ```
+    ///
+    /// That is the range of addresses the code is at, from
+    /// [`CompilerSettings::base_address`] on, its bytes, and the span of the
+    /// node rendered against `src`, the source it was produced from, with the
+    /// start of its text. Code that takes more than eight bytes continues on
+    /// the lines after. The ranges are those of [`CompilationStats::spans`],
+    /// so a loop or function gets a line before and one after its body.
+    pub fn compile_to_listing(
+        &mut self,
+        ast: Vec<IrNode>,
+        src: &str,
+    ) -> Result<String, CompilerError> {
+        use core::fmt::Write;
+
+        const BYTES_PER_LINE: usize = 8;
+        const EXCERPT_LENGTH: usize = 24;
+
+        let code = self.compiler.compile_to_bytecode(ast)?;
+        let spans = &self.compiler.stats().spans;
+        let lines = src.lines().collect::<Vec<_>>();
+        // the text of the span with line breaks left out, cut short if long
+        let excerpt = |span: &Span| {
+            let (line, column) = span.location;
+            let text = lines
+                .get(line..)
+                .unwrap_or_default()
+                .iter()
+                .enumerate()
+                .flat_map(|(i, line)| line.chars().skip(if i == 0 { column } else { 0 }))
+                .take(span.length)
+                .map(|c| if c.is_whitespace() { ' ' } else { c })
+                .collect::<String>();
+            match text.char_indices().nth(EXCERPT_LENGTH) {
+                Some((end, _)) => format!("{}...", &text[..end]),
+                None => text,
+            }
+        };
+
+        // code before the first node that emitted any has no source
+        let first = spans.first().map_or(code.len(), |&(_, offset)| offset);
+        let ranges = (first > 0)
+            .then_some((None, 0))
+            .into_iter()
+            .chain(spans.iter().map(|(span, offset)| (Some(span), *offset)))
+            .collect::<Vec<_>>();
+
+        let mut listing = String::new();
+        for (i, &(span, start)) in ranges.iter().enumerate() {
+            let end = ranges.get(i + 1).map_or(code.len(), |&(_, end)| end);
+            let address = |offset: usize| self.base_address + offset as u64;
+            let source = span.map_or(String::new(), |span| {
+                format!("{:<12} {}", span.render(src), excerpt(span))
+            });
+            for (j, chunk) in code[start..end].chunks(BYTES_PER_LINE).enumerate() {
+                let bytes = chunk
+                    .iter()
+                    .map(|byte| format!("{byte:02x}"))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                let line = if j == 0 {
+                    format!(
+                        "{:08x}-{:08x}  {bytes:<23}  {source}",
+                        address(start),
+                        address(end)
+                    )
+                } else {
+                    format!(
+                        "{:<17}  {bytes}",
+                        format!("{:08x}", address(start + j * BYTES_PER_LINE))
+                    )
+                };
+                writeln!(listing, "{}", line.trim_end()).unwrap();
+            }
+        }
+        Ok(listing)
+    }
+
+    /// Compiles to bytecode of at most `max_bytes`, for targets with a hard
+    /// limit on the size of the code.
+    ///
+    /// Starting at the configured [`CompilerSettings::optimization_level`],
+    /// the level is raised one step at a time up to
+    /// [`optimizer::MAX_OPTIMIZATION_LEVEL`] until the code fits, enabling
+    /// more size-reducing passes, like [`optimizer::ClearLoops`], on the way.
+    /// Fails with [`CompilerErrorKind::CodeSizeLimitExceeded`] if it doesn't
+    /// fit at the highest level either. The settings are left as they were.
+    pub fn compile_within_size(
+        &mut self,
+        ast: Vec<IrNode>,
+        max_bytes: usize,
+    ) -> Result<Vec<u8>, CompilerError> {
+        let settings = self.compiler.settings_mut();
+        let (optimization_level, max_code_size) =
+            (settings.optimization_level, settings.max_code_size);
+        settings.max_code_size =
+            Some(max_code_size.map_or(max_bytes, |limit| limit.min(max_bytes)));
+
+        let mut level = optimization_level;
+        let result = loop {
+            self.compiler.settings_mut().optimization_level = level;
+            match self.compiler.compile_to_bytecode(ast.clone()) {
+                Err(CompilerError {
+                    kind: CompilerErrorKind::CodeSizeLimitExceeded(_),
+                    ..
+                }) if level < optimizer::MAX_OPTIMIZATION_LEVEL => level += 1,
+                result => break result,
+            }
+        };
+
+        let settings = self.compiler.settings_mut();
+        settings.optimization_level = optimization_level;
+        settings.max_code_size = max_code_size;
+        result
+    }
+
+    /// Compiles IR to bytecode as it is produced, without collecting it into a
+    /// `Vec` first. Meant for huge, machine-generated programs.
+    ///
+    /// Top-level nodes are lowered one at a time and dropped afterwards.
+    /// `Function` and `Condition` nodes own their whole body, so a large
+    /// function or loop is still held in memory in full. The emitted
+    /// instructions are buffered until the end as well, because jumps and calls
+    /// can only be resolved once everything is lowered.
+    ///
+    /// Since the stream can't be looked ahead in, functions must be defined
+    /// before they are called, unlike with [`HfCompiler::compile_to_bytecode`].
+    /// For the same reason no optimization passes run and the entry point
+    /// can't be moved, so this fails with [`CompilerErrorKind::UnsupportedOp`]
+    /// if [`CompilerSettings::optimization_level`] is above 0 or
+    /// [`CompilerSettings::entry_offset`] is set.
+    /// [`CompilerSettings::max_code_size`] is enforced as usual.
+    pub fn compile_stream(
+        &mut self,
+        ir: impl IntoIterator<Item = IrNode>,
+    ) -> Result<Vec<u8>, CompilerError> {
+        self.compiler.compile_stream(&mut ir.into_iter())
+    }
+
+    /// Compiles to bytecode like [`HfCompiler::compile_to_bytecode`], but reuses
+    /// the code of the parts of the program that didn't change since the last
+    /// call, for editors that compile on every keystroke.
+    ///
+    /// Every top-level function is a part of its own, and so is the code
+    /// between two of them. Each part is lowered and assembled on its own, and
+    /// the parts are then laid out one after the other, with the calls and
+    /// `IndirectCall` table entries from one part to a function of another
+    /// patched. A part is reused if its IR is the same, whatever its spans,
+    /// if the cell is known to hold the same value when it is reached, and if
+    /// the names it calls still are, or aren't, functions of other parts. See
+    /// [`CompilationStats::reused_functions`].
+    ///
+    /// Only the parts of the last program are kept, and changing the settings
+    /// drops them. The code is the same as that of
+    /// [`HfCompiler::compile_to_bytecode`], except that jump tables follow the
+    /// code of their part, instead of all of the code.
+    pub fn compile_incremental(&mut self, ast: Vec<IrNode>) -> Result<Vec<u8>, CompilerError> {
+        self.compiler.compile_incremental(ast)
+    }
+
+    /// Compiles a single `Function` node to bytecode on its own, without an
+    /// entry point or any other function, for hot-patching and testing. Returns
+    /// the code along with the offset of the function's entry point in it.
+    ///
+    /// The function can only call itself and the functions nested in it, calls
+    /// to anything else fail with [`CompilerErrorKind::FunctionNotFound`].
+    pub fn compile_function(&mut self, func: IrNode) -> Result<(Vec<u8>, usize), CompilerError> {
+        self.compiler.compile_function(func)
+    }
+
+    /// Compiles to code that can be called from this very process, many
+    /// times over, see [`JitFunction`]. Needs the `jit` feature, and a target
+    /// of x86-64 with the System V calling convention, which is what the
+    /// feature builds for.
+    ///
+    /// The top-level code becomes a function of its own, which is entered
+    /// like an entry point of an object file, see
+    /// [`CompilerSettings::entry_points`]. The functions of the program are
+    /// laid out before it.
+    #[cfg(feature = "jit")]
+    pub fn jit_compile(&mut self, ast: Vec<IrNode>) -> Result<JitFunction, CompilerError> {
+        if self.target.arch != Arch::X86_64
+            || self.target.calling_convention != CallingConvention::X86_64_SystemVAMD64
+        {
+            return Err(CompilerError {
+                kind: CompilerErrorKind::UnsupportedTarget("the JIT"),
+                span: None,
+            });
+        }
+        let code = self.compiler.compile_to_callable(ast)?;
+        JitFunction::new(
+            code,
+            &self.compiler.stats().absolute_references,
+            self.base_address,
+            self.compiler.settings().stack_size,
+        )
+    }
+
+    /// Compiles to an x86-64 ELF executable that runs as is, without being
+    /// linked first: the dynamic loader named in its `PT_INTERP` loads the
+    /// shared libraries of `linking`, as `DT_NEEDED` entries, and resolves
+    /// every external the code calls before the program starts.
+    ///
+    /// The top-level code becomes a function of its own, like with
+    /// `HfCompiler::jit_compile`, which `_start` calls with a
+    /// [`CompilerSettings::static_tape`] of cells, [`DEFAULT_TAPE_SIZE`] of
+    /// them without one, and a [`CompilerSettings::stack_size`] stack, both
+    /// zero-filled by the loader. The program then ends with libc's `exit(0)`,
+    /// which flushes what stdio buffered.
+    pub fn compile_to_dynamic_executable(
+        &mut self,
+        ast: Vec<IrNode>,
+        linking: &DynamicLinking,
+    ) -> Result<Vec<u8>, CompilerError> {
+        if self.target.arch != Arch::X86_64
+            || self.target.calling_convention != CallingConvention::X86_64_SystemVAMD64
+        {
+            return Err(CompilerError {
+                kind: CompilerErrorKind::UnsupportedTarget("dynamic executables"),
+                span: None,
+            });
+        }
+        let code = self.compiler.compile_to_callable(ast)?;
+        let settings = self.compiler.settings();
+        Ok(executable::write_dynamic_executable(
+            code,
+            &self.compiler.stats().absolute_references,
+            self.base_address,
+            linking,
+            settings.static_tape.unwrap_or(DEFAULT_TAPE_SIZE),
+            settings.stack_size,
+        ))
+    }
+
     pub fn compile_to_object_file(
         &mut self,
         ast: Vec<IrNode>,
         source_filename: &str,
-    ) -> Result<object::write::Object, CompilerError> {
+    ) -> Result<object::write::Object<'static>, CompilerError> {
         self.compiler.compile_to_object_file(ast, source_filename)
     }
+
+    /// Like [`HfCompiler::compile_to_object_file`], but `FunctionCall`s to the
+    /// functions named in `externals` become relocations against undefined
+    /// symbols, for calling into separately compiled modules. Without this,
+    /// they fail with [`CompilerErrorKind::FunctionNotFound`]. Functions of the
+    /// program itself take precedence over externals of the same name.
+    ///
+    /// `IndirectCall` tables can only name functions of the program.
+    pub fn compile_to_object_file_with_externals(
+        &mut self,
+        ast: Vec<IrNode>,
+        source_filename: &str,
+        externals: &[String],
+    ) -> Result<object::write::Object<'static>, CompilerError> {
+        self.compiler
+            .compile_to_object_file_with_externals(ast, source_filename, externals)
+    }
+
+    /// Like [`HfCompiler::compile_to_object_file`], but also returns the
+    /// warnings found along the way, see [`HfCompiler::warnings`].
+    pub fn compile_with_warnings(
+        &mut self,
+        ast: Vec<IrNode>,
+        source_filename: &str,
+    ) -> Result<CompileOutput, CompilerError> {
+        let object = self.compiler.compile_to_object_file(ast, source_filename)?;
+        Ok(CompileOutput {
+            object,
+            warnings: self.compiler.warnings().to_vec(),
+        })
+    }
+
+    /// Like [`HfCompiler::compile_to_object_file`], but always writes a COFF
+    /// object, whatever [`CompilerSettings::object_format`] says, and also
+    /// returns the functions it calls, for generating an import library to
+    /// link it against. That includes the ones the lowering of I/O calls.
+    ///
+    /// External data is referenced directly rather than through an `__imp_`
+    /// pointer, so it has to be linked statically and isn't listed.
+    pub fn compile_to_coff_with_imports(
+        &mut self,
+        ast: Vec<IrNode>,
+        source_filename: &str,
+    ) -> Result<CoffOutput, CompilerError> {
+        let settings = self.compiler.settings_mut();
+        let format = core::mem::replace(&mut settings.object_format, ObjectFormat::Coff);
+        let object = self.compiler.compile_to_object_file(ast, source_filename);
+        self.compiler.settings_mut().object_format = format;
+        Ok(CoffOutput {
+            object: object?,
+            imports: self.compiler.imports(),
+        })
+    }
+
+    /// Like [`HfCompiler::compile_to_object_file`], but adds the code, symbols
+    /// and relocations to an existing x86-64 ELF or COFF object, so it can be
+    /// combined with code from elsewhere. The code is appended to
+    /// `text_section`, after anything already in it.
+    pub fn compile_into_object(
+        &mut self,
+        ast: Vec<IrNode>,
+        obj: &mut object::write::Object,
+        text_section: object::write::SectionId,
+    ) -> Result<(), CompilerError> {
+        self.compiler.compile_into_object(ast, obj, text_section)
+    }
+
+    /// Works out what [`HfCompiler::compile_to_object_file`] would emit for
+    /// `ast`, without assembling the code or writing an object file. Editors
+    /// and other tools can use it for quick feedback, as it runs the same
+    /// checks and finds the same warnings.
+    pub fn plan(&mut self, ast: Vec<IrNode>) -> Result<CompilePlan, CompilerError> {
+        self.compiler.plan(ast)
+    }
+
+    /// Statistics about the last compilation.
+    pub fn stats(&self) -> &CompilationStats {
+        self.compiler.stats()
+    }
+
+    /// Warnings about the program of the last compilation, in the order they
+    /// were found.
+    pub fn warnings(&self) -> &[CompilerWarning] {
+        self.compiler.warnings()
+    }
+
+    /// The functions the compiler knows about in every scope, for debugging
+    /// why a call fails with [`CompilerErrorKind::FunctionNotFound`]. After a
+    /// compilation fails, the scopes are left as they were at the point of
+    /// failure, so this shows what the call could have resolved to.
+    pub fn dump_scopes(&self) -> String {
+        self.compiler.dump_scopes()
+    }
+
+    /// Whether the code of the last compilation keeps to the tape and the
+    /// stack: it calls no externals, which includes all I/O, refers to no
+    /// external data and makes no system calls, not even the exit of
+    /// `_start` with [`EntryExit::LinuxExit`]. Such code can be run without
+    /// granting it anything. Calls between the functions of the program don't
+    /// count. Only meaningful after a successful compilation.
+    pub fn is_pure(&self) -> bool {
+        self.compiler.is_pure()
+    }
+}
+
+/// Compiles `ast` to bytecode for the native target and disassembles it, for
+/// snapshot tests of the generated code. Needs the `disasm` feature.
+///
+/// The output is the same on every run: functions and debug markers keep
+/// their names, every other branch target and jump table gets a label
+/// numbered in order of its address, external calls are shown by name, and
+/// a header lists the externals sorted by name. Addresses are left out, so
+/// changing one part of the code doesn't show up as a change everywhere
+/// after it.
+#[cfg(feature = "disasm")]
+pub fn compile_and_format(
+    ast: Vec<IrNode>,
+    settings: CompilerSettings,
+) -> Result<String, CompilerError> {
+    HfCompiler::new(Target::native(), settings)
+        .compiler
+        .compile_to_text(ast)
 }
 
+/// Options for [`HfCompiler`].
+///
+/// New options are added with a default that keeps the previous behaviour, so
+/// build settings from [`Default`] to stay forward compatible:
+///
+/// ```ignore
+/// let settings = CompilerSettings {
+///     optimization_level: 1,
+///     ..Default::default()
+/// };
+/// ```
+#[derive(Debug, Clone)]
 pub struct CompilerSettings {
+    /// Which optimisation passes run, see [`crate::optimizer::passes`].
+    /// Defaults to 0, which leaves the IR untouched.
     pub optimization_level: u8,
+    /// Address plain bytecode is compiled to be loaded at. Defaults to 0.
+    /// Object files are always placed by the linker.
     pub base_address: u64,
+    /// Order in which functions are laid out in the `.text` section of an
+    /// object file.
+    pub function_order: FunctionOrder,
+    /// Have optimisation passes replace the nodes they eliminate with
+    /// [`IrOp::Nop`] instead of dropping them, keeping their spans around.
+    pub preserve_spans: bool,
+    /// Upper bound on the size of the generated code in bytes. Compilation
+    /// is aborted with [`CompilerErrorKind::CodeSizeLimitExceeded`] as soon as
+    /// the code is known to be larger, so adversarial IR can't exhaust memory.
+    pub max_code_size: Option<usize>,
+    /// Count the iterations of every `Condition` loop at runtime, see
+    /// [`PROFILE_COUNTERS_SYMBOL`]. Only object files are instrumented, as
+    /// plain bytecode has nowhere to keep the counters.
+    pub profile: bool,
+    /// Reserve a zeroed tape of this many bytes in the `.bss` section of object
+    /// files, see [`TAPE_SYMBOL`], and point the cell pointer at it on entry.
+    /// The loader zero-fills `.bss`, so this costs nothing at startup.
+    /// Plain bytecode has no sections, so it is left to the caller to set up
+    /// the tape there.
+    pub static_tape: Option<usize>,
+    /// How the entry point of object files ends once the program is done.
+    pub entry_exit: EntryExit,
+    /// Where the entry point of object files takes the cell and stack pointers
+    /// from.
+    pub entry_pointers: EntryPointers,
+    /// Wrap the top-level code of object files in a `_start` function, which
+    /// then can't also be defined by the program. Turn this off for libraries
+    /// of functions that are called from elsewhere; top-level code other than
+    /// `Nop`s is then an error, as nothing would run it.
+    pub auto_entry: bool,
+    /// Where the cell pointer is kept in object files. Plain bytecode always
+    /// keeps it in the register of [`RegisterAbi::cell_pointer`].
+    pub cell_pointer: CellPointer,
+    /// Warn about loops that never end for some values of the cell, see
+    /// [`CompilerWarningKind::SuspiciousLoop`].
+    pub warn_suspicious_loops: bool,
+    /// Trap with `ud2` when a `StackPush` would write into the
+    /// [`CompilerSettings::static_tape`], instead of overwriting a cell. The
+    /// stack is set up by the host, so its own bounds are unknown and cells
+    /// running into it can't be caught. Only has an effect with a static tape.
+    pub stack_tape_guard: bool,
+    /// The name of the `STT_FILE` symbol of object files.
+    pub file_symbol: FileSymbol,
+    /// The format of object files.
+    pub object_format: ObjectFormat,
+    /// When output is flushed after `WriteByte` and `WriteBytes`.
+    pub output_flush: OutputFlush,
+    /// Keep the auxiliary stack in a region the program manages itself, and
+    /// decide what a `StackPush` does when it is full. The entry point of
+    /// object files points r9 at the empty stack, functions compiled without
+    /// [`CompilerSettings::auto_entry`] have to be called with it set up the
+    /// same way. `None` leaves the stack to the host, unchecked, and so does
+    /// plain bytecode.
+    pub stack_overflow: Option<StackOverflow>,
+    /// The size of the auxiliary stack of [`CompilerSettings::stack_overflow`]
+    /// in bytes, or the size it starts out with if it grows, and of the stack
+    /// that JIT-compiled code gets. Must not be 0.
+    pub stack_size: usize,
+    /// Top-level functions of object files that are called from C. Each gets
+    /// a wrapper, exported under the function's name, that follows the
+    /// platform's calling convention:
+    ///
+    /// ```c
+    /// uint8_t *name(uint8_t *cell_pointer, uint8_t *stack_pointer);
+    /// ```
+    ///
+    /// The wrapper runs the function on the given tape and stack, and returns
+    /// where the cell pointer ended up. With a
+    /// [`CompilerSettings::static_tape`], a null cell pointer stands for its
+    /// first cell. With a [`CompilerSettings::stack_overflow`] policy, the
+    /// program manages the stack, so the stack pointer is ignored and every
+    /// call starts out with an empty stack.
+    ///
+    /// The function itself only gets a local symbol, as it expects the cell and
+    /// stack pointers in the registers of [`CompilerSettings::register_abi`].
+    pub entry_points: Vec<String>,
+    /// How object files call external functions, including the ones the
+    /// lowering of I/O calls.
+    pub external_calls: ExternalCalls,
+    /// How the names of all symbols in object files are decorated, including
+    /// those of externals and of the data the compiler adds, like
+    /// [`TAPE_SYMBOL`].
+    pub symbol_mangling: SymbolMangling,
+    /// Externals that [`HfCompiler::compile_to_coff_with_imports`] reports as
+    /// imported by ordinal, with their ordinal. Every other one is imported
+    /// by name.
+    pub import_ordinals: Vec<(String, u16)>,
+    /// What `ReadBytes` does when fewer bytes than asked for are read at once.
+    pub short_read: ShortRead,
+    /// The names of the runtime functions the generated code calls.
+    pub runtime_abi: RuntimeAbi,
+    /// Let the generated code use SSE2, for [`optimizer::VectorAdds`] at
+    /// optimisation level 2. Every x86-64 CPU has it, but code running where
+    /// nothing saves the xmm registers, like in a kernel, must not touch them,
+    /// so it is off by default.
+    pub sse2: bool,
+    /// How many bytes of code the program is expected to compile to, if
+    /// known, e.g. from an earlier compilation. Object files reserve that much
+    /// room for their `.text` section up front, instead of growing it as code
+    /// is appended. Only a hint: the output is the same either way.
+    pub expected_code_size: Option<usize>,
+    /// Make the code work where Intel CET enforces indirect branch tracking,
+    /// which only lets indirect calls and jumps land on an `endbr64`. Every
+    /// function starts with one, as it may be called through its symbol or
+    /// an `IndirectCall` table, and so does every wrapper of an entry point
+    /// and every target of a `Switch` jump table. ELF object files get a
+    /// `.note.gnu.property` marking them as compatible, without which the
+    /// linker turns tracking off for the whole program. Objects passed to
+    /// [`HfCompiler::compile_into_object`] are left to their owner to mark.
+    ///
+    /// `endbr64` is a `nop` on CPUs without CET.
+    pub cet: bool,
+    /// Make the jump tables of `Switch` and `IndirectCall` position
+    /// independent, for PIE executables and shared objects: every entry is
+    /// the 32-bit offset of its target from the start of the table, which the
+    /// dispatch adds to the address of the table. Object files relocate the
+    /// entries with PC-relative relocations, so nothing is left for the
+    /// loader to patch and [`CompilationStats::absolute_references`] stays
+    /// empty.
+    pub pic: bool,
+    /// The registers the generated code keeps its state in, for runtimes with
+    /// a register contract of their own. Compiling fails with
+    /// [`CompilerErrorKind::RegisterConflict`] or
+    /// [`CompilerErrorKind::CallerSavedBaseRegister`] if they don't fit
+    /// together, see [`RegisterAbi::validate`].
+    pub register_abi: RegisterAbi,
+    /// Give object files a `.debug_frame` section, with the DWARF call frame
+    /// information of every function and entry point wrapper, so debuggers and
+    /// profilers can unwind through the code where `.eh_frame` isn't loaded.
+    /// It describes where the return address is as the code pushes and pops,
+    /// but not where the callee-saved registers of
+    /// [`CompilerSettings::register_abi`] are saved. Bytecode has no sections
+    /// to put it in.
+    pub debug_frame: bool,
+    /// Where in bytecode the top-level code starts, for images whose loader
+    /// jumps to a fixed address. The top-level functions are laid out first,
+    /// followed by `int3`s up to the offset, so they have to fit in front of
+    /// it. Without an offset, everything is laid out in the order it is
+    /// defined in, and the code starts with whatever comes first. Only
+    /// [`HfCompiler::compile_to_bytecode`] and the compilations built on it
+    /// lay the code out this way.
+    pub entry_offset: Option<usize>,
+    /// Called with a line for every node as it is lowered, for debugging the
+    /// lowerings themselves: the op, its span and the range of instructions
+    /// it emitted, by index, as nothing is assembled yet. For a node without
+    /// a body, the [`iced_x86::Code`] of each instruction follows. Nodes come
+    /// after their bodies, indented by how deeply they are nested. Nothing is
+    /// formatted without a sink to call.
+    pub trace: Option<TraceSink>,
+    /// Append a checksum of the code to bytecode, for firmware whose
+    /// bootloader verifies the image before jumping to it. The checksum
+    /// covers every byte before it and goes at the very end, so the image is
+    /// that much longer than the code. Object files are checked by whatever
+    /// loads them, so they don't get one.
+    pub append_checksum: Option<ChecksumKind>,
+    /// Have `compile_and_format` print the node each stretch of code was
+    /// emitted for as a comment above it, like `; Add(3)`, so a miscompile
+    /// shows next to the IR it came from. The nodes are those left after
+    /// optimisation, matched to the code by [`CompilationStats::spans`], so a
+    /// loop or function is named above the code before and after its body.
+    pub ir_comments: bool,
 }
 
+impl CompilerSettings {
+    /// Settings that give a program which runs on `target` as is: object files
+    /// in the platform's format, with a [`DEFAULT_TAPE_SIZE`] static tape and
+    /// an entry point that ends the way the platform expects. Bytecode is
+    /// based at the usual image base of executables. Everything else is the
+    /// same as [`Default`].
+    ///
+    /// Targets using a Windows calling convention get COFF, anything else ELF.
+    pub fn for_target(target: &Target) -> Self {
+        let windows = matches!(
+            target.calling_convention,
+            CallingConvention::X86_64_MicrosoftX64
+                | CallingConvention::X86_CDeclWindows
+                | CallingConvention::X86_Fastcall
+        );
+        let defaults = Self {
+            static_tape: Some(DEFAULT_TAPE_SIZE),
+            ..Default::default()
+        };
+        if windows {
+            Self {
+                base_address: 0x1_4000_0000,
+                object_format: ObjectFormat::Coff,
+                // the entry point is called like a function, returning from
+                // it ends the process
+                entry_exit: EntryExit::Return,
+                ..defaults
+            }
+        } else {
+            Self {
+                base_address: 0x40_0000,
+                ..defaults
+            }
+        }
+    }
+}
+
+/// The size of the static tape of [`CompilerSettings::for_target`], which is
+/// what most Brainfuck implementations give programs.
+pub const DEFAULT_TAPE_SIZE: usize = 30_000;
+
+/// Symbol of the `.bss` tape of object files compiled with
+/// [`CompilerSettings::static_tape`].
+pub const TAPE_SYMBOL: &str = "__hf_tape";
+
+/// The default [`CompilerSettings::stack_size`].
+pub const DEFAULT_STACK_SIZE: usize = 0x1000;
+
+/// Symbol of the `.bss` region holding the auxiliary stack of object files
+/// compiled with [`StackOverflow::Trap`] or [`StackOverflow::Wrap`].
+pub const STACK_SYMBOL: &str = "__hf_stack";
+
+/// Symbol of the bounds of the heap-allocated stack of object files compiled
+/// with [`StackOverflow::Grow`]: a little-endian `u64` address of the stack,
+/// followed by its capacity in bytes. Both start out zeroed, as nothing is
+/// allocated before the first push. [`StackOverflow::Guard`] only keeps the
+/// address of its mapping there, which is zero before the first entry.
+pub const STACK_BOUNDS_SYMBOL: &str = "__hf_stack_bounds";
+
+/// Symbol of the cell pointer slot of object files compiled with
+/// [`CellPointer::Memory`].
+pub const CELL_POINTER_SYMBOL: &str = "__hf_cell_pointer";
+
+/// Symbol of the `.hf_counters` section in object files compiled with
+/// [`CompilerSettings::profile`]. It holds a little-endian `u64` per loop,
+/// incremented on every iteration.
+pub const PROFILE_COUNTERS_SYMBOL: &str = "__hf_loop_counters";
+/// Symbol of the `.hf_counter_spans` section, which maps each counter in
+/// [`PROFILE_COUNTERS_SYMBOL`] to the loop it counts. For every counter, in the
+/// same order, it holds the line, column and length of the loop's [`Span`] as
+/// little-endian `u64`s.
+///
+/// [`Span`]: crate::ir::Span
+pub const PROFILE_SPANS_SYMBOL: &str = "__hf_loop_counter_spans";
+
 impl Default for CompilerSettings {
     fn default() -> Self {
         Self {
             optimization_level: 0,
             base_address: 0,
+            function_order: FunctionOrder::SourceOrder,
+            preserve_spans: false,
+            max_code_size: None,
+            profile: false,
+            static_tape: None,
+            entry_exit: EntryExit::LinuxExit,
+            entry_pointers: EntryPointers::Implicit,
+            auto_entry: true,
+            cell_pointer: CellPointer::Register,
+            warn_suspicious_loops: false,
+            stack_tape_guard: false,
+            file_symbol: FileSymbol::SourceFilename,
+            object_format: ObjectFormat::Elf,
+            output_flush: OutputFlush::Never,
+            stack_overflow: None,
+            stack_size: DEFAULT_STACK_SIZE,
+            entry_points: Vec::new(),
+            external_calls: ExternalCalls::DirectRel32,
+            symbol_mangling: SymbolMangling::None,
+            import_ordinals: Vec::new(),
+            short_read: ShortRead::Accept,
+            runtime_abi: RuntimeAbi::default(),
+            sse2: false,
+            expected_code_size: None,
+            cet: false,
+            pic: false,
+            register_abi: RegisterAbi::default(),
+            debug_frame: false,
+            entry_offset: None,
+            trace: None,
+            append_checksum: None,
+            ir_comments: false,
+        }
+    }
+}
+
+/// What `_start` does after the last top-level node. It is entered by the
+/// loader rather than called, so plain `ret` would crash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryExit {
+    /// Exit with status 0 through the Linux `exit` system call.
+    LinuxExit,
+    /// Spin forever with `jmp $`, for freestanding targets without anything
+    /// to exit to.
+    Spin,
+    /// Return, for runtimes that call `_start` like a function.
+    Return,
+}
+
+/// Where `_start` takes the cell and stack pointers from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryPointers {
+    /// The [`CompilerSettings::static_tape`] and the stack of
+    /// [`CompilerSettings::stack_overflow`], if there are any. Otherwise r8 and
+    /// r9 are used as the loader left them, which only suits loaders that set
+    /// them up, or that zero every register and have the memory at address 0
+    /// to spare: a Linux process starts with `argc` and `argv` on the stack.
+    Implicit,
+    /// The [`CompilerSettings::static_tape`] and the stack of
+    /// [`CompilerSettings::stack_overflow`], which have to exist, for loaders
+    /// that leave the registers undefined, like that of a Linux process.
+    /// Compiling fails otherwise, rather than using r8 and r9 as they are.
+    Static,
+    /// The first two arguments of the platform's calling convention, for
+    /// runtimes that own the memory and call `_start` like the wrappers of
+    /// [`CompilerSettings::entry_points`]:
+    ///
+    /// ```c
+    /// uint8_t *_start(uint8_t *cell_pointer, uint8_t *stack_pointer);
+    /// ```
+    ///
+    /// A null cell pointer and a managed stack are handled the same way as in
+    /// those wrappers. With [`EntryExit::Return`], `_start` returns where the
+    /// cell pointer ended up.
+    Arguments,
+}
+
+/// The format of object files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObjectFormat {
+    /// ELF, for Linux and most other Unix-like systems.
+    Elf,
+    /// COFF, for Windows.
+    Coff,
+}
+
+/// When the lowering of writes calls the `flush()` external, which the runtime
+/// has to provide unless this is [`OutputFlush::Never`]. Interactive programs
+/// seem to hang if their output sits in a buffer, while batch programs are
+/// faster without a call after every byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFlush {
+    /// After every write.
+    EveryByte,
+    /// After writing a `\n`. `WriteBytes` flushes every time, rather than
+    /// checking each byte. When the value of the cell is known at compile
+    /// time, the check is left out.
+    OnNewline,
+    /// Never, the runtime flushes when it sees fit.
+    Never,
+}
+
+/// What `ReadBytes` does when `read` returns fewer bytes than were asked for,
+/// which it may at any time for pipes and terminals, not just at the end of
+/// the input. The interpreter has all of its input at once, so either way it
+/// reads as much as there is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShortRead {
+    /// Take what was read, the remaining cells are left unchanged.
+    Accept,
+    /// Call `read` again for the rest, until every cell is filled, the input
+    /// ends or `read` fails.
+    Retry,
+}
+
+/// Where the lines of [`CompilerSettings::trace`] go: a closure, which can
+/// capture whatever it collects them in, so every compilation can be traced
+/// to a sink of its own. Clones share the closure.
+#[derive(Clone)]
+pub struct TraceSink(pub Arc<dyn Fn(&str) + Send + Sync>);
+
+impl TraceSink {
+    /// Traces to `sink`.
+    pub fn new(sink: impl Fn(&str) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(sink))
+    }
+}
+
+impl core::fmt::Debug for TraceSink {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("TraceSink(..)")
+    }
+}
+
+/// The checksum of [`CompilerSettings::append_checksum`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumKind {
+    /// The CRC-32 of zlib and Ethernet, as a little-endian `u32`.
+    Crc32,
+    /// The Adler-32 of zlib streams, as a little-endian `u32`.
+    Adler32,
+}
+
+impl ChecksumKind {
+    /// The checksum of `bytes`, as it is appended to the code.
+    pub fn checksum(self, bytes: &[u8]) -> Vec<u8> {
+        let checksum = match self {
+            Self::Crc32 => !bytes.iter().fold(!0u32, |crc, &byte| {
+                (0..8).fold(crc ^ byte as u32, |crc, _| {
+                    (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg())
+                })
+            }),
+            Self::Adler32 => {
+                let (a, b) = bytes.iter().fold((1u32, 0u32), |(a, b), &byte| {
+                    let a = (a + byte as u32) % 65521;
+                    (a, (b + a) % 65521)
+                });
+                (b << 16) | a
+            }
+        };
+        checksum.to_le_bytes().to_vec()
+    }
+}
+
+/// The runtime functions the generated code calls, by name, for runtimes that
+/// provide them under other names than libc. They are mangled like any other
+/// external, see [`SymbolMangling`], and only called where needed: a program
+/// without I/O needs neither `read` nor `write`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuntimeAbi {
+    /// `read(fd, buf, count)`, used for reading stdin into cells. Defaults to
+    /// libc's `read`.
+    pub read: String,
+    /// `write(fd, buf, count)`, used for writing cells to stdout. Defaults to
+    /// libc's `write`.
+    pub write: String,
+    /// `flush()`, see [`CompilerSettings::output_flush`]. Defaults to `flush`,
+    /// as libc has no flush without arguments.
+    pub flush: String,
+    /// `realloc(ptr, size)`, used for growing the stack with
+    /// [`StackOverflow::Grow`]. Defaults to libc's `realloc`.
+    pub allocator: String,
+}
+
+impl Default for RuntimeAbi {
+    fn default() -> Self {
+        Self {
+            read: "read".into(),
+            write: "write".into(),
+            flush: "flush".into(),
+            allocator: "realloc".into(),
+        }
+    }
+}
+
+/// How [`HfCompiler::compile_to_dynamic_executable`] has the executable
+/// linked when it is loaded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DynamicLinking {
+    /// The dynamic loader, which goes into `PT_INTERP`. Defaults to that of
+    /// x86-64 glibc, `/lib64/ld-linux-x86-64.so.2`.
+    pub interpreter: String,
+    /// The shared libraries the externals are looked up in, in order.
+    /// Defaults to libc, `libc.so.6`.
+    pub needed: Vec<String>,
+}
+
+impl Default for DynamicLinking {
+    fn default() -> Self {
+        Self {
+            interpreter: "/lib64/ld-linux-x86-64.so.2".into(),
+            needed: vec!["libc.so.6".into()],
+        }
+    }
+}
+
+/// How calls to external functions are emitted in object files. Bytecode
+/// always calls directly, as it isn't linked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExternalCalls {
+    /// `call rel32`, relocated to the function, or to a PLT stub the linker
+    /// adds for functions from shared libraries.
+    DirectRel32,
+    /// `call [rip + disp32]` through the GOT entry of the function, so no PLT
+    /// is needed and preemptible functions can be called from PIE
+    /// executables. COFF has no GOT, so the call goes through the
+    /// `__imp_<name>` pointer of the import table instead, which only exists
+    /// for functions imported from DLLs.
+    IndirectGot,
+}
+
+/// What a `StackPush` does when the auxiliary stack of
+/// [`CompilerSettings::stack_overflow`] is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackOverflow {
+    /// Trap with `ud2`. The stack is a `.bss` region of
+    /// [`CompilerSettings::stack_size`] bytes, see [`STACK_SYMBOL`]. Popping
+    /// from an empty stack isn't checked.
+    Trap,
+    /// Wrap around to the start of the region, overwriting the oldest values
+    /// like a ring buffer. Popping past the start wraps around to the end, so
+    /// r9 never leaves the region.
+    Wrap,
+    /// Grow the stack to twice its size with [`RuntimeAbi::allocator`], and
+    /// trap if that fails. The stack lives on the heap, see
+    /// [`STACK_BOUNDS_SYMBOL`].
+    Grow,
+    /// Fault with `SIGSEGV` on the push past the end, without checking
+    /// anything. The first entry maps the stack with the Linux `mmap` and
+    /// `mprotect` system calls, [`CompilerSettings::stack_size`] rounded up
+    /// to whole pages, between two pages that can't be accessed. Every push
+    /// writes the byte right after the last one, so the first push past the
+    /// end writes to the page above, and popping from an empty stack reads
+    /// from the page below. The mapping is kept across entries, see
+    /// [`STACK_BOUNDS_SYMBOL`], and traps with `ud2` if it fails. Linux only.
+    Guard,
+}
+
+/// A register for [`RegisterAbi::scratch`]. None of them can hold a pointer,
+/// see [`PointerRegister`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScratchRegister {
+    Rax,
+    Rcx,
+    Rdx,
+    Rsi,
+    Rdi,
+    R10,
+    R11,
+}
+
+impl ScratchRegister {
+    /// Whether code following `calling_convention` may overwrite the register
+    /// without saving it first. 32-bit conventions only leave eax, ecx and edx
+    /// to it, and the Microsoft x64 one also expects rsi and rdi preserved.
+    pub fn is_caller_saved(self, calling_convention: CallingConvention) -> bool {
+        match calling_convention {
+            CallingConvention::X86_64_SystemVAMD64 => true,
+            CallingConvention::X86_64_MicrosoftX64 => !matches!(self, Self::Rsi | Self::Rdi),
+            _ => matches!(self, Self::Rax | Self::Rcx | Self::Rdx),
+        }
+    }
+}
+
+/// A register for the pointers of [`RegisterAbi`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PointerRegister {
+    R8,
+    R9,
+    Rbx,
+    Rbp,
+    R12,
+    R13,
+    R14,
+    R15,
+}
+
+impl PointerRegister {
+    /// Whether functions have to preserve the register for their callers,
+    /// which all but r8 and r9 are in both x86-64 calling conventions. The
+    /// wrappers of entry points save the ones the generated code overwrites.
+    pub fn is_callee_saved(self) -> bool {
+        !matches!(self, Self::R8 | Self::R9)
+    }
+}
+
+/// The registers the generated code keeps its state in, see
+/// [`CompilerSettings::register_abi`]. The default is the contract of plain
+/// bytecode: the cell pointer in r8, the stack pointer in r9, cells passed
+/// through rax and no base register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegisterAbi {
+    /// Points at the current cell.
+    pub cell_pointer: PointerRegister,
+    /// Points at the top of the auxiliary stack, which is the byte before it
+    /// when the stack is empty.
+    pub stack_pointer: PointerRegister,
+    /// The register `StackPush`, `StackPop`, `MulConst`, `MoveCell` and
+    /// `AddFromCell` pass cells through, for hosts that need rax left alone.
+    /// It has to be caller-saved in the target's calling convention, or
+    /// compiling any of those ops fails. Other code still uses rax and rcx as
+    /// it needs, like for the result of an external.
+    pub scratch: ScratchRegister,
+    /// A register the runtime pins for itself, like a base pointer, which the
+    /// generated code never touches. It has to be callee-saved, so externals
+    /// leave it alone too.
+    pub base: Option<PointerRegister>,
+}
+
+impl Default for RegisterAbi {
+    fn default() -> Self {
+        Self {
+            cell_pointer: PointerRegister::R8,
+            stack_pointer: PointerRegister::R9,
+            scratch: ScratchRegister::Rax,
+            base: None,
+        }
+    }
+}
+
+impl RegisterAbi {
+    /// Checks that no register has two roles, and that the base register
+    /// survives calls in both calling conventions. The scratch register can't
+    /// conflict with the others, it is only checked against the calling
+    /// convention by the ops that use it, see
+    /// [`CompilerErrorKind::CalleeSavedScratchRegister`].
+    pub fn validate(&self) -> Result<(), CompilerError> {
+        let conflict = if self.cell_pointer == self.stack_pointer {
+            Some((self.cell_pointer, "cell pointer", "stack pointer"))
+        } else if self.base == Some(self.cell_pointer) {
+            Some((self.cell_pointer, "cell pointer", "base"))
+        } else if self.base == Some(self.stack_pointer) {
+            Some((self.stack_pointer, "stack pointer", "base"))
+        } else {
+            None
+        };
+        let kind = match (conflict, self.base) {
+            (Some((register, first, second)), _) => {
+                CompilerErrorKind::RegisterConflict(register, first, second)
+            }
+            (None, Some(base)) if !base.is_callee_saved() => {
+                CompilerErrorKind::CallerSavedBaseRegister(base)
+            }
+            _ => return Ok(()),
+        };
+        Err(CompilerError { kind, span: None })
+    }
+}
+
+/// What the file symbol of an object file is called. An absolute path leaks
+/// the build environment into the object, and keeps builds from being
+/// reproducible.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileSymbol {
+    /// The source filename passed to [`HfCompiler::compile_to_object_file`].
+    SourceFilename,
+    /// The given name, whatever the source filename is.
+    Named(String),
+    /// No file symbol at all.
+    Omitted,
+}
+
+/// How the names of symbols are decorated in object files. The code refers to
+/// functions by their names in the IR either way, only the symbols the
+/// linker sees change.
+#[derive(Debug, Clone, Copy)]
+pub enum SymbolMangling {
+    /// Names are used as they are, as on ELF and 64-bit Windows.
+    None,
+    /// An underscore is prepended, as Mach-O and 32-bit Windows expect of C
+    /// symbols: `_start` becomes `__start`.
+    LeadingUnderscore,
+    /// Names are passed through the given function, for other schemes.
+    Custom(fn(&str) -> String),
+}
+
+impl SymbolMangling {
+    /// The symbol name for `name`.
+    pub fn mangle(&self, name: &str) -> Vec<u8> {
+        match self {
+            Self::None => name.as_bytes().to_vec(),
+            Self::LeadingUnderscore => format!("_{name}").into_bytes(),
+            Self::Custom(mangle) => mangle(name).into_bytes(),
+        }
+    }
+}
+
+/// Where the cell pointer lives between ops.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CellPointer {
+    /// In the register of [`RegisterAbi::cell_pointer`], which is saved on the
+    /// stack around calls to externals that may clobber it.
+    Register,
+    /// In a `.bss` slot, see [`CELL_POINTER_SYMBOL`]. The register only caches
+    /// it: every move is written through to the slot, and the register is
+    /// reloaded from it after external calls instead of being saved around
+    /// them. Externals that may clobber the cell pointer are passed the
+    /// address of the slot.
+    ///
+    /// The entry point loads the cell pointer from the slot, so the host has
+    /// to initialise it, unless [`CompilerSettings::static_tape`] is set.
+    ///
+    /// Only object files have a slot. Plain bytecode, and the code built on
+    /// it like that of JIT compilation, keeps the cell pointer in the
+    /// register even with this set.
+    Memory,
+}
+
+/// Controls the layout of functions in the `.text` section of an object file.
+///
+/// The entry point `_start` is treated like any other function, it just comes
+/// last in source order.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FunctionOrder {
+    /// Functions appear in the order they are defined in.
+    SourceOrder,
+    /// Functions with the most call sites come first, for locality. Functions
+    /// with the same number of call sites keep their source order.
+    HotFirst,
+    /// The named functions come first, in the given order, followed by every
+    /// other function in source order. Names that aren't defined are ignored.
+    Explicit(Vec<String>),
+}
+
+impl FunctionOrder {
+    pub(crate) fn sort(&self, functions: &mut [IrNode]) {
+        fn name(node: &IrNode) -> &str {
+            match &node.node {
+                IrOp::Function(name, _) => name,
+                _ => "",
+            }
+        }
+
+        match self {
+            FunctionOrder::SourceOrder => {}
+            FunctionOrder::HotFirst => {
+                let mut calls = Map::new();
+                count_calls(functions, &mut calls);
+                functions
+                    .sort_by_key(|f| core::cmp::Reverse(calls.get(name(f)).copied().unwrap_or(0)));
+            }
+            FunctionOrder::Explicit(names) => {
+                functions.sort_by_key(|f| {
+                    names
+                        .iter()
+                        .position(|n| n == name(f))
+                        .unwrap_or(names.len())
+                });
+            }
+        }
+    }
+}
+
+fn count_calls(nodes: &[IrNode], calls: &mut Map<String, usize>) {
+    for node in nodes {
+        match &node.node {
+            IrOp::FunctionCall(name) => *calls.entry(name.clone()).or_default() += 1,
+            IrOp::IndirectCall { table } => {
+                for name in table {
+                    *calls.entry(name.clone()).or_default() += 1;
+                }
+            }
+            IrOp::Function(_, children)
+            | IrOp::Condition(children)
+            | IrOp::IfEq { body: children, .. } => count_calls(children, calls),
+            IrOp::Switch { arms, default } => {
+                for (_, body) in arms {
+                    count_calls(body, calls);
+                }
+                count_calls(default, calls);
+            }
+            _ => {}
         }
     }
 }
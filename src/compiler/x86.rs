@@ -1,15 +1,26 @@
+use alloc::collections::BTreeSet;
 use alloc::string::{String, ToString};
 use alloc::vec::Vec;
 
-use hashbrown::HashMap;
 use iced_x86::code_asm::{CodeLabel, *};
-use iced_x86::BlockEncoderOptions;
+use iced_x86::{BlockEncoderOptions, Decoder, DecoderOptions, Encoder, IcedError, Instruction};
 
-use super::{CompilerError, CompilerErrorKind, CompilerSettings};
-use crate::ir::{IrNode, IrOp};
+use super::{
+    verify_callee_saved, verify_cell_pointer, verify_direction_flag, verify_encoding,
+    AbsoluteReference, CallableCode, CellPointer, CompilationStats, CompilePlan, CompilerError,
+    CompilerErrorKind, CompilerSettings, CompilerWarning, CompilerWarningKind, EntryExit,
+    EntryPointers, ExternalCalls, FileSymbol, Import, ImportLookup, ObjectFormat, OutputFlush,
+    PlannedNode, PointerRegister, ScratchRegister, ShortRead, StackOverflow, TraceSink,
+    CELL_POINTER_SYMBOL, PROFILE_COUNTERS_SYMBOL, PROFILE_SPANS_SYMBOL, STACK_BOUNDS_SYMBOL,
+    STACK_SYMBOL, TAPE_SYMBOL,
+};
+use crate::ir::{IrNode, IrOp, Span};
+use crate::optimizer::{self, arithmetic_step, cell_value_after};
 use crate::scope::ScopeManager;
 use crate::target::CallingConvention;
+use crate::Map;
 
+use object::elf;
 use object::endian::Endianness;
 use object::write::{
     Architecture, BinaryFormat, Object, Relocation, RelocationEncoding, RelocationFlags,
@@ -17,12 +28,131 @@ use object::write::{
     SymbolKind, SymbolScope, SymbolSection,
 };
 
+/// Emits an instruction through the code assembler and returns early with an
+/// [`CompilerErrorKind::EncodingFailed`] if iced-x86 rejects it.
+///
+/// The trailing arguments are a `format!` string describing the instruction,
+/// which is only rendered when the error actually happens.
+///
+/// ```ignore
+/// emit!(code_asm.add(byte_ptr(r8), n as u32), origin, "add byte ptr [r8], {n:#x}");
+/// ```
+macro_rules! emit {
+    ($instruction:expr, $origin:expr, $($description:tt)+) => {
+        $instruction.map_err(|e| encoding_error(e, $origin, format!($($description)+)))?
+    };
+}
+
+/// Alignment of the code in the `.text` section of object files.
+const CODE_ALIGNMENT: usize = 16;
+/// `int3`, which the code is padded with, so that falling into the padding
+/// traps instead of running whatever the bytes decode to.
+const CODE_PADDING: u8 = 0xcc;
+
+/// The label of a jump table, the names and labels of the functions or
+/// `Switch` arms in it, and the `IndirectCall` or `Switch` it is for.
+type JumpTable = (CodeLabel, Vec<(String, CodeLabel)>, Origin);
+
+/// The 32-bit and low byte parts of a register, with their names.
+type RegisterParts = ((AsmRegister32, &'static str), (AsmRegister8, &'static str));
+
+/// The fewest distinct arm values a `Switch` dispatches through a jump table
+/// for. Below that a few compares are about as fast and smaller.
+const MIN_JUMP_TABLE_ARMS: usize = 4;
+
 pub struct Compiler {
     bitness: u32,
     calling_convention: CallingConvention,
     settings: CompilerSettings,
-    external_calls: HashMap<String, Vec<CodeLabel>>,
+    external_calls: Map<String, Vec<CodeLabel>>,
+    /// Functions defined in another module, which `FunctionCall`s in object
+    /// files are relocated against, see
+    /// [`super::HfCompiler::compile_to_object_file_with_externals`]. Unlike the
+    /// rest of the state, this outlives [`Compiler::reset`].
+    known_externals: Vec<String>,
+    /// Labels of top-level functions that can be called before they are
+    /// defined, see [`Compiler::declare_functions`].
+    declared_functions: Map<String, CodeLabel>,
+    /// Whether an object file is being compiled, which unlike plain bytecode
+    /// can have data sections for the code to refer to.
+    object_file: bool,
+    /// The calls to externals that don't clobber the cell pointer: those that
+    /// promise to preserve it, see [`crate::ir::Clobbers::cell_pointer`], and
+    /// functions of other modules, which move it like any function of the
+    /// program. Every other external call may clobber it.
+    preserving_calls: Vec<CodeLabel>,
+    /// The `inc` of every loop counter, in the order of the counters, along
+    /// with the span of the loop.
+    loop_counters: Vec<(CodeLabel, Span)>,
+    /// Every `lea` of an address in the static tape, with the offset of the
+    /// address from the start of the tape.
+    tape_references: Vec<(CodeLabel, u64)>,
+    /// The name and position of every `DebugMarker`.
+    markers: Vec<(String, CodeLabel)>,
+    /// Every instruction accessing the cell pointer slot, see
+    /// [`CellPointer::Memory`].
+    cell_pointer_references: Vec<CodeLabel>,
+    /// Every rip-relative reference to the stack region, or to its bounds with
+    /// [`StackOverflow::Grow`] and [`StackOverflow::Guard`], with the offset
+    /// into it.
+    stack_references: Vec<(CodeLabel, i64)>,
+    /// The start of every function and entry point wrapper, for
+    /// [`CompilerSettings::debug_frame`].
+    function_labels: Vec<CodeLabel>,
+    /// The name and label of the wrapper of every function in
+    /// [`CompilerSettings::entry_points`].
+    entry_wrappers: Vec<(String, CodeLabel)>,
+    /// Every load of the address of an external data symbol, see
+    /// [`IrOp::MoveToExternal`].
+    external_data: Vec<(String, CodeLabel)>,
+    /// The jump table of every `IndirectCall` and dense `Switch`. Tables are
+    /// laid out after all the code of bytecode, see [`Compiler::assemble`],
+    /// and in `.rodata` in object files.
+    jump_tables: Vec<JumpTable>,
+    /// Every `lea` of read-only data in object files, with the label of the
+    /// data, see [`Compiler::load_data_address`].
+    data_references: Vec<(CodeLabel, CodeLabel)>,
+    /// The value of the current cell at the point that is being lowered, if it
+    /// is known. See [`optimizer::cell_value_after`].
+    cell_value: Option<u8>,
+    /// The node every instruction from the given index on is emitted for,
+    /// until the next entry. Turned into [`Compiler::stats`] after assembly.
+    owners: Vec<(Option<Origin>, usize)>,
+    /// The node currently being lowered.
+    current_origin: Option<Origin>,
+    /// The byte register the value of the current cell is still in, if the
+    /// node lowered last ended by storing it from there, see
+    /// [`IrOp::Condition`].
+    cell_register: Option<(AsmRegister8, &'static str)>,
+    /// How deeply the node being lowered is nested, for
+    /// [`CompilerSettings::trace`].
+    trace_depth: usize,
+    /// Whether the code makes a system call of its own, like the exit of
+    /// `_start`, rather than through an external. After
+    /// [`Compiler::compile_incremental`], whether any part of it isn't pure.
+    /// See [`Compiler::is_pure`].
+    makes_syscalls: bool,
+    stats: CompilationStats,
+    warnings: Vec<CompilerWarning>,
     scopes: ScopeManager,
+    /// The top-level functions of the rest of the program while a part of it
+    /// is compiled on its own, see [`Compiler::compile_unit`]. Calls to them
+    /// are left for [`Compiler::compile_incremental`] to patch.
+    other_units: Option<BTreeSet<String>>,
+    /// Every name looked up in [`Compiler::other_units`], and whether it was
+    /// one of them.
+    unit_lookups: Vec<(String, bool)>,
+    /// Every call to a function of [`Compiler::other_units`], with its name.
+    unit_calls: Vec<(String, CodeLabel)>,
+    /// Every `IndirectCall` table entry for a function of
+    /// [`Compiler::other_units`]: the index of the table in
+    /// [`Compiler::jump_tables`], that of the entry in the table and the name
+    /// of the function.
+    unit_table_entries: Vec<(usize, usize, String)>,
+    /// The parts of the program [`Compiler::compile_incremental`] compiled
+    /// last, keyed by [`unit_key`]. Unlike the rest of the state, this
+    /// outlives [`Compiler::reset`], until the settings change.
+    unit_cache: Map<u64, CachedUnit>,
 }
 
 impl Compiler {
@@ -35,8 +165,163 @@ impl Compiler {
             bitness,
             calling_convention,
             settings: compiler_settings,
-            external_calls: HashMap::new(),
+            external_calls: Map::new(),
+            known_externals: Vec::new(),
+            declared_functions: Map::new(),
+            object_file: false,
+            preserving_calls: Vec::new(),
+            loop_counters: Vec::new(),
+            tape_references: Vec::new(),
+            markers: Vec::new(),
+            cell_pointer_references: Vec::new(),
+            stack_references: Vec::new(),
+            function_labels: Vec::new(),
+            entry_wrappers: Vec::new(),
+            external_data: Vec::new(),
+            jump_tables: Vec::new(),
+            data_references: Vec::new(),
+            cell_value: None,
+            owners: Vec::new(),
+            current_origin: None,
+            cell_register: None,
+            trace_depth: 0,
+            makes_syscalls: false,
+            stats: CompilationStats::default(),
+            warnings: Vec::new(),
             scopes: ScopeManager::new(),
+            other_units: None,
+            unit_lookups: Vec::new(),
+            unit_calls: Vec::new(),
+            unit_table_entries: Vec::new(),
+            unit_cache: Map::new(),
+        }
+    }
+
+    /// Clears the state left behind by a previous compilation, like
+    /// [`Compiler::reset`], and checks the registers the new one is to use.
+    fn start(&mut self) -> Result<(), CompilerError> {
+        self.reset();
+        self.settings.register_abi.validate()
+    }
+
+    /// Clears the state left behind by a previous compilation, so the same
+    /// `Compiler` can be reused for several programs.
+    fn reset(&mut self) {
+        self.external_calls.clear();
+        self.declared_functions.clear();
+        self.object_file = false;
+        self.preserving_calls.clear();
+        self.loop_counters.clear();
+        self.tape_references.clear();
+        self.markers.clear();
+        self.cell_pointer_references.clear();
+        self.stack_references.clear();
+        self.function_labels.clear();
+        self.entry_wrappers.clear();
+        self.external_data.clear();
+        self.jump_tables.clear();
+        self.data_references.clear();
+        // bytecode runs on whatever tape the host passes in, like
+        // `optimizer::DeadLoops` assumes, see `Compiler::entry_cell_value` for
+        // object files
+        self.cell_value = None;
+        self.owners.clear();
+        self.current_origin = None;
+        self.cell_register = None;
+        self.trace_depth = 0;
+        self.makes_syscalls = false;
+        self.stats = CompilationStats::default();
+        self.warnings.clear();
+        self.scopes = ScopeManager::new();
+        self.other_units = None;
+        self.unit_lookups.clear();
+        self.unit_calls.clear();
+        self.unit_table_entries.clear();
+    }
+
+    /// Appends the checksum of [`CompilerSettings::append_checksum`] to
+    /// bytecode, if there is one.
+    fn append_checksum(&self, mut code: Vec<u8>) -> Vec<u8> {
+        if let Some(kind) = self.settings.append_checksum {
+            let checksum = kind.checksum(&code);
+            code.extend_from_slice(&checksum);
+        }
+        code
+    }
+
+    /// Warns about the loops in `ir` that never run, given the value of the
+    /// cell before it, see [`optimizer::find_dead_loops`]. Returns the value of
+    /// the cell after `ir`.
+    ///
+    /// Like [`optimizer::DeadLoops`], which removes exactly these loops, this
+    /// assumes nothing about the tape the program starts on.
+    fn warn_dead_loops(&mut self, ir: &[IrNode], value: Option<u8>) -> Option<u8> {
+        let mut spans = Vec::new();
+        let value = optimizer::find_dead_loops(ir, value, &mut spans);
+        self.warnings
+            .extend(spans.into_iter().map(|span| CompilerWarning {
+                kind: CompilerWarningKind::DeadLoop,
+                span,
+            }));
+        value
+    }
+
+    /// The value of the cell when `_start` is entered: zero on a fresh tape,
+    /// unknown on a tape the host passes in with [`EntryPointers::Arguments`].
+    fn entry_cell_value(&self) -> Option<u8> {
+        match self.settings.entry_pointers {
+            EntryPointers::Arguments => None,
+            _ => Some(0),
+        }
+    }
+
+    /// The top-level functions of an object file, in the order they are laid
+    /// out, with the top-level code wrapped in `_start` if
+    /// [`CompilerSettings::auto_entry`] is set.
+    fn arrange_functions(&self, ir: Vec<IrNode>) -> Result<Vec<IrNode>, CompilerError> {
+        let (mut fn_ir, non_fn_ir): (Vec<_>, Vec<_>) = ir
+            .into_iter()
+            .partition(|node| matches!(node.node, IrOp::Function(_, _)));
+        if self.settings.auto_entry {
+            // a span can't cover several lines without knowing their lengths,
+            // so the wrapper points at where the program starts running
+            let span = non_fn_ir
+                .first()
+                .map_or(Span::from_location((0, 0)), |node| node.span);
+            if let Some(node) = fn_ir
+                .iter()
+                .find(|node| matches!(&node.node, IrOp::Function(name, _) if name == "_start"))
+            {
+                return Err(CompilerError {
+                    kind: CompilerErrorKind::ReservedFunctionName("_start".to_string()),
+                    span: Some(node.span),
+                });
+            }
+            fn_ir.push(IrNode {
+                node: IrOp::Function("_start".to_string(), non_fn_ir),
+                span,
+            });
+        } else if let Some(node) = non_fn_ir.iter().find(|n| !matches!(n.node, IrOp::Nop)) {
+            return Err(CompilerError {
+                kind: CompilerErrorKind::CodeOutsideFunction(node.node.name()),
+                span: Some(node.span),
+            });
+        }
+        self.settings.function_order.sort(&mut fn_ir);
+        Ok(fn_ir)
+    }
+
+    /// Creates labels for the top-level functions up front, so they can be
+    /// called from code that is laid out before them.
+    fn declare_functions(&mut self, code_asm: &mut CodeAssembler, ir_nodes: &[IrNode]) {
+        for node in ir_nodes {
+            if let IrOp::Function(name, _) = &node.node {
+                if !self.declared_functions.contains_key(name) {
+                    let label = code_asm.create_label();
+                    self.declared_functions.insert(name.clone(), label);
+                    self.scopes.push_fn((name.clone(), label));
+                }
+            }
         }
     }
 
@@ -55,16 +340,348 @@ impl Compiler {
     /// R8: address of the current cell
     ///     access it via `byte_ptr(r8)` aka `byte ptr[r8]`
     ///
+    /// By default, see [`CompilerSettings::register_abi`] for the others.
+    ///
+    /// # Stack
+    ///
+    /// rsp stays 16-byte aligned between ops, so externals can be called the
+    /// way the C calling conventions expect by padding what is pushed around
+    /// the call, see [`Compiler::call_frame`]. A function is called like a C
+    /// function, which leaves rsp 8 bytes off once the return address is
+    /// pushed, and moves it down by another 8 bytes until it returns.
+    /// Top-level code has to be entered with rsp aligned, which the wrappers
+    /// of entry points and `_start` take care of in object files.
+    ///
+    /// # Cells
+    ///
+    /// Every cell is a single byte, so cells have no byte order: an external
+    /// reading the cell pointer sees exactly the bytes the program computed,
+    /// one per cell. Wider cells would have to be stored in the target's byte
+    /// order, which is little-endian on x86.
+    ///
     /// TODO: we might wanna return the hashmap here
     fn translate_ir_node(
         &mut self,
         ir_node: Vec<IrNode>,
     ) -> Result<CodeAssemblerResult, CompilerError> {
+        match self.settings.entry_offset {
+            Some(offset) if !self.object_file => self.translate_with_entry_offset(ir_node, offset),
+            _ => {
+                let code_asm = self.lower(ir_node)?;
+                self.assemble(code_asm)
+            }
+        }
+    }
+
+    /// Translates bytecode with the top-level code starting `offset` bytes in,
+    /// after the top-level functions, see [`CompilerSettings::entry_offset`].
+    ///
+    /// How much room the functions take is only known once they are
+    /// assembled, and the rest of the code doesn't change that, so they are
+    /// assembled on their own first.
+    fn translate_with_entry_offset(
+        &mut self,
+        ir_node: Vec<IrNode>,
+        offset: usize,
+    ) -> Result<CodeAssemblerResult, CompilerError> {
+        let (functions, top_level): (Vec<_>, Vec<_>) = ir_node
+            .into_iter()
+            .partition(|node| matches!(node.node, IrOp::Function(_, _)));
+
+        let warnings = core::mem::take(&mut self.warnings);
+        let code_asm = self.lower(functions.clone())?;
+        let result = self.assemble(code_asm)?;
+        let size = self
+            .jump_tables
+            .first()
+            .map_or(result.inner.code_buffer.len(), |(label, _, _)| {
+                self.code_index(&result, label)
+            });
+        if size > offset {
+            return Err(CompilerError {
+                kind: CompilerErrorKind::EntryOffsetTooSmall { offset, size },
+                span: None,
+            });
+        }
+        self.reset();
+        self.warnings = warnings;
+
+        let mut code_asm = CodeAssembler::new(self.bitness).unwrap();
+        self.declare_functions(&mut code_asm, &functions);
+        for node in functions {
+            self.translate_ir_node_impl(&mut code_asm, node)?;
+        }
+        // the padding belongs to no node
+        self.owners.push((None, code_asm.instructions().len()));
+        let padding = vec![CODE_PADDING; offset - size];
+        if !padding.is_empty() {
+            code_asm.db(&padding).map_err(|e| CompilerError {
+                kind: CompilerErrorKind::AssemblerError(e.to_string()),
+                span: None,
+            })?;
+        }
+        for node in top_level {
+            self.translate_ir_node_impl(&mut code_asm, node)?;
+        }
+        self.assemble(code_asm)
+    }
+
+    /// Lowers `ir_node` to instructions, along with the entry point wrappers,
+    /// without assembling them.
+    fn lower(&mut self, ir_node: Vec<IrNode>) -> Result<CodeAssembler, CompilerError> {
         let mut code_asm = CodeAssembler::new(self.bitness).unwrap();
+        self.declare_functions(&mut code_asm, &ir_node);
+        let entry_points = if self.object_file {
+            self.entry_point_spans(&ir_node)?
+        } else {
+            Vec::new()
+        };
         for node in ir_node {
             self.translate_ir_node_impl(&mut code_asm, node)?;
         }
-        code_asm
+        for (name, span) in entry_points {
+            self.emit_entry_wrapper(&mut code_asm, name, span)?;
+        }
+        Ok(code_asm)
+    }
+
+    /// The name and span of every function in
+    /// [`CompilerSettings::entry_points`], which have to be defined at the top
+    /// level of `ir`.
+    fn entry_point_spans(&self, ir: &[IrNode]) -> Result<Vec<(String, Span)>, CompilerError> {
+        self.settings
+            .entry_points
+            .iter()
+            .map(|name| {
+                ir.iter()
+                    .find_map(|node| match &node.node {
+                        IrOp::Function(fn_name, _) if fn_name == name => {
+                            Some((name.clone(), node.span))
+                        }
+                        _ => None,
+                    })
+                    .ok_or_else(|| CompilerError {
+                        kind: CompilerErrorKind::FunctionNotFound(name.clone()),
+                        span: None,
+                    })
+            })
+            .collect()
+    }
+
+    /// Emits the C-callable wrapper of an entry point, see
+    /// [`CompilerSettings::entry_points`].
+    ///
+    /// A C function is entered with the stack 8 bytes off 16-byte alignment,
+    /// so the wrapper realigns it before calling the function, which expects
+    /// to be called that way too, see [`Compiler::translate_ir_node`]. The
+    /// Microsoft x64 convention also has the caller reserve 32 bytes of
+    /// shadow space.
+    ///
+    /// The only callee-saved registers to preserve are those of
+    /// [`CompilerSettings::register_abi`] that the code overwrites, which are
+    /// pushed first: it otherwise only uses rax, rcx, rdx, r8 to r11, along
+    /// with rdi and rsi under System V, all of which the callee may clobber in
+    /// either convention, see [`verify_callee_saved`]. Externals preserve the
+    /// rest themselves.
+    fn emit_entry_wrapper(
+        &mut self,
+        code_asm: &mut CodeAssembler,
+        name: String,
+        span: Span,
+    ) -> Result<(), CompilerError> {
+        // push rbx                     ; for every callee-saved pointer register
+        // sub rsp, 8
+        // test rdi, rdi                ; with a static tape
+        // jnz have_tape
+        // lea rdi, [rip + tape]
+        // have_tape:
+        // mov r8, rdi
+        // mov r9, rsi                  ; or set up the managed stack
+        // call function
+        // mov rax, r8
+        // add rsp, 8
+        // pop rbx
+        // ret
+        let origin = Origin {
+            op: "Function",
+            span,
+        };
+        let (cell, cell_name) = self.cell_pointer();
+        let saved = self.owned_callee_saved();
+        // the pushes count towards the alignment
+        let frame_size = match self.calling_convention {
+            CallingConvention::X86_64_SystemVAMD64 => 8,
+            CallingConvention::X86_64_MicrosoftX64 => 0x28,
+            _ => {
+                return Err(CompilerError {
+                    kind: CompilerErrorKind::UnsupportedOp("`entry_points`"),
+                    span: Some(span),
+                })
+            }
+        } - 8 * (saved.len() as i32 % 2);
+        let fn_label = self
+            .scopes
+            .get_fn(&name)
+            .expect("couldnt find function label");
+        let mut label = code_asm.create_label();
+
+        emit!(code_asm.zero_bytes(), origin, "<label anchor>");
+        emit!(code_asm.set_label(&mut label), origin, "{name}:");
+        self.function_labels.push(label);
+        self.emit_branch_target(code_asm, origin)?;
+        for &(register, register_name) in &saved {
+            emit!(code_asm.push(register), origin, "push {register_name}");
+        }
+        if frame_size > 0 {
+            emit!(
+                code_asm.sub(rsp, frame_size),
+                origin,
+                "sub rsp, {frame_size:#x}"
+            );
+        }
+        self.load_entry_arguments(code_asm, origin)?;
+        emit!(code_asm.call(fn_label), origin, "call {name}");
+        emit!(code_asm.mov(rax, cell), origin, "mov rax, {cell_name}");
+        if frame_size > 0 {
+            emit!(
+                code_asm.add(rsp, frame_size),
+                origin,
+                "add rsp, {frame_size:#x}"
+            );
+        }
+        for &(register, register_name) in saved.iter().rev() {
+            emit!(code_asm.pop(register), origin, "pop {register_name}");
+        }
+        emit!(code_asm.ret(), origin, "ret");
+        self.entry_wrappers.push((name, label));
+        Ok(())
+    }
+
+    /// Lets indirect calls and jumps land here under Intel CET, see
+    /// [`CompilerSettings::cet`].
+    fn emit_branch_target(
+        &mut self,
+        code_asm: &mut CodeAssembler,
+        origin: Origin,
+    ) -> Result<(), CompilerError> {
+        if self.settings.cet {
+            emit!(code_asm.endbr64(), origin, "endbr64");
+        }
+        Ok(())
+    }
+
+    /// Points the cell and stack pointers at the tape and stack passed as the
+    /// first two arguments, see [`EntryPointers::Arguments`].
+    fn load_entry_arguments(
+        &mut self,
+        code_asm: &mut CodeAssembler,
+        origin: Origin,
+    ) -> Result<(), CompilerError> {
+        let (cell, cell_name) = self.cell_pointer();
+        let (stack, stack_name) = self.stack_pointer();
+        let ((cell_arg, cell_arg_name), (stack_arg, stack_arg_name)) = match self.calling_convention
+        {
+            CallingConvention::X86_64_SystemVAMD64 => ((rdi, "rdi"), (rsi, "rsi")),
+            CallingConvention::X86_64_MicrosoftX64 => ((rcx, "rcx"), (rdx, "rdx")),
+            _ => {
+                return Err(CompilerError {
+                    kind: CompilerErrorKind::UnsupportedOp("`EntryPointers::Arguments`"),
+                    span: Some(origin.span),
+                })
+            }
+        };
+        if self.object_file && self.settings.static_tape.is_some() {
+            let mut have_tape_label = code_asm.create_label();
+            emit!(
+                code_asm.test(cell_arg, cell_arg),
+                origin,
+                "test {cell_arg_name}, {cell_arg_name}"
+            );
+            emit!(code_asm.jne(have_tape_label), origin, "jnz have_tape");
+            self.load_tape_address(code_asm, origin, (cell_arg, cell_arg_name), 0)?;
+            emit!(
+                code_asm.set_label(&mut have_tape_label),
+                origin,
+                "have_tape:"
+            );
+        }
+        emit!(
+            code_asm.mov(cell, cell_arg),
+            origin,
+            "mov {cell_name}, {cell_arg_name}"
+        );
+        if self.cell_pointer_in_memory() {
+            self.store_cell_pointer(code_asm, origin)?;
+        }
+        if self.stack_overflow().is_some() {
+            self.set_up_stack(code_asm, origin)?;
+        } else {
+            emit!(
+                code_asm.mov(stack, stack_arg),
+                origin,
+                "mov {stack_name}, {stack_arg_name}"
+            );
+        }
+        Ok(())
+    }
+
+    /// Aborts once `code_asm` holds more instructions than
+    /// [`CompilerSettings::max_code_size`] allows bytes. Every instruction but
+    /// the zero-length label anchors takes at least one byte, so this is a
+    /// cheap lower bound that can be checked while lowering. The exact size is
+    /// checked by [`Compiler::assemble`].
+    fn check_code_size(&self, code_asm: &CodeAssembler, span: Span) -> Result<(), CompilerError> {
+        match self.settings.max_code_size {
+            Some(limit) if code_asm.instructions().len() > limit => Err(CompilerError {
+                kind: CompilerErrorKind::CodeSizeLimitExceeded(limit),
+                span: Some(span),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Assembles the lowered code, followed by the jump tables of bytecode.
+    ///
+    /// Jump tables hold the absolute address of each function or arm, or its
+    /// offset from the table with [`CompilerSettings::pic`]. Bytecode is
+    /// loaded at [`CompilerSettings::base_address`], so they are filled in
+    /// here. Object files have them in `.rodata` instead, where the linker
+    /// relocates them, see [`Compiler::load_data_address`].
+    fn assemble(
+        &mut self,
+        mut code_asm: CodeAssembler,
+    ) -> Result<CodeAssemblerResult, CompilerError> {
+        let tables = if self.object_file {
+            &mut []
+        } else {
+            &mut self.jump_tables[..]
+        };
+        if let Some(&(_, _, origin)) = tables.first() {
+            self.owners
+                .push((Some(origin), code_asm.instructions().len()));
+        }
+        let pic = self.settings.pic;
+        for (label, entries, origin) in tables {
+            let origin = *origin;
+            emit!(code_asm.set_label(label), origin, "table:");
+            if pic {
+                emit!(
+                    code_asm.dd(&vec![0; entries.len()]),
+                    origin,
+                    "dd {} dup (0)",
+                    entries.len()
+                );
+            } else {
+                emit!(
+                    code_asm.dq(&vec![0; entries.len()]),
+                    origin,
+                    "dq {} dup (0)",
+                    entries.len()
+                );
+            }
+        }
+
+        let mut result = code_asm
             .assemble_options(
                 self.settings.base_address,
                 BlockEncoderOptions::RETURN_RELOC_INFOS
@@ -73,416 +690,3512 @@ impl Compiler {
             .map_err(|e| CompilerError {
                 kind: super::CompilerErrorKind::AssemblerError(e.to_string()),
                 span: None,
+            })?;
+        match self.settings.max_code_size {
+            Some(limit) if result.inner.code_buffer.len() > limit => Err(CompilerError {
+                kind: CompilerErrorKind::CodeSizeLimitExceeded(limit),
+                span: None,
+            }),
+            _ => {
+                if !self.object_file {
+                    self.fill_jump_tables(&mut result);
+                    self.debug_verify_encoding(&result)?;
+                }
+                self.collect_stats(&result);
+                Ok(result)
+            }
+        }
+    }
+
+    /// Checks the code in `result` with [`verify_encoding`] in debug builds, to
+    /// catch encoder bugs and bytes patched in the wrong place, with
+    /// [`verify_callee_saved`] and [`verify_direction_flag`], to catch the
+    /// calling convention being broken, and with [`verify_cell_pointer`]
+    /// unless the cell pointer is kept in memory, to catch its register being
+    /// clobbered.
+    fn debug_verify_encoding(&self, result: &CodeAssemblerResult) -> Result<(), CompilerError> {
+        if !cfg!(debug_assertions) {
+            return Ok(());
+        }
+        let code = &result.inner.code_buffer;
+        let code_end = self
+            .jump_tables
+            .first()
+            .filter(|_| !self.object_file)
+            .map_or(code.len(), |(label, _, _)| self.code_index(result, label));
+        verify_encoding(&code[..code_end], self.bitness)?;
+        let owned = self
+            .owned_callee_saved()
+            .into_iter()
+            .map(|(register, _)| register.into())
+            .collect::<Vec<_>>();
+        verify_callee_saved(&code[..code_end], self.bitness, &owned)?;
+        verify_direction_flag(&code[..code_end], self.bitness)?;
+        if self.cell_pointer_in_memory() {
+            return Ok(());
+        }
+        let clobbering_calls = self
+            .external_calls
+            .values()
+            .flatten()
+            .filter(|label| !self.preserving_calls.contains(label))
+            .map(|label| self.code_index(result, label))
+            .collect::<Vec<_>>();
+        let (cell, _) = self.cell_pointer();
+        verify_cell_pointer(
+            &code[..code_end],
+            self.bitness,
+            cell.into(),
+            &clobbering_calls,
+        )
+    }
+
+    /// Writes the address of every function or arm into the jump tables, or
+    /// its offset from the table with [`CompilerSettings::pic`].
+    fn fill_jump_tables(&self, result: &mut CodeAssemblerResult) {
+        let size = self.jump_table_entry_size();
+        for (label, entries, _span) in &self.jump_tables {
+            let index = self.code_index(result, label);
+            let table = result.label_ip(label).expect("couldnt find label ip");
+            for (i, (_name, entry)) in entries.iter().enumerate() {
+                let address = result.label_ip(entry).expect("couldnt find label ip");
+                let slot = &mut result.inner.code_buffer[index + i * size..][..size];
+                if self.settings.pic {
+                    let offset = address.wrapping_sub(table) as i32;
+                    slot.copy_from_slice(&offset.to_le_bytes());
+                } else {
+                    slot.copy_from_slice(&address.to_le_bytes());
+                }
+            }
+        }
+    }
+
+    /// Attributes the assembled code to the nodes it was emitted for.
+    fn collect_stats(&mut self, result: &CodeAssemblerResult) {
+        let code_size = result.inner.code_buffer.len();
+        let offsets = &result.inner.new_instruction_offsets;
+        let offset = |index: usize| match offsets.get(index) {
+            Some(&offset) => offset as usize,
+            None => code_size,
+        };
+
+        self.stats.code_size = code_size;
+        // object files have the tables in `.rodata`, one after the other
+        let mut table_offset = 0;
+        self.stats.absolute_references = self
+            .jump_tables
+            .iter()
+            .filter(|_| !self.settings.pic)
+            .flat_map(|(label, entries, origin)| {
+                let index = if self.object_file {
+                    table_offset
+                } else {
+                    self.code_index(result, label)
+                };
+                table_offset += entries.len() * 8;
+                entries
+                    .iter()
+                    .enumerate()
+                    .map(move |(i, (name, _label))| AbsoluteReference {
+                        offset: index + i * 8,
+                        target: name.clone(),
+                        span: origin.span,
+                    })
             })
+            .collect();
+        self.stats.markers = self
+            .markers
+            .iter()
+            .map(|(name, label)| (name.clone(), self.code_index(result, label)))
+            .collect();
+        for (i, &(owner, start)) in self.owners.iter().enumerate() {
+            let end = self
+                .owners
+                .get(i + 1)
+                .map_or(offsets.len(), |&(_, end)| end);
+            if let Some(origin) = owner {
+                let bytes = offset(end) - offset(start);
+                self.stats.ops.entry(origin.op).or_default().bytes += bytes;
+                if bytes > 0 {
+                    self.stats.spans.push((origin.span, offset(start)));
+                }
+            }
+        }
     }
 
     fn translate_function_impl(
         &mut self,
         code_asm: &mut CodeAssembler,
         name: String,
-        span: crate::ir::Span,
+        span: Span,
         children: Vec<IrNode>,
     ) -> Result<(), CompilerError> {
-        let mut fn_label = code_asm.create_label();
+        let (cell, cell_name) = self.cell_pointer();
+        let origin = Origin {
+            op: "Function",
+            span,
+        };
+        let top_level = self.scopes.get_top_scope_name().is_none();
+        let entry = self.object_file && top_level && name == "_start";
+        let declared = if top_level {
+            self.declared_functions.remove(&name)
+        } else {
+            None
+        };
+        let mut fn_label = declared.unwrap_or_else(|| code_asm.create_label());
 
-        code_asm.zero_bytes().map_err(|e| CompilerError {
-            kind: super::CompilerErrorKind::AssemblerError(e.to_string()),
-            span: Some(span),
-        })?;
+        emit!(code_asm.zero_bytes(), origin, "<label anchor>");
 
-        code_asm
-            .set_label(&mut fn_label)
-            .map_err(|e| CompilerError {
-                kind: super::CompilerErrorKind::AssemblerError(e.to_string()),
-                span: Some(span),
-            })?;
+        emit!(code_asm.set_label(&mut fn_label), origin, "{name}:");
+        self.function_labels.push(fn_label);
+        self.emit_branch_target(code_asm, origin)?;
+        let from_arguments = self.settings.entry_pointers == EntryPointers::Arguments;
+        if entry && self.settings.entry_pointers == EntryPointers::Static {
+            let kind = if self.settings.static_tape.is_none() {
+                Some(CompilerErrorKind::RequiresStaticTape("_start"))
+            } else if self.stack_overflow().is_none() {
+                Some(CompilerErrorKind::RequiresStackRegion("_start"))
+            } else {
+                None
+            };
+            if let Some(kind) = kind {
+                return Err(CompilerError {
+                    kind,
+                    span: Some(span),
+                });
+            }
+        }
+        // the call of a function pushed the return address, so the stack is
+        // padded until it is aligned again, see `Compiler::translate_ir_node`.
+        // The Linux loader jumps to `_start` with it aligned already, but a
+        // runtime that calls `_start` like a function also expects its
+        // callee-saved registers back.
+        let called = !entry || self.settings.entry_exit == EntryExit::Return;
+        let saved = if entry && called {
+            self.owned_callee_saved()
+        } else {
+            Vec::new()
+        };
+        let padding = 8 * ((saved.len() + called as usize) % 2) as i32;
+        for &(register, register_name) in &saved {
+            emit!(code_asm.push(register), origin, "push {register_name}");
+        }
+        if padding > 0 {
+            emit!(code_asm.sub(rsp, padding), origin, "sub rsp, {padding}");
+        }
+        if entry && from_arguments {
+            self.load_entry_arguments(code_asm, origin)?;
+        } else if entry && self.settings.static_tape.is_some() {
+            self.load_tape_address(code_asm, origin, (cell, cell_name), 0)?;
+            if self.cell_pointer_in_memory() {
+                self.store_cell_pointer(code_asm, origin)?;
+            }
+        } else if entry && self.cell_pointer_in_memory() {
+            self.load_cell_pointer(code_asm, origin)?;
+        }
+        if entry && !from_arguments {
+            self.set_up_stack(code_asm, origin)?;
+        }
         self.scopes.push_fn((name.clone(), fn_label));
         self.scopes.push_scope(name.clone());
+        // functions can be called from anywhere, except for the entry point
+        self.cell_value = if entry { self.entry_cell_value() } else { None };
         for fn_ir_node in children {
             self.translate_ir_node_impl(code_asm, fn_ir_node)?;
         }
         self.scopes.pop_scope();
-        code_asm.ret().map_err(|e| CompilerError {
-            kind: super::CompilerErrorKind::AssemblerError(e.to_string()),
-            span: Some(span),
-        })?;
+        match self.settings.entry_exit {
+            EntryExit::LinuxExit if entry => {
+                emit!(code_asm.mov(eax, 60u32), origin, "mov eax, 60");
+                emit!(code_asm.xor(edi, edi), origin, "xor edi, edi");
+                emit!(code_asm.syscall(), origin, "syscall");
+                self.makes_syscalls = true;
+            }
+            EntryExit::Spin if entry => {
+                emit!(code_asm.zero_bytes(), origin, "<label anchor>");
+                let mut spin_label = code_asm.create_label();
+                emit!(code_asm.set_label(&mut spin_label), origin, "spin:");
+                emit!(code_asm.jmp(spin_label), origin, "jmp spin");
+            }
+            _ => {
+                if entry && from_arguments {
+                    emit!(code_asm.mov(rax, cell), origin, "mov rax, {cell_name}");
+                }
+                if padding > 0 {
+                    emit!(code_asm.add(rsp, padding), origin, "add rsp, {padding}");
+                }
+                for &(register, register_name) in saved.iter().rev() {
+                    emit!(code_asm.pop(register), origin, "pop {register_name}");
+                }
+                emit!(code_asm.ret(), origin, "ret");
+            }
+        }
 
         Ok(())
     }
 
-    fn translate_ir_node_impl(
+    /// Lowers an [`IrOp::AddToCells`]: 16 cells at a time with SSE2, the rest
+    /// one by one.
+    ///
+    /// Only xmm0 and xmm1 are used, which are volatile in both calling
+    /// conventions, so there is nothing to save.
+    fn translate_add_to_cells(
         &mut self,
         code_asm: &mut CodeAssembler,
-        ir_node: IrNode,
+        origin: Origin,
+        offset: i32,
+        count: u32,
+        amount: u8,
     ) -> Result<(), CompilerError> {
-        match ir_node.node {
-            IrOp::Add(n) => {
-                let mut rem = n;
-                while rem > 255 {
-                    rem -= 255;
-                    code_asm
-                        // add byte ptr[r8], n
-                        .add(byte_ptr(r8), 255 as u32)
-                        .map_err(|e| CompilerError {
-                            kind: super::CompilerErrorKind::AssemblerError(e.to_string()),
-                            span: Some(ir_node.span),
-                        })?;
-                }
-                code_asm
-                    // add byte ptr[r8], n
-                    .add(byte_ptr(r8), rem as u32)
-                    .map_err(|e| CompilerError {
-                        kind: super::CompilerErrorKind::AssemblerError(e.to_string()),
-                        span: Some(ir_node.span),
-                    })?;
-            }
-            IrOp::Subtract(n) => {
-                code_asm
-                    // sub byte ptr[r8], n
-                    .sub(byte_ptr(r8), n as u32)
-                    .map_err(|e| CompilerError {
-                        kind: super::CompilerErrorKind::AssemblerError(e.to_string()),
-                        span: Some(ir_node.span),
-                    })?;
-            }
-            IrOp::MoveRight(n) => {
-                if n > 0x7FFFFFFF {
-                    return Err(CompilerError {
-                        kind: super::CompilerErrorKind::MoveTooLarge(n as u32),
-                        span: Some(ir_node.span),
-                    });
-                }
-                code_asm
-                    // lea r8, [r8 + n]
-                    .lea(r8, dword_ptr(r8 + n as u32))
-                    .map_err(|e| CompilerError {
-                        kind: super::CompilerErrorKind::AssemblerError(e.to_string()),
-                        span: Some(ir_node.span),
-                    })?;
-            }
-            IrOp::MoveLeft(n) => {
-                if n > 0x7FFFFFFF {
-                    return Err(CompilerError {
-                        kind: super::CompilerErrorKind::MoveTooLarge(n as u32),
-                        span: Some(ir_node.span),
-                    });
-                }
-                code_asm
-                    // lea r8, [r8 - n]
-                    .lea(r8, dword_ptr(r8 - n as u32))
-                    .map_err(|e| CompilerError {
-                        kind: super::CompilerErrorKind::AssemblerError(e.to_string()),
-                        span: Some(ir_node.span),
-                    })?;
-            }
-            IrOp::StackPush => {
-                code_asm
-                    .lea(r9, dword_ptr(r9 + 1))
-                    .map_err(|e| CompilerError {
-                        kind: super::CompilerErrorKind::AssemblerError(e.to_string()),
-                        span: Some(ir_node.span),
-                    })?;
-                code_asm.mov(al, byte_ptr(r8)).map_err(|e| CompilerError {
-                    kind: super::CompilerErrorKind::AssemblerError(e.to_string()),
-                    span: Some(ir_node.span),
-                })?;
-                code_asm.mov(byte_ptr(r9), al).map_err(|e| CompilerError {
-                    kind: super::CompilerErrorKind::AssemblerError(e.to_string()),
-                    span: Some(ir_node.span),
-                })?;
-            }
-            IrOp::StackPop => {
-                code_asm.mov(al, byte_ptr(r9)).map_err(|e| CompilerError {
-                    kind: super::CompilerErrorKind::AssemblerError(e.to_string()),
-                    span: Some(ir_node.span),
-                })?;
-                code_asm.mov(byte_ptr(r8), al).map_err(|e| CompilerError {
-                    kind: super::CompilerErrorKind::AssemblerError(e.to_string()),
-                    span: Some(ir_node.span),
-                })?;
-                code_asm
-                    .lea(r9, dword_ptr(r9 - 1))
-                    .map_err(|e| CompilerError {
-                        kind: super::CompilerErrorKind::AssemblerError(e.to_string()),
-                        span: Some(ir_node.span),
-                    })?;
-            }
-            // equivalent:
-            //
-            // while *r8 != 0 {
-            //    // code
-            // }
-            //
-            // or in assembly:
-            //
-            // start_label:
-            //    cmp byte ptr[r8], 0
-            //    je end_label
-            //    ... ; code
-            //    jmp start_label
-            // end_label:
-            //
-            IrOp::Condition(cond_ir_nodes) => {
-                let mut start_label = code_asm.create_label();
-                let mut end_label = code_asm.create_label();
+        let (cell, cell_name) = self.cell_pointer();
+        // mov eax, amount * 0x01010101
+        // movd xmm1, eax
+        // pshufd xmm1, xmm1, 0         ; amount in every byte
+        // movdqu xmm0, [r8 + offset]   ; for every 16 cells
+        // paddb xmm0, xmm1
+        // movdqu [r8 + offset], xmm0
+        // add byte ptr [r8 + offset], amount   ; for the rest
+        if amount == 0 || count == 0 {
+            return Ok(());
+        }
+        if i32::try_from(i64::from(offset) + i64::from(count)).is_err() {
+            return Err(CompilerError {
+                kind: CompilerErrorKind::MoveTooLarge {
+                    count: count as usize,
+                    limit: i32::MAX as usize,
+                },
+                span: Some(origin.span),
+            });
+        }
+        let vectors = count / 16;
+        if vectors > 0 {
+            let splat = u32::from(amount) * 0x0101_0101;
+            emit!(code_asm.mov(eax, splat), origin, "mov eax, {splat:#x}");
+            emit!(code_asm.movd(xmm1, eax), origin, "movd xmm1, eax");
+            emit!(
+                code_asm.pshufd(xmm1, xmm1, 0),
+                origin,
+                "pshufd xmm1, xmm1, 0"
+            );
+        }
+        for vector in 0..vectors {
+            let offset = offset + 16 * vector as i32;
+            emit!(
+                code_asm.movdqu(xmm0, xmmword_ptr(cell + offset)),
+                origin,
+                "movdqu xmm0, [{cell_name} + {offset:#x}]"
+            );
+            emit!(code_asm.paddb(xmm0, xmm1), origin, "paddb xmm0, xmm1");
+            emit!(
+                code_asm.movdqu(xmmword_ptr(cell + offset), xmm0),
+                origin,
+                "movdqu [{cell_name} + {offset:#x}], xmm0"
+            );
+        }
+        for i in vectors * 16..count {
+            let offset = offset + i as i32;
+            emit!(
+                code_asm.add(byte_ptr(cell + offset), amount as u32),
+                origin,
+                "add byte ptr [{cell_name} + {offset:#x}], {amount:#x}"
+            );
+        }
+        Ok(())
+    }
 
-                // phantom instruction so we have an address
-                code_asm.zero_bytes().map_err(|e| CompilerError {
-                    kind: super::CompilerErrorKind::AssemblerError(e.to_string()),
-                    span: Some(ir_node.span),
-                })?;
-
-                code_asm
-                    .set_label(&mut start_label)
-                    .map_err(|e| CompilerError {
-                        kind: super::CompilerErrorKind::AssemblerError(e.to_string()),
-                        span: Some(ir_node.span),
-                    })?;
-
-                code_asm.cmp(byte_ptr(r8), 0).map_err(|e| CompilerError {
-                    kind: super::CompilerErrorKind::AssemblerError(e.to_string()),
-                    span: Some(ir_node.span),
-                })?;
-                code_asm.je(end_label).map_err(|e| CompilerError {
-                    kind: super::CompilerErrorKind::AssemblerError(e.to_string()),
-                    span: Some(ir_node.span),
-                })?;
-
-                let scope_name = format!(
-                    "{};{}",
-                    self.scopes.get_top_scope_name().unwrap_or(String::new()),
-                    self.scopes.next_unnamed_scope_number()
-                );
-                self.scopes.push_scope(scope_name);
-                for cond_ir_node in cond_ir_nodes {
-                    self.translate_ir_node_impl(code_asm, cond_ir_node)?;
-                }
-                self.scopes.pop_scope();
+    /// Whether the register only caches the cell pointer, which is kept in
+    /// memory.
+    fn cell_pointer_in_memory(&self) -> bool {
+        self.object_file && self.settings.cell_pointer == CellPointer::Memory
+    }
 
-                code_asm.jmp(start_label).map_err(|e| CompilerError {
-                    kind: super::CompilerErrorKind::AssemblerError(e.to_string()),
-                    span: Some(ir_node.span),
-                })?;
+    /// Emits an instruction accessing the cell pointer slot through the given
+    /// label, which the instruction must be labeled with. Its displacement is
+    /// relocated to the slot.
+    fn cell_pointer_reference(
+        &mut self,
+        code_asm: &mut CodeAssembler,
+        origin: Origin,
+        instruction: impl FnOnce(&mut CodeAssembler, CodeLabel) -> Result<(), IcedError>,
+        description: &str,
+    ) -> Result<(), CompilerError> {
+        let label = self.rip_relative_reference(
+            code_asm,
+            origin,
+            "cell_pointer",
+            instruction,
+            description,
+        )?;
+        self.cell_pointer_references.push(label);
+        Ok(())
+    }
 
-                code_asm
-                    .set_label(&mut end_label)
-                    .map_err(|e| CompilerError {
-                        kind: super::CompilerErrorKind::AssemblerError(e.to_string()),
-                        span: Some(ir_node.span),
-                    })?;
+    /// Loads the cell pointer register from its slot.
+    fn load_cell_pointer(
+        &mut self,
+        code_asm: &mut CodeAssembler,
+        origin: Origin,
+    ) -> Result<(), CompilerError> {
+        let (cell, cell_name) = self.cell_pointer();
+        self.cell_pointer_reference(
+            code_asm,
+            origin,
+            |code_asm, slot| code_asm.mov(cell, qword_ptr(slot)),
+            &format!("mov {cell_name}, [rip + cell_pointer]"),
+        )
+    }
 
-                // phantom instruction so we have an address
-                code_asm.zero_bytes().map_err(|e| CompilerError {
-                    kind: super::CompilerErrorKind::AssemblerError(e.to_string()),
-                    span: Some(ir_node.span),
-                })?;
-            }
-            IrOp::Function(name, fn_ir_nodes) => {
-                self.translate_function_impl(code_asm, name, ir_node.span, fn_ir_nodes)?;
-            }
-            IrOp::FunctionCall(name) => {
-                let fn_label = self.scopes.get_fn(&name).ok_or(CompilerError {
-                    kind: CompilerErrorKind::FunctionNotFound(name),
-                    span: Some(ir_node.span),
-                })?;
-                code_asm.call(fn_label).map_err(|e| CompilerError {
-                    kind: super::CompilerErrorKind::AssemblerError(e.to_string()),
-                    span: Some(ir_node.span),
-                })?;
-            }
-            IrOp::ExternalFunctionCall(name) => {
-                let mut label = code_asm.create_label();
-                code_asm.zero_bytes().map_err(|e| CompilerError {
-                    kind: super::CompilerErrorKind::AssemblerError(e.to_string()),
-                    span: Some(ir_node.span),
-                })?;
-                // calling convention specific setup for the call
-                match self.calling_convention {
-                    CallingConvention::X86_64_SystemVAMD64 => {
-                        // push r8 and r9 on the stack, then put the
-                        // address of each stack element in rdi and rsi
-                        code_asm.push(r8).map_err(|e| CompilerError {
-                            kind: super::CompilerErrorKind::AssemblerError(e.to_string()),
-                            span: Some(ir_node.span),
-                        })?;
-                        code_asm.push(r9).map_err(|e| CompilerError {
-                            kind: super::CompilerErrorKind::AssemblerError(e.to_string()),
-                            span: Some(ir_node.span),
-                        })?;
-                        code_asm
-                            .lea(rdi, qword_ptr(rsp + 8))
-                            .map_err(|e| CompilerError {
-                                kind: super::CompilerErrorKind::AssemblerError(e.to_string()),
-                                span: Some(ir_node.span),
-                            })?;
-                        code_asm
-                            .lea(rsi, qword_ptr(rsp))
-                            .map_err(|e| CompilerError {
-                                kind: super::CompilerErrorKind::AssemblerError(e.to_string()),
-                                span: Some(ir_node.span),
-                            })?;
-                    }
-                    CallingConvention::X86_64_MicrosoftX64 => {
-                        code_asm.push(r8).map_err(|e| CompilerError {
-                            kind: super::CompilerErrorKind::AssemblerError(e.to_string()),
-                            span: Some(ir_node.span),
-                        })?;
-                        code_asm.push(r9).map_err(|e| CompilerError {
-                            kind: super::CompilerErrorKind::AssemblerError(e.to_string()),
-                            span: Some(ir_node.span),
-                        })?;
-                        code_asm
-                            .lea(rcx, qword_ptr(rsp + 8))
-                            .map_err(|e| CompilerError {
-                                kind: super::CompilerErrorKind::AssemblerError(e.to_string()),
-                                span: Some(ir_node.span),
-                            })?;
-                        code_asm
-                            .lea(rdx, qword_ptr(rsp))
-                            .map_err(|e| CompilerError {
-                                kind: super::CompilerErrorKind::AssemblerError(e.to_string()),
-                                span: Some(ir_node.span),
-                            })?;
-                    }
-                    _ => todo!(),
-                }
-                // call
-                code_asm.set_label(&mut label).map_err(|e| CompilerError {
-                    kind: super::CompilerErrorKind::AssemblerError(e.to_string()),
-                    span: Some(ir_node.span),
-                })?;
-                self.add_external_call(name, label);
-                code_asm.call(label).map_err(|e| CompilerError {
-                    kind: super::CompilerErrorKind::AssemblerError(e.to_string()),
-                    span: Some(ir_node.span),
-                })?;
-                // calling convention specific cleanup for the call
-                match self.calling_convention {
-                    CallingConvention::X86_64_SystemVAMD64 => {
-                        code_asm.pop(r9).map_err(|e| CompilerError {
-                            kind: super::CompilerErrorKind::AssemblerError(e.to_string()),
-                            span: Some(ir_node.span),
-                        })?;
-                        code_asm.pop(r8).map_err(|e| CompilerError {
-                            kind: super::CompilerErrorKind::AssemblerError(e.to_string()),
-                            span: Some(ir_node.span),
-                        })?;
-                    }
-                    CallingConvention::X86_64_MicrosoftX64 => {
-                        code_asm.pop(r9).map_err(|e| CompilerError {
-                            kind: super::CompilerErrorKind::AssemblerError(e.to_string()),
-                            span: Some(ir_node.span),
-                        })?;
-                        code_asm.pop(r8).map_err(|e| CompilerError {
-                            kind: super::CompilerErrorKind::AssemblerError(e.to_string()),
-                            span: Some(ir_node.span),
-                        })?;
-                    }
-                    _ => todo!(),
-                }
-            }
-            _ => todo!(),
+    /// Writes the cell pointer register through to its slot.
+    fn store_cell_pointer(
+        &mut self,
+        code_asm: &mut CodeAssembler,
+        origin: Origin,
+    ) -> Result<(), CompilerError> {
+        let (cell, cell_name) = self.cell_pointer();
+        self.cell_pointer_reference(
+            code_asm,
+            origin,
+            |code_asm, slot| code_asm.mov(qword_ptr(slot), cell),
+            &format!("mov [rip + cell_pointer], {cell_name}"),
+        )
+    }
+
+    /// Emits `lea reg, [rip + tape + offset]`, whose displacement is relocated
+    /// to the static tape.
+    fn load_tape_address(
+        &mut self,
+        code_asm: &mut CodeAssembler,
+        origin: Origin,
+        (reg, reg_name): (AsmRegister64, &str),
+        offset: u64,
+    ) -> Result<(), CompilerError> {
+        let label = self.rip_relative_reference(
+            code_asm,
+            origin,
+            "tape",
+            |code_asm, label| code_asm.lea(reg, qword_ptr(label)),
+            &format!("lea {reg_name}, [rip + tape + {offset:#x}]"),
+        )?;
+        self.tape_references.push((label, offset));
+        Ok(())
+    }
+
+    /// Emits `lea reg, [rip + data]`, the address of read-only data the code
+    /// refers to, like a jump table, which is labeled `data`. Bytecode has the
+    /// data laid out after the code, see [`Compiler::assemble`], where the
+    /// label is set. Object files keep it in `.rodata` instead, so the
+    /// displacement is relocated there, see [`Compiler::data_references`].
+    fn load_data_address(
+        &mut self,
+        code_asm: &mut CodeAssembler,
+        origin: Origin,
+        (reg, reg_name): (AsmRegister64, &str),
+        (data, data_name): (CodeLabel, &str),
+    ) -> Result<(), CompilerError> {
+        if !self.object_file {
+            emit!(
+                code_asm.lea(reg, qword_ptr(data)),
+                origin,
+                "lea {reg_name}, [rip + {data_name}]"
+            );
+            return Ok(());
         }
+        let label = self.rip_relative_reference(
+            code_asm,
+            origin,
+            data_name,
+            |code_asm, label| code_asm.lea(reg, qword_ptr(label)),
+            &format!("lea {reg_name}, [rip + {data_name}]"),
+        )?;
+        self.data_references.push((label, data));
         Ok(())
     }
-}
 
-impl super::CompilerTrait for Compiler {
-    fn compile_to_bytecode(&mut self, ir: Vec<IrNode>) -> Result<Vec<u8>, CompilerError> {
-        Ok(self.translate_ir_node(ir)?.inner.code_buffer)
+    /// The size of an entry of a jump table: an absolute address, or the
+    /// 32-bit offset of [`CompilerSettings::pic`].
+    fn jump_table_entry_size(&self) -> usize {
+        if self.settings.pic {
+            4
+        } else {
+            8
+        }
     }
 
-    fn compile_to_object_file(
+    /// Jumps to, or calls, what entry `rax` of the jump table labeled `table`
+    /// points at.
+    fn branch_through_table(
         &mut self,
-        ast: Vec<IrNode>,
-        filename: &str,
-    ) -> Result<Object, CompilerError> {
-        let mut obj = Object::new(BinaryFormat::Elf, Architecture::X86_64, Endianness::Little);
-        obj.add_file_symbol(filename.as_bytes().to_vec());
+        code_asm: &mut CodeAssembler,
+        origin: Origin,
+        table: CodeLabel,
+        call: bool,
+    ) -> Result<(), CompilerError> {
+        // lea rcx, [rip + table]
+        // jmp qword ptr [rcx + rax * 8]            ; or call
+        //
+        // with `pic`:
+        // lea rcx, [rip + table]
+        // movsxd rax, dword ptr [rcx + rax * 4]
+        // add rax, rcx
+        // jmp rax                                  ; or call
+        self.load_data_address(code_asm, origin, (rcx, "rcx"), (table, "table"))?;
+        if !self.settings.pic {
+            if call {
+                emit!(
+                    code_asm.call(qword_ptr(rcx + rax * 8)),
+                    origin,
+                    "call qword ptr [rcx + rax * 8]"
+                );
+            } else {
+                emit!(
+                    code_asm.jmp(qword_ptr(rcx + rax * 8)),
+                    origin,
+                    "jmp qword ptr [rcx + rax * 8]"
+                );
+            }
+            return Ok(());
+        }
+        emit!(
+            code_asm.movsxd(rax, dword_ptr(rcx + rax * 4)),
+            origin,
+            "movsxd rax, dword ptr [rcx + rax * 4]"
+        );
+        emit!(code_asm.add(rax, rcx), origin, "add rax, rcx");
+        if call {
+            emit!(code_asm.call(rax), origin, "call rax");
+        } else {
+            emit!(code_asm.jmp(rax), origin, "jmp rax");
+        }
+        Ok(())
+    }
 
+    /// Emits an instruction with a `[rip + disp32]` operand, which refers to
+    /// the label the instruction is labeled with until its displacement is
+    /// relocated to whatever it is named after. Returns the label, for the
+    /// caller to record what that is.
+    fn rip_relative_reference(
+        &mut self,
+        code_asm: &mut CodeAssembler,
+        origin: Origin,
+        name: &str,
+        instruction: impl FnOnce(&mut CodeAssembler, CodeLabel) -> Result<(), IcedError>,
+        description: &str,
+    ) -> Result<CodeLabel, CompilerError> {
+        emit!(code_asm.zero_bytes(), origin, "<label anchor>");
+        let mut label = code_asm.create_label();
+        emit!(code_asm.set_label(&mut label), origin, "{name}:");
+        emit!(instruction(code_asm, label), origin, "{description}");
+        Ok(label)
+    }
+
+    /// The register of [`super::RegisterAbi::cell_pointer`], with its name.
+    fn cell_pointer(&self) -> (AsmRegister64, &'static str) {
+        pointer_register(self.settings.register_abi.cell_pointer)
+    }
+
+    /// The register of [`super::RegisterAbi::stack_pointer`], with its name.
+    fn stack_pointer(&self) -> (AsmRegister64, &'static str) {
+        pointer_register(self.settings.register_abi.stack_pointer)
+    }
+
+    /// The callee-saved registers of [`CompilerSettings::register_abi`] the
+    /// generated code overwrites, which whatever calls into it from outside
+    /// has to save.
+    fn owned_callee_saved(&self) -> Vec<(AsmRegister64, &'static str)> {
+        let abi = self.settings.register_abi;
+        [abi.cell_pointer, abi.stack_pointer]
+            .into_iter()
+            .filter(|register| register.is_callee_saved())
+            .map(pointer_register)
+            .collect()
+    }
+
+    /// The 32-bit and low byte parts of [`super::RegisterAbi::scratch`],
+    /// with their names. Fails for the node at `span` if the calling convention
+    /// has callers expect the register to be preserved.
+    fn scratch_register(&self, span: Span) -> Result<RegisterParts, CompilerError> {
+        let register = self.settings.register_abi.scratch;
+        if !register.is_caller_saved(self.calling_convention) {
+            return Err(CompilerError {
+                kind: CompilerErrorKind::CalleeSavedScratchRegister(register),
+                span: Some(span),
+            });
+        }
+        Ok(match register {
+            ScratchRegister::Rax => ((eax, "eax"), (al, "al")),
+            ScratchRegister::Rcx => ((ecx, "ecx"), (cl, "cl")),
+            ScratchRegister::Rdx => ((edx, "edx"), (dl, "dl")),
+            ScratchRegister::Rsi => ((esi, "esi"), (sil, "sil")),
+            ScratchRegister::Rdi => ((edi, "edi"), (dil, "dil")),
+            ScratchRegister::R10 => ((r10d, "r10d"), (r10b, "r10b")),
+            ScratchRegister::R11 => ((r11d, "r11d"), (r11b, "r11b")),
+        })
+    }
+
+    /// The policy of [`CompilerSettings::stack_overflow`], if the program
+    /// manages its stack.
+    fn stack_overflow(&self) -> Option<StackOverflow> {
+        self.settings.stack_overflow.filter(|_| self.object_file)
+    }
+
+    /// Emits an instruction accessing the stack region, or its bounds with
+    /// [`StackOverflow::Grow`] and [`StackOverflow::Guard`], through the given
+    /// label, which the instruction must be labeled with. Its displacement is
+    /// relocated to `offset` bytes into the region or bounds.
+    fn stack_reference(
+        &mut self,
+        code_asm: &mut CodeAssembler,
+        origin: Origin,
+        offset: i64,
+        instruction: impl FnOnce(&mut CodeAssembler, CodeLabel) -> Result<(), IcedError>,
+        description: &str,
+    ) -> Result<(), CompilerError> {
+        let label =
+            self.rip_relative_reference(code_asm, origin, "stack", instruction, description)?;
+        self.stack_references.push((label, offset));
+        Ok(())
+    }
+
+    /// Points the stack pointer at the empty stack on entry, see
+    /// [`CompilerSettings::stack_overflow`]. The top of the stack is where it
+    /// points, so an empty stack has it one byte before the start.
+    fn set_up_stack(
+        &mut self,
+        code_asm: &mut CodeAssembler,
+        origin: Origin,
+    ) -> Result<(), CompilerError> {
+        let (stack, stack_name) = self.stack_pointer();
+        let size = self.settings.stack_size as i64;
+        match self.stack_overflow() {
+            Some(StackOverflow::Trap) => self.stack_reference(
+                code_asm,
+                origin,
+                -1,
+                |code_asm, region| code_asm.lea(stack, qword_ptr(region)),
+                &format!("lea {stack_name}, [rip + stack - 1]"),
+            )?,
+            // the byte before the start is the end of the ring
+            Some(StackOverflow::Wrap) => self.stack_reference(
+                code_asm,
+                origin,
+                size - 1,
+                |code_asm, region| code_asm.lea(stack, qword_ptr(region)),
+                &format!("lea {stack_name}, [rip + stack + size - 1]"),
+            )?,
+            // the stack is kept across entries, and its base is 0 before the
+            // first push allocates it
+            Some(StackOverflow::Grow) => {
+                self.stack_reference(
+                    code_asm,
+                    origin,
+                    0,
+                    |code_asm, base| code_asm.mov(stack, qword_ptr(base)),
+                    &format!("mov {stack_name}, [rip + base]"),
+                )?;
+                emit!(
+                    code_asm.lea(stack, qword_ptr(stack - 1)),
+                    origin,
+                    "lea {stack_name}, [{stack_name} - 1]"
+                );
+            }
+            Some(StackOverflow::Guard) => self.map_guarded_stack(code_asm, origin)?,
+            None => {}
+        }
+        Ok(())
+    }
+
+    /// Points the stack pointer at the empty stack of [`StackOverflow::Guard`],
+    /// mapping it between two inaccessible pages on the first entry.
+    fn map_guarded_stack(
+        &mut self,
+        code_asm: &mut CodeAssembler,
+        origin: Origin,
+    ) -> Result<(), CompilerError> {
+        let (cell, cell_name) = self.cell_pointer();
+        let (stack, stack_name) = self.stack_pointer();
+        // mov r9, [rip + base]
+        // test r9, r9
+        // jnz mapped
+        // push r8
+        // mov eax, 9                   ; mmap
+        // xor edi, edi
+        // mov rsi, <size + 2 pages>
+        // xor edx, edx                 ; PROT_NONE
+        // mov r10d, 0x22               ; MAP_PRIVATE | MAP_ANONYMOUS
+        // mov r8, -1
+        // xor r9d, r9d
+        // syscall
+        // pop r8
+        // cmp rax, -4095
+        // jae failed
+        // lea rdi, [rax + 0x1000]
+        // mov r9, rdi
+        // mov eax, 10                  ; mprotect
+        // mov rsi, <size>
+        // mov edx, 3                   ; PROT_READ | PROT_WRITE
+        // syscall
+        // test rax, rax
+        // jz protected
+        // failed:
+        // ud2
+        // protected:
+        // mov [rip + base], r9
+        // mapped:
+        // lea r9, [r9 - 1]
+        self.makes_syscalls = true;
+        let size = guarded_stack_size(self.settings.stack_size);
+        let mut mapped_label = code_asm.create_label();
+        let mut failed_label = code_asm.create_label();
+        let mut protected_label = code_asm.create_label();
+
+        self.stack_reference(
+            code_asm,
+            origin,
+            0,
+            |code_asm, base| code_asm.mov(stack, qword_ptr(base)),
+            &format!("mov {stack_name}, [rip + base]"),
+        )?;
+        emit!(
+            code_asm.test(stack, stack),
+            origin,
+            "test {stack_name}, {stack_name}"
+        );
+        emit!(code_asm.jne(mapped_label), origin, "jnz mapped");
+        // the syscall takes its last two arguments in r8 and r9, which the
+        // stack pointer is set from afterwards
+        emit!(code_asm.push(cell), origin, "push {cell_name}");
+        emit!(code_asm.mov(eax, 9u32), origin, "mov eax, 9");
+        emit!(code_asm.xor(edi, edi), origin, "xor edi, edi");
+        emit!(
+            code_asm.mov(rsi, size + 2 * PAGE_SIZE),
+            origin,
+            "mov rsi, {:#x}",
+            size + 2 * PAGE_SIZE
+        );
+        emit!(code_asm.xor(edx, edx), origin, "xor edx, edx");
+        emit!(code_asm.mov(r10d, 0x22u32), origin, "mov r10d, 0x22");
+        emit!(code_asm.mov(r8, -1i64), origin, "mov r8, -1");
+        emit!(code_asm.xor(r9d, r9d), origin, "xor r9d, r9d");
+        emit!(code_asm.syscall(), origin, "syscall");
+        emit!(code_asm.pop(cell), origin, "pop {cell_name}");
+        // errors are returned as -4095 to -1
+        emit!(code_asm.cmp(rax, -4095), origin, "cmp rax, -4095");
+        emit!(code_asm.jae(failed_label), origin, "jae failed");
+        emit!(
+            code_asm.lea(rdi, qword_ptr(rax + PAGE_SIZE as i32)),
+            origin,
+            "lea rdi, [rax + {PAGE_SIZE:#x}]"
+        );
+        emit!(code_asm.mov(stack, rdi), origin, "mov {stack_name}, rdi");
+        emit!(code_asm.mov(eax, 10u32), origin, "mov eax, 10");
+        emit!(code_asm.mov(rsi, size), origin, "mov rsi, {size:#x}");
+        emit!(code_asm.mov(edx, 3u32), origin, "mov edx, 3");
+        emit!(code_asm.syscall(), origin, "syscall");
+        emit!(code_asm.test(rax, rax), origin, "test rax, rax");
+        emit!(code_asm.je(protected_label), origin, "jz protected");
+        emit!(code_asm.set_label(&mut failed_label), origin, "failed:");
+        emit!(code_asm.ud2(), origin, "ud2");
+        emit!(
+            code_asm.set_label(&mut protected_label),
+            origin,
+            "protected:"
+        );
+        self.stack_reference(
+            code_asm,
+            origin,
+            0,
+            |code_asm, base| code_asm.mov(qword_ptr(base), stack),
+            &format!("mov [rip + base], {stack_name}"),
+        )?;
+        emit!(code_asm.set_label(&mut mapped_label), origin, "mapped:");
+        emit!(
+            code_asm.lea(stack, qword_ptr(stack - 1)),
+            origin,
+            "lea {stack_name}, [{stack_name} - 1]"
+        );
+        Ok(())
+    }
+
+    /// Handles a `StackPush` that just moved the stack pointer past the end of
+    /// the stack, see [`CompilerSettings::stack_overflow`].
+    fn check_stack_push(
+        &mut self,
+        code_asm: &mut CodeAssembler,
+        origin: Origin,
+        policy: StackOverflow,
+    ) -> Result<(), CompilerError> {
+        let (stack, stack_name) = self.stack_pointer();
+        match policy {
+            StackOverflow::Grow => return self.grow_stack(code_asm, origin),
+            // every push touches the next byte, so the first one past the end
+            // faults on the guard page
+            StackOverflow::Guard => return Ok(()),
+            _ => {}
+        }
+        // lea rax, [rip + stack + size]
+        // cmp r9, rax
+        // jb ok
+        // ud2                      ; Trap
+        // lea r9, [rip + stack]    ; Wrap
+        // ok:
+        let size = self.settings.stack_size as i64;
+        let mut ok_label = code_asm.create_label();
+        self.stack_reference(
+            code_asm,
+            origin,
+            size,
+            |code_asm, stack| code_asm.lea(rax, qword_ptr(stack)),
+            "lea rax, [rip + stack + size]",
+        )?;
+        emit!(code_asm.cmp(stack, rax), origin, "cmp {stack_name}, rax");
+        emit!(code_asm.jb(ok_label), origin, "jb ok");
+        if policy == StackOverflow::Trap {
+            emit!(code_asm.ud2(), origin, "ud2");
+        } else {
+            self.stack_reference(
+                code_asm,
+                origin,
+                0,
+                |code_asm, region| code_asm.lea(stack, qword_ptr(region)),
+                &format!("lea {stack_name}, [rip + stack]"),
+            )?;
+        }
+        emit!(code_asm.set_label(&mut ok_label), origin, "ok:");
+        // phantom instruction so we have an address
+        emit!(code_asm.zero_bytes(), origin, "<label anchor>");
+        Ok(())
+    }
+
+    /// Wraps the stack pointer around to the end of the ring after a `StackPop`
+    /// with [`StackOverflow::Wrap`] moved it before the start.
+    fn wrap_stack_pop(
+        &mut self,
+        code_asm: &mut CodeAssembler,
+        origin: Origin,
+    ) -> Result<(), CompilerError> {
+        let (stack, stack_name) = self.stack_pointer();
+        // lea rax, [rip + stack]
+        // cmp r9, rax
+        // jae ok
+        // lea r9, [rip + stack + size - 1]
+        // ok:
+        let size = self.settings.stack_size as i64;
+        let mut ok_label = code_asm.create_label();
+        self.stack_reference(
+            code_asm,
+            origin,
+            0,
+            |code_asm, stack| code_asm.lea(rax, qword_ptr(stack)),
+            "lea rax, [rip + stack]",
+        )?;
+        emit!(code_asm.cmp(stack, rax), origin, "cmp {stack_name}, rax");
+        emit!(code_asm.jae(ok_label), origin, "jae ok");
+        self.stack_reference(
+            code_asm,
+            origin,
+            size - 1,
+            |code_asm, region| code_asm.lea(stack, qword_ptr(region)),
+            &format!("lea {stack_name}, [rip + stack + size - 1]"),
+        )?;
+        emit!(code_asm.set_label(&mut ok_label), origin, "ok:");
+        // phantom instruction so we have an address
+        emit!(code_asm.zero_bytes(), origin, "<label anchor>");
+        Ok(())
+    }
+
+    /// Reallocates the stack with [`super::RuntimeAbi::allocator`] when a `StackPush` moved
+    /// the stack pointer past its end, to twice its capacity, or
+    /// [`CompilerSettings::stack_size`] for the first push.
+    fn grow_stack(
+        &mut self,
+        code_asm: &mut CodeAssembler,
+        origin: Origin,
+    ) -> Result<(), CompilerError> {
+        let (cell, cell_name) = self.cell_pointer();
+        let (stack, stack_name) = self.stack_pointer();
+        // mov rax, r9
+        // sub rax, [rip + base]
+        // cmp rax, [rip + capacity]
+        // jb ok
+        // mov rax, [rip + capacity]
+        // add rax, rax
+        // mov rcx, <stack size>
+        // cmp rax, rcx
+        // cmovb rax, rcx
+        // mov [rip + capacity], rax
+        // push r8
+        // push r9
+        // mov rdi, [rip + base]
+        // mov rsi, rax
+        // sub rsp, 0x20                ; Win64 only
+        // call realloc
+        // add rsp, 0x20                ; Win64 only
+        // pop r9
+        // pop r8
+        // test rax, rax
+        // jnz grown
+        // ud2
+        // grown:
+        // sub r9, [rip + base]
+        // add r9, rax
+        // mov [rip + base], rax
+        // ok:
+        let ((ptr_arg, ptr_arg_name), (size_arg, size_arg_name)) = match self.calling_convention {
+            CallingConvention::X86_64_SystemVAMD64 => ((rdi, "rdi"), (rsi, "rsi")),
+            CallingConvention::X86_64_MicrosoftX64 => ((rcx, "rcx"), (rdx, "rdx")),
+            _ => {
+                return Err(CompilerError {
+                    kind: CompilerErrorKind::UnsupportedOp(origin.op),
+                    span: Some(origin.span),
+                })
+            }
+        };
+        let mut ok_label = code_asm.create_label();
+        let mut grown_label = code_asm.create_label();
+        let size = self.settings.stack_size as u64;
+
+        emit!(code_asm.mov(rax, stack), origin, "mov rax, {stack_name}");
+        self.stack_reference(
+            code_asm,
+            origin,
+            0,
+            |code_asm, base| code_asm.sub(rax, qword_ptr(base)),
+            "sub rax, [rip + base]",
+        )?;
+        self.stack_reference(
+            code_asm,
+            origin,
+            8,
+            |code_asm, capacity| code_asm.cmp(rax, qword_ptr(capacity)),
+            "cmp rax, [rip + capacity]",
+        )?;
+        emit!(code_asm.jb(ok_label), origin, "jb ok");
+        self.stack_reference(
+            code_asm,
+            origin,
+            8,
+            |code_asm, capacity| code_asm.mov(rax, qword_ptr(capacity)),
+            "mov rax, [rip + capacity]",
+        )?;
+        emit!(code_asm.add(rax, rax), origin, "add rax, rax");
+        emit!(code_asm.mov(rcx, size), origin, "mov rcx, {size:#x}");
+        emit!(code_asm.cmp(rax, rcx), origin, "cmp rax, rcx");
+        emit!(code_asm.cmovb(rax, rcx), origin, "cmovb rax, rcx");
+        self.stack_reference(
+            code_asm,
+            origin,
+            8,
+            |code_asm, capacity| code_asm.mov(qword_ptr(capacity), rax),
+            "mov [rip + capacity], rax",
+        )?;
+        emit!(code_asm.push(cell), origin, "push {cell_name}");
+        emit!(code_asm.push(stack), origin, "push {stack_name}");
+        self.stack_reference(
+            code_asm,
+            origin,
+            0,
+            |code_asm, base| code_asm.mov(ptr_arg, qword_ptr(base)),
+            &format!("mov {ptr_arg_name}, [rip + base]"),
+        )?;
+        emit!(
+            code_asm.mov(size_arg, rax),
+            origin,
+            "mov {size_arg_name}, rax"
+        );
+        let frame = self.call_frame(2);
+        if frame > 0 {
+            emit!(code_asm.sub(rsp, frame), origin, "sub rsp, {frame:#x}");
+        }
+        let allocator = self.settings.runtime_abi.allocator.clone();
+        self.call_external(code_asm, origin, allocator)?;
+        if frame > 0 {
+            emit!(code_asm.add(rsp, frame), origin, "add rsp, {frame:#x}");
+        }
+        emit!(code_asm.pop(stack), origin, "pop {stack_name}");
+        emit!(code_asm.pop(cell), origin, "pop {cell_name}");
+        emit!(code_asm.test(rax, rax), origin, "test rax, rax");
+        emit!(code_asm.jne(grown_label), origin, "jnz grown");
+        emit!(code_asm.ud2(), origin, "ud2");
+        emit!(code_asm.set_label(&mut grown_label), origin, "grown:");
+        // the stack moved, so r9 has to move along with it
+        self.stack_reference(
+            code_asm,
+            origin,
+            0,
+            |code_asm, base| code_asm.sub(stack, qword_ptr(base)),
+            &format!("sub {stack_name}, [rip + base]"),
+        )?;
+        emit!(code_asm.add(stack, rax), origin, "add {stack_name}, rax");
+        self.stack_reference(
+            code_asm,
+            origin,
+            0,
+            |code_asm, base| code_asm.mov(qword_ptr(base), rax),
+            "mov [rip + base], rax",
+        )?;
+        emit!(code_asm.set_label(&mut ok_label), origin, "ok:");
+        // phantom instruction so we have an address
+        emit!(code_asm.zero_bytes(), origin, "<label anchor>");
+        Ok(())
+    }
+
+    /// Traps with `ud2` if the stack pointer points into the static tape, see
+    /// [`CompilerSettings::stack_tape_guard`].
+    fn guard_stack_pointer(
+        &mut self,
+        code_asm: &mut CodeAssembler,
+        origin: Origin,
+        tape_size: u64,
+    ) -> Result<(), CompilerError> {
+        let (stack, stack_name) = self.stack_pointer();
+        let mut ok_label = code_asm.create_label();
+        self.load_tape_address(code_asm, origin, (rax, "rax"), 0)?;
+        emit!(code_asm.cmp(stack, rax), origin, "cmp {stack_name}, rax");
+        emit!(code_asm.jb(ok_label), origin, "jb ok");
+        self.load_tape_address(code_asm, origin, (rax, "rax"), tape_size)?;
+        emit!(code_asm.cmp(stack, rax), origin, "cmp {stack_name}, rax");
+        emit!(code_asm.jae(ok_label), origin, "jae ok");
+        emit!(code_asm.ud2(), origin, "ud2");
+        emit!(code_asm.set_label(&mut ok_label), origin, "ok:");
+        // phantom instruction so we have an address
+        emit!(code_asm.zero_bytes(), origin, "<label anchor>");
+        Ok(())
+    }
+
+    /// Emits a `call` to a function outside of the program, which the linker
+    /// resolves through a relocation.
+    fn call_external(
+        &mut self,
+        code_asm: &mut CodeAssembler,
+        origin: Origin,
+        name: String,
+    ) -> Result<CodeLabel, CompilerError> {
+        let mut label = code_asm.create_label();
+        emit!(code_asm.set_label(&mut label), origin, "{name}:");
+        match self.external_calls() {
+            ExternalCalls::DirectRel32 => emit!(code_asm.call(label), origin, "call {name}"),
+            ExternalCalls::IndirectGot => emit!(
+                code_asm.call(qword_ptr(label)),
+                origin,
+                "call [rip + {name}@GOTPCREL]"
+            ),
+        }
+        self.add_external_call(name, label);
+        Ok(label)
+    }
+
+    /// How external functions are called, see
+    /// [`CompilerSettings::external_calls`].
+    fn external_calls(&self) -> ExternalCalls {
+        if self.object_file {
+            self.settings.external_calls
+        } else {
+            ExternalCalls::DirectRel32
+        }
+    }
+
+    /// The 32 bytes of shadow space the Microsoft x64 convention has the
+    /// caller reserve right above the return address, which the callee may
+    /// overwrite, so nothing pushed before a call can be left there. Being a
+    /// multiple of 16, it leaves the stack as aligned as it was.
+    fn shadow_space(&self) -> i32 {
+        match self.calling_convention {
+            CallingConvention::X86_64_MicrosoftX64 => 0x20,
+            _ => 0,
+        }
+    }
+
+    /// How far to move rsp down before calling an external once `pushes`
+    /// registers were pushed: past the shadow space, and by 8 more bytes if an
+    /// odd number of pushes left the stack off the alignment the callee
+    /// expects.
+    fn call_frame(&self, pushes: i32) -> i32 {
+        8 * (pushes % 2) + self.shadow_space()
+    }
+
+    /// Lowers I/O on the current cell to `read`/`write(fd, cell, count)`.
+    fn translate_io(
+        &mut self,
+        code_asm: &mut CodeAssembler,
+        origin: Origin,
+        symbol: String,
+        fd: u32,
+        count: usize,
+    ) -> Result<(), CompilerError> {
+        let (cell, cell_name) = self.cell_pointer();
+        let (stack, stack_name) = self.stack_pointer();
+        if count > u32::MAX as usize {
+            return Err(CompilerError {
+                kind: CompilerErrorKind::IoTooLarge(count),
+                span: Some(origin.span),
+            });
+        }
+        let count = count as u32;
+        let in_memory = self.cell_pointer_in_memory();
+
+        // the cell pointer is reloaded from its slot instead of being saved
+        let pushes = !in_memory as i32 + 1;
+        if !in_memory {
+            emit!(code_asm.push(cell), origin, "push {cell_name}");
+        }
+        emit!(code_asm.push(stack), origin, "push {stack_name}");
+        match self.calling_convention {
+            CallingConvention::X86_64_SystemVAMD64 => {
+                emit!(code_asm.mov(edi, fd), origin, "mov edi, {fd}");
+                emit!(code_asm.mov(rsi, cell), origin, "mov rsi, {cell_name}");
+                emit!(code_asm.mov(edx, count), origin, "mov edx, {count:#x}");
+            }
+            CallingConvention::X86_64_MicrosoftX64 => {
+                // r8 is the third argument register here, so it is
+                // overwritten last
+                emit!(code_asm.mov(ecx, fd), origin, "mov ecx, {fd}");
+                emit!(code_asm.mov(rdx, cell), origin, "mov rdx, {cell_name}");
+                emit!(code_asm.mov(r8d, count), origin, "mov r8d, {count:#x}");
+            }
+            _ => {
+                return Err(CompilerError {
+                    kind: CompilerErrorKind::UnsupportedOp(origin.op),
+                    span: Some(origin.span),
+                })
+            }
+        }
+        let frame = self.call_frame(pushes);
+        if frame > 0 {
+            emit!(code_asm.sub(rsp, frame), origin, "sub rsp, {frame:#x}");
+        }
+        self.call_external(code_asm, origin, symbol)?;
+        if frame > 0 {
+            emit!(code_asm.add(rsp, frame), origin, "add rsp, {frame:#x}");
+        }
+        emit!(code_asm.pop(stack), origin, "pop {stack_name}");
+        if in_memory {
+            self.load_cell_pointer(code_asm, origin)?;
+        } else {
+            emit!(code_asm.pop(cell), origin, "pop {cell_name}");
+        }
+        Ok(())
+    }
+
+    /// Lowers `ReadBytes(count)` to `read(0, cell, count)`, repeated for the
+    /// rest of the cells with [`ShortRead::Retry`].
+    fn translate_read_bytes(
+        &mut self,
+        code_asm: &mut CodeAssembler,
+        origin: Origin,
+        count: u32,
+    ) -> Result<(), CompilerError> {
+        let (cell, cell_name) = self.cell_pointer();
+        let (stack, stack_name) = self.stack_pointer();
+        // the end of the buffer has to fit a displacement
+        let Ok(count) = i32::try_from(count) else {
+            return Err(CompilerError {
+                kind: CompilerErrorKind::IoTooLarge(count as usize),
+                span: Some(origin.span),
+            });
+        };
+
+        // r8 is the cursor of the read, the end of the buffer is kept on the
+        // stack:
+        //
+        //    lea rax, [r8 + count]
+        //    push rax                  ; end
+        //    push r9
+        // again:
+        //    push r8
+        //    sub rsp, 8                ; 0x28 with the Win64 shadow space
+        //    mov edi, 0
+        //    mov rsi, r8
+        //    mov rdx, [rsp + 24]
+        //    sub rdx, r8               ; what is left of the buffer
+        //    call read
+        //    add rsp, 8
+        //    pop r8
+        //    test rax, rax
+        //    jle done                  ; the input ended, or read failed
+        //    add r8, rax
+        //    cmp r8, [rsp + 8]         ; Retry only
+        //    jb again                  ; Retry only
+        // done:
+        //    pop r9
+        //    mov rcx, r8
+        //    pop r8                    ; the pointer moves past the buffer
+        //    sub rcx, r8
+        //    add rcx, count            ; how many bytes were read
+        //    ...                       ; saturated to 255 if count > 255
+        //    mov byte ptr [r8], cl
+        let mut again = code_asm.create_label();
+        let mut done = code_asm.create_label();
+        emit!(
+            code_asm.lea(rax, qword_ptr(cell + count)),
+            origin,
+            "lea rax, [{cell_name} + {count:#x}]"
+        );
+        emit!(code_asm.push(rax), origin, "push rax");
+        emit!(code_asm.push(stack), origin, "push {stack_name}");
+        emit!(code_asm.set_label(&mut again), origin, "again:");
+        emit!(code_asm.push(cell), origin, "push {cell_name}");
+        let frame_size = self.call_frame(3);
+        // where the end of the buffer is
+        let end = frame_size + 16;
+        emit!(
+            code_asm.sub(rsp, frame_size),
+            origin,
+            "sub rsp, {frame_size:#x}"
+        );
+        match self.calling_convention {
+            CallingConvention::X86_64_SystemVAMD64 => {
+                emit!(code_asm.mov(edi, 0), origin, "mov edi, 0");
+                emit!(code_asm.mov(rsi, cell), origin, "mov rsi, {cell_name}");
+                emit!(
+                    code_asm.mov(rdx, qword_ptr(rsp + end)),
+                    origin,
+                    "mov rdx, [rsp + {end}]"
+                );
+                emit!(code_asm.sub(rdx, cell), origin, "sub rdx, {cell_name}");
+            }
+            CallingConvention::X86_64_MicrosoftX64 => {
+                emit!(code_asm.mov(ecx, 0), origin, "mov ecx, 0");
+                emit!(code_asm.mov(rdx, cell), origin, "mov rdx, {cell_name}");
+                emit!(
+                    code_asm.mov(r8, qword_ptr(rsp + end)),
+                    origin,
+                    "mov r8, [rsp + {end}]"
+                );
+                emit!(code_asm.sub(r8, rdx), origin, "sub r8, rdx");
+            }
+            _ => {
+                return Err(CompilerError {
+                    kind: CompilerErrorKind::UnsupportedOp(origin.op),
+                    span: Some(origin.span),
+                })
+            }
+        }
+        let read = self.settings.runtime_abi.read.clone();
+        self.call_external(code_asm, origin, read)?;
+        emit!(
+            code_asm.add(rsp, frame_size),
+            origin,
+            "add rsp, {frame_size:#x}"
+        );
+        emit!(code_asm.pop(cell), origin, "pop {cell_name}");
+        emit!(code_asm.test(rax, rax), origin, "test rax, rax");
+        emit!(code_asm.jle(done), origin, "jle done");
+        emit!(code_asm.add(cell, rax), origin, "add {cell_name}, rax");
+        if self.settings.short_read == ShortRead::Retry {
+            emit!(
+                code_asm.cmp(cell, qword_ptr(rsp + 8)),
+                origin,
+                "cmp {cell_name}, [rsp + 8]"
+            );
+            emit!(code_asm.jb(again), origin, "jb again");
+        }
+        emit!(code_asm.set_label(&mut done), origin, "done:");
+        emit!(code_asm.pop(stack), origin, "pop {stack_name}");
+        emit!(code_asm.mov(rcx, cell), origin, "mov rcx, {cell_name}");
+        emit!(code_asm.pop(cell), origin, "pop {cell_name}");
+        emit!(code_asm.sub(rcx, cell), origin, "sub rcx, {cell_name}");
+        emit!(code_asm.add(rcx, count), origin, "add rcx, {count:#x}");
+        if count > u8::MAX as i32 {
+            emit!(code_asm.mov(eax, 0xff), origin, "mov eax, 0xff");
+            emit!(code_asm.cmp(rcx, rax), origin, "cmp rcx, rax");
+            emit!(code_asm.cmova(rcx, rax), origin, "cmova rcx, rax");
+        }
+        emit!(
+            code_asm.mov(byte_ptr(cell), cl),
+            origin,
+            "mov byte ptr [{cell_name}], cl"
+        );
+        if self.cell_pointer_in_memory() {
+            self.store_cell_pointer(code_asm, origin)?;
+        }
+        Ok(())
+    }
+
+    /// Calls [`super::RuntimeAbi::flush`] after a write, as often as
+    /// [`CompilerSettings::output_flush`] asks for. `value` is the value of the
+    /// written cell, if it is known, and `single` whether only that one cell
+    /// was written.
+    fn flush_output(
+        &mut self,
+        code_asm: &mut CodeAssembler,
+        origin: Origin,
+        value: Option<u8>,
+        single: bool,
+    ) -> Result<(), CompilerError> {
+        let (cell, cell_name) = self.cell_pointer();
+        let (stack, stack_name) = self.stack_pointer();
+        let check_newline = match self.settings.output_flush {
+            OutputFlush::Never => return Ok(()),
+            OutputFlush::EveryByte => false,
+            OutputFlush::OnNewline if !single => false,
+            OutputFlush::OnNewline => match value {
+                Some(b'\n') => false,
+                Some(_) => return Ok(()),
+                None => true,
+            },
+        };
+        let in_memory = self.cell_pointer_in_memory();
+
+        // cmp byte ptr [r8], 0xa
+        // jne skip
+        // push r8                      ; unless the cell pointer is in memory
+        // push r9
+        // sub rsp, frame               ; see `Compiler::call_frame`
+        // call flush
+        // add rsp, frame
+        // pop r9
+        // pop r8                       ; or reload it from its slot
+        // skip:
+        let mut skip_label = code_asm.create_label();
+        if check_newline {
+            emit!(
+                code_asm.cmp(byte_ptr(cell), b'\n' as u32),
+                origin,
+                "cmp byte ptr [{cell_name}], 0xa"
+            );
+            emit!(code_asm.jne(skip_label), origin, "jne skip");
+        }
+        let pushes = !in_memory as i32 + 1;
+        if !in_memory {
+            emit!(code_asm.push(cell), origin, "push {cell_name}");
+        }
+        emit!(code_asm.push(stack), origin, "push {stack_name}");
+        let frame = self.call_frame(pushes);
+        if frame > 0 {
+            emit!(code_asm.sub(rsp, frame), origin, "sub rsp, {frame:#x}");
+        }
+        let flush = self.settings.runtime_abi.flush.clone();
+        self.call_external(code_asm, origin, flush)?;
+        if frame > 0 {
+            emit!(code_asm.add(rsp, frame), origin, "add rsp, {frame:#x}");
+        }
+        emit!(code_asm.pop(stack), origin, "pop {stack_name}");
+        if in_memory {
+            self.load_cell_pointer(code_asm, origin)?;
+        } else {
+            emit!(code_asm.pop(cell), origin, "pop {cell_name}");
+        }
+        if check_newline {
+            emit!(code_asm.set_label(&mut skip_label), origin, "skip:");
+            // phantom instruction so we have an address
+            emit!(code_asm.zero_bytes(), origin, "<label anchor>");
+        }
+        Ok(())
+    }
+
+    /// Whether `name` is a top-level function of another part of the program
+    /// than the one being compiled on its own, see [`Compiler::other_units`].
+    /// The answer is noted down, as the code of the part depends on it.
+    fn is_other_unit(&mut self, name: &str) -> bool {
+        let Some(functions) = &self.other_units else {
+            return false;
+        };
+        let found = functions.contains(name);
+        self.unit_lookups.push((name.to_string(), found));
+        found
+    }
+
+    /// Calls the top-level function `name` of another part of the program.
+    /// The call targets itself until [`Compiler::compile_incremental`] knows
+    /// where the function ends up and patches it.
+    fn call_unit(
+        &mut self,
+        code_asm: &mut CodeAssembler,
+        origin: Origin,
+        name: String,
+    ) -> Result<(), CompilerError> {
+        emit!(code_asm.zero_bytes(), origin, "<label anchor>");
+        let mut label = code_asm.create_label();
+        emit!(code_asm.set_label(&mut label), origin, "{name}:");
+        emit!(code_asm.call(label), origin, "call {name}");
+        self.unit_calls.push((name, label));
+        Ok(())
+    }
+
+    /// Lowers and assembles `part` of a program on its own, for
+    /// [`Compiler::compile_incremental`], with the cell at `value` when it is
+    /// reached. Calls to the other top-level `functions` of the program are
+    /// left to be patched.
+    fn compile_unit(
+        &mut self,
+        part: Vec<IrNode>,
+        value: Option<u8>,
+        functions: &BTreeSet<String>,
+    ) -> Result<CachedUnit, CompilerError> {
+        self.reset();
+        let mut other_units = functions.clone();
+        for node in &part {
+            if let IrOp::Function(name, _) = &node.node {
+                other_units.remove(name);
+            }
+        }
+        self.other_units = Some(other_units);
+        self.cell_value = value;
+        let (ir, spans) = strip_spans(&part);
+
+        let code_asm = self.lower(part)?;
+        let exit_value = self.cell_value;
+        let result = self.assemble(code_asm)?;
+        let code = &result.inner.code_buffer;
+        let calls = self
+            .unit_calls
+            .iter()
+            .map(|(name, label)| {
+                let index = self.code_index(&result, label);
+                let (start, addend) = call_displacement(code, index, self.bitness)?;
+                Ok((index + start, addend, name.clone()))
+            })
+            .collect::<Result<Vec<_>, CompilerError>>()?;
+        let size = self.jump_table_entry_size();
+        let table_entries = self
+            .unit_table_entries
+            .iter()
+            .map(|(table, i, name)| {
+                let index = self.code_index(&result, &self.jump_tables[*table].0);
+                (index + i * size, index, name.clone())
+            })
+            .collect();
+
+        Ok(CachedUnit {
+            ir,
+            spans,
+            entry_value: value,
+            exit_value,
+            lookups: core::mem::take(&mut self.unit_lookups),
+            code: result.inner.code_buffer,
+            calls,
+            table_entries,
+            pure: super::CompilerTrait::is_pure(self),
+            stats: core::mem::take(&mut self.stats),
+            warnings: core::mem::take(&mut self.warnings),
+        })
+    }
+
+    /// How far a single move can take the cell pointer, or `None` if there is
+    /// no limit. A move is a `lea` with the distance as its displacement,
+    /// which is signed and as wide as the address size, up to 32 bits. In
+    /// 64-bit code, longer moves load the distance into rax instead.
+    fn max_move(&self) -> Option<usize> {
+        match self.bitness {
+            16 => Some(i16::MAX as usize),
+            32 => Some(i32::MAX as usize),
+            _ => None,
+        }
+    }
+
+    /// Moves the cell pointer `n` cells to the right, or to the left.
+    fn translate_move(
+        &mut self,
+        code_asm: &mut CodeAssembler,
+        origin: Origin,
+        n: usize,
+        left: bool,
+    ) -> Result<(), CompilerError> {
+        let (cell, cell_name) = self.cell_pointer();
+        if let Some(limit) = self.max_move().filter(|&limit| n > limit) {
+            return Err(CompilerError {
+                kind: CompilerErrorKind::MoveTooLarge { count: n, limit },
+                span: Some(origin.span),
+            });
+        }
+        match (i32::try_from(n), left) {
+            (Ok(n), false) => emit!(
+                code_asm.lea(cell, dword_ptr(cell + n)),
+                origin,
+                "lea {cell_name}, [{cell_name} + {n:#x}]"
+            ),
+            (Ok(n), true) => emit!(
+                code_asm.lea(cell, dword_ptr(cell - n)),
+                origin,
+                "lea {cell_name}, [{cell_name} - {n:#x}]"
+            ),
+            // mov rax, n
+            // add r8, rax      ; or sub
+            (Err(_), left) => {
+                emit!(code_asm.mov(rax, n as u64), origin, "mov rax, {n:#x}");
+                if left {
+                    emit!(code_asm.sub(cell, rax), origin, "sub {cell_name}, rax");
+                } else {
+                    emit!(code_asm.add(cell, rax), origin, "add {cell_name}, rax");
+                }
+            }
+        }
+        if self.cell_pointer_in_memory() {
+            self.store_cell_pointer(code_asm, origin)?;
+        }
+        Ok(())
+    }
+
+    /// Lowers the body of a loop, an `IfEq` or a `Switch` arm in an unnamed
+    /// scope of its own, given the value of the cell when the body starts.
+    fn translate_block(
+        &mut self,
+        code_asm: &mut CodeAssembler,
+        nodes: Vec<IrNode>,
+        cell_value: Option<u8>,
+    ) -> Result<(), CompilerError> {
+        self.scopes.push_unnamed_scope();
+        self.cell_value = cell_value;
+        // a block is jumped into, not only fallen into from the code before
+        self.cell_register = None;
+        for node in nodes {
+            self.translate_ir_node_impl(code_asm, node)?;
+        }
+        self.scopes.pop_scope();
+        Ok(())
+    }
+
+    // dense arms, through a jump table:
+    //    movzx eax, byte ptr [r8]
+    //    sub eax, <lowest value>
+    //    cmp eax, <table length>
+    //    jae default_label
+    //    lea rcx, [rip + table]
+    //    jmp qword ptr [rcx + rax * 8]   ; see `Compiler::branch_through_table`
+    // arm_label:
+    //    ... ; code of the arm
+    //    jmp end_label
+    //    ... ; the other arms
+    // default_label:
+    //    ... ; default code
+    // end_label:
+    //
+    // sparse arms, compared one by one:
+    //    cmp byte ptr [r8], <value>
+    //    jne next_label
+    //    ... ; code of the arm
+    //    jmp end_label
+    // next_label:
+    //    ... ; the other arms
+    //    ... ; default code
+    // end_label:
+    fn translate_switch(
+        &mut self,
+        code_asm: &mut CodeAssembler,
+        origin: Origin,
+        arms: Vec<(u8, Vec<IrNode>)>,
+        default: Vec<IrNode>,
+    ) -> Result<(), CompilerError> {
+        let (cell, cell_name) = self.cell_pointer();
+        // takes any label of the previous op, as a switch without arms starts
+        // with labels of its own
+        emit!(code_asm.zero_bytes(), origin, "<label anchor>");
+        let mut end_label = code_asm.create_label();
+        let mut default_label = code_asm.create_label();
+
+        let mut table = None;
+        if let Some((low, high)) = jump_table_range(&arms) {
+            let table_label = code_asm.create_label();
+            let len = (high - low) as u32 + 1;
+
+            emit!(
+                code_asm.movzx(eax, byte_ptr(cell)),
+                origin,
+                "movzx eax, byte ptr [{cell_name}]"
+            );
+            if low != 0 {
+                emit!(code_asm.sub(eax, low as i32), origin, "sub eax, {low:#x}");
+            }
+            emit!(code_asm.cmp(eax, len), origin, "cmp eax, {len:#x}");
+            emit!(code_asm.jae(default_label), origin, "jae default");
+            self.branch_through_table(code_asm, origin, table_label, false)?;
+
+            let mut arm_labels = Vec::with_capacity(arms.len());
+            for (value, body) in arms {
+                let mut arm_label = code_asm.create_label();
+                emit!(code_asm.set_label(&mut arm_label), origin, "arm:");
+                // phantom instruction so we have an address
+                emit!(code_asm.zero_bytes(), origin, "<label anchor>");
+                self.emit_branch_target(code_asm, origin)?;
+                self.translate_block(code_asm, body, Some(value))?;
+                emit!(code_asm.jmp(end_label), origin, "jmp end");
+                arm_labels.push((value, arm_label));
+            }
+            table = Some((table_label, low..=high, arm_labels));
+        } else {
+            for (value, body) in arms {
+                let mut next_label = code_asm.create_label();
+                emit!(
+                    code_asm.cmp(byte_ptr(cell), value as u32),
+                    origin,
+                    "cmp byte ptr [{cell_name}], {value:#x}"
+                );
+                emit!(code_asm.jne(next_label), origin, "jne next");
+                self.translate_block(code_asm, body, Some(value))?;
+                emit!(code_asm.jmp(end_label), origin, "jmp end");
+                emit!(code_asm.set_label(&mut next_label), origin, "next:");
+                // phantom instruction so we have an address
+                emit!(code_asm.zero_bytes(), origin, "<label anchor>");
+            }
+        }
+
+        emit!(code_asm.set_label(&mut default_label), origin, "default:");
+        // phantom instruction so we have an address
+        emit!(code_asm.zero_bytes(), origin, "<label anchor>");
+        // where the table has no arm for a value
+        if table.is_some() {
+            self.emit_branch_target(code_asm, origin)?;
+        }
+        self.translate_block(code_asm, default, None)?;
+        emit!(code_asm.set_label(&mut end_label), origin, "end:");
+        // phantom instruction so we have an address
+        emit!(code_asm.zero_bytes(), origin, "<label anchor>");
+
+        // the table can only take the labels once they are set. Values without
+        // an arm go to the default, and the first arm for a value wins, like
+        // with the compares
+        if let Some((table_label, values, arm_labels)) = table {
+            let entries = values
+                .map(|value| match arm_labels.iter().find(|(v, _)| *v == value) {
+                    Some(&(_, arm_label)) => (format!("case {value}"), arm_label),
+                    None => ("default".to_string(), default_label),
+                })
+                .collect();
+            self.jump_tables.push((table_label, entries, origin));
+        }
+        Ok(())
+    }
+
+    fn translate_ir_node_impl(
+        &mut self,
+        code_asm: &mut CodeAssembler,
+        ir_node: IrNode,
+    ) -> Result<(), CompilerError> {
+        let (cell, cell_name) = self.cell_pointer();
+        let (stack, stack_name) = self.stack_pointer();
+        let origin = Origin {
+            op: ir_node.node.name(),
+            span: ir_node.span,
+        };
+        let span = ir_node.span;
+        self.check_code_size(code_asm, span)?;
+        let entry_value = self.cell_value;
+        let exit_value = cell_value_after(entry_value, &ir_node.node);
+
+        let outer_origin = self.current_origin.replace(origin);
+        let start = code_asm.instructions().len();
+        self.owners.push((self.current_origin, start));
+        self.stats.ops.entry(origin.op).or_default().count += 1;
+        let nested = match &ir_node.node {
+            IrOp::Function(_, children)
+            | IrOp::Condition(children)
+            | IrOp::IfEq { body: children, .. } => !children.is_empty(),
+            IrOp::Switch { .. } => true,
+            _ => false,
+        };
+        self.trace_depth += 1;
+        let cell_register = self.cell_register.take();
+        let mut leaves_cell_in = None;
+
+        match ir_node.node {
+            // cells wrap around, so only `n` mod 256 matters, however large
+            IrOp::Add(n) | IrOp::Subtract(n) if n as u8 == 0 => {}
+            IrOp::Add(n) => {
+                let n = n as u8;
+                emit!(
+                    code_asm.add(byte_ptr(cell), n as u32),
+                    origin,
+                    "add byte ptr [{cell_name}], {n:#x}"
+                );
+            }
+            IrOp::Subtract(n) => {
+                let n = n as u8;
+                emit!(
+                    code_asm.sub(byte_ptr(cell), n as u32),
+                    origin,
+                    "sub byte ptr [{cell_name}], {n:#x}"
+                );
+            }
+            IrOp::MoveRight(n) => self.translate_move(code_asm, origin, n, false)?,
+            IrOp::MoveLeft(n) => self.translate_move(code_asm, origin, n, true)?,
+            IrOp::StackPush => {
+                emit!(
+                    code_asm.lea(stack, dword_ptr(stack + 1)),
+                    origin,
+                    "lea {stack_name}, [{stack_name} + 1]"
+                );
+                match self.settings.static_tape {
+                    Some(size) if self.object_file && self.settings.stack_tape_guard => {
+                        self.guard_stack_pointer(code_asm, origin, size as u64)?;
+                    }
+                    _ => {}
+                }
+                if let Some(policy) = self.stack_overflow() {
+                    self.check_stack_push(code_asm, origin, policy)?;
+                }
+                let (_, (scratch, scratch_name)) = self.scratch_register(span)?;
+                emit!(
+                    code_asm.mov(scratch, byte_ptr(cell)),
+                    origin,
+                    "mov {scratch_name}, byte ptr [{cell_name}]"
+                );
+                emit!(
+                    code_asm.mov(byte_ptr(stack), scratch),
+                    origin,
+                    "mov byte ptr [{stack_name}], {scratch_name}"
+                );
+            }
+            IrOp::StackPop => {
+                let (_, (scratch, scratch_name)) = self.scratch_register(span)?;
+                emit!(
+                    code_asm.mov(scratch, byte_ptr(stack)),
+                    origin,
+                    "mov {scratch_name}, byte ptr [{stack_name}]"
+                );
+                emit!(
+                    code_asm.mov(byte_ptr(cell), scratch),
+                    origin,
+                    "mov byte ptr [{cell_name}], {scratch_name}"
+                );
+                emit!(
+                    code_asm.lea(stack, dword_ptr(stack - 1)),
+                    origin,
+                    "lea {stack_name}, [{stack_name} - 1]"
+                );
+                if self.stack_overflow() == Some(StackOverflow::Wrap) {
+                    self.wrap_stack_pop(code_asm, origin)?;
+                } else {
+                    leaves_cell_in = Some((scratch, scratch_name));
+                }
+            }
+            // equivalent:
+            //
+            // while *r8 != 0 {
+            //    // code
+            // }
+            //
+            // or in assembly:
+            //
+            // start_label:
+            //    cmp byte ptr[r8], 0
+            //    je end_label
+            //    ... ; code
+            //    jmp start_label
+            // end_label:
+            //
+            IrOp::Condition(cond_ir_nodes) => {
+                // A loop that is known to be entered only has to test the
+                // cell at the bottom:
+                //
+                // start_label:
+                //    ... ; code
+                //    cmp byte ptr[r8], 0
+                //    jne start_label
+                if self.settings.warn_suspicious_loops {
+                    if let Some(step) = arithmetic_step(&cond_ir_nodes).filter(|step| step % 2 == 0)
+                    {
+                        self.warnings.push(CompilerWarning {
+                            kind: CompilerWarningKind::SuspiciousLoop(step),
+                            span,
+                        });
+                    }
+                }
+                let bottom_test = self.settings.optimization_level >= 1
+                    && matches!(entry_value, Some(value) if value != 0);
+                // When the cell is still in a register, the loop is entered
+                // by testing that instead, and tests the cell at the bottom
+                // after that, as the register is only the cell on entry:
+                //
+                //    test al, al
+                //    je end_label
+                // start_label:
+                //    ... ; code
+                //    cmp byte ptr[r8], 0
+                //    jne start_label
+                // end_label:
+                let register_guard =
+                    cell_register.filter(|_| self.settings.optimization_level >= 1 && !bottom_test);
+                let mut start_label = code_asm.create_label();
+                let mut end_label = code_asm.create_label();
+
+                if let Some((register, name)) = register_guard {
+                    emit!(
+                        code_asm.test(register, register),
+                        origin,
+                        "test {name}, {name}"
+                    );
+                    emit!(code_asm.je(end_label), origin, "je end");
+                }
+
+                // phantom instruction so we have an address
+                emit!(code_asm.zero_bytes(), origin, "<label anchor>");
+
+                emit!(code_asm.set_label(&mut start_label), origin, "start:");
+
+                if !bottom_test && register_guard.is_none() {
+                    emit!(
+                        code_asm.cmp(byte_ptr(cell), 0),
+                        origin,
+                        "cmp byte ptr [{cell_name}], 0"
+                    );
+                    emit!(code_asm.je(end_label), origin, "je end");
+                }
+
+                self.translate_block(code_asm, cond_ir_nodes, None)?;
+
+                if self.object_file && self.settings.profile {
+                    // the displacement is relocated to the loop's counter
+                    let mut counter_label = code_asm.create_label();
+                    emit!(code_asm.set_label(&mut counter_label), origin, "counter:");
+                    emit!(
+                        code_asm.inc(qword_ptr(counter_label)),
+                        origin,
+                        "inc qword ptr [rip + counter]"
+                    );
+                    self.loop_counters.push((counter_label, span));
+                }
+
+                if bottom_test || register_guard.is_some() {
+                    emit!(
+                        code_asm.cmp(byte_ptr(cell), 0),
+                        origin,
+                        "cmp byte ptr [{cell_name}], 0"
+                    );
+                    emit!(code_asm.jne(start_label), origin, "jne start");
+                }
+                if register_guard.is_some() {
+                    emit!(code_asm.set_label(&mut end_label), origin, "end:");
+
+                    // phantom instruction so we have an address
+                    emit!(code_asm.zero_bytes(), origin, "<label anchor>");
+                } else if !bottom_test {
+                    emit!(code_asm.jmp(start_label), origin, "jmp start");
+
+                    emit!(code_asm.set_label(&mut end_label), origin, "end:");
+
+                    // phantom instruction so we have an address
+                    emit!(code_asm.zero_bytes(), origin, "<label anchor>");
+                }
+            }
+            //    cmp byte ptr [r8], value
+            //    jne end_label
+            //    ... ; code
+            // end_label:
+            IrOp::IfEq { value, body } => {
+                let mut end_label = code_asm.create_label();
+                emit!(
+                    code_asm.cmp(byte_ptr(cell), value as u32),
+                    origin,
+                    "cmp byte ptr [{cell_name}], {value:#x}"
+                );
+                emit!(code_asm.jne(end_label), origin, "jne end");
+
+                self.translate_block(code_asm, body, Some(value))?;
+
+                emit!(code_asm.set_label(&mut end_label), origin, "end:");
+                // phantom instruction so we have an address
+                emit!(code_asm.zero_bytes(), origin, "<label anchor>");
+            }
+            IrOp::Switch { arms, default } => {
+                self.translate_switch(code_asm, origin, arms, default)?;
+            }
+            IrOp::Function(name, fn_ir_nodes) => {
+                self.translate_function_impl(code_asm, name, span, fn_ir_nodes)?;
+            }
+            IrOp::FunctionCall(name) => match self.scopes.get_fn(&name) {
+                Some(fn_label) => emit!(code_asm.call(fn_label), origin, "call {name}"),
+                None if self.is_other_unit(&name) => self.call_unit(code_asm, origin, name)?,
+                None if self.object_file && self.known_externals.contains(&name) => {
+                    emit!(code_asm.zero_bytes(), origin, "<label anchor>");
+                    // other modules keep the cell pointer in r8 too
+                    let call_site = self.call_external(code_asm, origin, name)?;
+                    self.preserving_calls.push(call_site);
+                }
+                None => {
+                    return Err(CompilerError {
+                        kind: CompilerErrorKind::FunctionNotFound(name),
+                        span: Some(span),
+                    })
+                }
+            },
+            // movzx eax, byte ptr [r8]
+            // cmp eax, <table length>
+            // jae skip_label
+            // lea rcx, [rip + table]
+            // call qword ptr [rcx + rax * 8]   ; see `Compiler::branch_through_table`
+            // skip_label:
+            IrOp::IndirectCall { table } => {
+                let table_label = code_asm.create_label();
+                let mut skip_label = code_asm.create_label();
+                let mut entries = Vec::with_capacity(table.len());
+                let mut unit_entries = Vec::new();
+                for (i, name) in table.into_iter().enumerate() {
+                    let label = match self.scopes.get_fn(&name) {
+                        Some(label) => label,
+                        // points past the call until the entry is patched
+                        None if self.is_other_unit(&name) => {
+                            unit_entries.push((self.jump_tables.len(), i, name.clone()));
+                            skip_label
+                        }
+                        None => {
+                            return Err(CompilerError {
+                                kind: CompilerErrorKind::FunctionNotFound(name),
+                                span: Some(span),
+                            })
+                        }
+                    };
+                    entries.push((name, label));
+                }
+                let len = entries.len() as u32;
+
+                emit!(
+                    code_asm.movzx(eax, byte_ptr(cell)),
+                    origin,
+                    "movzx eax, byte ptr [{cell_name}]"
+                );
+                emit!(code_asm.cmp(eax, len), origin, "cmp eax, {len:#x}");
+                emit!(code_asm.jae(skip_label), origin, "jae skip");
+                self.branch_through_table(code_asm, origin, table_label, true)?;
+                emit!(code_asm.set_label(&mut skip_label), origin, "skip:");
+                // phantom instruction so we have an address
+                emit!(code_asm.zero_bytes(), origin, "<label anchor>");
+                for (_, i, _) in &unit_entries {
+                    entries[*i].1 = skip_label;
+                }
+                self.unit_table_entries.extend(unit_entries);
+                self.jump_tables.push((table_label, entries, origin));
+            }
+            // defined by the program itself, which works on r8 and r9 directly
+            // and leaves any result in the cell, so there is nothing to pass
+            IrOp::ExternalFunctionCall(call) if self.scopes.get_fn(&call.name).is_some() => {
+                let fn_label = self.scopes.get_fn(&call.name).unwrap();
+                emit!(code_asm.call(fn_label), origin, "call {}", call.name);
+            }
+            IrOp::ExternalFunctionCall(call) if self.is_other_unit(&call.name) => {
+                self.call_unit(code_asm, origin, call.name)?;
+            }
+            IrOp::ExternalFunctionCall(call) => {
+                let name = call.name;
+                emit!(code_asm.zero_bytes(), origin, "<label anchor>");
+                let ((cell_arg, cell_arg_name), (stack_arg, stack_arg_name)) =
+                    match self.calling_convention {
+                        CallingConvention::X86_64_SystemVAMD64 => ((rdi, "rdi"), (rsi, "rsi")),
+                        CallingConvention::X86_64_MicrosoftX64 => ((rcx, "rcx"), (rdx, "rdx")),
+                        _ => {
+                            return Err(CompilerError {
+                                kind: CompilerErrorKind::UnsupportedOp(origin.op),
+                                span: Some(origin.span),
+                            })
+                        }
+                    };
+                // r8 and r9 are caller-saved, so unless the callee promises to
+                // leave them alone we push them on the stack and pass the
+                // address of each stack slot, which also lets the callee move
+                // them. Pointers the callee preserves are passed by value.
+                //
+                // With the cell pointer in memory, the callee gets the address
+                // of its slot instead, and r8 is reloaded from it afterwards.
+                let clobbers = call.clobbers;
+                let in_memory = self.cell_pointer_in_memory();
+                let pushes =
+                    (clobbers.cell_pointer && !in_memory) as i32 + clobbers.stack_pointer as i32;
+                if clobbers.cell_pointer && !in_memory {
+                    emit!(code_asm.push(cell), origin, "push {cell_name}");
+                }
+                if clobbers.stack_pointer {
+                    emit!(code_asm.push(stack), origin, "push {stack_name}");
+                }
+                // the pushed slots have to stay clear of the shadow space
+                let frame = self.call_frame(pushes);
+                if frame > 0 {
+                    emit!(code_asm.sub(rsp, frame), origin, "sub rsp, {frame:#x}");
+                }
+                if clobbers.cell_pointer && in_memory {
+                    self.cell_pointer_reference(
+                        code_asm,
+                        origin,
+                        |code_asm, slot| code_asm.lea(cell_arg, qword_ptr(slot)),
+                        &format!("lea {cell_arg_name}, [rip + cell_pointer]"),
+                    )?;
+                } else if clobbers.cell_pointer {
+                    let slot = frame + if clobbers.stack_pointer { 8 } else { 0 };
+                    emit!(
+                        code_asm.lea(cell_arg, qword_ptr(rsp + slot)),
+                        origin,
+                        "lea {cell_arg_name}, [rsp + {slot}]"
+                    );
+                } else {
+                    emit!(
+                        code_asm.mov(cell_arg, cell),
+                        origin,
+                        "mov {cell_arg_name}, {cell_name}"
+                    );
+                }
+                if clobbers.stack_pointer {
+                    emit!(
+                        code_asm.lea(stack_arg, qword_ptr(rsp + frame)),
+                        origin,
+                        "lea {stack_arg_name}, [rsp + {frame}]"
+                    );
+                } else {
+                    emit!(
+                        code_asm.mov(stack_arg, stack),
+                        origin,
+                        "mov {stack_arg_name}, {stack_name}"
+                    );
+                }
+                let call_site = self.call_external(code_asm, origin, name)?;
+                if !clobbers.cell_pointer {
+                    self.preserving_calls.push(call_site);
+                }
+                if frame > 0 {
+                    emit!(code_asm.add(rsp, frame), origin, "add rsp, {frame:#x}");
+                }
+                if clobbers.stack_pointer {
+                    emit!(code_asm.pop(stack), origin, "pop {stack_name}");
+                }
+                if clobbers.cell_pointer && in_memory {
+                    self.load_cell_pointer(code_asm, origin)?;
+                } else if clobbers.cell_pointer {
+                    emit!(code_asm.pop(cell), origin, "pop {cell_name}");
+                }
+                // the return value is still in al, only now that r8 is
+                // restored can we address the cells with it
+                if let Some(offset) = call.status_offset {
+                    emit!(
+                        code_asm.mov(byte_ptr(cell + offset), al),
+                        origin,
+                        "mov byte ptr [{cell_name} + {offset:#x}], al"
+                    );
+                }
+                if call.store_result {
+                    emit!(
+                        code_asm.mov(byte_ptr(cell), al),
+                        origin,
+                        "mov byte ptr [{cell_name}], al"
+                    );
+                    leaves_cell_in = Some((al, "al"));
+                }
+            }
+            IrOp::AddAtOffset { amount: 0, .. } => {}
+            IrOp::AddAtOffset { offset, amount } => emit!(
+                code_asm.add(byte_ptr(cell + offset), amount as u32),
+                origin,
+                "add byte ptr [{cell_name} + {offset:#x}], {amount:#x}"
+            ),
+            IrOp::AddToCells {
+                offset,
+                count,
+                amount,
+            } => self.translate_add_to_cells(code_asm, origin, offset, count, amount)?,
+            IrOp::AddFromCell { src_offset } => {
+                let (_, (scratch, scratch_name)) = self.scratch_register(span)?;
+                emit!(
+                    code_asm.mov(scratch, byte_ptr(cell + src_offset)),
+                    origin,
+                    "mov {scratch_name}, byte ptr [{cell_name} + {src_offset:#x}]"
+                );
+                emit!(
+                    code_asm.add(byte_ptr(cell), scratch),
+                    origin,
+                    "add byte ptr [{cell_name}], {scratch_name}"
+                );
+            }
+            // what a move loop becomes, see [`optimizer::MoveLoops`]
+            IrOp::MoveCell { offset } => {
+                let (_, (scratch, scratch_name)) = self.scratch_register(span)?;
+                emit!(
+                    code_asm.mov(scratch, byte_ptr(cell)),
+                    origin,
+                    "mov {scratch_name}, byte ptr [{cell_name}]"
+                );
+                emit!(
+                    code_asm.add(byte_ptr(cell + offset), scratch),
+                    origin,
+                    "add byte ptr [{cell_name} + {offset:#x}], {scratch_name}"
+                );
+                emit!(
+                    code_asm.mov(byte_ptr(cell), 0),
+                    origin,
+                    "mov byte ptr [{cell_name}], 0"
+                );
+            }
+            // what a clear loop becomes, see [`optimizer::ClearLoops`]
+            IrOp::MulConst(0) => emit!(
+                code_asm.mov(byte_ptr(cell), 0),
+                origin,
+                "mov byte ptr [{cell_name}], 0"
+            ),
+            IrOp::MulConst(k) => {
+                let ((scratch, scratch_name), (scratch_byte, scratch_byte_name)) =
+                    self.scratch_register(span)?;
+                emit!(
+                    code_asm.movzx(scratch, byte_ptr(cell)),
+                    origin,
+                    "movzx {scratch_name}, byte ptr [{cell_name}]"
+                );
+                emit!(
+                    code_asm.imul_3(scratch, scratch, k as i32),
+                    origin,
+                    "imul {scratch_name}, {scratch_name}, {k:#x}"
+                );
+                emit!(
+                    code_asm.mov(byte_ptr(cell), scratch_byte),
+                    origin,
+                    "mov byte ptr [{cell_name}], {scratch_byte_name}"
+                );
+                leaves_cell_in = Some((scratch_byte, scratch_byte_name));
+            }
+            IrOp::WriteByte => {
+                let write = self.settings.runtime_abi.write.clone();
+                self.translate_io(code_asm, origin, write, 1, 1)?;
+                self.flush_output(code_asm, origin, entry_value, true)?;
+            }
+            IrOp::WriteBytes(n) => {
+                let write = self.settings.runtime_abi.write.clone();
+                self.translate_io(code_asm, origin, write, 1, n)?;
+                self.flush_output(code_asm, origin, entry_value, n == 1)?;
+            }
+            IrOp::ReadByte => {
+                let read = self.settings.runtime_abi.read.clone();
+                self.translate_io(code_asm, origin, read, 0, 1)?
+            }
+            IrOp::ReadBytes(n) => self.translate_read_bytes(code_asm, origin, n)?,
+            IrOp::Nop => {}
+            IrOp::DebugMarker(name) => {
+                // takes any label of the previous op, as the marker has its own
+                emit!(code_asm.zero_bytes(), origin, "<label anchor>");
+                let mut label = code_asm.create_label();
+                emit!(code_asm.set_label(&mut label), origin, "{name}:");
+                // phantom instruction so we have an address
+                emit!(code_asm.zero_bytes(), origin, "<label anchor>");
+                self.markers.push((name, label));
+            }
+            // ELF:  mov r8, [rip + name@GOTPCREL]
+            // COFF: lea r8, [rip + name]
+            IrOp::MoveToExternal(name) => {
+                if !self.object_file {
+                    return Err(CompilerError {
+                        kind: CompilerErrorKind::RequiresObjectFile(origin.op),
+                        span: Some(span),
+                    });
+                }
+                let label = match self.settings.object_format {
+                    // the symbol may live in a shared library, so its address
+                    // is loaded from the GOT
+                    ObjectFormat::Elf => self.rip_relative_reference(
+                        code_asm,
+                        origin,
+                        &name,
+                        |code_asm, label| code_asm.mov(cell, qword_ptr(label)),
+                        &format!("mov {cell_name}, [rip + {name}@GOTPCREL]"),
+                    )?,
+                    ObjectFormat::Coff => self.rip_relative_reference(
+                        code_asm,
+                        origin,
+                        &name,
+                        |code_asm, label| code_asm.lea(cell, qword_ptr(label)),
+                        &format!("lea {cell_name}, [rip + {name}]"),
+                    )?,
+                };
+                self.external_data.push((name, label));
+                if self.cell_pointer_in_memory() {
+                    self.store_cell_pointer(code_asm, origin)?;
+                }
+            }
+            // lea r8, [rip + tape + index]
+            IrOp::SeekCell(index) => {
+                let size = match self.settings.static_tape {
+                    Some(size) if self.object_file => size,
+                    _ => {
+                        return Err(CompilerError {
+                            kind: CompilerErrorKind::RequiresStaticTape(origin.op),
+                            span: Some(span),
+                        })
+                    }
+                };
+                if index >= size {
+                    return Err(CompilerError {
+                        kind: CompilerErrorKind::SeekOutOfTape { index, size },
+                        span: Some(span),
+                    });
+                }
+                self.load_tape_address(code_asm, origin, (cell, cell_name), index as u64)?;
+                if self.cell_pointer_in_memory() {
+                    self.store_cell_pointer(code_asm, origin)?;
+                }
+            }
+            // the parser accepts it, but there is no allocator to call yet
+            IrOp::MemAlloc(_) => {
+                return Err(CompilerError {
+                    kind: CompilerErrorKind::UnsupportedOp(origin.op),
+                    span: Some(span),
+                })
+            }
+        }
+        self.cell_value = exit_value;
+        self.cell_register = leaves_cell_in;
+        self.current_origin = outer_origin;
+        self.owners
+            .push((outer_origin, code_asm.instructions().len()));
+        self.trace_depth -= 1;
+        if let Some(TraceSink(trace)) = &self.settings.trace {
+            let instructions = &code_asm.instructions()[start..];
+            trace(&trace_line(
+                origin,
+                self.trace_depth,
+                start,
+                instructions,
+                nested,
+            ));
+        }
+        Ok(())
+    }
+
+    /// Where the instruction at `label` is in the assembled code.
+    fn code_index(&self, result: &CodeAssemblerResult, label: &CodeLabel) -> usize {
+        let ip = result.label_ip(label).expect("couldnt find label ip");
+        (ip - self.settings.base_address) as usize
+    }
+
+    /// Adds the sections of the loop counters and their spans, and points every
+    /// counter's `inc` at its counter.
+    fn add_loop_counters(
+        &self,
+        obj: &mut Object,
+        text_section: SectionId,
+        offset_of: impl Fn(&CodeLabel) -> u64,
+    ) -> Result<(), CompilerError> {
+        let count = self.loop_counters.len() as u64;
+
+        let counters_section = obj.add_section(
+            Vec::new(),
+            b".hf_counters".to_vec(),
+            SectionKind::UninitializedData,
+        );
+        let counters_offset = obj.append_section_bss(counters_section, count * 8, 8);
+        let counters_symbol = obj.add_symbol(Symbol {
+            name: self
+                .settings
+                .symbol_mangling
+                .mangle(PROFILE_COUNTERS_SYMBOL),
+            value: counters_offset,
+            size: count * 8,
+            kind: SymbolKind::Data,
+            scope: SymbolScope::Dynamic,
+            weak: false,
+            section: SymbolSection::Section(counters_section),
+            flags: SymbolFlags::None,
+        });
+
+        let spans = self
+            .loop_counters
+            .iter()
+            .flat_map(|(_, span)| [span.location.0, span.location.1, span.length])
+            .flat_map(|n| (n as u64).to_le_bytes())
+            .collect::<Vec<u8>>();
+        let spans_section = obj.add_section(
+            Vec::new(),
+            b".hf_counter_spans".to_vec(),
+            SectionKind::ReadOnlyData,
+        );
+        let spans_offset = obj.append_section_data(spans_section, &spans, 8);
+        obj.add_symbol(Symbol {
+            name: self.settings.symbol_mangling.mangle(PROFILE_SPANS_SYMBOL),
+            value: spans_offset,
+            size: spans.len() as u64,
+            kind: SymbolKind::Data,
+            scope: SymbolScope::Dynamic,
+            weak: false,
+            section: SymbolSection::Section(spans_section),
+            flags: SymbolFlags::None,
+        });
+
+        for (index, (label, _span)) in self.loop_counters.iter().enumerate() {
+            // `inc qword ptr [rip + disp32]` is 48 ff 05 followed by the
+            // displacement
+            add_rip_relative_relocation(
+                obj,
+                text_section,
+                offset_of(label) + 3,
+                (counters_symbol, PROFILE_COUNTERS_SYMBOL),
+                index as i64 * 8 - 4,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Adds a `.debug_frame` section with an FDE for each of `functions`, the
+    /// start and end of the code of every function in `code`, which is
+    /// `code_offset` bytes into `text_section`. See
+    /// [`CompilerSettings::debug_frame`].
+    fn add_debug_frame(
+        &self,
+        obj: &mut Object,
+        text_section: SectionId,
+        code_offset: u64,
+        code: &[u8],
+        functions: &[(usize, usize)],
+    ) -> Result<(), CompilerError> {
+        let section = obj.add_section(Vec::new(), b".debug_frame".to_vec(), SectionKind::Debug);
+        let mut data = debug_frame_cie();
+        let mut fdes = Vec::with_capacity(functions.len());
+        for &(start, end) in functions {
+            let fde = data.len();
+            let instructions = cfa_instructions(&code[start..end], self.bitness);
+            // the length, pointer to the CIE, start address and size
+            let mut entry = Vec::with_capacity(4 + 4 + 8 + 8 + instructions.len());
+            entry.extend_from_slice(&[0; 4]);
+            entry.extend_from_slice(&[0; 4]);
+            entry.extend_from_slice(&[0; 8]);
+            entry.extend_from_slice(&((end - start) as u64).to_le_bytes());
+            entry.extend_from_slice(&instructions);
+            pad_frame_entry(&mut entry);
+            data.extend_from_slice(&entry);
+            fdes.push((fde, start));
+        }
+        let offset = obj.append_section_data(section, &data, 8);
+
+        let section_symbol = obj.section_symbol(section);
+        let text_symbol = obj.section_symbol(text_section);
+        let cie_pointer = match self.settings.object_format {
+            ObjectFormat::Elf => RelocationKind::Absolute,
+            ObjectFormat::Coff => RelocationKind::SectionOffset,
+        };
+        for (fde, start) in fdes {
+            let fde = offset + fde as u64;
+            add_relocation(
+                obj,
+                section,
+                ".debug_frame",
+                Relocation {
+                    offset: fde + 4,
+                    symbol: section_symbol,
+                    addend: offset as i64,
+                    flags: RelocationFlags::Generic {
+                        kind: cie_pointer,
+                        encoding: RelocationEncoding::Generic,
+                        size: 32,
+                    },
+                },
+            )?;
+            add_relocation(
+                obj,
+                section,
+                ".text",
+                Relocation {
+                    offset: fde + 8,
+                    symbol: text_symbol,
+                    addend: (code_offset + start as u64) as i64,
+                    flags: RelocationFlags::Generic {
+                        kind: RelocationKind::Absolute,
+                        encoding: RelocationEncoding::Generic,
+                        size: 64,
+                    },
+                },
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Zeroes the 32-bit displacement `offset` bytes into the instruction at
+/// `index`, which the linker fills in. A displacement that runs past the end
+/// of the code means a label isn't on the instruction it was meant for, and is
+/// reported instead of panicking.
+pub(super) fn zero_displacement(
+    code: &mut [u8],
+    index: usize,
+    offset: usize,
+) -> Result<(), CompilerError> {
+    let start = index.checked_add(offset);
+    let bytes = start.and_then(|start| code.get_mut(start..start.checked_add(4)?));
+    match bytes {
+        Some(bytes) => {
+            bytes.fill(0);
+            Ok(())
+        }
+        None => Err(CompilerError {
+            kind: CompilerErrorKind::DisplacementOutOfBounds(index),
+            span: None,
+        }),
+    }
+}
+
+/// The 64-bit register `register` stands for, with its name.
+fn pointer_register(register: PointerRegister) -> (AsmRegister64, &'static str) {
+    match register {
+        PointerRegister::R8 => (r8, "r8"),
+        PointerRegister::R9 => (r9, "r9"),
+        PointerRegister::Rbx => (rbx, "rbx"),
+        PointerRegister::Rbp => (rbp, "rbp"),
+        PointerRegister::R12 => (r12, "r12"),
+        PointerRegister::R13 => (r13, "r13"),
+        PointerRegister::R14 => (r14, "r14"),
+        PointerRegister::R15 => (r15, "r15"),
+    }
+}
+
+/// Where the 32-bit displacement of the call at `index` starts within it, and
+/// the addend a relocation of it needs. The displacement is relative to the end
+/// of the call, so the addend is minus the number of bytes from its start to
+/// there: -4 for both `call rel32` and `call [rip + disp32]`, which end with
+/// it, but not for an encoding with anything after the displacement.
+fn call_displacement(
+    code: &[u8],
+    index: usize,
+    bitness: u32,
+) -> Result<(usize, i64), CompilerError> {
+    let bytes = code.get(index..).unwrap_or_default();
+    let mut decoder = Decoder::new(bitness, bytes, DecoderOptions::NONE);
+    let call = decoder.decode();
+    if call.is_invalid() {
+        return Err(CompilerError {
+            kind: CompilerErrorKind::InvalidEncoding(index),
+            span: None,
+        });
+    }
+    // the target of a direct call is an immediate to the decoder
+    let offsets = decoder.get_constant_offsets(&call);
+    let (start, size) = match offsets.has_displacement() {
+        true => (offsets.displacement_offset(), offsets.displacement_size()),
+        false => (offsets.immediate_offset(), offsets.immediate_size()),
+    };
+    if size != 4 {
+        return Err(CompilerError {
+            kind: CompilerErrorKind::DisplacementOutOfBounds(index),
+            span: None,
+        });
+    }
+    Ok((start, start as i64 - call.len() as i64))
+}
+
+/// Adds a relocation against the symbol called `name`, reporting which symbol
+/// and kind of relocation failed if the object format can't represent it.
+pub(super) fn add_relocation(
+    obj: &mut Object,
+    section: SectionId,
+    name: &str,
+    relocation: Relocation,
+) -> Result<(), CompilerError> {
+    let flags = relocation.flags;
+    obj.add_relocation(section, relocation)
+        .map_err(|e| CompilerError {
+            kind: CompilerErrorKind::RelocationFailed {
+                symbol: name.to_string(),
+                relocation: match flags {
+                    RelocationFlags::Generic {
+                        kind,
+                        encoding,
+                        size,
+                    } => format!("{size}-bit {kind:?} ({encoding:?})"),
+                    flags => format!("{flags:?}"),
+                },
+                message: e.to_string(),
+            },
+            span: None,
+        })
+}
+
+/// The size of `instruction` once assembled. Branches are counted at their
+/// near form, which is an upper bound: the block encoder uses the short form
+/// wherever the target is close enough.
+fn max_instruction_size(instruction: &Instruction, bitness: u32) -> Result<usize, CompilerError> {
+    let mut instruction = *instruction;
+    instruction.set_code(instruction.code().as_near_branch());
+    // targets are labels until the block encoder lays out the code, so the
+    // instruction is encoded right at its target, which keeps the
+    // displacement in range without changing the size
+    let ip = if instruction.is_ip_rel_memory_operand() {
+        instruction.ip_rel_memory_address()
+    } else {
+        instruction.near_branch_target()
+    };
+    Encoder::new(bitness)
+        .encode(&instruction, ip)
+        .map_err(|e| CompilerError {
+            kind: CompilerErrorKind::AssemblerError(e.to_string()),
+            span: None,
+        })
+}
+
+/// Appends `code` to `section` at the next multiple of [`CODE_ALIGNMENT`] and
+/// returns its offset. object pads sections with zeros, which decode as
+/// `add [rax], al`, so the padding is filled in here first.
+fn append_code(obj: &mut Object, section: SectionId, code: &[u8]) -> u64 {
+    let len = obj.section(section).data().len();
+    let padding = len.next_multiple_of(CODE_ALIGNMENT) - len;
+    obj.append_section_data(section, &vec![CODE_PADDING; padding], 1);
+    obj.append_section_data(section, code, CODE_ALIGNMENT as u64)
+}
+
+/// Reserves `size` zeroed bytes in a new `.bss` section, under a global symbol
+/// called `name`, as it is mangled.
+fn add_bss_symbol(obj: &mut Object, name: Vec<u8>, size: u64, align: u64) -> SymbolId {
+    let section = obj.add_section(Vec::new(), b".bss".to_vec(), SectionKind::UninitializedData);
+    let offset = obj.append_section_bss(section, size, align);
+    obj.add_symbol(Symbol {
+        name,
+        value: offset,
+        size,
+        kind: SymbolKind::Data,
+        scope: SymbolScope::Dynamic,
+        weak: false,
+        section: SymbolSection::Section(section),
+        flags: SymbolFlags::None,
+    })
+}
+
+/// Relocates the 32-bit displacement of a `[rip + disp32]` operand at `offset`
+/// to `symbol + addend`. The displacement is relative to the end of the
+/// instruction, so for one that ends right after it, `addend` has to be 4 less
+/// than the offset into the symbol.
+fn add_rip_relative_relocation(
+    obj: &mut Object,
+    section: SectionId,
+    offset: u64,
+    (symbol, name): (SymbolId, &str),
+    addend: i64,
+) -> Result<(), CompilerError> {
+    add_relocation(
+        obj,
+        section,
+        name,
+        Relocation {
+            offset,
+            symbol,
+            addend,
+            flags: RelocationFlags::Generic {
+                kind: RelocationKind::Relative,
+                encoding: RelocationEncoding::X86RipRelative,
+                size: 32,
+            },
+        },
+    )
+}
+
+/// The range of values the jump table for a `Switch` with `arms` covers, if
+/// they are dense enough for one: at least [`MIN_JUMP_TABLE_ARMS`] distinct
+/// values, which have an arm for at least half of the range.
+fn jump_table_range(arms: &[(u8, Vec<IrNode>)]) -> Option<(u8, u8)> {
+    let mut values = arms.iter().map(|(value, _)| *value).collect::<Vec<_>>();
+    values.sort_unstable();
+    values.dedup();
+    let (&low, &high) = (values.first()?, values.last()?);
+    let dense = values.len() >= MIN_JUMP_TABLE_ARMS && ((high - low) as usize) < 2 * values.len();
+    dense.then_some((low, high))
+}
+
+/// Describes every node in `ir` and the bodies within it, for the comments of
+/// [`CompilerSettings::ir_comments`]. A node with a body is only named, as its
+/// body is described on its own.
+#[cfg(feature = "disasm")]
+fn describe_ops(ir: &[IrNode], ops: &mut Vec<(Span, String)>) {
+    for node in ir {
+        match &node.node {
+            IrOp::Function(_, body) | IrOp::Condition(body) | IrOp::IfEq { body, .. } => {
+                ops.push((node.span, node.node.name().to_string()));
+                describe_ops(body, ops);
+            }
+            IrOp::Switch { arms, default } => {
+                ops.push((node.span, node.node.name().to_string()));
+                for (_, body) in arms {
+                    describe_ops(body, ops);
+                }
+                describe_ops(default, ops);
+            }
+            op => ops.push((node.span, format!("{op:?}"))),
+        }
+    }
+}
+
+/// Names the labels in the text of [`Compiler::compile_to_text`], by their
+/// address. Only branch targets and rip-relative operands are looked up, so
+/// that immediates and displacements that happen to equal an address stay
+/// numbers.
+#[cfg(feature = "disasm")]
+struct LabelResolver(alloc::collections::BTreeMap<u64, String>);
+
+#[cfg(feature = "disasm")]
+impl iced_x86::SymbolResolver for LabelResolver {
+    fn symbol(
+        &mut self,
+        instruction: &Instruction,
+        _operand: u32,
+        instruction_operand: Option<u32>,
+        address: u64,
+        _address_size: u32,
+    ) -> Option<iced_x86::SymbolResult<'_>> {
+        use iced_x86::OpKind;
+
+        let label = match instruction.op_kind(instruction_operand?) {
+            OpKind::NearBranch16 | OpKind::NearBranch32 | OpKind::NearBranch64 => true,
+            OpKind::Memory => instruction.is_ip_rel_memory_operand(),
+            _ => false,
+        };
+        if !label {
+            return None;
+        }
+        let name = self.0.get(&address)?;
+        Some(iced_x86::SymbolResult::with_str(address, name))
+    }
+}
+
+/// The line [`CompilerSettings::trace`] gets for the node `origin`, nested
+/// `depth` deep, whose `instructions` start at index `start`. Those of a node
+/// with a body include the body's, so only their number is given.
+fn trace_line(
+    origin: Origin,
+    depth: usize,
+    start: usize,
+    instructions: &[Instruction],
+    nested: bool,
+) -> String {
+    use core::fmt::Write as _;
+
+    let (line, column) = origin.span.location;
+    let end = start + instructions.len();
+    let mut text = format!(
+        "{:indent$}{} {line}:{column} #{start}..#{end}",
+        "",
+        origin.op,
+        indent = depth * 2
+    );
+    if nested {
+        let _ = write!(text, ": {} instructions", instructions.len());
+    } else {
+        for (i, instruction) in instructions.iter().enumerate() {
+            let separator = if i == 0 { ": " } else { "; " };
+            let _ = write!(text, "{separator}{:?}", instruction.code());
+        }
+    }
+    text
+}
+
+/// The CIE of `.debug_frame`, which every FDE refers to: version 1, without
+/// augmentation, code aligned to bytes, data to 8 bytes, and the return
+/// address in DWARF register 16. Functions are entered with the CFA 8 bytes
+/// above rsp, right above the return address.
+fn debug_frame_cie() -> Vec<u8> {
+    const DW_CFA_DEF_CFA: u8 = 0x0c;
+    const DW_CFA_OFFSET: u8 = 0x80;
+    const RSP: u8 = 7;
+    const RETURN_ADDRESS: u8 = 16;
+
+    let mut cie = Vec::new();
+    cie.extend_from_slice(&[0; 4]);
+    // the CIE id
+    cie.extend_from_slice(&u32::MAX.to_le_bytes());
+    cie.push(1);
+    // no augmentation
+    cie.push(0);
+    // the code and data alignment factors, as ULEB128 1 and SLEB128 -8
+    cie.extend_from_slice(&[0x01, 0x78]);
+    cie.push(RETURN_ADDRESS);
+    cie.extend_from_slice(&[DW_CFA_DEF_CFA, RSP, 8]);
+    cie.extend_from_slice(&[DW_CFA_OFFSET | RETURN_ADDRESS, 1]);
+    pad_frame_entry(&mut cie);
+    cie
+}
+
+/// Pads a CIE or FDE to a multiple of 8 bytes with `DW_CFA_nop`, and fills in
+/// its length, which doesn't count the length itself.
+fn pad_frame_entry(entry: &mut Vec<u8>) {
+    entry.resize(entry.len().next_multiple_of(8), 0);
+    let length = entry.len() as u32 - 4;
+    entry[..4].copy_from_slice(&length.to_le_bytes());
+}
+
+/// The call frame instructions of an FDE for the code of a function: where
+/// the CFA is after every push, pop and adjustment of rsp. Every op pushes no
+/// more than it pops, so every branch lands where the stack is as deep as
+/// right before it, and following the code from start to end is enough.
+fn cfa_instructions(code: &[u8], bitness: u32) -> Vec<u8> {
+    use iced_x86::{Mnemonic, OpKind, Register};
+
+    const DW_CFA_ADVANCE_LOC: u8 = 0x40;
+    const DW_CFA_ADVANCE_LOC1: u8 = 0x02;
+    const DW_CFA_ADVANCE_LOC2: u8 = 0x03;
+    const DW_CFA_ADVANCE_LOC4: u8 = 0x04;
+    const DW_CFA_DEF_CFA_OFFSET: u8 = 0x0e;
+
+    let mut instructions = Vec::new();
+    let (mut depth, mut location) = (0i64, 0usize);
+    for instruction in Decoder::new(bitness, code, DecoderOptions::NONE) {
+        let adjusts_rsp = instruction.op_count() == 2
+            && instruction.op0_kind() == OpKind::Register
+            && instruction.op0_register() == Register::RSP
+            && instruction.op1_kind() != OpKind::Register;
+        let change = match instruction.mnemonic() {
+            Mnemonic::Push => 8,
+            Mnemonic::Pop => -8,
+            Mnemonic::Sub if adjusts_rsp => instruction.immediate(1) as i64,
+            Mnemonic::Add if adjusts_rsp => -(instruction.immediate(1) as i64),
+            _ => continue,
+        };
+        depth += change;
+        let end = instruction.next_ip() as usize;
+        match end - location {
+            delta @ 0..=0x3f => instructions.push(DW_CFA_ADVANCE_LOC | delta as u8),
+            delta @ 0..=0xff => instructions.extend_from_slice(&[DW_CFA_ADVANCE_LOC1, delta as u8]),
+            delta @ 0..=0xffff => {
+                instructions.push(DW_CFA_ADVANCE_LOC2);
+                instructions.extend_from_slice(&(delta as u16).to_le_bytes());
+            }
+            delta => {
+                instructions.push(DW_CFA_ADVANCE_LOC4);
+                instructions.extend_from_slice(&(delta as u32).to_le_bytes());
+            }
+        }
+        location = end;
+        instructions.push(DW_CFA_DEF_CFA_OFFSET);
+        // ULEB128
+        let mut offset = (depth + 8) as u64;
+        loop {
+            let byte = (offset & 0x7f) as u8;
+            offset >>= 7;
+            if offset == 0 {
+                instructions.push(byte);
+                break;
+            }
+            instructions.push(byte | 0x80);
+        }
+    }
+    instructions
+}
+
+/// The size of a page, as far as [`StackOverflow::Guard`] is concerned.
+const PAGE_SIZE: u64 = 0x1000;
+
+/// The size of the stack of [`StackOverflow::Guard`], which is
+/// [`CompilerSettings::stack_size`] rounded up to whole pages, so the guard
+/// page above it starts right at its end.
+fn guarded_stack_size(stack_size: usize) -> u64 {
+    (stack_size as u64).next_multiple_of(PAGE_SIZE)
+}
+
+/// A part of a program as [`Compiler::compile_incremental`] keeps it for the
+/// next compilation: a top-level function, or the code between two of them.
+#[derive(Clone)]
+struct CachedUnit {
+    /// The IR of the part with every span zeroed, as the code doesn't depend
+    /// on them, see [`strip_spans`].
+    ir: Vec<IrNode>,
+    /// The spans of the IR, in the order [`strip_spans`] takes them out.
+    spans: Vec<Span>,
+    /// The value of the cell when the part is reached, and when it is left.
+    entry_value: Option<u8>,
+    exit_value: Option<u8>,
+    /// The names looked up in [`Compiler::other_units`], and whether they
+    /// were found.
+    lookups: Vec<(String, bool)>,
+    /// The code, assembled for [`CompilerSettings::base_address`].
+    code: Vec<u8>,
+    /// Where the displacement of every call to a function of another part is,
+    /// the addend it needs and the name of the function.
+    calls: Vec<(usize, i64, String)>,
+    /// Where every `IndirectCall` table entry for a function of another part
+    /// is, where its table starts and the name of the function.
+    table_entries: Vec<(usize, usize, String)>,
+    /// See [`Compiler::is_pure`].
+    pure: bool,
+    stats: CompilationStats,
+    warnings: Vec<CompilerWarning>,
+}
+
+impl CachedUnit {
+    /// Moves every span in the stats and warnings of the part to where the
+    /// node it belongs to is now: `spans` are those of the same IR, in the
+    /// same order as [`CachedUnit::spans`].
+    fn respan(&mut self, spans: Vec<Span>) {
+        let key = |span: &Span| (span.location, span.length);
+        let mut moves = self.spans.iter().zip(&spans).collect::<Vec<_>>();
+        moves.sort_by_key(|(old, _)| key(old));
+        let respan = |span: &mut Span| {
+            if let Ok(i) = moves.binary_search_by_key(&key(span), |(old, _)| key(old)) {
+                *span = *moves[i].1;
+            }
+        };
+        for (span, _) in &mut self.stats.spans {
+            respan(span);
+        }
+        for reference in &mut self.stats.absolute_references {
+            respan(&mut reference.span);
+        }
+        for warning in &mut self.warnings {
+            respan(&mut warning.span);
+        }
+        self.spans = spans;
+    }
+}
+
+/// Copies `ir` with the span of every node, nested ones included, zeroed, and
+/// returns them in the order they are visited in.
+fn strip_spans(ir: &[IrNode]) -> (Vec<IrNode>, Vec<Span>) {
+    fn strip(ir: &mut [IrNode], spans: &mut Vec<Span>) {
+        for node in ir {
+            spans.push(core::mem::replace(
+                &mut node.span,
+                Span::from_location((0, 0)),
+            ));
+            match &mut node.node {
+                IrOp::Function(_, children)
+                | IrOp::Condition(children)
+                | IrOp::IfEq { body: children, .. } => strip(children, spans),
+                IrOp::Switch { arms, default } => {
+                    for body in arms.iter_mut().map(|(_, body)| body).chain([default]) {
+                        strip(body, spans);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let mut stripped = ir.to_vec();
+    let mut spans = Vec::new();
+    strip(&mut stripped, &mut spans);
+    (stripped, spans)
+}
+
+/// The key of a part of a program in [`Compiler::compile_incremental`]'s
+/// cache: an FNV-1a hash of its IR, with the spans stripped, and of the value
+/// of the cell when it is reached.
+fn unit_key(ir: &[IrNode], value: Option<u8>) -> u64 {
+    use core::hash::{Hash, Hasher};
+
+    struct Fnv1a(u64);
+
+    impl Hasher for Fnv1a {
+        fn finish(&self) -> u64 {
+            self.0
+        }
+
+        fn write(&mut self, bytes: &[u8]) {
+            for &byte in bytes {
+                self.0 = (self.0 ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3);
+            }
+        }
+    }
+
+    let mut hasher = Fnv1a(0xcbf2_9ce4_8422_2325);
+    ir.hash(&mut hasher);
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// The IR op an instruction is emitted for, kept around for error reporting.
+#[derive(Debug, Clone, Copy)]
+struct Origin {
+    op: &'static str,
+    span: Span,
+}
+
+/// Wraps an error from iced-x86 with the IR op and the instruction that was
+/// being emitted for it, since iced-x86's own messages carry neither.
+fn encoding_error(error: IcedError, origin: Origin, instruction: String) -> CompilerError {
+    CompilerError {
+        kind: CompilerErrorKind::EncodingFailed {
+            op: origin.op,
+            instruction,
+            message: error.to_string(),
+        },
+        span: Some(origin.span),
+    }
+}
+
+impl super::CompilerTrait for Compiler {
+    fn compile_to_bytecode(&mut self, ir: Vec<IrNode>) -> Result<Vec<u8>, CompilerError> {
+        self.start()?;
+        self.warn_dead_loops(&ir, None);
+        let ir = optimizer::optimize(ir, &self.settings);
+        let code = self.translate_ir_node(ir)?.inner.code_buffer;
+        Ok(self.append_checksum(code))
+    }
+
+    fn compile_stream(
+        &mut self,
+        ir: &mut dyn Iterator<Item = IrNode>,
+    ) -> Result<Vec<u8>, CompilerError> {
+        // both need the whole program up front
+        let unsupported = if self.settings.optimization_level > 0 {
+            Some("`optimization_level`")
+        } else if self.settings.entry_offset.is_some() {
+            Some("`entry_offset`")
+        } else {
+            None
+        };
+        if let Some(setting) = unsupported {
+            return Err(CompilerError {
+                kind: CompilerErrorKind::UnsupportedOp(setting),
+                span: None,
+            });
+        }
+        self.start()?;
+        let mut code_asm = CodeAssembler::new(self.bitness).unwrap();
+        let mut value = None;
+        for node in ir {
+            value = self.warn_dead_loops(core::slice::from_ref(&node), value);
+            self.translate_ir_node_impl(&mut code_asm, node)?;
+        }
+        let code = self.assemble(code_asm)?.inner.code_buffer;
+        Ok(self.append_checksum(code))
+    }
+
+    fn compile_incremental(&mut self, ir: Vec<IrNode>) -> Result<Vec<u8>, CompilerError> {
+        self.start()?;
+        self.warn_dead_loops(&ir, None);
+        let mut warnings = core::mem::take(&mut self.warnings);
+        let ir = optimizer::optimize(ir, &self.settings);
+
+        let is_function = |node: &IrNode| matches!(node.node, IrOp::Function(_, _));
+        let functions = ir
+            .iter()
+            .filter_map(|node| match &node.node {
+                IrOp::Function(name, _) => Some(name.clone()),
+                _ => None,
+            })
+            .collect::<BTreeSet<_>>();
+        let mut parts: Vec<Vec<IrNode>> = Vec::new();
+        for node in ir {
+            match parts.last_mut() {
+                Some(part) if !is_function(&node) && !is_function(&part[0]) => part.push(node),
+                _ => parts.push(vec![node]),
+            }
+        }
+
+        let mut units = Vec::with_capacity(parts.len());
+        let mut reused_functions = Vec::new();
+        let mut value = None;
+        for part in parts {
+            // a function starts out knowing nothing about the cell
+            let entry_value = if is_function(&part[0]) { None } else { value };
+            let (ir, spans) = strip_spans(&part);
+            let cached = self
+                .unit_cache
+                .get(&unit_key(&ir, entry_value))
+                .filter(|unit| {
+                    unit.ir == ir
+                        && unit.entry_value == entry_value
+                        && unit
+                            .lookups
+                            .iter()
+                            .all(|(name, found)| functions.contains(name) == *found)
+                })
+                .cloned();
+            let unit = match cached {
+                Some(mut unit) => {
+                    if let IrOp::Function(name, _) = &part[0].node {
+                        reused_functions.push(name.clone());
+                    }
+                    unit.respan(spans);
+                    unit
+                }
+                None => self.compile_unit(part, entry_value, &functions)?,
+            };
+            value = unit.exit_value;
+            units.push(unit);
+        }
+
+        // lay the parts out one after the other, and patch up what refers
+        // from one to another
+        let mut starts = Vec::with_capacity(units.len());
+        let mut function_starts = Map::new();
+        let mut code = Vec::new();
+        for unit in &units {
+            if let IrOp::Function(name, _) = &unit.ir[0].node {
+                function_starts.entry(name.clone()).or_insert(code.len());
+            }
+            starts.push(code.len());
+            code.extend_from_slice(&unit.code);
+        }
+        let base = self.settings.base_address;
+        let mut stats = CompilationStats::default();
+        for (unit, &start) in units.iter().zip(&starts) {
+            for reference in &unit.stats.absolute_references {
+                let slot = &mut code[start + reference.offset..][..8];
+                let address = u64::from_le_bytes(slot.try_into().unwrap()) + start as u64;
+                slot.copy_from_slice(&address.to_le_bytes());
+            }
+            for (offset, table, name) in &unit.table_entries {
+                let slot = &mut code[start + offset..];
+                if self.settings.pic {
+                    let offset = function_starts[name] as i64 - (start + table) as i64;
+                    slot[..4].copy_from_slice(&(offset as i32).to_le_bytes());
+                } else {
+                    let address = base + function_starts[name] as u64;
+                    slot[..8].copy_from_slice(&address.to_le_bytes());
+                }
+            }
+            for (offset, addend, name) in &unit.calls {
+                let displacement = function_starts[name] as i64 + addend - (start + offset) as i64;
+                code[start + offset..][..4].copy_from_slice(&(displacement as i32).to_le_bytes());
+            }
+
+            for (&op, op_stats) in &unit.stats.ops {
+                let total = stats.ops.entry(op).or_default();
+                total.count += op_stats.count;
+                total.bytes += op_stats.bytes;
+            }
+            stats
+                .absolute_references
+                .extend(
+                    unit.stats
+                        .absolute_references
+                        .iter()
+                        .map(|reference| AbsoluteReference {
+                            offset: start + reference.offset,
+                            ..reference.clone()
+                        }),
+                );
+            stats.markers.extend(
+                unit.stats
+                    .markers
+                    .iter()
+                    .map(|(name, offset)| (name.clone(), start + offset)),
+            );
+            stats.spans.extend(
+                unit.stats
+                    .spans
+                    .iter()
+                    .map(|&(span, offset)| (span, start + offset)),
+            );
+            warnings.extend_from_slice(&unit.warnings);
+        }
+        stats.code_size = code.len();
+        stats.reused_functions = reused_functions;
+
+        // drop what is left of the last part compiled
+        self.reset();
+        self.makes_syscalls = !units.iter().all(|unit| unit.pure);
+        self.stats = stats;
+        self.warnings = warnings;
+        self.unit_cache = units
+            .into_iter()
+            .map(|unit| (unit_key(&unit.ir, unit.entry_value), unit))
+            .collect();
+        match self.settings.max_code_size {
+            Some(limit) if code.len() > limit => Err(CompilerError {
+                kind: CompilerErrorKind::CodeSizeLimitExceeded(limit),
+                span: None,
+            }),
+            _ => Ok(self.append_checksum(code)),
+        }
+    }
+
+    fn compile_function(&mut self, func: IrNode) -> Result<(Vec<u8>, usize), CompilerError> {
+        self.start()?;
+        if !matches!(func.node, IrOp::Function(_, _)) {
+            return Err(CompilerError {
+                kind: CompilerErrorKind::NotAFunction(func.node.name()),
+                span: Some(func.span),
+            });
+        }
+        self.warn_dead_loops(core::slice::from_ref(&func), None);
+        let func = optimizer::optimize(vec![func], &self.settings)
+            .pop()
+            .expect("optimisation passes keep functions");
+        let IrOp::Function(name, children) = func.node else {
+            unreachable!("optimisation passes keep functions");
+        };
+
+        let mut code_asm = CodeAssembler::new(self.bitness).unwrap();
+        self.translate_function_impl(&mut code_asm, name.clone(), func.span, children)?;
+        let result = self.assemble(code_asm)?;
+        let label = self
+            .scopes
+            .get_fn(&name)
+            .expect("couldnt find function label");
+        let entry = self.code_index(&result, &label);
+        Ok((result.inner.code_buffer, entry))
+    }
+
+    fn compile_to_callable(&mut self, ast: Vec<IrNode>) -> Result<CallableCode, CompilerError> {
+        // can't be the name of a function of the program
+        const ENTRY: &str = "<entry>";
+
+        self.start()?;
+        // the caller passes in a tape of its own, which may not be zeroed
+        self.warn_dead_loops(&ast, None);
+        let ir = optimizer::optimize(ast, &self.settings);
+        let (mut functions, top_level): (Vec<_>, Vec<_>) = ir
+            .into_iter()
+            .partition(|node| matches!(node.node, IrOp::Function(_, _)));
+        let span = top_level
+            .first()
+            .map_or(Span::from_location((0, 0)), |node| node.span);
+        functions.push(IrNode {
+            node: IrOp::Function(ENTRY.to_string(), top_level),
+            span,
+        });
+
+        let mut code_asm = CodeAssembler::new(self.bitness).unwrap();
+        self.declare_functions(&mut code_asm, &functions);
+        for node in functions {
+            self.translate_ir_node_impl(&mut code_asm, node)?;
+        }
+        self.emit_entry_wrapper(&mut code_asm, ENTRY.to_string(), span)?;
+        let result = self.assemble(code_asm)?;
+
+        let (_, label) = self
+            .entry_wrappers
+            .last()
+            .expect("the wrapper was just emitted");
+        let entry = self.code_index(&result, label);
+        let external_calls = self
+            .external_calls
+            .iter()
+            .map(|(name, labels)| {
+                let calls = labels
+                    .iter()
+                    .map(|label| self.code_index(&result, label))
+                    .collect();
+                (name.clone(), calls)
+            })
+            .collect();
+        Ok(CallableCode {
+            code: result.inner.code_buffer,
+            entry,
+            external_calls,
+        })
+    }
+
+    fn compile_to_object_file(
+        &mut self,
+        ast: Vec<IrNode>,
+        filename: &str,
+    ) -> Result<Object<'static>, CompilerError> {
+        let format = match self.settings.object_format {
+            ObjectFormat::Elf => BinaryFormat::Elf,
+            ObjectFormat::Coff => BinaryFormat::Coff,
+        };
+        let mut obj = Object::new(format, Architecture::X86_64, Endianness::Little);
+        let file_symbol = match &self.settings.file_symbol {
+            FileSymbol::SourceFilename => Some(filename),
+            FileSymbol::Named(name) => Some(name.as_str()),
+            FileSymbol::Omitted => None,
+        };
+        if let Some(name) = file_symbol {
+            obj.add_file_symbol(name.as_bytes().to_vec());
+        }
+        let text_section = obj.add_section(Vec::new(), b".text".to_vec(), SectionKind::Text);
+        if let Some(size) = self.settings.expected_code_size {
+            obj.section_mut(text_section)
+                .set_data(Vec::with_capacity(size), 1);
+        }
+        self.compile_into_object(ast, &mut obj, text_section)?;
+        if self.settings.cet {
+            // only ELF has the note, COFF objects are left as they are
+            obj.add_elf_gnu_property_u32(
+                elf::GNU_PROPERTY_X86_FEATURE_1_AND,
+                elf::GNU_PROPERTY_X86_FEATURE_1_IBT,
+            );
+        }
+        Ok(obj)
+    }
+
+    fn compile_to_object_file_with_externals(
+        &mut self,
+        ast: Vec<IrNode>,
+        filename: &str,
+        externals: &[String],
+    ) -> Result<Object<'static>, CompilerError> {
+        self.known_externals = externals.to_vec();
+        let result = self.compile_to_object_file(ast, filename);
+        self.known_externals.clear();
+        result
+    }
+
+    fn plan(&mut self, ast: Vec<IrNode>) -> Result<CompilePlan, CompilerError> {
+        self.start()?;
+        self.object_file = true;
+        self.warn_dead_loops(&ast, None);
+        let ast = optimizer::optimize(ast, &self.settings);
+        let fn_ast = self.arrange_functions(ast)?;
+        let functions = fn_ast
+            .iter()
+            .filter_map(|node| match &node.node {
+                IrOp::Function(name, _) => Some(name.clone()),
+                _ => None,
+            })
+            .collect();
+        let code_asm = self.lower(fn_ast)?;
+
+        let instructions = code_asm.instructions();
+        let sizes = instructions
+            .iter()
+            .map(|instruction| max_instruction_size(instruction, self.bitness))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut nodes = Vec::new();
+        for (i, &(owner, start)) in self.owners.iter().enumerate() {
+            let end = self
+                .owners
+                .get(i + 1)
+                .map_or(instructions.len(), |&(_, end)| end);
+            if let Some(origin) = owner.filter(|_| start < end) {
+                nodes.push(PlannedNode {
+                    op: origin.op,
+                    span: origin.span,
+                    instructions: instructions[start..end].to_vec(),
+                    max_size: sizes[start..end].iter().sum(),
+                });
+            }
+        }
+        let mut external_calls = self
+            .external_calls
+            .iter()
+            .map(|(name, call_sites)| (name.clone(), call_sites.len()))
+            .collect::<Vec<_>>();
+        external_calls.sort();
+
+        Ok(CompilePlan {
+            nodes,
+            max_code_size: sizes.iter().sum(),
+            external_calls,
+            functions,
+            warnings: self.warnings.clone(),
+        })
+    }
+
+    #[cfg(feature = "disasm")]
+    fn compile_to_text(&mut self, ir: Vec<IrNode>) -> Result<String, CompilerError> {
+        use alloc::boxed::Box;
+        use alloc::collections::{BTreeMap, BTreeSet};
+        use core::fmt::Write as _;
+        use iced_x86::{Formatter as _, IntelFormatter, OpKind};
+
+        self.start()?;
+        self.warn_dead_loops(&ir, None);
+        let ir = optimizer::optimize(ir, &self.settings);
+        let mut ops = Vec::new();
+        if self.settings.ir_comments {
+            describe_ops(&ir, &mut ops);
+        }
+        let result = self.translate_ir_node(ir)?;
+        let code = &result.inner.code_buffer;
+        let base = self.settings.base_address;
+        // every stretch of code starts at a different offset, as only those
+        // that aren't empty are in the map
+        let comments = self
+            .stats
+            .spans
+            .iter()
+            .filter_map(|(span, offset)| {
+                let (_, op) = ops.iter().find(|(owner, _)| owner == span)?;
+                Some((base + *offset as u64, op))
+            })
+            .collect::<BTreeMap<_, _>>();
+        let ip = |label: &CodeLabel| result.label_ip(label).expect("couldnt find label ip");
+        let code_end = self
+            .jump_tables
+            .first()
+            .map_or(code.len(), |(label, _, _)| self.code_index(&result, label));
+        let instructions =
+            Decoder::with_ip(self.bitness, &code[..code_end], base, DecoderOptions::NONE)
+                .into_iter()
+                .collect::<Vec<_>>();
+
+        // in bytecode, each external is called through a `call` to itself
+        let external_calls = self
+            .external_calls
+            .iter()
+            .flat_map(|(name, labels)| labels.iter().map(move |label| (ip(label), name)))
+            .collect::<BTreeMap<_, _>>();
+
+        // functions and markers keep their names, anything else that is
+        // jumped to or referred to is numbered in order
+        let mut names = BTreeMap::new();
+        for (name, label) in self.scopes.get_global_functions() {
+            names.insert(ip(label), name.clone());
+        }
+        for (name, label) in &self.markers {
+            names.entry(ip(label)).or_insert_with(|| name.clone());
+        }
+        let mut targets = BTreeSet::new();
+        for instruction in &instructions {
+            if external_calls.contains_key(&instruction.ip()) {
+                continue;
+            }
+            if matches!(
+                instruction.op0_kind(),
+                OpKind::NearBranch16 | OpKind::NearBranch32 | OpKind::NearBranch64
+            ) {
+                targets.insert(instruction.near_branch_target());
+            }
+            if instruction.is_ip_rel_memory_operand() {
+                targets.insert(instruction.ip_rel_memory_address());
+            }
+        }
+        for (label, entries, _) in &self.jump_tables {
+            targets.insert(ip(label));
+            targets.extend(entries.iter().map(|(_, entry)| ip(entry)));
+        }
+        let unnamed = targets
+            .into_iter()
+            .filter(|target| !names.contains_key(target))
+            .collect::<Vec<_>>();
+        for (i, target) in unnamed.into_iter().enumerate() {
+            names.insert(target, format!(".L{i}"));
+        }
+
+        let mut formatter =
+            IntelFormatter::with_options(Some(Box::new(LabelResolver(names.clone()))), None);
+        let options = formatter.options_mut();
+        options.set_space_after_operand_separator(true);
+        options.set_space_between_memory_add_operators(true);
+        options.set_hex_prefix("0x");
+        options.set_hex_suffix("");
+        options.set_uppercase_hex(false);
+        options.set_show_branch_size(false);
+
+        let mut text = String::new();
+        let mut externals = self.external_calls.keys().collect::<Vec<_>>();
+        externals.sort();
+        if !externals.is_empty() {
+            let externals = externals
+                .into_iter()
+                .map(String::as_str)
+                .collect::<Vec<_>>();
+            writeln!(text, "; externals: {}", externals.join(", ")).unwrap();
+        }
+        for instruction in &instructions {
+            if let Some(name) = names.get(&instruction.ip()) {
+                writeln!(text, "{name}:").unwrap();
+            }
+            if let Some(op) = comments.get(&instruction.ip()) {
+                writeln!(text, "    ; {op}").unwrap();
+            }
+            text.push_str("    ");
+            match external_calls.get(&instruction.ip()) {
+                Some(name) => write!(text, "call {name}").unwrap(),
+                None => formatter.format(instruction, &mut text),
+            }
+            text.push('\n');
+        }
+        // a branch to the very end, unless a jump table is there
+        if self.jump_tables.is_empty() {
+            if let Some(name) = names.get(&(base + code_end as u64)) {
+                writeln!(text, "{name}:").unwrap();
+            }
+        }
+        for (label, entries, _) in &self.jump_tables {
+            let table = &names[&ip(label)];
+            writeln!(text, "{table}:").unwrap();
+            for (_, entry) in entries {
+                match self.settings.pic {
+                    true => writeln!(text, "    dd {} - {table}", names[&ip(entry)]).unwrap(),
+                    false => writeln!(text, "    dq {}", names[&ip(entry)]).unwrap(),
+                }
+            }
+        }
+        Ok(text)
+    }
+
+    fn stats(&self) -> &CompilationStats {
+        &self.stats
+    }
+
+    fn warnings(&self) -> &[CompilerWarning] {
+        &self.warnings
+    }
+
+    fn dump_scopes(&self) -> String {
+        self.scopes.dump()
+    }
+
+    fn is_pure(&self) -> bool {
+        self.external_calls.is_empty() && self.external_data.is_empty() && !self.makes_syscalls
+    }
+
+    fn imports(&self) -> Vec<Import> {
+        let settings = &self.settings;
+        let mut imports = self
+            .external_calls
+            .keys()
+            .map(|name| {
+                let mut symbol = settings.symbol_mangling.mangle(name);
+                if settings.external_calls == ExternalCalls::IndirectGot {
+                    symbol = [&b"__imp_"[..], &symbol].concat();
+                }
+                let ordinal = settings.import_ordinals.iter().find(|(n, _)| n == name);
+                Import {
+                    name: name.clone(),
+                    symbol: String::from_utf8_lossy(&symbol).into_owned(),
+                    lookup: match ordinal {
+                        Some(&(_, ordinal)) => ImportLookup::Ordinal(ordinal),
+                        None => ImportLookup::Name,
+                    },
+                }
+            })
+            .collect::<Vec<_>>();
+        imports.sort_by(|a, b| a.name.cmp(&b.name));
+        imports
+    }
+
+    fn settings(&self) -> &CompilerSettings {
+        &self.settings
+    }
+
+    fn settings_mut(&mut self) -> &mut CompilerSettings {
+        // the cached code may not be what the new settings compile to
+        self.unit_cache.clear();
+        &mut self.settings
+    }
+
+    fn compile_into_object(
+        &mut self,
+        ast: Vec<IrNode>,
+        obj: &mut Object,
+        text_section: SectionId,
+    ) -> Result<(), CompilerError> {
+        self.start()?;
+        self.object_file = true;
+        self.warn_dead_loops(&ast, None);
+        let ast = optimizer::optimize(ast, &self.settings);
+
+        /// Adds a relocation for every call site of an external, all against a
+        /// single undefined symbol per name, which is created on first use.
+        /// Indirect calls go through the GOT entry of the symbol, or its
+        /// `__imp_` pointer in COFF. Call sites are the offsets of their
+        /// displacements, along with the addend each needs.
         fn add_relocations_for_external_symbol(
             obj: &mut Object,
+            external_symbols: &mut Map<String, SymbolId>,
             section: SectionId,
             symbol: &str,
-            call_sites: Vec<u64>,
+            call_sites: Vec<(u64, i64)>,
+            settings: &CompilerSettings,
         ) -> Result<(), CompilerError> {
-            let alloc_sym = obj.add_symbol(Symbol {
-                name: symbol.as_bytes().to_vec(),
-                value: 0, // not our symbol, so we don't know the value
-                size: 0,  // same here
-                kind: SymbolKind::Text,
-                scope: SymbolScope::Dynamic,
-                weak: false,
-                section: SymbolSection::Undefined,
-                flags: SymbolFlags::None,
-            });
-            for call_site in call_sites {
-                obj.add_relocation(
-                    section,
-                    Relocation {
-                        // the +1 here is crucial because the address of the call doesn't start until
-                        // one byte in (skips e8), we basically want to tell the linker "please replace the
-                        // 32 bits at this address with the address of the symbol"
-                        offset: call_site + 1,
-                        symbol: alloc_sym,
-                        addend: -4,
-                        flags: RelocationFlags::Generic {
-                            kind: RelocationKind::Relative,
-                            encoding: RelocationEncoding::X86RipRelative,
-                            size: 32, // size of the address to replace
+            let (calls, format) = (settings.external_calls, settings.object_format);
+            let mangled = settings.symbol_mangling.mangle(symbol);
+            let (name, kind) = match (calls, format) {
+                (ExternalCalls::IndirectGot, ObjectFormat::Coff) => {
+                    ([&b"__imp_"[..], &mangled].concat(), SymbolKind::Data)
+                }
+                _ => (mangled, SymbolKind::Text),
+            };
+            let alloc_sym = *external_symbols
+                .entry(symbol.to_string())
+                .or_insert_with(|| {
+                    obj.add_symbol(Symbol {
+                        name: name.clone(),
+                        value: 0, // not our symbol, so we don't know the value
+                        size: 0,  // same here
+                        kind,
+                        scope: SymbolScope::Dynamic,
+                        weak: false,
+                        section: SymbolSection::Undefined,
+                        flags: SymbolFlags::None,
+                    })
+                });
+            for (offset, addend) in call_sites {
+                match (calls, format) {
+                    (ExternalCalls::DirectRel32, _) => add_relocation(
+                        obj,
+                        section,
+                        symbol,
+                        Relocation {
+                            offset,
+                            symbol: alloc_sym,
+                            addend,
+                            flags: RelocationFlags::Generic {
+                                kind: RelocationKind::Relative,
+                                encoding: RelocationEncoding::X86RipRelative,
+                                size: 32, // size of the address to replace
+                            },
                         },
-                    },
-                )
-                .map_err(|e| CompilerError {
-                    kind: CompilerErrorKind::RelocationFailed(e.to_string()),
-                    span: None,
-                })?;
+                    )?,
+                    (ExternalCalls::IndirectGot, ObjectFormat::Elf) => add_relocation(
+                        obj,
+                        section,
+                        symbol,
+                        Relocation {
+                            offset,
+                            symbol: alloc_sym,
+                            addend,
+                            flags: RelocationFlags::Generic {
+                                kind: RelocationKind::GotRelative,
+                                encoding: RelocationEncoding::Generic,
+                                size: 32,
+                            },
+                        },
+                    )?,
+                    (ExternalCalls::IndirectGot, ObjectFormat::Coff) => {
+                        add_rip_relative_relocation(
+                            obj,
+                            section,
+                            offset,
+                            (alloc_sym, symbol),
+                            addend,
+                        )?
+                    }
+                }
             }
             Ok(())
         }
 
-        let text_section = obj.add_section(Vec::new(), b".text".to_vec(), SectionKind::Text);
+        // entry points are exported through their wrappers instead
+        let entry_points = self.settings.entry_points.clone();
+        let fn_scope = |name: &String| {
+            if entry_points.contains(name) {
+                SymbolScope::Compilation
+            } else {
+                SymbolScope::Dynamic
+            }
+        };
+        let mangling = self.settings.symbol_mangling;
+        let mut fn_symbol_map = Map::new();
 
-        let mut fn_symbol_map = HashMap::new();
-
-        let mut fn_ast = Vec::new();
-        let mut non_fn_ast = Vec::new();
-        for node in ast {
-            match &node.node {
-                IrOp::Function(name, _children) => {
-                    let name_bytes = name.as_bytes().to_vec();
-                    let fn_symbol = obj.add_symbol(Symbol {
-                        name: name_bytes.clone(),
-                        value: 0,
-                        size: 0,
-                        kind: SymbolKind::Text,
-                        scope: SymbolScope::Dynamic,
-                        weak: false,
-                        section: SymbolSection::Section(text_section),
-                        flags: SymbolFlags::None,
-                    });
+        for node in &ast {
+            if let IrOp::Function(name, _children) = &node.node {
+                let name_bytes = mangling.mangle(name);
+                let fn_symbol = obj.add_symbol(Symbol {
+                    name: name_bytes.clone(),
+                    value: 0,
+                    size: 0,
+                    kind: SymbolKind::Text,
+                    scope: fn_scope(name),
+                    weak: false,
+                    section: SymbolSection::Section(text_section),
+                    flags: SymbolFlags::None,
+                });
 
-                    fn_symbol_map.insert(name.clone(), fn_symbol);
-                    fn_ast.push(node);
-                }
-                _ => non_fn_ast.push(node),
+                fn_symbol_map.insert(name.clone(), fn_symbol);
             }
         }
-
-        fn_ast.push(IrNode {
-            node: IrOp::Function("_start".to_string(), non_fn_ast),
-            span: crate::ir::Span {
-                location: (0, 0),
-                length: 1,
-            },
-        });
+        let fn_ast = self.arrange_functions(ast)?;
         let mut result = self.translate_ir_node(fn_ast)?;
 
-        for (name, label) in self.scopes.get_global_functions() {
-            let name_bytes = name.as_bytes().to_vec();
-            let _fn_symbol = obj.add_symbol(Symbol {
-                name: name_bytes.clone(),
-                value: result.label_ip(&label).expect("couldnt find label ip"),
+        // Because of iced-x86 shenanigans, we must force the call bytes to zero
+        // for any externals we try to call.
+        // Sorry :(
+        // Where the displacement is, and so the addend, depends on how the call
+        // is encoded, so it is taken from the encoded call.
+        let mut call_sites = Vec::new();
+        for (name, labels) in &self.external_calls {
+            let mut sites = Vec::with_capacity(labels.len());
+            for label in labels {
+                let index = self.code_index(&result, label);
+                let code = &mut result.inner.code_buffer;
+                let (start, addend) = call_displacement(code, index, self.bitness)?;
+                zero_displacement(code, index, start)?;
+                sites.push(((index + start) as u64, addend));
+            }
+            call_sites.push((name, sites));
+        }
+        // Same for the displacements of the references to data sections
+        let data_references = self.loop_counters.iter().map(|(label, _)| label);
+        let data_references = data_references
+            .chain(self.tape_references.iter().map(|(label, _)| label))
+            .chain(&self.cell_pointer_references)
+            .chain(self.external_data.iter().map(|(_, label)| label))
+            .chain(self.stack_references.iter().map(|(label, _)| label))
+            .chain(self.data_references.iter().map(|(label, _)| label));
+        for label in data_references {
+            let index = self.code_index(&result, label);
+            zero_displacement(&mut result.inner.code_buffer, index, 3)?;
+        }
+        self.debug_verify_encoding(&result)?;
+
+        // the section may hold code or data of the caller already
+        let code_offset = append_code(obj, text_section, &result.inner.code_buffer);
+        let offset_of = |label: &CodeLabel| self.code_index(&result, label) as u64 + code_offset;
+
+        for (name, label) in &self.entry_wrappers {
+            obj.add_symbol(Symbol {
+                name: mangling.mangle(name),
+                value: offset_of(label),
                 size: 0,
                 kind: SymbolKind::Text,
                 scope: SymbolScope::Dynamic,
@@ -492,61 +4205,201 @@ impl super::CompilerTrait for Compiler {
             });
         }
 
-        // Because of iced-x86 shenanigans, we must force the call bytes to zero
-        // for any externals we try to call.
-        // Sorry :(
-        for (_name, labels) in &self.external_calls {
-            for label in labels {
-                let ip = result
-                    .label_ip(label)
-                    .expect("couldnt find label ip for external call");
-                for i in (ip + 1)..(ip + 5) {
-                    result.inner.code_buffer[i as usize] = 0;
+        if self.settings.auto_entry {
+            let label = self
+                .scopes
+                .get_fn(&"_start".to_string())
+                .expect("couldnt find function label for _start");
+            obj.add_symbol(Symbol {
+                name: mangling.mangle("_start"),
+                value: offset_of(&label),
+                size: 0,
+                kind: SymbolKind::Text,
+                scope: SymbolScope::Dynamic,
+                weak: false,
+                section: SymbolSection::Section(text_section),
+                flags: SymbolFlags::None,
+            });
+        }
+
+        let mut external_symbols = Map::new();
+        for (name, sites) in call_sites {
+            let sites = sites
+                .into_iter()
+                .map(|(offset, addend)| (offset + code_offset, addend))
+                .collect();
+            add_relocations_for_external_symbol(
+                obj,
+                &mut external_symbols,
+                text_section,
+                name,
+                sites,
+                &self.settings,
+            )?;
+        }
+
+        if !self.loop_counters.is_empty() {
+            self.add_loop_counters(obj, text_section, offset_of)?;
+        }
+        match self.settings.static_tape {
+            Some(size) if !self.tape_references.is_empty() => {
+                let tape_symbol =
+                    add_bss_symbol(obj, mangling.mangle(TAPE_SYMBOL), size as u64, 16);
+                for (label, offset) in &self.tape_references {
+                    // `lea reg, [rip + disp32]` is a REX prefix, 8d and the
+                    // ModRM byte, followed by the displacement
+                    add_rip_relative_relocation(
+                        obj,
+                        text_section,
+                        offset_of(label) + 3,
+                        (tape_symbol, TAPE_SYMBOL),
+                        *offset as i64 - 4,
+                    )?;
                 }
             }
+            _ => {}
         }
 
-        let name_bytes = b"_start".to_vec();
-        let fn_symbol = obj.add_symbol(Symbol {
-            name: name_bytes.clone(),
-            value: 0,
-            size: 0,
-            kind: SymbolKind::Text,
-            scope: SymbolScope::Dynamic,
-            weak: false,
-            section: SymbolSection::Section(text_section),
-            flags: SymbolFlags::None,
-        });
-
-        let _fn_offset =
-            obj.add_symbol_data(fn_symbol, text_section, &result.inner.code_buffer, 16);
+        match self.settings.stack_overflow {
+            Some(policy) if !self.stack_references.is_empty() => {
+                let (name, size) = match policy {
+                    StackOverflow::Grow | StackOverflow::Guard => (STACK_BOUNDS_SYMBOL, 16),
+                    _ => (STACK_SYMBOL, self.settings.stack_size as u64),
+                };
+                let symbol = add_bss_symbol(obj, mangling.mangle(name), size, 16);
+                for (label, offset) in &self.stack_references {
+                    // every access has a REX prefix, the displacement comes
+                    // right after the opcode and ModRM byte
+                    add_rip_relative_relocation(
+                        obj,
+                        text_section,
+                        offset_of(label) + 3,
+                        (symbol, name),
+                        offset - 4,
+                    )?;
+                }
+            }
+            _ => {}
+        }
 
-        // Update the IP for our start symbol
-        let label = self
-            .scopes
-            .get_fn(&"_start".to_string())
-            .expect("couldnt find function label for _start");
-        let ip = result
-            .label_ip(&label)
-            .expect("couldnt find label ip for _start");
-        obj.set_symbol_data(fn_symbol, text_section, ip, 0);
-
-        // Map from a
-        // HashMap<String, Vec<CodeLabel>>
-        // to a
-        // HashMap<String, Vec<u64>>
-        // where each u64 is the ip of the label
-        let externals = self.external_calls.iter().map(|(name, label_vec)| {
-            (
-                name,
-                label_vec
+        if !self.jump_tables.is_empty() {
+            let text_symbol = obj.section_symbol(text_section);
+            // `.rdata` in COFF
+            let rodata = obj.section_id(StandardSection::ReadOnlyData);
+            let rodata_symbol = obj.section_symbol(rodata);
+            let mut data_offsets = Vec::with_capacity(self.jump_tables.len());
+            let size = self.jump_table_entry_size();
+            for (label, entries, _span) in &self.jump_tables {
+                let table_offset =
+                    obj.append_section_data(rodata, &vec![0; entries.len() * size], 8);
+                data_offsets.push((*label, table_offset));
+                for (i, (_name, entry)) in entries.iter().enumerate() {
+                    let offset = table_offset + (i * size) as u64;
+                    // a relative entry is relocated relative to itself, the
+                    // addend makes up for how far it is from the table
+                    let (kind, addend) = match self.settings.pic {
+                        true => (RelocationKind::Relative, offset - table_offset),
+                        false => (RelocationKind::Absolute, 0),
+                    };
+                    add_relocation(
+                        obj,
+                        rodata,
+                        ".rodata",
+                        Relocation {
+                            offset,
+                            symbol: text_symbol,
+                            addend: (offset_of(entry) + addend) as i64,
+                            flags: RelocationFlags::Generic {
+                                kind,
+                                encoding: RelocationEncoding::Generic,
+                                size: size as u8 * 8,
+                            },
+                        },
+                    )?;
+                }
+            }
+            for (label, data) in &self.data_references {
+                let (_, data_offset) = data_offsets
                     .iter()
-                    .map(|label| result.label_ip(label).unwrap() as u64)
-                    .collect::<Vec<u64>>(),
-            )
-        });
-        for (name, call_sites) in externals {
-            add_relocations_for_external_symbol(&mut obj, text_section, &name, call_sites)?;
+                    .find(|(table, _)| table == data)
+                    .expect("couldnt find the data of a reference");
+                // `lea reg, [rip + disp32]` is a REX prefix, 8d and the ModRM
+                // byte, followed by the displacement
+                add_rip_relative_relocation(
+                    obj,
+                    text_section,
+                    offset_of(label) + 3,
+                    (rodata_symbol, ".rodata"),
+                    *data_offset as i64 - 4,
+                )?;
+            }
+        }
+
+        if !self.cell_pointer_references.is_empty() {
+            let slot_symbol = add_bss_symbol(obj, mangling.mangle(CELL_POINTER_SYMBOL), 8, 8);
+            for label in &self.cell_pointer_references {
+                // every access is a `mov` or `lea` with a REX prefix, the
+                // displacement comes right after the opcode and ModRM byte
+                add_rip_relative_relocation(
+                    obj,
+                    text_section,
+                    offset_of(label) + 3,
+                    (slot_symbol, CELL_POINTER_SYMBOL),
+                    -4,
+                )?;
+            }
+        }
+
+        let mut data_symbols = Map::new();
+        for (name, label) in &self.external_data {
+            let symbol = *data_symbols.entry(name).or_insert_with(|| {
+                obj.add_symbol(Symbol {
+                    name: mangling.mangle(name),
+                    value: 0,
+                    size: 0,
+                    kind: SymbolKind::Data,
+                    scope: SymbolScope::Dynamic,
+                    weak: false,
+                    section: SymbolSection::Undefined,
+                    flags: SymbolFlags::None,
+                })
+            });
+            // both the `mov` and the `lea` have a REX prefix, so the
+            // displacement starts 3 bytes in
+            let offset = offset_of(label) + 3;
+            match self.settings.object_format {
+                ObjectFormat::Elf => add_relocation(
+                    obj,
+                    text_section,
+                    name,
+                    Relocation {
+                        offset,
+                        symbol,
+                        addend: -4,
+                        flags: RelocationFlags::Generic {
+                            kind: RelocationKind::GotRelative,
+                            encoding: RelocationEncoding::Generic,
+                            size: 32,
+                        },
+                    },
+                )?,
+                ObjectFormat::Coff => {
+                    add_rip_relative_relocation(obj, text_section, offset, (symbol, name), -4)?
+                }
+            }
+        }
+
+        for (name, label) in &self.markers {
+            obj.add_symbol(Symbol {
+                name: mangling.mangle(name),
+                value: offset_of(label),
+                size: 0,
+                kind: SymbolKind::Label,
+                scope: SymbolScope::Compilation,
+                weak: false,
+                section: SymbolSection::Section(text_section),
+                flags: SymbolFlags::None,
+            });
         }
 
         // Update the IP for symbols
@@ -555,10 +4408,65 @@ impl super::CompilerTrait for Compiler {
                 .scopes
                 .get_fn(&name)
                 .expect("couldnt find function label");
-            let ip = result.label_ip(&label).expect("couldnt find label ip");
-            obj.set_symbol_data(symbol_id, text_section, ip, 0);
+            obj.set_symbol_data(symbol_id, text_section, offset_of(&label), 0);
         }
 
-        Ok(obj)
+        if self.settings.debug_frame {
+            let code = &result.inner.code_buffer;
+            let code_end = self
+                .jump_tables
+                .first()
+                .map_or(code.len(), |(label, _, _)| self.code_index(&result, label));
+            let mut starts = self
+                .function_labels
+                .iter()
+                .map(|label| self.code_index(&result, label))
+                .collect::<Vec<_>>();
+            starts.sort_unstable();
+            starts.dedup();
+            let ends = starts.iter().skip(1).copied().chain([code_end]);
+            let functions = starts.iter().copied().zip(ends).collect::<Vec<_>>();
+            self.add_debug_frame(
+                obj,
+                text_section,
+                code_offset,
+                &code[..code_end],
+                &functions,
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_emit_wraps_encoding_errors() {
+        // no op gets an instruction rejected, so emit one directly
+        let origin = Origin {
+            op: "Subtract",
+            span: Span::from_location((2, 4)),
+        };
+        let emit = |n: u32| -> Result<(), CompilerError> {
+            let mut code_asm = CodeAssembler::new(64).unwrap();
+            emit!(
+                code_asm.sub(byte_ptr(r8), n),
+                origin,
+                "sub byte ptr [r8], {n:#x}"
+            );
+            Ok(())
+        };
+        assert!(emit(1).is_ok());
+        let error = emit(300).unwrap_err();
+        assert!(matches!(
+            &error.kind,
+            CompilerErrorKind::EncodingFailed { op: "Subtract", instruction, message }
+                if instruction == "sub byte ptr [r8], 0x12c"
+                    && message.starts_with("Invalid unsigned immediate")
+        ));
+        assert_eq!(error.span, Some(origin.span));
     }
 }
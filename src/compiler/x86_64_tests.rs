@@ -1,9 +1,22 @@
+use alloc::string::{String, ToString};
 use alloc::vec::Vec;
 use assert_hex::assert_eq_hex;
 use hf_parser_rust::{ast, token};
+use object::read::{Object as _, ObjectSection as _, ObjectSymbol as _};
 
-use super::{x86::*, CompilerSettings, CompilerTrait};
-use crate::{ir::IrNode, target::Target};
+use super::{
+    full_register, verify_callee_saved, verify_cell_pointer, verify_direction_flag,
+    verify_encoding, x86::*, AbsoluteReference, CellPointer, ChecksumKind, CompilerError,
+    CompilerErrorKind, CompilerSettings, CompilerTrait, CompilerWarning, CompilerWarningKind,
+    EntryExit, EntryPointers, ExternalCalls, FileSymbol, FunctionOrder, HfCompiler, Import,
+    ImportLookup, ObjectFormat, OpStats, OutputFlush, PointerRegister, RegisterAbi, RuntimeAbi,
+    ScratchRegister, ShortRead, StackOverflow, SymbolMangling, TraceSink,
+};
+use crate::{
+    ir::{Clobbers, ExternalCall, IrNode, IrOp, Span},
+    target::Target,
+    Map,
+};
 
 fn get_compiler() -> Compiler {
     Compiler::new(
@@ -19,6 +32,100 @@ fn compile_to_ir(source: &str) -> Vec<IrNode> {
     crate::ir::from_ast(ast)
 }
 
+fn node(op: IrOp) -> IrNode {
+    IrNode {
+        node: op,
+        span: Span::from_location((0, 0)),
+    }
+}
+
+fn get_compiler_with(settings: CompilerSettings) -> Compiler {
+    Compiler::new(64, settings, Target::native().calling_convention)
+}
+
+fn compile_to_object_bytes(compiler: &mut Compiler, ir: Vec<IrNode>) -> Vec<u8> {
+    compiler
+        .compile_to_object_file(ir, "test.hf")
+        .expect("failed to compile to an object file")
+        .write()
+        .expect("failed to write the object file")
+}
+
+/// The parts of an object file that matter for linking, in a stable order.
+///
+/// Symbol and relocation order in the written file depends on hash map
+/// iteration, so comparing raw object bytes is not meaningful.
+#[derive(Debug, PartialEq)]
+struct ObjectSummary {
+    text: Vec<u8>,
+    symbols: Vec<(String, u64, bool)>,
+    relocations: Vec<(u64, String)>,
+}
+
+fn summarize_object(bytes: &[u8]) -> ObjectSummary {
+    let file = object::File::parse(bytes).expect("failed to parse the object file");
+    let text = file.section_by_name(".text").expect("no .text section");
+
+    let mut symbols = file
+        .symbols()
+        .map(|s| (s.name().unwrap().to_string(), s.address(), s.is_undefined()))
+        .collect::<Vec<_>>();
+    symbols.sort();
+
+    let mut relocations = text
+        .relocations()
+        .map(|(offset, relocation)| {
+            let name = match relocation.target() {
+                object::RelocationTarget::Symbol(id) => file
+                    .symbol_by_index(id)
+                    .unwrap()
+                    .name()
+                    .unwrap()
+                    .to_string(),
+                _ => String::new(),
+            };
+            (offset, name)
+        })
+        .collect::<Vec<_>>();
+    relocations.sort();
+
+    ObjectSummary {
+        text: text.data().unwrap().to_vec(),
+        symbols,
+        relocations,
+    }
+}
+
+/// Links `object` into a program with `main` from `c_source`, with the C
+/// compiler of the system, runs it and returns what it printed. The program
+/// has to exit successfully.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+fn run_with_c_main(object: &[u8], c_source: &str) -> Vec<u8> {
+    extern crate std;
+
+    // tests run in parallel, so every call gets a directory of its own
+    static RUNS: core::sync::atomic::AtomicUsize = core::sync::atomic::AtomicUsize::new(0);
+    let run = RUNS.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+    let dir = std::env::temp_dir().join(format!("hf_c_main_{}_{run}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (object_path, source_path, path) = (dir.join("hf.o"), dir.join("main.c"), dir.join("main"));
+    std::fs::write(&object_path, object).unwrap();
+    std::fs::write(&source_path, c_source).unwrap();
+    let status = std::process::Command::new("cc")
+        .arg("-no-pie")
+        .arg(&source_path)
+        .arg(&object_path)
+        .arg("-o")
+        .arg(&path)
+        .status()
+        .unwrap();
+    assert!(status.success(), "{status:?}");
+    let output = std::process::Command::new(&path).output().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(output.status.success(), "{:?}", output.status);
+    output.stdout
+}
+
 fn compile_to_bytecode(source: &str) -> Vec<u8> {
     let ir = compile_to_ir(source);
     let mut compiler = get_compiler();
@@ -50,7 +157,7 @@ fn test_emit_sub() {
 fn test_emit_function_call() {
     assert_eq_hex!(
         compile_to_bytecode(":test{}@test;"),
-        vec![0xc3, 0xe8, 0xfa, 0xff, 0xff, 0xff,]
+        vec![0x48, 0x83, 0xec, 0x08, 0x48, 0x83, 0xc4, 0x08, 0xc3, 0xe8, 0xf2, 0xff, 0xff, 0xff,]
     )
 }
 
@@ -87,6 +194,28 @@ fn test_arith_limits() {
         compile_to_bytecode(&"-".repeat(255)),
         vec![0x41, 0x80, 0x28, 0xff,],
     );
+
+    // a single instruction however large the count, rather than one per 255
+    let bytes = get_compiler()
+        .compile_to_bytecode(vec![
+            node(IrOp::Add(u32::MAX as usize)),
+            node(IrOp::Subtract(257)),
+        ])
+        .unwrap();
+    assert_eq_hex!(
+        bytes,
+        [
+            &[0x41, 0x80, 0x00, 0xff][..], // add byte ptr [r8], 0xff
+            &[0x41, 0x80, 0x28, 0x01],     // sub byte ptr [r8], 1
+        ]
+        .concat()
+    );
+
+    // whole turns leave the cell as it was
+    let bytes = get_compiler()
+        .compile_to_bytecode(vec![node(IrOp::Add(256)), node(IrOp::Subtract(512))])
+        .unwrap();
+    assert!(bytes.is_empty());
 }
 
 #[test]
@@ -143,8 +272,8 @@ fn test_fn_with_body() {
     assert_eq_hex!(
         compile_to_bytecode(":test{++++[-]}"),
         vec![
-            0x41, 0x80, 0x0, 0x4, 0x41, 0x80, 0x38, 0x0, 0x74, 0x6, 0x41, 0x80, 0x28, 0x1, 0xeb,
-            0xf4, 0xc3,
+            0x48, 0x83, 0xec, 0x08, 0x41, 0x80, 0x0, 0x4, 0x41, 0x80, 0x38, 0x0, 0x74, 0x6, 0x41,
+            0x80, 0x28, 0x1, 0xeb, 0xf4, 0x48, 0x83, 0xc4, 0x08, 0xc3,
         ]
     )
 }
@@ -153,7 +282,8 @@ fn test_fn_with_body() {
 fn test_fns_with_same_name() {
     assert_eq_hex!(
         compile_to_bytecode(":test{}@test;:test{}@test;"),
-        vec![0xc3, 0xe8, 0xfa, 0xff, 0xff, 0xff, 0xc3, 0xe8, 0xfa, 0xff, 0xff, 0xff,],
+        [[0x48, 0x83, 0xec, 0x08, 0x48, 0x83, 0xc4, 0x08, 0xc3, 0xe8, 0xf2, 0xff, 0xff, 0xff]; 2]
+            .concat(),
     )
 }
 
@@ -162,19 +292,4508 @@ fn test_long_add() {
     assert_eq_hex!(
         compile_to_bytecode(&"+".repeat(300)),
         vec![
-            0x41, 0x80, 0x0, 0xff, // 255
-            0x41, 0x80, 0x0, 0x2d, // 45
+            0x41, 0x80, 0x0, 0x2c, // 300 mod 256
         ]
     );
 }
 
 #[test]
 fn test_long_code() {
+    // the call is well past the reach of a rel8 from the function
+    assert_eq_hex!(
+        compile_to_bytecode(&format!("{}{}{}", ":test{}", "+>".repeat(64), "@test;")),
+        [
+            vec![0x48, 0x83, 0xec, 0x08, 0x48, 0x83, 0xc4, 0x08, 0xc3], // test
+            [
+                0x41, 0x80, 0x00, 0x01, // add byte ptr [r8], 1
+                0x4d, 0x8d, 0x40, 0x01, // lea r8, [r8 + 1]
+            ]
+            .repeat(64),
+            vec![0xe8, 0xf2, 0xfd, 0xff, 0xff], // call test
+        ]
+        .concat()
+    )
+}
+
+#[test]
+fn test_compiler_reuse() {
+    let program_a = vec![
+        node(IrOp::Function("a".to_string(), vec![node(IrOp::Add(1))])),
+        node(IrOp::ExternalFunctionCall(ExternalCall::new(
+            "putchar".to_string(),
+        ))),
+        node(IrOp::FunctionCall("a".to_string())),
+    ];
+    let program_b = vec![
+        node(IrOp::Function(
+            "b".to_string(),
+            vec![node(IrOp::Subtract(1))],
+        )),
+        node(IrOp::FunctionCall("b".to_string())),
+        node(IrOp::MoveRight(2)),
+    ];
+
+    let mut reused = get_compiler();
+    compile_to_object_bytes(&mut reused, program_a.clone());
+    let reused_b = compile_to_object_bytes(&mut reused, program_b.clone());
+    let fresh_b = compile_to_object_bytes(&mut get_compiler(), program_b.clone());
+    assert_eq!(summarize_object(&reused_b), summarize_object(&fresh_b));
+
+    let mut reused = get_compiler();
+    reused.compile_to_bytecode(program_a).unwrap();
+    assert_eq_hex!(
+        reused.compile_to_bytecode(program_b.clone()).unwrap(),
+        get_compiler().compile_to_bytecode(program_b).unwrap()
+    );
+}
+
+#[test]
+fn test_encoding_error_display() {
+    use iced_x86::code_asm::{byte_ptr, r8, CodeAssembler};
+
+    let message = CodeAssembler::new(64)
+        .unwrap()
+        .sub(byte_ptr(r8), 300u32)
+        .unwrap_err()
+        .to_string();
+    let error = CompilerError {
+        kind: CompilerErrorKind::EncodingFailed {
+            op: "Subtract",
+            instruction: "sub byte ptr [r8], 0x12c".to_string(),
+            message,
+        },
+        span: Some(Span::from_location((2, 4))),
+    };
+    assert_eq!(
+        error.to_string(),
+        "3:5: failed to encode `sub byte ptr [r8], 0x12c` for Subtract: Invalid unsigned immediate"
+    );
+}
+
+#[test]
+fn test_function_order() {
+    let program = vec![
+        node(IrOp::Function("a".to_string(), vec![node(IrOp::Add(1))])),
+        node(IrOp::Function("b".to_string(), vec![node(IrOp::Add(2))])),
+        node(IrOp::Function("c".to_string(), vec![node(IrOp::Add(3))])),
+        node(IrOp::FunctionCall("c".to_string())),
+        node(IrOp::Condition(vec![node(IrOp::FunctionCall(
+            "c".to_string(),
+        ))])),
+        node(IrOp::FunctionCall("b".to_string())),
+    ];
+    let layout = |function_order| {
+        let mut compiler = get_compiler_with(CompilerSettings {
+            function_order,
+            ..Default::default()
+        });
+        let summary = summarize_object(&compile_to_object_bytes(&mut compiler, program.clone()));
+        let mut functions = ["a", "b", "c", "_start"]
+            .into_iter()
+            .map(|name| {
+                let (_, address, _) = summary
+                    .symbols
+                    .iter()
+                    .find(|(symbol, _, _)| symbol == name)
+                    .unwrap();
+                (*address, name)
+            })
+            .collect::<Vec<_>>();
+        functions.sort();
+        functions
+            .into_iter()
+            .map(|(_, name)| name)
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(
+        layout(FunctionOrder::SourceOrder),
+        ["a", "b", "c", "_start"]
+    );
+    assert_eq!(layout(FunctionOrder::HotFirst), ["c", "b", "a", "_start"]);
+    assert_eq!(
+        layout(FunctionOrder::Explicit(vec![
+            "_start".to_string(),
+            "b".to_string()
+        ])),
+        ["_start", "b", "a", "c"]
+    );
+}
+
+#[test]
+fn test_compile_stream() {
+    let program = compile_to_ir(":test{++[-]}@test;>>+++<[-@test;].,");
+    assert_eq_hex!(
+        get_compiler()
+            .compile_stream(&mut program.clone().into_iter())
+            .unwrap(),
+        get_compiler().compile_to_bytecode(program.clone()).unwrap()
+    );
+
+    let mut stream = (0..1000).map(|i| {
+        node(if i % 2 == 0 {
+            IrOp::Add(1)
+        } else {
+            IrOp::MoveRight(1)
+        })
+    });
+    let bytes = get_compiler().compile_stream(&mut stream).unwrap();
+    assert_eq!(bytes.len(), 500 * 4 + 500 * 4);
+
+    let err = get_compiler_with(CompilerSettings {
+        optimization_level: 1,
+        ..Default::default()
+    })
+    .compile_stream(&mut program.clone().into_iter())
+    .unwrap_err();
+    assert!(matches!(
+        err.kind,
+        CompilerErrorKind::UnsupportedOp("`optimization_level`")
+    ));
+    let err = get_compiler_with(CompilerSettings {
+        entry_offset: Some(0),
+        ..Default::default()
+    })
+    .compile_stream(&mut program.into_iter())
+    .unwrap_err();
+    assert!(matches!(
+        err.kind,
+        CompilerErrorKind::UnsupportedOp("`entry_offset`")
+    ));
+}
+
+#[test]
+fn test_optimized_cancellation() {
+    let mut compiler = get_compiler_with(CompilerSettings {
+        optimization_level: 1,
+        ..Default::default()
+    });
+    assert_eq_hex!(
+        compiler
+            .compile_to_bytecode(compile_to_ir("+->><<"))
+            .unwrap(),
+        vec![]
+    );
+    assert_eq_hex!(
+        compiler.compile_to_bytecode(compile_to_ir("++-")).unwrap(),
+        vec![0x41, 0x80, 0x00, 0x01]
+    );
+}
+
+#[test]
+fn test_external_call_store_result() {
+    let call = |store_result| {
+        let bytes = get_compiler()
+            .compile_to_bytecode(vec![node(IrOp::ExternalFunctionCall(ExternalCall {
+                store_result,
+                ..ExternalCall::new("getchar".to_string())
+            }))])
+            .unwrap();
+        // everything after the call instruction
+        let call_end = bytes.iter().position(|&b| b == 0xe8).unwrap() + 5;
+        bytes[call_end..].to_vec()
+    };
+
+    // pop r9, pop r8
+    assert_eq_hex!(call(false), vec![0x41, 0x59, 0x41, 0x58]);
+    // pop r9, pop r8, mov byte ptr [r8], al
+    assert_eq_hex!(call(true), vec![0x41, 0x59, 0x41, 0x58, 0x41, 0x88, 0x00]);
+}
+
+#[test]
+fn test_loop_tests_cell_register() {
+    let compile = |optimization_level| {
+        let bytes = get_compiler_with(CompilerSettings {
+            optimization_level,
+            ..Default::default()
+        })
+        .compile_to_bytecode(vec![
+            node(IrOp::ExternalFunctionCall(ExternalCall {
+                store_result: true,
+                ..ExternalCall::new("getchar".to_string())
+            })),
+            node(IrOp::Condition(vec![node(IrOp::Subtract(1))])),
+        ])
+        .unwrap();
+        // everything after storing the result
+        let call_end = bytes.iter().position(|&b| b == 0xe8).unwrap() + 5;
+        bytes[call_end + 7..].to_vec()
+    };
+
+    // the result is still in al, so the loop doesn't read the cell to enter
+    // test al, al
+    // je end
+    let bytes = compile(1);
+    assert_eq_hex!(bytes[..3], [0x84, 0xc0, 0x74]);
+    // the cell is only read again at the bottom of the loop
+    // cmp byte ptr [r8], 0
+    // jne start
+    let compares = bytes
+        .windows(5)
+        .filter(|w| w[..4] == [0x41, 0x80, 0x38, 0x00])
+        .collect::<Vec<_>>();
+    assert_eq!(compares, [[0x41, 0x80, 0x38, 0x00, 0x75]]);
+
+    // cmp byte ptr [r8], 0
+    // je end
+    let bytes = compile(0);
+    assert_eq_hex!(bytes[..5], [0x41, 0x80, 0x38, 0x00, 0x74]);
+}
+
+#[test]
+fn test_external_call_status() {
+    // the error code goes to the next cell, which a loop then checks
+    let bytes = get_compiler()
+        .compile_to_bytecode(vec![
+            node(IrOp::ExternalFunctionCall(ExternalCall {
+                store_result: true,
+                status_offset: Some(1),
+                ..ExternalCall::new("read".to_string())
+            })),
+            node(IrOp::MoveRight(1)),
+            node(IrOp::Condition(vec![node(IrOp::Subtract(1))])),
+        ])
+        .unwrap();
+    let call_end = bytes.iter().position(|&b| b == 0xe8).unwrap() + 5;
+    assert_eq_hex!(
+        bytes[call_end..call_end + 20],
+        [
+            &[0x41, 0x59][..],         // pop r9
+            &[0x41, 0x58],             // pop r8
+            &[0x41, 0x88, 0x40, 0x01], // mov byte ptr [r8 + 1], al
+            &[0x41, 0x88, 0x00],       // mov byte ptr [r8], al
+            &[0x4d, 0x8d, 0x40, 0x01], // lea r8, [r8 + 1]
+            &[0x41, 0x80, 0x38, 0x00], // cmp byte ptr [r8], 0
+            &[0x74],                   // je end
+        ]
+        .concat()
+    );
+}
+
+#[test]
+fn test_external_call_clobbers() {
+    let call = |clobbers| {
+        let bytes = get_compiler()
+            .compile_to_bytecode(vec![node(IrOp::ExternalFunctionCall(ExternalCall {
+                clobbers,
+                ..ExternalCall::new("putchar".to_string())
+            }))])
+            .unwrap();
+        // drop the displacement of the call, it is patched by the linker
+        let call = bytes.iter().position(|&b| b == 0xe8).unwrap();
+        [&bytes[..=call], &bytes[call + 5..]].concat()
+    };
+
+    assert_eq_hex!(
+        call(Clobbers::ALL),
+        vec![
+            0x41, 0x50, // push r8
+            0x41, 0x51, // push r9
+            0x48, 0x8d, 0x7c, 0x24, 0x08, // lea rdi, [rsp + 8]
+            0x48, 0x8d, 0x34, 0x24, // lea rsi, [rsp]
+            0xe8, // call
+            0x41, 0x59, // pop r9
+            0x41, 0x58, // pop r8
+        ]
+    );
+    assert_eq_hex!(
+        call(Clobbers {
+            cell_pointer: true,
+            stack_pointer: false,
+        }),
+        vec![
+            0x41, 0x50, // push r8
+            0x48, 0x83, 0xec, 0x08, // sub rsp, 8 ; keeps the stack aligned
+            0x48, 0x8d, 0x7c, 0x24, 0x08, // lea rdi, [rsp + 8]
+            0x4c, 0x89, 0xce, // mov rsi, r9
+            0xe8, // call
+            0x48, 0x83, 0xc4, 0x08, // add rsp, 8
+            0x41, 0x58, // pop r8
+        ]
+    );
+    assert_eq_hex!(
+        call(Clobbers {
+            cell_pointer: false,
+            stack_pointer: true,
+        }),
+        vec![
+            0x41, 0x51, // push r9
+            0x48, 0x83, 0xec, 0x08, // sub rsp, 8 ; keeps the stack aligned
+            0x4c, 0x89, 0xc7, // mov rdi, r8
+            0x48, 0x8d, 0x74, 0x24, 0x08, // lea rsi, [rsp + 8]
+            0xe8, // call
+            0x48, 0x83, 0xc4, 0x08, // add rsp, 8
+            0x41, 0x59, // pop r9
+        ]
+    );
+    assert_eq_hex!(
+        call(Clobbers::NONE),
+        vec![
+            0x4c, 0x89, 0xc7, // mov rdi, r8
+            0x4c, 0x89, 0xce, // mov rsi, r9
+            0xe8, // call
+        ]
+    );
+}
+
+#[test]
+fn test_external_call_shadow_space() {
+    let call = |clobbers| {
+        let bytes = Compiler::new(
+            64,
+            CompilerSettings::default(),
+            crate::target::CallingConvention::X86_64_MicrosoftX64,
+        )
+        .compile_to_bytecode(vec![node(IrOp::ExternalFunctionCall(ExternalCall {
+            clobbers,
+            ..ExternalCall::new("putchar".to_string())
+        }))])
+        .unwrap();
+        let call = bytes.iter().position(|&b| b == 0xe8).unwrap();
+        [&bytes[..=call], &bytes[call + 5..]].concat()
+    };
+
+    // the pushed slots stay clear of the 32 bytes the callee may overwrite
+    assert_eq_hex!(
+        call(Clobbers::ALL),
+        vec![
+            0x41, 0x50, // push r8
+            0x41, 0x51, // push r9
+            0x48, 0x83, 0xec, 0x20, // sub rsp, 0x20
+            0x48, 0x8d, 0x4c, 0x24, 0x28, // lea rcx, [rsp + 0x28]
+            0x48, 0x8d, 0x54, 0x24, 0x20, // lea rdx, [rsp + 0x20]
+            0xe8, // call
+            0x48, 0x83, 0xc4, 0x20, // add rsp, 0x20
+            0x41, 0x59, // pop r9
+            0x41, 0x58, // pop r8
+        ]
+    );
+    assert_eq_hex!(
+        call(Clobbers {
+            cell_pointer: true,
+            stack_pointer: false,
+        }),
+        vec![
+            0x41, 0x50, // push r8
+            0x48, 0x83, 0xec, 0x28, // sub rsp, 0x28
+            0x48, 0x8d, 0x4c, 0x24, 0x28, // lea rcx, [rsp + 0x28]
+            0x4c, 0x89, 0xca, // mov rdx, r9
+            0xe8, // call
+            0x48, 0x83, 0xc4, 0x28, // add rsp, 0x28
+            0x41, 0x58, // pop r8
+        ]
+    );
+    assert_eq_hex!(
+        call(Clobbers::NONE),
+        vec![
+            0x48, 0x83, 0xec, 0x20, // sub rsp, 0x20
+            0x4c, 0x89, 0xc1, // mov rcx, r8
+            0x4c, 0x89, 0xca, // mov rdx, r9
+            0xe8, // call
+            0x48, 0x83, 0xc4, 0x20, // add rsp, 0x20
+        ]
+    );
+}
+
+#[test]
+fn test_batched_write() {
+    let mut compiler = get_compiler_with(CompilerSettings {
+        optimization_level: 1,
+        ..Default::default()
+    });
+    let bytes = compiler
+        .compile_to_bytecode(vec![
+            node(IrOp::WriteByte),
+            node(IrOp::MoveRight(1)),
+            node(IrOp::WriteByte),
+        ])
+        .unwrap();
+    let call = bytes.iter().position(|&b| b == 0xe8).unwrap();
+
+    assert_eq_hex!(
+        [&bytes[..=call], &bytes[call + 5..]].concat(),
+        vec![
+            0x41, 0x50, // push r8
+            0x41, 0x51, // push r9
+            0xbf, 0x01, 0x00, 0x00, 0x00, // mov edi, 1
+            0x4c, 0x89, 0xc6, // mov rsi, r8
+            0xba, 0x02, 0x00, 0x00, 0x00, // mov edx, 2
+            0xe8, // call write
+            0x41, 0x59, // pop r9
+            0x41, 0x58, // pop r8
+            0x4d, 0x8d, 0x40, 0x01, // lea r8, [r8 + 1]
+        ]
+    );
+}
+
+#[test]
+fn test_io_shadow_space() {
+    use crate::target::{Arch, CallingConvention};
+
+    let mut compiler = Compiler::new(
+        64,
+        CompilerSettings::default(),
+        CallingConvention::X86_64_MicrosoftX64,
+    );
+    let bytes = compiler
+        .compile_to_bytecode(vec![node(IrOp::WriteByte)])
+        .unwrap();
+    let call = bytes.iter().position(|&b| b == 0xe8).unwrap();
+
+    // r8 and r9 stay clear of the 32 bytes `write` may overwrite
+    assert_eq_hex!(
+        [&bytes[..=call], &bytes[call + 5..]].concat(),
+        vec![
+            0x41, 0x50, // push r8
+            0x41, 0x51, // push r9
+            0xb9, 0x01, 0x00, 0x00, 0x00, // mov ecx, 1
+            0x4c, 0x89, 0xc2, // mov rdx, r8
+            0x41, 0xb8, 0x01, 0x00, 0x00, 0x00, // mov r8d, 1
+            0x48, 0x83, 0xec, 0x20, // sub rsp, 0x20
+            0xe8, // call write
+            0x48, 0x83, 0xc4, 0x20, // add rsp, 0x20
+            0x41, 0x59, // pop r9
+            0x41, 0x58, // pop r8
+        ]
+    );
+
+    // 32-bit targets have no lowering of I/O
+    let target = Target::new(Arch::X86, CallingConvention::X86_CDeclGcc);
+    let err = HfCompiler::new(target, CompilerSettings::default())
+        .compile_to_bytecode(vec![node(IrOp::WriteByte)])
+        .unwrap_err();
+    assert!(matches!(
+        err.kind,
+        CompilerErrorKind::UnsupportedOp("WriteByte")
+    ));
+}
+
+#[test]
+fn test_max_code_size() {
+    let settings = || CompilerSettings {
+        max_code_size: Some(8),
+        ..Default::default()
+    };
+
+    // a single `add`, however large the amount
+    let bytes = get_compiler_with(settings())
+        .compile_to_bytecode(vec![node(IrOp::Add(usize::MAX))])
+        .unwrap();
+    assert_eq_hex!(bytes, [0x41, 0x80, 0x00, 0xff]);
+
+    // few instructions, but too many bytes
+    let err = get_compiler_with(settings())
+        .compile_to_bytecode(vec![node(IrOp::Add(1)); 3])
+        .unwrap_err();
+    assert!(matches!(
+        err.kind,
+        CompilerErrorKind::CodeSizeLimitExceeded(8)
+    ));
+
+    let bytes = get_compiler_with(settings())
+        .compile_to_bytecode(vec![node(IrOp::Add(1)); 2])
+        .unwrap();
+    assert_eq!(bytes.len(), 8);
+}
+
+#[test]
+fn test_mul_const() {
+    let bytes = get_compiler()
+        .compile_to_bytecode(vec![node(IrOp::MulConst(3)), node(IrOp::MulConst(200))])
+        .unwrap();
+
+    assert_eq_hex!(
+        bytes,
+        vec![
+            0x41, 0x0f, 0xb6, 0x00, // movzx eax, byte ptr [r8]
+            0x6b, 0xc0, 0x03, // imul eax, eax, 3
+            0x41, 0x88, 0x00, // mov byte ptr [r8], al
+            0x41, 0x0f, 0xb6, 0x00, // movzx eax, byte ptr [r8]
+            0x69, 0xc0, 0xc8, 0x00, 0x00, 0x00, // imul eax, eax, 200
+            0x41, 0x88, 0x00, // mov byte ptr [r8], al
+        ]
+    );
+}
+
+#[test]
+fn test_external_symbol_shared_by_call_sites() {
+    let call = node(IrOp::ExternalFunctionCall(ExternalCall::new(
+        "putchar".to_string(),
+    )));
+    let summary = summarize_object(&compile_to_object_bytes(
+        &mut get_compiler(),
+        vec![call.clone(), call.clone(), call],
+    ));
+
+    let undefined = summary
+        .symbols
+        .iter()
+        .filter(|(name, _, undefined)| name == "putchar" && *undefined)
+        .count();
+    assert_eq!(undefined, 1);
+    assert_eq!(
+        summary.symbols.iter().filter(|s| s.0 == "putchar").count(),
+        1
+    );
+    assert_eq!(
+        summary
+            .relocations
+            .iter()
+            .filter(|(_, name)| name == "putchar")
+            .count(),
+        3
+    );
+}
+
+#[test]
+fn test_profile_loop_counters() {
+    let at = |op, column| IrNode {
+        node: op,
+        span: Span {
+            location: (1, column),
+            length: 4,
+        },
+    };
+    let ir = vec![at(
+        IrOp::Condition(vec![
+            at(IrOp::Add(1), 1),
+            at(IrOp::Condition(vec![at(IrOp::Subtract(1), 3)]), 2),
+        ]),
+        0,
+    )];
+
+    let bytes = compile_to_object_bytes(
+        &mut get_compiler_with(CompilerSettings {
+            profile: true,
+            ..Default::default()
+        }),
+        ir.clone(),
+    );
+    let file = object::File::parse(&*bytes).unwrap();
+
+    let counters = file.section_by_name(".hf_counters").unwrap();
+    assert_eq!(counters.kind(), object::SectionKind::UninitializedData);
+    assert_eq!(counters.size(), 16);
+
+    // the inner loop's back-edge comes first
+    let spans = file.section_by_name(".hf_counter_spans").unwrap();
+    let expected = [1u64, 2, 4, 1, 0, 4]
+        .iter()
+        .flat_map(|n| n.to_le_bytes())
+        .collect::<Vec<u8>>();
+    assert_eq!(spans.data().unwrap(), expected);
+
+    let text_section = file.section_by_name(".text").unwrap();
+    let text = text_section.data().unwrap();
+    let mut addends = text_section
+        .relocations()
+        .map(|(offset, relocation)| {
+            let object::RelocationTarget::Symbol(symbol) = relocation.target() else {
+                panic!("relocation against something other than a symbol");
+            };
+            let offset = offset as usize;
+            assert_eq!(
+                file.symbol_by_index(symbol).unwrap().name().unwrap(),
+                super::PROFILE_COUNTERS_SYMBOL
+            );
+            // inc qword ptr [rip + 0]
+            assert_eq!(text[offset - 3..offset + 4], [0x48, 0xff, 0x05, 0, 0, 0, 0]);
+            relocation.addend()
+        })
+        .collect::<Vec<_>>();
+    addends.sort();
+    assert_eq!(addends, [-4, 4]);
+
+    let bytes = compile_to_object_bytes(&mut get_compiler(), ir);
+    let file = object::File::parse(&*bytes).unwrap();
+    assert!(file.section_by_name(".hf_counters").is_none());
+}
+
+#[test]
+fn test_static_tape() {
+    let bytes = compile_to_object_bytes(
+        &mut get_compiler_with(CompilerSettings {
+            static_tape: Some(0x10000),
+            ..Default::default()
+        }),
+        vec![node(IrOp::Add(1))],
+    );
+    let file = object::File::parse(&*bytes).unwrap();
+
+    let bss = file.section_by_name(".bss").unwrap();
+    assert_eq!(bss.kind(), object::SectionKind::UninitializedData);
+    assert_eq!(bss.size(), 0x10000);
+    let tape = file.symbol_by_name(super::TAPE_SYMBOL).unwrap();
+    assert_eq!(tape.section_index(), Some(bss.index()));
+
+    let start = file.symbol_by_name("_start").unwrap().address();
+    let text_section = file.section_by_name(".text").unwrap();
+    let text = &text_section.data().unwrap()[start as usize..];
+    assert_eq_hex!(
+        text[..7],
+        [0x4c, 0x8d, 0x05, 0x00, 0x00, 0x00, 0x00] // lea r8, [rip + tape]
+    );
+
+    let (offset, relocation) = text_section.relocations().next().unwrap();
+    assert_eq!(offset, start + 3);
+    assert_eq!(relocation.addend(), -4);
+    assert_eq!(
+        relocation.target(),
+        object::RelocationTarget::Symbol(tape.index())
+    );
+}
+
+#[test]
+fn test_cell_pointer_in_memory() {
+    let mut compiler = get_compiler_with(CompilerSettings {
+        cell_pointer: CellPointer::Memory,
+        ..Default::default()
+    });
+    let summary = summarize_object(&compile_to_object_bytes(
+        &mut compiler,
+        vec![node(IrOp::ExternalFunctionCall(ExternalCall::new(
+            "putchar".to_string(),
+        )))],
+    ));
+
+    let (_, start, _) = summary
+        .symbols
+        .iter()
+        .find(|(name, _, _)| name == "_start")
+        .unwrap();
+    assert_eq!(*start, 0);
     assert_eq_hex!(
-        compile_to_bytecode(&format!("{}{}{}", ":test{}", "+".repeat(1024), "@test;")),
+        summary.text[..43],
+        [
+            &[0x4c, 0x8b, 0x05, 0x00, 0x00, 0x00, 0x00][..], // mov r8, [rip + cell_pointer]
+            &[0x41, 0x51],                                   // push r9
+            &[0x48, 0x83, 0xec, 0x08],                       // sub rsp, 8
+            &[0x48, 0x8d, 0x3d, 0x00, 0x00, 0x00, 0x00],     // lea rdi, [rip + cell_pointer]
+            &[0x48, 0x8d, 0x74, 0x24, 0x08],                 // lea rsi, [rsp + 8]
+            &[0xe8, 0x00, 0x00, 0x00, 0x00],                 // call putchar
+            &[0x48, 0x83, 0xc4, 0x08],                       // add rsp, 8
+            &[0x41, 0x59],                                   // pop r9
+            &[0x4c, 0x8b, 0x05, 0x00, 0x00, 0x00, 0x00],     // mov r8, [rip + cell_pointer]
+        ]
+        .concat()
+    );
+    assert_eq!(
+        summary.relocations,
         vec![
-            0xc3, 0x41, 0x80, 0x0, 0xff, 0x41, 0x80, 0x0, 0xff, 0x41, 0x80, 0x0, 0xff, 0x41, 0x80,
-            0x0, 0xff, 0x41, 0x80, 0x0, 0x4, 0xe8, 0xe6, 0xff, 0xff, 0xff,
+            (3, super::CELL_POINTER_SYMBOL.to_string()),
+            (16, super::CELL_POINTER_SYMBOL.to_string()),
+            (26, "putchar".to_string()),
+            (39, super::CELL_POINTER_SYMBOL.to_string()),
+        ]
+    );
+}
+
+#[test]
+fn test_io_with_cell_pointer_in_memory() {
+    let mut compiler = get_compiler_with(CompilerSettings {
+        cell_pointer: CellPointer::Memory,
+        ..Default::default()
+    });
+    let summary = summarize_object(&compile_to_object_bytes(
+        &mut compiler,
+        vec![node(IrOp::WriteByte)],
+    ));
+
+    // only the stack pointer is pushed, so the stack is padded
+    assert_eq_hex!(
+        summary.text[..44],
+        [
+            &[0x4c, 0x8b, 0x05, 0x00, 0x00, 0x00, 0x00][..], // mov r8, [rip + cell_pointer]
+            &[0x41, 0x51],                                   // push r9
+            &[0xbf, 0x01, 0x00, 0x00, 0x00],                 // mov edi, 1
+            &[0x4c, 0x89, 0xc6],                             // mov rsi, r8
+            &[0xba, 0x01, 0x00, 0x00, 0x00],                 // mov edx, 1
+            &[0x48, 0x83, 0xec, 0x08],                       // sub rsp, 8
+            &[0xe8, 0x00, 0x00, 0x00, 0x00],                 // call write
+            &[0x48, 0x83, 0xc4, 0x08],                       // add rsp, 8
+            &[0x41, 0x59],                                   // pop r9
+            &[0x4c, 0x8b, 0x05, 0x00, 0x00, 0x00, 0x00],     // mov r8, [rip + cell_pointer]
+        ]
+        .concat()
+    );
+    assert_eq!(
+        summary.relocations[..3],
+        [
+            (3, super::CELL_POINTER_SYMBOL.to_string()),
+            (27, "write".to_string()),
+            (40, super::CELL_POINTER_SYMBOL.to_string()),
+        ]
+    );
+}
+
+#[test]
+fn test_stack_tape_guard() {
+    let bytes = compile_to_object_bytes(
+        &mut get_compiler_with(CompilerSettings {
+            static_tape: Some(0x10),
+            stack_tape_guard: true,
+            ..Default::default()
+        }),
+        vec![node(IrOp::StackPush)],
+    );
+    let file = object::File::parse(&*bytes).unwrap();
+    let tape = file.symbol_by_name(super::TAPE_SYMBOL).unwrap();
+
+    let text_section = file.section_by_name(".text").unwrap();
+    assert_eq_hex!(
+        text_section.data().unwrap()[..43],
+        [
+            &[0x4c, 0x8d, 0x05, 0x00, 0x00, 0x00, 0x00][..], // lea r8, [rip + tape]
+            &[0x4d, 0x8d, 0x49, 0x01],                       // lea r9, [r9 + 1]
+            &[0x48, 0x8d, 0x05, 0x00, 0x00, 0x00, 0x00],     // lea rax, [rip + tape]
+            &[0x49, 0x39, 0xc1],                             // cmp r9, rax
+            &[0x72, 0x0e],                                   // jb ok
+            &[0x48, 0x8d, 0x05, 0x00, 0x00, 0x00, 0x00],     // lea rax, [rip + tape + 0x10]
+            &[0x49, 0x39, 0xc1],                             // cmp r9, rax
+            &[0x73, 0x02],                                   // jae ok
+            &[0x0f, 0x0b],                                   // ud2
+            // ok:
+            &[0x41, 0x8a, 0x00], // mov al, byte ptr [r8]
+            &[0x41, 0x88, 0x01], // mov byte ptr [r9], al
+        ]
+        .concat()
+    );
+
+    let mut relocations = text_section
+        .relocations()
+        .map(|(offset, relocation)| {
+            assert_eq!(
+                relocation.target(),
+                object::RelocationTarget::Symbol(tape.index())
+            );
+            (offset, relocation.addend())
+        })
+        .collect::<Vec<_>>();
+    relocations.sort();
+    assert_eq!(relocations, vec![(3, -4), (14, -4), (26, 0x10 - 4)]);
+}
+
+#[test]
+fn test_stack_overflow() {
+    let compile = |policy| {
+        let mut compiler = get_compiler_with(CompilerSettings {
+            stack_overflow: Some(policy),
+            stack_size: 0x10,
+            ..Default::default()
+        });
+        summarize_object(&compile_to_object_bytes(
+            &mut compiler,
+            vec![node(IrOp::StackPush)],
+        ))
+    };
+
+    let trap = compile(StackOverflow::Trap);
+    assert_eq_hex!(
+        trap.text[..28],
+        [
+            &[0x4c, 0x8d, 0x0d, 0x00, 0x00, 0x00, 0x00][..], // lea r9, [rip + stack - 1]
+            &[0x4d, 0x8d, 0x49, 0x01],                       // lea r9, [r9 + 1]
+            &[0x48, 0x8d, 0x05, 0x00, 0x00, 0x00, 0x00],     // lea rax, [rip + stack + 0x10]
+            &[0x49, 0x39, 0xc1],                             // cmp r9, rax
+            &[0x72, 0x02],                                   // jb ok
+            &[0x0f, 0x0b],                                   // ud2
+            // ok:
+            &[0x41, 0x8a, 0x00], // mov al, byte ptr [r8]
+        ]
+        .concat()
+    );
+    let stack = (super::STACK_SYMBOL.to_string(), 0, false);
+    assert!(trap.symbols.contains(&stack));
+    assert_eq!(
+        trap.relocations,
+        vec![(3, stack.0.clone()), (14, stack.0.clone())]
+    );
+
+    // wrapping also checks pops, which a push alone doesn't need
+    let wrap = compile(StackOverflow::Wrap);
+    assert_eq!(wrap.relocations.len(), 3);
+
+    // the stack is only allocated on the first push, and is empty at its
+    // base until then
+    let grow = compile(StackOverflow::Grow);
+    assert_eq_hex!(
+        grow.text[..11],
+        [
+            0x4c, 0x8b, 0x0d, 0x00, 0x00, 0x00, 0x00, // mov r9, [rip + base]
+            0x4d, 0x8d, 0x49, 0xff, // lea r9, [r9 - 1]
+        ]
+    );
+    assert_eq!(
+        grow.relocations[0],
+        (3, super::STACK_BOUNDS_SYMBOL.to_string())
+    );
+    assert!(grow
+        .symbols
+        .contains(&(super::STACK_BOUNDS_SYMBOL.to_string(), 0, false)));
+    assert!(grow.relocations.iter().any(|(_, name)| name == "realloc"));
+    assert!(!grow
+        .symbols
+        .iter()
+        .any(|(name, _, _)| name == super::STACK_SYMBOL));
+
+    // Win64 keeps r8 and r9 clear of the shadow space of `realloc`, and other
+    // conventions can't call it
+    let grow_with = |calling_convention| {
+        let settings = CompilerSettings {
+            stack_overflow: Some(StackOverflow::Grow),
+            stack_size: 0x10,
+            ..Default::default()
+        };
+        Compiler::new(64, settings, calling_convention)
+            .compile_to_object_file(vec![node(IrOp::StackPush)], "test.hf")
+    };
+    let bytes = grow_with(crate::target::CallingConvention::X86_64_MicrosoftX64)
+        .unwrap()
+        .write()
+        .unwrap();
+    let summary = summarize_object(&bytes);
+    let realloc = summary
+        .relocations
+        .iter()
+        .find(|(_, name)| name == "realloc")
+        .unwrap()
+        .0 as usize;
+    assert_eq_hex!(
+        summary.text[realloc - 5..realloc + 8],
+        [
+            &[0x48, 0x83, 0xec, 0x20][..],   // sub rsp, 0x20
+            &[0xe8, 0x00, 0x00, 0x00, 0x00], // call realloc
+            &[0x48, 0x83, 0xc4, 0x20],       // add rsp, 0x20
         ]
+        .concat()
+    );
+    let err = grow_with(crate::target::CallingConvention::X86_CDeclGcc).unwrap_err();
+    assert!(matches!(
+        err.kind,
+        CompilerErrorKind::UnsupportedOp("StackPush")
+    ));
+
+    // a guarded stack is mapped on the first entry, and pushes aren't checked
+    // at all
+    let guard = compile(StackOverflow::Guard);
+    let mnemonics = iced_x86::Decoder::new(64, &guard.text, iced_x86::DecoderOptions::NONE)
+        .into_iter()
+        .map(|instruction| instruction.mnemonic())
+        .collect::<Vec<_>>();
+    use iced_x86::Mnemonic::*;
+    assert_eq!(mnemonics[..4], [Mov, Test, Jne, Push]);
+    assert_eq!(mnemonics.iter().filter(|&&m| m == Syscall).count(), 3);
+    assert_eq!(
+        mnemonics[mnemonics.len() - 7..],
+        [Lea, Lea, Mov, Mov, Mov, Xor, Syscall]
+    );
+    assert!(guard
+        .relocations
+        .iter()
+        .all(|(_, name)| name == super::STACK_BOUNDS_SYMBOL));
+    assert_eq!(guard.relocations.len(), 2);
+}
+
+#[test]
+fn test_runtime_abi() {
+    let compile = |runtime_abi| {
+        let mut compiler = get_compiler_with(CompilerSettings {
+            stack_overflow: Some(StackOverflow::Grow),
+            output_flush: OutputFlush::EveryByte,
+            runtime_abi,
+            ..Default::default()
+        });
+        let ir = vec![
+            node(IrOp::StackPush),
+            node(IrOp::ReadByte),
+            node(IrOp::WriteByte),
+        ];
+        let mut externals = summarize_object(&compile_to_object_bytes(&mut compiler, ir))
+            .symbols
+            .into_iter()
+            .filter(|(_, _, undefined)| *undefined)
+            .map(|(name, _, _)| name)
+            .collect::<Vec<_>>();
+        externals.sort();
+        externals
+    };
+
+    assert_eq!(
+        compile(RuntimeAbi::default()),
+        ["flush", "read", "realloc", "write"]
+    );
+    assert_eq!(
+        compile(RuntimeAbi {
+            allocator: "hf_grow".to_string(),
+            flush: "hf_flush".to_string(),
+            ..Default::default()
+        }),
+        ["hf_flush", "hf_grow", "read", "write"]
+    );
+}
+
+#[test]
+fn test_file_symbol() {
+    let file_symbols = |file_symbol| {
+        let bytes = compile_to_object_bytes(
+            &mut get_compiler_with(CompilerSettings {
+                file_symbol,
+                ..Default::default()
+            }),
+            vec![node(IrOp::Add(1))],
+        );
+        let file = object::File::parse(&*bytes).unwrap();
+        file.symbols()
+            .filter(|s| s.kind() == object::SymbolKind::File)
+            .map(|s| s.name().unwrap().to_string())
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(file_symbols(FileSymbol::SourceFilename), vec!["test.hf"]);
+    assert_eq!(
+        file_symbols(FileSymbol::Named("main.hf".to_string())),
+        vec!["main.hf"]
+    );
+    assert_eq!(file_symbols(FileSymbol::Omitted), Vec::<String>::new());
+}
+
+#[test]
+fn test_settings_for_target() {
+    use crate::target::{Arch, CallingConvention, Os};
+
+    let compile = |os| {
+        let target = Target::new(
+            Arch::X86_64,
+            CallingConvention::from_arch_os(Arch::X86_64, os),
+        );
+        let settings = CompilerSettings::for_target(&target);
+        let bytes = HfCompiler::new(target, settings.clone())
+            .compile_to_object_file(vec![node(IrOp::Add(1)), node(IrOp::WriteByte)], "test.hf")
+            .unwrap()
+            .write()
+            .unwrap();
+        (settings, bytes)
+    };
+
+    let (settings, bytes) = compile(Os::Linux);
+    assert_eq!(settings.object_format, ObjectFormat::Elf);
+    assert_eq!(settings.entry_exit, EntryExit::LinuxExit);
+    let file = object::File::parse(&*bytes).unwrap();
+    assert_eq!(file.format(), object::BinaryFormat::Elf);
+    assert!(file.symbol_by_name("_start").is_some());
+    assert!(file.symbol_by_name(super::TAPE_SYMBOL).is_some());
+
+    let (settings, bytes) = compile(Os::Windows);
+    assert_eq!(settings.object_format, ObjectFormat::Coff);
+    assert_eq!(settings.entry_exit, EntryExit::Return);
+    let file = object::File::parse(&*bytes).unwrap();
+    assert_eq!(file.format(), object::BinaryFormat::Coff);
+    assert!(file.symbol_by_name("_start").is_some());
+    // Win64 passes the file descriptor of `write` in ecx
+    let text = file.section_by_name(".text").unwrap();
+    assert!(text
+        .data()
+        .unwrap()
+        .windows(5)
+        .any(|bytes| bytes == [0xb9, 0x01, 0x00, 0x00, 0x00])); // mov ecx, 1
+}
+
+#[test]
+fn test_relocation_failed_context() {
+    use object::write::{Object, Relocation, Symbol, SymbolSection};
+    use object::{
+        Architecture, BinaryFormat, Endianness, RelocationEncoding, RelocationFlags,
+        RelocationKind, SectionKind, SymbolFlags, SymbolKind, SymbolScope,
+    };
+
+    let mut obj = Object::new(BinaryFormat::Elf, Architecture::X86_64, Endianness::Little);
+    let text = obj.add_section(Vec::new(), b".text".to_vec(), SectionKind::Text);
+    let symbol = obj.add_symbol(Symbol {
+        name: b"putchar".to_vec(),
+        value: 0,
+        size: 0,
+        kind: SymbolKind::Text,
+        scope: SymbolScope::Dynamic,
+        weak: false,
+        section: SymbolSection::Undefined,
+        flags: SymbolFlags::None,
+    });
+
+    // ELF on x86-64 has no 24-bit relative relocations
+    let err = add_relocation(
+        &mut obj,
+        text,
+        "putchar",
+        Relocation {
+            offset: 1,
+            symbol,
+            addend: -4,
+            flags: RelocationFlags::Generic {
+                kind: RelocationKind::Relative,
+                encoding: RelocationEncoding::Generic,
+                size: 24,
+            },
+        },
     )
+    .unwrap_err();
+    assert!(err
+        .to_string()
+        .starts_with("failed to relocate `putchar` with a 24-bit Relative (Generic) relocation: "));
+}
+
+#[test]
+fn test_bottom_tested_loop() {
+    let ir = vec![
+        node(IrOp::Add(1)),
+        node(IrOp::Condition(vec![node(IrOp::Subtract(1))])),
+    ];
+    let cleared = [vec![node(IrOp::MulConst(0))], ir.clone()].concat();
+    let bytes = get_compiler_with(CompilerSettings {
+        optimization_level: 1,
+        ..Default::default()
+    })
+    .compile_to_bytecode(cleared)
+    .unwrap();
+
+    assert_eq_hex!(
+        bytes,
+        vec![
+            0x41, 0xc6, 0x00, 0x00, // mov byte ptr [r8], 0
+            0x41, 0x80, 0x00, 0x01, // add byte ptr [r8], 1
+            // start:
+            0x41, 0x80, 0x00, 0xff, // add byte ptr [r8], 0xff
+            0x41, 0x80, 0x38, 0x00, // cmp byte ptr [r8], 0
+            0x75, 0xf6, // jne start
+        ]
+    );
+
+    // nor on a tape the host passes in, which may hold anything
+    let bytes = get_compiler_with(CompilerSettings {
+        optimization_level: 1,
+        ..Default::default()
+    })
+    .compile_to_bytecode(ir.clone())
+    .unwrap();
+    assert_eq!(bytes[4..8], [0x41, 0x80, 0x38, 0x00]); // cmp byte ptr [r8], 0
+
+    // the cell isn't known to be nonzero on entry to a function
+    let bytes = get_compiler_with(CompilerSettings {
+        optimization_level: 1,
+        ..Default::default()
+    })
+    .compile_to_bytecode(vec![node(IrOp::Function("f".to_string(), ir))])
+    .unwrap();
+    assert_eq!(bytes[8..12], [0x41, 0x80, 0x38, 0x00]); // cmp byte ptr [r8], 0
+}
+
+#[cfg(feature = "jit")]
+#[test]
+fn test_bottom_tested_loop_on_filled_tape() {
+    // +[-->+<] never enters the loop if the host's cell was 255
+    let ir = vec![
+        node(IrOp::Add(1)),
+        node(IrOp::Condition(vec![
+            node(IrOp::Subtract(2)),
+            node(IrOp::MoveRight(1)),
+            node(IrOp::Add(1)),
+            node(IrOp::MoveLeft(1)),
+        ])),
+    ];
+    let bytes = get_compiler_with(CompilerSettings {
+        optimization_level: 1,
+        ..Default::default()
+    })
+    .compile_to_bytecode(ir)
+    .unwrap();
+
+    // called like the entry of the JIT, with the tape and the stack passed in
+    // and the cell pointer returned
+    let code = super::CallableCode {
+        code: [
+            &[0x49, 0x89, 0xf8][..], // mov r8, rdi
+            &[0x49, 0x89, 0xf1],     // mov r9, rsi
+            &bytes,
+            &[0x4c, 0x89, 0xc0], // mov rax, r8
+            &[0xc3],             // ret
+        ]
+        .concat(),
+        entry: 0,
+        external_calls: Vec::new(),
+    };
+    let mut jit = super::jit::JitFunction::new(code, &[], 0, 0).unwrap();
+    let mut tape = [255, 0];
+    assert_eq!(unsafe { jit.call(&mut tape) }.unwrap(), 0);
+    assert_eq!(tape, [0, 0]);
+}
+
+#[test]
+fn test_regions() {
+    // +++[->+<] on a cleared cell
+    let ir = vec![
+        node(IrOp::MulConst(0)),
+        node(IrOp::Add(3)),
+        node(IrOp::Condition(vec![
+            node(IrOp::Subtract(1)),
+            node(IrOp::MoveRight(1)),
+            node(IrOp::Add(1)),
+            node(IrOp::MoveLeft(1)),
+        ])),
+    ];
+    let bytes = get_compiler_with(CompilerSettings {
+        optimization_level: 1,
+        ..Default::default()
+    })
+    .compile_to_bytecode(ir)
+    .unwrap();
+
+    // the body doesn't move the cell pointer
+    assert_eq_hex!(
+        bytes,
+        [
+            &[0x41, 0xc6, 0x00, 0x00][..], // mov byte ptr [r8], 0
+            &[0x41, 0x80, 0x00, 0x03],     // add byte ptr [r8], 3
+            // start:
+            &[0x41, 0x80, 0x00, 0xff],       // add byte ptr [r8], 0xff
+            &[0x41, 0x80, 0x40, 0x01, 0x01], // add byte ptr [r8 + 1], 1
+            &[0x41, 0x80, 0x38, 0x00],       // cmp byte ptr [r8], 0
+            &[0x75, 0xf1],                   // jne start
+        ]
+        .concat()
+    );
+}
+
+#[test]
+fn test_expected_code_size() {
+    let ir = || {
+        vec![
+            node(IrOp::Add(3)),
+            node(IrOp::MoveRight(2)),
+            node(IrOp::WriteByte),
+        ]
+    };
+    let compile = |expected_code_size| {
+        let mut compiler = get_compiler_with(CompilerSettings {
+            expected_code_size,
+            ..Default::default()
+        });
+        compile_to_object_bytes(&mut compiler, ir())
+    };
+
+    let bytes = compile(None);
+    // too small or too large, the hint only changes how much is reserved
+    for hint in [0, 1, 0x10000] {
+        assert_eq!(compile(Some(hint)), bytes);
+    }
+}
+
+#[test]
+fn test_compile_into_object() {
+    use object::write::{Object, Symbol, SymbolSection};
+    use object::{
+        Architecture, BinaryFormat, Endianness, SectionKind, SymbolFlags, SymbolKind, SymbolScope,
+    };
+
+    let mut obj = Object::new(BinaryFormat::Elf, Architecture::X86_64, Endianness::Little);
+    let data = obj.add_section(Vec::new(), b".data".to_vec(), SectionKind::Data);
+    let greeting = obj.add_symbol(Symbol {
+        name: b"greeting".to_vec(),
+        value: 0,
+        size: 0,
+        kind: SymbolKind::Data,
+        scope: SymbolScope::Dynamic,
+        weak: false,
+        section: SymbolSection::Undefined,
+        flags: SymbolFlags::None,
+    });
+    obj.add_symbol_data(greeting, data, b"hello", 1);
+    let text = obj.add_section(Vec::new(), b".text".to_vec(), SectionKind::Text);
+    obj.append_section_data(text, &[0xc3], 1); // someone else's ret
+
+    let ir = vec![node(IrOp::ExternalFunctionCall(ExternalCall::new(
+        "putchar".to_string(),
+    )))];
+    get_compiler()
+        .compile_into_object(ir.clone(), &mut obj, text)
+        .unwrap();
+    let bytes = obj.write().unwrap();
+    let file = object::File::parse(&*bytes).unwrap();
+
+    assert_eq!(
+        file.section_by_name(".data").unwrap().data().unwrap(),
+        b"hello"
+    );
+    assert_eq!(file.symbol_by_name("greeting").unwrap().address(), 0);
+
+    // our code is aligned to 16 bytes, after the ret, and the padding traps
+    let summary = summarize_object(&bytes);
+    let standalone = summarize_object(&compile_to_object_bytes(&mut get_compiler(), ir));
+    assert_eq!(summary.text[0], 0xc3);
+    assert_eq!(summary.text[1..16], [0xcc; 15]);
+    assert_eq!(summary.text[16..], standalone.text);
+    assert!(summary.symbols.contains(&("_start".to_string(), 16, false)));
+    assert_eq!(
+        summary.relocations,
+        standalone
+            .relocations
+            .iter()
+            .map(|(offset, name)| (offset + 16, name.clone()))
+            .collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn test_dead_loop_removed() {
+    let settings = || CompilerSettings {
+        optimization_level: 1,
+        ..Default::default()
+    };
+    let clear = || node(IrOp::Condition(vec![node(IrOp::Subtract(1))]));
+    let add = || node(IrOp::Condition(vec![node(IrOp::Add(1))]));
+
+    let with_dead_loop = get_compiler_with(settings())
+        .compile_to_bytecode(vec![node(IrOp::MoveRight(1)), clear(), add()])
+        .unwrap();
+    let without = get_compiler_with(settings())
+        .compile_to_bytecode(vec![node(IrOp::MoveRight(1)), clear()])
+        .unwrap();
+    assert_eq_hex!(with_dead_loop, without);
+}
+
+#[test]
+fn test_dead_loop_warnings_match_pass() {
+    use crate::optimizer::{DeadLoops, IrPass as _};
+
+    let at = |column, op| IrNode {
+        node: op,
+        span: Span::from_location((0, column)),
+    };
+    // [+]++[-][+]
+    let ir = vec![
+        at(0, IrOp::Condition(vec![node(IrOp::Add(1))])),
+        at(3, IrOp::Add(2)),
+        at(5, IrOp::Condition(vec![node(IrOp::Subtract(1))])),
+        at(8, IrOp::Condition(vec![node(IrOp::Add(1))])),
+    ];
+    let mut compiler = get_compiler_with(CompilerSettings {
+        optimization_level: 1,
+        ..Default::default()
+    });
+    compiler.compile_to_bytecode(ir.clone()).unwrap();
+    let warned = compiler
+        .warnings()
+        .iter()
+        .map(|warning| warning.span)
+        .collect::<Vec<_>>();
+
+    let kept = DeadLoops
+        .run(ir.clone(), &CompilerSettings::default())
+        .into_iter()
+        .map(|node| node.span)
+        .collect::<Vec<_>>();
+    let removed = ir
+        .iter()
+        .map(|node| node.span)
+        .filter(|span| !kept.contains(span))
+        .collect::<Vec<_>>();
+    assert_eq!(warned, removed);
+    assert_eq!(warned, [Span::from_location((0, 8))]);
+}
+
+#[test]
+fn test_entry_exit() {
+    let start = |entry_exit| {
+        let mut compiler = get_compiler_with(CompilerSettings {
+            entry_exit,
+            ..Default::default()
+        });
+        let summary = summarize_object(&compile_to_object_bytes(
+            &mut compiler,
+            vec![node(IrOp::Add(1))],
+        ));
+        let (_, address, _) = summary
+            .symbols
+            .iter()
+            .find(|(name, _, _)| name == "_start")
+            .unwrap();
+        summary.text[*address as usize..].to_vec()
+    };
+
+    let add = [0x41, 0x80, 0x00, 0x01]; // add byte ptr [r8], 1
+    assert_eq_hex!(
+        start(EntryExit::LinuxExit),
+        [
+            &add[..],
+            &[0xb8, 0x3c, 0x00, 0x00, 0x00], // mov eax, 60
+            &[0x31, 0xff],                   // xor edi, edi
+            &[0x0f, 0x05],                   // syscall
+        ]
+        .concat()
+    );
+    assert_eq_hex!(start(EntryExit::Spin), [&add[..], &[0xeb, 0xfe]].concat()); // jmp $
+
+    // padded so externals are called with the stack aligned
+    assert_eq_hex!(
+        start(EntryExit::Return),
+        [
+            &[0x48, 0x83, 0xec, 0x08][..], // sub rsp, 8
+            &add,
+            &[0x48, 0x83, 0xc4, 0x08], // add rsp, 8
+            &[0xc3],                   // ret
+        ]
+        .concat()
+    );
+}
+
+#[test]
+fn test_no_auto_entry() {
+    let settings = || CompilerSettings {
+        auto_entry: false,
+        ..Default::default()
+    };
+
+    let mut compiler = get_compiler_with(settings());
+    let summary = summarize_object(&compile_to_object_bytes(
+        &mut compiler,
+        vec![node(IrOp::Function(
+            "f".to_string(),
+            vec![node(IrOp::Add(1))],
+        ))],
+    ));
+    assert!(summary.symbols.iter().any(|(name, _, _)| name == "f"));
+    assert!(!summary.symbols.iter().any(|(name, _, _)| name == "_start"));
+
+    let mut compiler = get_compiler_with(settings());
+    let err = compiler
+        .compile_to_object_file(vec![node(IrOp::Add(1))], "test.hf")
+        .unwrap_err();
+    assert!(matches!(
+        err.kind,
+        CompilerErrorKind::CodeOutsideFunction("Add")
+    ));
+}
+
+#[test]
+fn test_user_defined_start() {
+    let ir = || {
+        vec![
+            node(IrOp::Function("f".to_string(), vec![node(IrOp::Add(1))])),
+            node(IrOp::Function(
+                "_start".to_string(),
+                vec![node(IrOp::FunctionCall("f".to_string()))],
+            )),
+        ]
+    };
+
+    let err = get_compiler()
+        .compile_to_object_file(ir(), "test.hf")
+        .unwrap_err();
+    assert!(matches!(
+        err.kind,
+        CompilerErrorKind::ReservedFunctionName(ref name) if name == "_start"
+    ));
+
+    // without the generated entry point, the program's `_start` is the entry
+    let mut compiler = get_compiler_with(CompilerSettings {
+        auto_entry: false,
+        ..Default::default()
+    });
+    let summary = summarize_object(&compile_to_object_bytes(&mut compiler, ir()));
+    let count = |symbol: &str| {
+        summary
+            .symbols
+            .iter()
+            .filter(|(name, _, _)| name == symbol)
+            .count()
+    };
+    assert_eq!((count("_start"), count("f")), (1, 1));
+}
+
+#[test]
+fn test_indirect_call() {
+    let mut compiler = get_compiler_with(CompilerSettings {
+        base_address: 0x1000,
+        ..Default::default()
+    });
+    let code = compiler
+        .compile_to_bytecode(vec![
+            node(IrOp::Function("zero".to_string(), vec![node(IrOp::Add(1))])),
+            node(IrOp::Function("one".to_string(), vec![node(IrOp::Add(2))])),
+            node(IrOp::IndirectCall {
+                table: vec!["zero".to_string(), "one".to_string()],
+            }),
+        ])
+        .unwrap();
+
+    assert_eq_hex!(
+        code,
+        [
+            // zero:
+            &[0x48, 0x83, 0xec, 0x08][..],   // sub rsp, 8
+            &[0x41, 0x80, 0x00, 0x01],       // add byte ptr [r8], 1
+            &[0x48, 0x83, 0xc4, 0x08, 0xc3], // add rsp, 8; ret
+            // one:
+            &[0x48, 0x83, 0xec, 0x08],                   // sub rsp, 8
+            &[0x41, 0x80, 0x00, 0x02],                   // add byte ptr [r8], 2
+            &[0x48, 0x83, 0xc4, 0x08, 0xc3],             // add rsp, 8; ret
+            &[0x41, 0x0f, 0xb6, 0x00],                   // movzx eax, byte ptr [r8]
+            &[0x3d, 0x02, 0x00, 0x00, 0x00],             // cmp eax, 2
+            &[0x73, 0x0a],                               // jae skip
+            &[0x48, 0x8d, 0x0d, 0x03, 0x00, 0x00, 0x00], // lea rcx, [rip + table]
+            &[0xff, 0x14, 0xc1],                         // call qword ptr [rcx + rax * 8]
+            // skip: table:
+            &0x1000u64.to_le_bytes(),
+            &0x100du64.to_le_bytes(),
+        ]
+        .concat()
+    );
+}
+
+#[test]
+fn test_position_independence() {
+    let functions = || {
+        vec![
+            node(IrOp::Function("zero".to_string(), vec![node(IrOp::Add(1))])),
+            node(IrOp::Function("one".to_string(), vec![node(IrOp::Add(2))])),
+            node(IrOp::FunctionCall("zero".to_string())),
+            node(IrOp::ExternalFunctionCall(ExternalCall::new(
+                "putchar".to_string(),
+            ))),
+        ]
+    };
+
+    let mut compiler = get_compiler();
+    compile_to_object_bytes(&mut compiler, functions());
+    assert!(compiler.stats().is_position_independent());
+
+    let mut ir = functions();
+    ir.push(node(IrOp::IndirectCall {
+        table: vec!["zero".to_string(), "one".to_string()],
+    }));
+    let mut compiler = get_compiler();
+    let code = compiler.compile_to_bytecode(ir).unwrap();
+    let stats = compiler.stats();
+    assert!(!stats.is_position_independent());
+    // the table is at the very end
+    assert_eq!(
+        stats.absolute_references,
+        vec![
+            AbsoluteReference {
+                offset: code.len() - 16,
+                target: "zero".to_string(),
+                span: node(IrOp::Nop).span,
+            },
+            AbsoluteReference {
+                offset: code.len() - 8,
+                target: "one".to_string(),
+                span: node(IrOp::Nop).span,
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_compile_function() {
+    let mut compiler = get_compiler();
+    let (code, entry) = compiler
+        .compile_function(node(IrOp::Function(
+            "f".to_string(),
+            vec![
+                node(IrOp::Add(1)),
+                node(IrOp::FunctionCall("f".to_string())),
+            ],
+        )))
+        .unwrap();
+    assert_eq!(entry, 0);
+    assert_eq_hex!(
+        code,
+        [
+            0x48, 0x83, 0xec, 0x08, // sub rsp, 8
+            0x41, 0x80, 0x00, 0x01, // add byte ptr [r8], 1
+            0xe8, 0xf3, 0xff, 0xff, 0xff, // call f
+            0x48, 0x83, 0xc4, 0x08, // add rsp, 8
+            0xc3, // ret
+        ]
+    );
+
+    let err = compiler
+        .compile_function(node(IrOp::Function(
+            "f".to_string(),
+            vec![node(IrOp::FunctionCall("g".to_string()))],
+        )))
+        .unwrap_err();
+    assert!(matches!(err.kind, CompilerErrorKind::FunctionNotFound(name) if name == "g"));
+
+    let err = compiler.compile_function(node(IrOp::Add(1))).unwrap_err();
+    assert!(matches!(err.kind, CompilerErrorKind::NotAFunction("Add")));
+}
+
+#[test]
+fn test_settings_variants() {
+    let base = CompilerSettings {
+        base_address: 0x1000,
+        ..Default::default()
+    };
+    let optimized = CompilerSettings {
+        optimization_level: 1,
+        ..base.clone()
+    };
+    assert_eq!(base.optimization_level, 0);
+    assert_eq!(optimized.base_address, 0x1000);
+
+    let ir = || vec![node(IrOp::Add(1)), node(IrOp::Subtract(1))];
+    let unoptimized = get_compiler_with(base).compile_to_bytecode(ir()).unwrap();
+    let optimized = get_compiler_with(optimized)
+        .compile_to_bytecode(ir())
+        .unwrap();
+    assert_eq!(unoptimized.len(), 8);
+    assert!(optimized.is_empty());
+}
+
+#[test]
+fn test_suspicious_loop_warning() {
+    let warnings = |body: Vec<IrNode>| {
+        let mut compiler = get_compiler_with(CompilerSettings {
+            warn_suspicious_loops: true,
+            ..Default::default()
+        });
+        compiler
+            .compile_to_bytecode(vec![node(IrOp::Add(3)), node(IrOp::Condition(body))])
+            .unwrap();
+        compiler.warnings().to_vec()
+    };
+
+    // [--]
+    assert_eq!(
+        warnings(vec![node(IrOp::Subtract(2))]),
+        vec![CompilerWarning {
+            kind: CompilerWarningKind::SuspiciousLoop(0xfe),
+            span: node(IrOp::Nop).span,
+        }]
+    );
+    // [-]
+    assert_eq!(warnings(vec![node(IrOp::Subtract(1))]), vec![]);
+    // [->+<] does more than arithmetic on the cell
+    assert_eq!(
+        warnings(vec![
+            node(IrOp::Subtract(2)),
+            node(IrOp::MoveRight(1)),
+            node(IrOp::Add(1)),
+            node(IrOp::MoveLeft(1)),
+        ]),
+        vec![]
+    );
+}
+
+#[test]
+fn test_compile_with_warnings() {
+    for optimization_level in [0, 1] {
+        let mut compiler = HfCompiler::new(
+            Target::native(),
+            CompilerSettings {
+                optimization_level,
+                ..Default::default()
+            },
+        );
+        let clear = node(IrOp::Condition(vec![node(IrOp::Subtract(1))]));
+        let dead_loop = IrNode {
+            node: IrOp::Condition(vec![node(IrOp::Subtract(1))]),
+            span: Span::from_location((1, 0)),
+        };
+        let output = compiler
+            .compile_with_warnings(vec![clear, dead_loop, node(IrOp::Add(1))], "test.hf")
+            .unwrap();
+        assert_eq!(
+            output.warnings,
+            vec![CompilerWarning {
+                kind: CompilerWarningKind::DeadLoop,
+                span: Span::from_location((1, 0)),
+            }]
+        );
+        output.object.write().unwrap();
+    }
+}
+
+#[test]
+fn test_debug_marker() {
+    let ir = || {
+        vec![
+            node(IrOp::Add(1)),
+            node(IrOp::DebugMarker("middle".to_string())),
+            node(IrOp::Add(1)),
+        ]
+    };
+
+    let mut compiler = get_compiler();
+    let code = compiler.compile_to_bytecode(ir()).unwrap();
+    assert_eq_hex!(code, [0x41, 0x80, 0x00, 0x01, 0x41, 0x80, 0x00, 0x01]);
+    assert_eq!(compiler.stats().markers, vec![("middle".to_string(), 4)]);
+
+    // the entry point's label is still pending at the first marker
+    let summary = summarize_object(&compile_to_object_bytes(
+        &mut get_compiler(),
+        [vec![node(IrOp::DebugMarker("first".to_string()))], ir()].concat(),
+    ));
+    let (_, start, _) = summary
+        .symbols
+        .iter()
+        .find(|(name, _, _)| name == "_start")
+        .unwrap();
+    assert!(summary
+        .symbols
+        .contains(&("first".to_string(), *start, false)));
+    assert!(summary
+        .symbols
+        .contains(&("middle".to_string(), start + 4, false)));
+}
+
+#[test]
+fn test_move_to_external() {
+    let ir = || {
+        vec![
+            node(IrOp::MoveToExternal("answer".to_string())),
+            node(IrOp::Add(1)),
+            node(IrOp::MoveToExternal("answer".to_string())),
+        ]
+    };
+
+    let err = get_compiler().compile_to_bytecode(ir()).unwrap_err();
+    assert!(matches!(
+        err.kind,
+        CompilerErrorKind::RequiresObjectFile("MoveToExternal")
+    ));
+
+    let bytes = compile_to_object_bytes(&mut get_compiler(), ir());
+    let file = object::File::parse(&*bytes).unwrap();
+    let text_section = file.section_by_name(".text").unwrap();
+    assert_eq_hex!(
+        text_section.data().unwrap()[..18],
+        [
+            &[0x4c, 0x8b, 0x05, 0x00, 0x00, 0x00, 0x00][..], // mov r8, [rip + answer@GOTPCREL]
+            &[0x41, 0x80, 0x00, 0x01],                       // add byte ptr [r8], 1
+            &[0x4c, 0x8b, 0x05, 0x00, 0x00, 0x00, 0x00],     // mov r8, [rip + answer@GOTPCREL]
+        ]
+        .concat()
+    );
+
+    // both loads go through the same undefined data symbol
+    let answer = file.symbol_by_name("answer").unwrap();
+    assert!(answer.is_undefined());
+    let relocations = text_section.relocations().collect::<Vec<_>>();
+    assert_eq!(relocations.len(), 2);
+    for ((offset, relocation), expected) in relocations.into_iter().zip([3, 14]) {
+        assert_eq!(offset, expected);
+        assert_eq!(
+            relocation.target(),
+            object::RelocationTarget::Symbol(answer.index())
+        );
+        assert_eq!(relocation.kind(), object::RelocationKind::GotRelative);
+        assert_eq!(relocation.addend(), -4);
+    }
+}
+
+#[test]
+fn test_output_flush() {
+    let flushes = |output_flush, ir: Vec<IrNode>| {
+        let mut compiler = get_compiler_with(CompilerSettings {
+            output_flush,
+            ..Default::default()
+        });
+        summarize_object(&compile_to_object_bytes(&mut compiler, ir))
+            .relocations
+            .into_iter()
+            .filter(|(_, name)| name == "flush")
+            .count()
+    };
+    // the value of the cell isn't known after a move
+    let unknown = || vec![node(IrOp::MoveRight(1)), node(IrOp::WriteByte)];
+    let letter = || vec![node(IrOp::Add(b'A' as usize)), node(IrOp::WriteByte)];
+    let newline = || vec![node(IrOp::Add(b'\n' as usize)), node(IrOp::WriteByte)];
+
+    for ir in [unknown(), letter(), newline()] {
+        assert_eq!(flushes(OutputFlush::Never, ir.clone()), 0);
+        assert_eq!(flushes(OutputFlush::EveryByte, ir), 1);
+    }
+    assert_eq!(flushes(OutputFlush::OnNewline, letter()), 0);
+    assert_eq!(flushes(OutputFlush::OnNewline, newline()), 1);
+    assert_eq!(
+        flushes(OutputFlush::OnNewline, vec![node(IrOp::WriteBytes(2))]),
+        1
+    );
+
+    let mut compiler = get_compiler_with(CompilerSettings {
+        output_flush: OutputFlush::OnNewline,
+        ..Default::default()
+    });
+    let summary = summarize_object(&compile_to_object_bytes(&mut compiler, unknown()));
+    let write_end = summary
+        .relocations
+        .iter()
+        .find(|(_, name)| name == "write")
+        .unwrap()
+        .0 as usize
+        + 4;
+    assert_eq_hex!(
+        summary.text[write_end..write_end + 19],
+        [
+            &[0x41, 0x59][..],               // pop r9
+            &[0x41, 0x58],                   // pop r8
+            &[0x41, 0x80, 0x38, 0x0a],       // cmp byte ptr [r8], 0xa
+            &[0x75, 0x0d],                   // jne skip
+            &[0x41, 0x50],                   // push r8
+            &[0x41, 0x51],                   // push r9
+            &[0xe8, 0x00, 0x00, 0x00, 0x00], // call flush
+        ]
+        .concat()
+    );
+
+    // Win64 keeps r8 and r9 clear of the shadow space of `flush`
+    let mut compiler = Compiler::new(
+        64,
+        CompilerSettings {
+            output_flush: OutputFlush::EveryByte,
+            ..Default::default()
+        },
+        crate::target::CallingConvention::X86_64_MicrosoftX64,
+    );
+    let summary = summarize_object(&compile_to_object_bytes(&mut compiler, letter()));
+    let flush = summary
+        .relocations
+        .iter()
+        .find(|(_, name)| name == "flush")
+        .unwrap()
+        .0 as usize;
+    assert_eq_hex!(
+        summary.text[flush - 9..flush + 12],
+        [
+            &[0x41, 0x50][..],               // push r8
+            &[0x41, 0x51],                   // push r9
+            &[0x48, 0x83, 0xec, 0x20],       // sub rsp, 0x20
+            &[0xe8, 0x00, 0x00, 0x00, 0x00], // call flush
+            &[0x48, 0x83, 0xc4, 0x20],       // add rsp, 0x20
+            &[0x41, 0x59],                   // pop r9
+            &[0x41, 0x58],                   // pop r8
+        ]
+        .concat()
+    );
+
+    // with the cell pointer in memory only r9 is pushed, so the stack is
+    // padded
+    let mut compiler = get_compiler_with(CompilerSettings {
+        output_flush: OutputFlush::EveryByte,
+        cell_pointer: CellPointer::Memory,
+        ..Default::default()
+    });
+    let summary = summarize_object(&compile_to_object_bytes(&mut compiler, letter()));
+    let flush = summary
+        .relocations
+        .iter()
+        .find(|(_, name)| name == "flush")
+        .unwrap()
+        .0 as usize;
+    assert_eq_hex!(
+        summary.text[flush - 7..flush + 17],
+        [
+            &[0x41, 0x51][..],                           // push r9
+            &[0x48, 0x83, 0xec, 0x08],                   // sub rsp, 8
+            &[0xe8, 0x00, 0x00, 0x00, 0x00],             // call flush
+            &[0x48, 0x83, 0xc4, 0x08],                   // add rsp, 8
+            &[0x41, 0x59],                               // pop r9
+            &[0x4c, 0x8b, 0x05, 0x00, 0x00, 0x00, 0x00], // mov r8, [rip + cell_pointer]
+        ]
+        .concat()
+    );
+}
+
+#[test]
+fn test_external_functions() {
+    let library = vec![node(IrOp::Function(
+        "helper".to_string(),
+        vec![node(IrOp::Add(1))],
+    ))];
+    let program = || vec![node(IrOp::FunctionCall("helper".to_string()))];
+
+    let err = get_compiler()
+        .compile_to_object_file(program(), "main.hf")
+        .unwrap_err();
+    assert!(matches!(err.kind, CompilerErrorKind::FunctionNotFound(_)));
+
+    let mut compiler = get_compiler_with(CompilerSettings {
+        auto_entry: false,
+        ..Default::default()
+    });
+    let library = summarize_object(&compile_to_object_bytes(&mut compiler, library));
+    assert!(library.symbols.contains(&("helper".to_string(), 0, false)));
+
+    let mut compiler = get_compiler();
+    let program = compiler
+        .compile_to_object_file_with_externals(program(), "main.hf", &["helper".to_string()])
+        .unwrap()
+        .write()
+        .unwrap();
+    let program = summarize_object(&program);
+    assert!(program.symbols.contains(&("helper".to_string(), 0, true)));
+    assert_eq!(program.relocations, vec![(1, "helper".to_string())]);
+    assert_eq!(program.text[0], 0xe8); // call helper
+
+    // the externals only apply to the one compilation
+    let err = compiler
+        .compile_to_object_file(
+            vec![node(IrOp::FunctionCall("helper".to_string()))],
+            "main.hf",
+        )
+        .unwrap_err();
+    assert!(matches!(err.kind, CompilerErrorKind::FunctionNotFound(_)));
+}
+
+#[test]
+fn test_external_calls() {
+    let compile = |external_calls, object_format| {
+        let mut compiler = get_compiler_with(CompilerSettings {
+            auto_entry: false,
+            external_calls,
+            object_format,
+            ..Default::default()
+        });
+        let ir = vec![node(IrOp::Function(
+            "f".to_string(),
+            vec![node(IrOp::FunctionCall("helper".to_string()))],
+        ))];
+        compiler
+            .compile_to_object_file_with_externals(ir, "test.hf", &["helper".to_string()])
+            .unwrap()
+            .write()
+            .unwrap()
+    };
+
+    let direct = summarize_object(&compile(ExternalCalls::DirectRel32, ObjectFormat::Elf));
+    assert_eq_hex!(direct.text[4..9], [0xe8, 0x00, 0x00, 0x00, 0x00]); // call helper
+    assert_eq!(direct.relocations, vec![(5, "helper".to_string())]);
+
+    let bytes = compile(ExternalCalls::IndirectGot, ObjectFormat::Elf);
+    let indirect = summarize_object(&bytes);
+    assert_eq_hex!(
+        indirect.text[4..10],
+        [0xff, 0x15, 0x00, 0x00, 0x00, 0x00] // call [rip + helper@GOTPCREL]
+    );
+    assert_eq!(indirect.relocations, vec![(6, "helper".to_string())]);
+    let file = object::File::parse(&*bytes).unwrap();
+    let (_, relocation) = file
+        .section_by_name(".text")
+        .unwrap()
+        .relocations()
+        .next()
+        .unwrap();
+    assert_eq!(relocation.kind(), object::RelocationKind::GotRelative);
+    assert_eq!(relocation.addend(), -4);
+
+    // COFF calls through the import table instead
+    let coff = summarize_object(&compile(ExternalCalls::IndirectGot, ObjectFormat::Coff));
+    assert_eq!(coff.relocations, vec![(6, "__imp_helper".to_string())]);
+    assert!(coff
+        .symbols
+        .contains(&("__imp_helper".to_string(), 0, true)));
+
+    // bytecode isn't linked, so it keeps calling directly
+    let bytes = get_compiler_with(CompilerSettings {
+        external_calls: ExternalCalls::IndirectGot,
+        ..Default::default()
+    })
+    .compile_to_bytecode(vec![node(IrOp::WriteByte)])
+    .unwrap();
+    assert!(!bytes.windows(2).any(|bytes| bytes == [0xff, 0x15]));
+}
+
+#[test]
+fn test_call_relocations_resolve() {
+    // where the linker puts .text, and `write` or its GOT entry
+    const TEXT: u64 = 0x1000;
+    const TARGET: u64 = 0x40_0000;
+
+    for external_calls in [ExternalCalls::DirectRel32, ExternalCalls::IndirectGot] {
+        let mut compiler = get_compiler_with(CompilerSettings {
+            external_calls,
+            ..Default::default()
+        });
+        let bytes = compile_to_object_bytes(
+            &mut compiler,
+            vec![node(IrOp::WriteByte), node(IrOp::WriteBytes(2))],
+        );
+        let file = object::File::parse(&*bytes).unwrap();
+        let text = file.section_by_name(".text").unwrap();
+        let mut code = text.data().unwrap().to_vec();
+
+        // S + A - P, as the linker would
+        let relocations = text.relocations().collect::<Vec<_>>();
+        assert_eq!(relocations.len(), 2);
+        for (offset, relocation) in relocations {
+            let place = TEXT + offset;
+            let value = (TARGET as i64 + relocation.addend() - place as i64) as i32;
+            code[offset as usize..][..4].copy_from_slice(&value.to_le_bytes());
+        }
+
+        let calls = iced_x86::Decoder::with_ip(64, &code, TEXT, iced_x86::DecoderOptions::NONE)
+            .into_iter()
+            .filter(|instruction| instruction.mnemonic() == iced_x86::Mnemonic::Call)
+            .map(|call| match external_calls {
+                ExternalCalls::DirectRel32 => call.near_branch_target(),
+                ExternalCalls::IndirectGot => call.ip_rel_memory_address(),
+            })
+            .collect::<Vec<_>>();
+        assert_eq_hex!(calls, [TARGET, TARGET]);
+    }
+}
+
+#[test]
+fn test_symbol_mangling() {
+    let compile = |symbol_mangling| {
+        let mut compiler = get_compiler_with(CompilerSettings {
+            symbol_mangling,
+            static_tape: Some(8),
+            ..Default::default()
+        });
+        let ir = vec![
+            node(IrOp::Function("f".to_string(), vec![node(IrOp::WriteByte)])),
+            node(IrOp::FunctionCall("f".to_string())),
+        ];
+        summarize_object(&compile_to_object_bytes(&mut compiler, ir))
+    };
+
+    let mangled = compile(SymbolMangling::LeadingUnderscore);
+    let names = mangled
+        .symbols
+        .iter()
+        .map(|(name, _, _)| name.as_str())
+        .collect::<Vec<_>>();
+    for name in ["__start", "_f", "_write", "___hf_tape"] {
+        assert!(names.contains(&name), "{name} is missing from {names:?}");
+    }
+    for name in ["_start", "f", "write", "__hf_tape"] {
+        assert!(!names.contains(&name), "{name} wasn't mangled");
+    }
+    assert!(mangled
+        .relocations
+        .iter()
+        .all(|(_, name)| name.starts_with('_')));
+    // the call to `f` is resolved within the code, under any name
+    assert_eq!(mangled.text, compile(SymbolMangling::None).text);
+
+    let custom = compile(SymbolMangling::Custom(|name| format!("{name}@0")));
+    assert!(custom.symbols.contains(&("write@0".to_string(), 0, true)));
+}
+
+#[test]
+fn test_verify_encoding() {
+    let code = get_compiler()
+        .compile_to_bytecode(vec![node(IrOp::Add(1)), node(IrOp::MoveRight(2))])
+        .unwrap();
+    assert!(verify_encoding(&code, 64).is_ok());
+
+    let err = verify_encoding(&code[..code.len() - 1], 64).unwrap_err();
+    assert!(matches!(err.kind, CompilerErrorKind::InvalidEncoding(4)));
+
+    // `push es` doesn't exist in 64-bit mode
+    let mut corrupted = code.clone();
+    corrupted[4] = 0x06;
+    let err = verify_encoding(&corrupted, 64).unwrap_err();
+    assert!(matches!(err.kind, CompilerErrorKind::InvalidEncoding(4)));
+}
+
+#[test]
+fn test_verify_cell_pointer() {
+    let summary = summarize_object(&compile_to_object_bytes(
+        &mut get_compiler(),
+        vec![node(IrOp::WriteByte)],
+    ));
+    let mut code = summary.text;
+    // the displacement of `call write` starts after its e8
+    let call = summary.relocations[0].0 as usize - 1;
+    assert!(verify_cell_pointer(&code, 64, iced_x86::Register::R8, &[call]).is_ok());
+
+    // `xor r8d, r8d` after the write
+    let mut zeroed = code.clone();
+    zeroed.extend([0x45, 0x31, 0xc0]);
+    let err = verify_cell_pointer(&zeroed, 64, iced_x86::Register::R8, &[call]).unwrap_err();
+    assert!(matches!(err.kind, CompilerErrorKind::CellPointerClobbered(i) if i == code.len()));
+
+    // only the forms the backend emits may write r8 outside of a save
+    let appended = |bytes: &[u8]| {
+        let mut appended = code.clone();
+        appended.extend_from_slice(bytes);
+        verify_cell_pointer(&appended, 64, iced_x86::Register::R8, &[call])
+    };
+    for bytes in [
+        &[0x49, 0x89, 0xf8][..],                     // mov r8, rdi
+        &[0x4c, 0x8b, 0x05, 0x00, 0x00, 0x00, 0x00], // mov r8, [rip]
+        &[0x4d, 0x8d, 0x40, 0x01],                   // lea r8, [r8 + 1]
+        &[0x49, 0x8d, 0x40, 0x04, 0x50, 0x41, 0x58], // lea rax, [r8 + 4]; push rax; pop r8
+        &[0x41, 0x50, 0x49, 0x89, 0xc0, 0x41, 0x58], // push r8; mov r8, rax; pop r8
+    ] {
+        assert!(appended(bytes).is_ok());
+    }
+    for (bytes, at) in [
+        (&[0x49, 0x89, 0xc0][..], 0), // mov r8, rax
+        (&[0x41, 0x58], 0),           // pop r8
+        (&[0x50, 0x41, 0x58], 1),     // push rax; pop r8
+        (&[0x4d, 0x8b, 0x00], 0),     // mov r8, [r8]
+    ] {
+        let err = appended(bytes).unwrap_err();
+        assert!(
+            matches!(err.kind, CompilerErrorKind::CellPointerClobbered(i) if i == code.len() + at)
+        );
+    }
+
+    // without the save, only a call that preserves r8 is fine
+    for save in [[0x41, 0x50], [0x41, 0x58]] {
+        let index = code.windows(2).position(|bytes| bytes == save).unwrap();
+        code[index..index + 2].copy_from_slice(&[0x66, 0x90]); // nop
+    }
+    let err = verify_cell_pointer(&code, 64, iced_x86::Register::R8, &[call]).unwrap_err();
+    assert!(matches!(err.kind, CompilerErrorKind::CellPointerClobbered(i) if i == call));
+    assert!(verify_cell_pointer(&code, 64, iced_x86::Register::R8, &[]).is_ok());
+}
+
+#[test]
+fn test_verify_callee_saved() {
+    use crate::target::CallingConvention;
+
+    // a C-callable function marshaling the arguments of every kind of external
+    let external = |clobbers| {
+        node(IrOp::ExternalFunctionCall(ExternalCall {
+            store_result: true,
+            status_offset: Some(-1),
+            clobbers,
+            ..ExternalCall::new("ext".to_string())
+        }))
+    };
+    let ir = || {
+        vec![node(IrOp::Function(
+            "f".to_string(),
+            vec![
+                external(Clobbers::ALL),
+                external(Clobbers::NONE),
+                external(Clobbers {
+                    cell_pointer: true,
+                    stack_pointer: false,
+                }),
+                node(IrOp::WriteBytes(3)),
+                node(IrOp::ReadBytes(0x300)),
+                node(IrOp::ReadByte),
+            ],
+        ))]
+    };
+    for calling_convention in [
+        CallingConvention::X86_64_SystemVAMD64,
+        CallingConvention::X86_64_MicrosoftX64,
+    ] {
+        let mut compiler = Compiler::new(
+            64,
+            CompilerSettings {
+                entry_points: vec!["f".to_string()],
+                short_read: ShortRead::Retry,
+                ..Default::default()
+            },
+            calling_convention,
+        );
+        let code = summarize_object(&compile_to_object_bytes(&mut compiler, ir())).text;
+        assert!(verify_callee_saved(&code, 64, &[]).is_ok());
+    }
+
+    // saving a register is fine, anything else isn't
+    assert!(verify_callee_saved(&[0x53, 0x41, 0x54], 64, &[]).is_ok()); // push rbx; push r12
+    for (code, description) in [
+        (&[0x48, 0x89, 0xfb][..], "mov rbx, rdi"),
+        (&[0x41, 0x5c], "pop r12"),
+        (&[0x45, 0x31, 0xff], "xor r15d, r15d"),
+        (&[0x40, 0x88, 0xc5], "mov bpl, al"),
+    ] {
+        let err = verify_callee_saved(&[&[0x90][..], code].concat(), 64, &[]).unwrap_err();
+        assert!(
+            matches!(err.kind, CompilerErrorKind::CalleeSavedClobbered(1)),
+            "{description}"
+        );
+    }
+}
+
+#[test]
+fn test_verify_direction_flag() {
+    const STD: [u8; 1] = [0xfd];
+    const REP_MOVSB: [u8; 2] = [0xf3, 0xa4];
+    const CLD: [u8; 1] = [0xfc];
+    const CALL: [u8; 5] = [0xe8, 0x00, 0x00, 0x00, 0x00];
+    const RET: [u8; 1] = [0xc3];
+
+    // a backward copy has to clear DF again before an external is called
+    let code = [&STD[..], &REP_MOVSB, &CLD, &CALL, &RET].concat();
+    assert!(verify_direction_flag(&code, 64).is_ok());
+    let code = [&STD[..], &REP_MOVSB, &CALL, &CLD, &RET].concat();
+    let err = verify_direction_flag(&code, 64).unwrap_err();
+    assert!(matches!(err.kind, CompilerErrorKind::DirectionFlagSet(3)));
+    // and before returning
+    let code = [&STD[..], &REP_MOVSB, &RET].concat();
+    let err = verify_direction_flag(&code, 64).unwrap_err();
+    assert!(matches!(err.kind, CompilerErrorKind::DirectionFlagSet(3)));
+}
+
+#[test]
+fn test_zero_displacement() {
+    // call rel32 with a displacement, and one cut off at the end of the code
+    let mut code = [0x90, 0xe8, 0x11, 0x22, 0x33, 0x44];
+    zero_displacement(&mut code, 1, 1).unwrap();
+    assert_eq_hex!(code, [0x90, 0xe8, 0x00, 0x00, 0x00, 0x00]);
+
+    let mut code = [0x90, 0xe8, 0x11, 0x22, 0x33];
+    let err = zero_displacement(&mut code, 1, 1).unwrap_err();
+    assert!(matches!(
+        err.kind,
+        CompilerErrorKind::DisplacementOutOfBounds(1)
+    ));
+    assert_eq_hex!(code, [0x90, 0xe8, 0x11, 0x22, 0x33]);
+
+    let err = zero_displacement(&mut code, usize::MAX - 2, 3).unwrap_err();
+    assert!(matches!(
+        err.kind,
+        CompilerErrorKind::DisplacementOutOfBounds(_)
+    ));
+}
+
+#[test]
+fn test_seek_cell() {
+    let ir = |index| {
+        vec![
+            node(IrOp::MoveRight(7)),
+            node(IrOp::SeekCell(index)),
+            node(IrOp::Add(1)),
+        ]
+    };
+    let with_tape = || {
+        get_compiler_with(CompilerSettings {
+            static_tape: Some(0x200),
+            ..Default::default()
+        })
+    };
+
+    let bytes = compile_to_object_bytes(&mut with_tape(), ir(100));
+    let file = object::File::parse(&*bytes).unwrap();
+    let tape = file.symbol_by_name(super::TAPE_SYMBOL).unwrap();
+    let text_section = file.section_by_name(".text").unwrap();
+    assert_eq_hex!(
+        text_section.data().unwrap()[..22],
+        [
+            &[0x4c, 0x8d, 0x05, 0x00, 0x00, 0x00, 0x00][..], // lea r8, [rip + tape]
+            &[0x4d, 0x8d, 0x40, 0x07],                       // lea r8, [r8 + 7]
+            &[0x4c, 0x8d, 0x05, 0x00, 0x00, 0x00, 0x00],     // lea r8, [rip + tape + 100]
+            &[0x41, 0x80, 0x00, 0x01],                       // add byte ptr [r8], 1
+        ]
+        .concat()
+    );
+    let relocations = text_section
+        .relocations()
+        .map(|(offset, relocation)| {
+            assert_eq!(
+                relocation.target(),
+                object::RelocationTarget::Symbol(tape.index())
+            );
+            (offset, relocation.addend())
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(relocations, vec![(3, -4), (14, 100 - 4)]);
+
+    let err = with_tape()
+        .compile_to_object_file(ir(0x200), "test.hf")
+        .unwrap_err();
+    assert!(matches!(
+        err.kind,
+        CompilerErrorKind::SeekOutOfTape {
+            index: 0x200,
+            size: 0x200
+        }
+    ));
+    let err = get_compiler().compile_to_bytecode(ir(100)).unwrap_err();
+    assert!(matches!(
+        err.kind,
+        CompilerErrorKind::RequiresStaticTape("SeekCell")
+    ));
+}
+
+#[test]
+fn test_external_defined_by_program() {
+    let summary = summarize_object(&compile_to_object_bytes(
+        &mut get_compiler(),
+        vec![
+            node(IrOp::Function(
+                "putchar".to_string(),
+                vec![node(IrOp::Add(1))],
+            )),
+            node(IrOp::ExternalFunctionCall(ExternalCall {
+                name: "putchar".to_string(),
+                store_result: true,
+                status_offset: None,
+                clobbers: Clobbers::ALL,
+            })),
+        ],
+    ));
+
+    assert!(summary.relocations.is_empty());
+    assert!(summary
+        .symbols
+        .iter()
+        .all(|(name, _, undefined)| name != "putchar" || !undefined));
+    let (_, start, _) = summary
+        .symbols
+        .iter()
+        .find(|(name, _, _)| name == "_start")
+        .unwrap();
+    let start = *start as usize;
+    // putchar comes first, the entry point calls it like any other function
+    assert_eq_hex!(
+        summary.text[..start + 5],
+        [
+            &[0x48, 0x83, 0xec, 0x08][..],   // sub rsp, 8
+            &[0x41, 0x80, 0x00, 0x01],       // add byte ptr [r8], 1
+            &[0x48, 0x83, 0xc4, 0x08],       // add rsp, 8
+            &[0xc3],                         // ret
+            &[0xe8, 0xee, 0xff, 0xff, 0xff], // call putchar
+        ]
+        .concat()
+    );
+}
+
+#[test]
+fn test_compile_to_artifact() {
+    use crate::target::{Arch, CallingConvention};
+
+    let target = Target::new(Arch::X86_64, CallingConvention::X86_64_MicrosoftX64);
+    let mut compiler = HfCompiler::new(
+        target,
+        CompilerSettings {
+            base_address: 0x1_4000_0000,
+            ..Default::default()
+        },
+    );
+    assert_eq!(compiler.target().arch, Arch::X86_64);
+
+    let artifact = compiler
+        .compile_to_artifact(vec![node(IrOp::Add(1))])
+        .unwrap();
+    assert_eq_hex!(artifact.code, [0x41, 0x80, 0x00, 0x01]);
+    assert_eq!(artifact.target.arch, Arch::X86_64);
+    assert_eq!(
+        artifact.target.calling_convention,
+        CallingConvention::X86_64_MicrosoftX64
+    );
+    assert_eq!(artifact.bitness, 64);
+    assert_eq!(artifact.entry, 0x1_4000_0000);
+}
+
+#[test]
+fn test_compile_within_size() {
+    let ir = || {
+        vec![
+            node(IrOp::MulConst(0)),
+            node(IrOp::Add(5)),
+            node(IrOp::Condition(vec![node(IrOp::Subtract(1))])),
+            node(IrOp::Add(2)),
+        ]
+    };
+    let mut compiler = HfCompiler::new(Target::native(), CompilerSettings::default());
+    assert_eq!(compiler.compile_to_bytecode(ir()).unwrap().len(), 24);
+    // testing at the bottom only gets it down to 22 bytes
+    assert_eq!(compiler.compile_within_size(ir(), 22).unwrap().len(), 22);
+
+    // only clearing the cell directly fits
+    assert_eq_hex!(
+        compiler.compile_within_size(ir(), 16).unwrap(),
+        [
+            &[0x41, 0xc6, 0x00, 0x00][..], // mov byte ptr [r8], 0
+            &[0x41, 0x80, 0x00, 0x05],     // add byte ptr [r8], 5
+            &[0x41, 0xc6, 0x00, 0x00],     // mov byte ptr [r8], 0
+            &[0x41, 0x80, 0x00, 0x02],     // add byte ptr [r8], 2
+        ]
+        .concat()
+    );
+
+    let err = compiler.compile_within_size(ir(), 15).unwrap_err();
+    assert!(matches!(
+        err.kind,
+        CompilerErrorKind::CodeSizeLimitExceeded(15)
+    ));
+
+    // the settings are restored afterwards
+    assert_eq!(compiler.compile_to_bytecode(ir()).unwrap().len(), 24);
+}
+
+#[test]
+fn test_cet() {
+    const ENDBR64: [u8; 4] = [0xf3, 0x0f, 0x1e, 0xfa];
+
+    let settings = |cet| CompilerSettings {
+        cet,
+        entry_points: vec!["g".to_string()],
+        ..Default::default()
+    };
+    let ir = || {
+        vec![
+            node(IrOp::Function("f".to_string(), vec![node(IrOp::Add(1))])),
+            node(IrOp::Function(
+                "g".to_string(),
+                vec![node(IrOp::IndirectCall {
+                    table: vec!["f".to_string()],
+                })],
+            )),
+            node(IrOp::FunctionCall("g".to_string())),
+        ]
+    };
+
+    let bytes = compile_to_object_bytes(&mut get_compiler_with(settings(true)), ir());
+    let file = object::File::parse(&*bytes).unwrap();
+    let text = file.section_by_name(".text").unwrap().data().unwrap();
+    let mut exported = file
+        .symbols()
+        .filter(|s| s.is_global() && s.is_definition() && s.kind() == object::SymbolKind::Text)
+        .map(|s| {
+            let start = s.address() as usize;
+            assert_eq!(text[start..start + 4], ENDBR64, "{:?}", s.name());
+            s.name().unwrap()
+        })
+        .collect::<Vec<_>>();
+    exported.sort();
+    assert_eq!(exported, ["_start", "f", "g"]);
+
+    // GNU_PROPERTY_X86_FEATURE_1_AND with GNU_PROPERTY_X86_FEATURE_1_IBT
+    let note = file.section_by_name(".note.gnu.property").unwrap();
+    let property = [
+        0x02, 0x00, 0x00, 0xc0, 0x04, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00,
+    ];
+    assert!(note
+        .data()
+        .unwrap()
+        .windows(property.len())
+        .any(|window| window == property));
+
+    let bytes = compile_to_object_bytes(&mut get_compiler_with(settings(false)), ir());
+    let file = object::File::parse(&*bytes).unwrap();
+    assert!(file.section_by_name(".note.gnu.property").is_none());
+    let text = file.section_by_name(".text").unwrap().data().unwrap();
+    assert!(!text.windows(4).any(|window| window == ENDBR64));
+
+    // the arms of a jump table, including the default for the values in
+    // between, are reached through it
+    let bytes = get_compiler_with(CompilerSettings {
+        cet: true,
+        ..Default::default()
+    })
+    .compile_to_bytecode(vec![node(IrOp::Switch {
+        arms: (0..4)
+            .map(|value| (value * 2, vec![node(IrOp::Add(1))]))
+            .collect(),
+        default: vec![node(IrOp::Add(2))],
+    })])
+    .unwrap();
+    let endbr64s = bytes.windows(4).filter(|&window| window == ENDBR64).count();
+    assert_eq!(endbr64s, 5);
+}
+
+#[test]
+fn test_dump_scopes() {
+    let mut compiler = HfCompiler::new(Target::native(), CompilerSettings::default());
+    let err = compiler
+        .compile_to_bytecode(vec![
+            node(IrOp::Function(
+                "outer".to_string(),
+                vec![
+                    node(IrOp::Function("inner".to_string(), vec![])),
+                    node(IrOp::FunctionCall("missing".to_string())),
+                ],
+            )),
+            node(IrOp::FunctionCall("outer".to_string())),
+        ])
+        .unwrap_err();
+    assert!(matches!(err.kind, CompilerErrorKind::FunctionNotFound(_)));
+
+    // as they were when the call failed
+    let dump = compiler.dump_scopes();
+    let lines = dump
+        .lines()
+        .map(|line| line.split(':').next().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(lines, ["<global>", "  fn outer", "  outer", "    fn inner"]);
+}
+
+#[test]
+fn test_entry_points() {
+    let mut compiler = get_compiler_with(CompilerSettings {
+        auto_entry: false,
+        entry_points: vec!["fill".to_string()],
+        ..Default::default()
+    });
+    let ir = vec![node(IrOp::Function(
+        "fill".to_string(),
+        vec![node(IrOp::Add(3))],
+    ))];
+    let bytes = compile_to_object_bytes(&mut compiler, ir);
+    let file = object::File::parse(&*bytes).unwrap();
+
+    // the function itself is local, the wrapper is what's exported
+    let fill = file
+        .symbols()
+        .filter(|s| s.name() == Ok("fill"))
+        .map(|s| (s.address(), s.is_global()))
+        .collect::<Vec<_>>();
+    assert!(fill.contains(&(0, false)));
+    assert!(fill.contains(&(13, true)));
+    assert!(!fill.contains(&(0, true)));
+
+    let text_section = file.section_by_name(".text").unwrap();
+    assert_eq_hex!(
+        text_section.data().unwrap(),
+        [
+            &[0x48, 0x83, 0xec, 0x08][..], // sub rsp, 8
+            &[0x41, 0x80, 0x00, 0x03],     // add byte ptr [r8], 3
+            &[0x48, 0x83, 0xc4, 0x08],     // add rsp, 8
+            &[0xc3],                       // ret
+            // fill:
+            &[0x48, 0x83, 0xec, 0x08],       // sub rsp, 8
+            &[0x49, 0x89, 0xf8],             // mov r8, rdi
+            &[0x49, 0x89, 0xf1],             // mov r9, rsi
+            &[0xe8, 0xe4, 0xff, 0xff, 0xff], // call fill
+            &[0x4c, 0x89, 0xc0],             // mov rax, r8
+            &[0x48, 0x83, 0xc4, 0x08],       // add rsp, 8
+            &[0xc3],                         // ret
+        ]
+        .concat()
+    );
+
+    let err = get_compiler_with(CompilerSettings {
+        entry_points: vec!["missing".to_string()],
+        ..Default::default()
+    })
+    .compile_to_object_file(vec![], "test.hf")
+    .unwrap_err();
+    assert!(matches!(err.kind, CompilerErrorKind::FunctionNotFound(_)));
+}
+
+#[test]
+fn test_entry_point_fallbacks() {
+    let mut compiler = get_compiler_with(CompilerSettings {
+        auto_entry: false,
+        entry_points: vec!["fill".to_string()],
+        static_tape: Some(8),
+        stack_overflow: Some(StackOverflow::Trap),
+        ..Default::default()
+    });
+    let ir = vec![node(IrOp::Function(
+        "fill".to_string(),
+        vec![node(IrOp::Add(3))],
+    ))];
+    let summary = summarize_object(&compile_to_object_bytes(&mut compiler, ir));
+
+    // a null cell pointer falls back to the static tape, and the stack
+    // argument is replaced by the managed stack
+    assert_eq_hex!(
+        summary.text[13..],
+        [
+            &[0x48, 0x83, 0xec, 0x08][..],               // sub rsp, 8
+            &[0x48, 0x85, 0xff],                         // test rdi, rdi
+            &[0x75, 0x07],                               // jne have_tape
+            &[0x48, 0x8d, 0x3d, 0x00, 0x00, 0x00, 0x00], // lea rdi, [rip + tape]
+            &[0x49, 0x89, 0xf8],                         // have_tape: mov r8, rdi
+            &[0x4c, 0x8d, 0x0d, 0x00, 0x00, 0x00, 0x00], // lea r9, [rip + stack - 1]
+            &[0xe8, 0xd4, 0xff, 0xff, 0xff],             // call fill
+            &[0x4c, 0x89, 0xc0],                         // mov rax, r8
+            &[0x48, 0x83, 0xc4, 0x08],                   // add rsp, 8
+            &[0xc3],                                     // ret
+        ]
+        .concat()
+    );
+    assert_eq!(
+        summary.relocations,
+        vec![
+            (25, super::TAPE_SYMBOL.to_string()),
+            (35, super::STACK_SYMBOL.to_string()),
+        ]
+    );
+}
+
+#[test]
+fn test_entry_pointers_from_arguments() {
+    let mut compiler = get_compiler_with(CompilerSettings {
+        entry_pointers: EntryPointers::Arguments,
+        entry_exit: EntryExit::Return,
+        ..Default::default()
+    });
+    let summary = summarize_object(&compile_to_object_bytes(
+        &mut compiler,
+        vec![node(IrOp::Add(3))],
+    ));
+
+    assert_eq_hex!(
+        summary.text,
+        [
+            &[0x48, 0x83, 0xec, 0x08][..], // sub rsp, 8
+            &[0x49, 0x89, 0xf8],           // mov r8, rdi
+            &[0x49, 0x89, 0xf1],           // mov r9, rsi
+            &[0x41, 0x80, 0x00, 0x03],     // add byte ptr [r8], 3
+            &[0x4c, 0x89, 0xc0],           // mov rax, r8
+            &[0x48, 0x83, 0xc4, 0x08],     // add rsp, 8
+            &[0xc3],                       // ret
+        ]
+        .concat()
+    );
+    assert!(summary.symbols.contains(&("_start".to_string(), 0, false)));
+}
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+#[test]
+fn test_entry_called_from_c() {
+    // crt1.o has a `_start` of its own
+    fn rename_start(name: &str) -> String {
+        match name {
+            "_start" => "hf_start".to_string(),
+            name => name.to_string(),
+        }
+    }
+
+    let mut compiler = get_compiler_with(CompilerSettings {
+        entry_pointers: EntryPointers::Arguments,
+        entry_exit: EntryExit::Return,
+        symbol_mangling: SymbolMangling::Custom(rename_start),
+        ..Default::default()
+    });
+    let ir = vec![node(IrOp::ExternalFunctionCall(ExternalCall::new(
+        "show".to_string(),
+    )))];
+    let object = compile_to_object_bytes(&mut compiler, ir);
+
+    // printf saves the vector registers with movaps when it is passed a
+    // double, which faults unless it is called with the stack aligned
+    let output = run_with_c_main(
+        &object,
+        r#"
+        #include <stdio.h>
+
+        char *hf_start(char *tape, char *stack);
+
+        void show(char **cell, char **stack) {
+            printf("%s %.1f\n", *cell, 1.5);
+        }
+
+        int main(void) {
+            char tape[16] = "ok";
+            char stack[16];
+            return hf_start(tape, stack - 1) != tape;
+        }
+        "#,
+    );
+    assert_eq!(output, b"ok 1.5\n");
+}
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+#[test]
+fn test_function_called_from_c() {
+    let mut compiler = get_compiler_with(CompilerSettings {
+        auto_entry: false,
+        entry_points: vec!["f".to_string()],
+        ..Default::default()
+    });
+    // the external is called from within a function, so one return address
+    // deeper than from top-level code
+    let ir = vec![node(IrOp::Function(
+        "f".to_string(),
+        vec![node(IrOp::ExternalFunctionCall(ExternalCall::new(
+            "show".to_string(),
+        )))],
+    ))];
+    let object = compile_to_object_bytes(&mut compiler, ir);
+
+    let output = run_with_c_main(
+        &object,
+        r#"
+        #include <stdio.h>
+
+        char *f(char *tape, char *stack);
+
+        void show(char **cell, char **stack) {
+            printf("%s %.1f\n", *cell, 2.5);
+        }
+
+        int main(void) {
+            char tape[16] = "ok";
+            char stack[16];
+            return f(tape, stack - 1) != tape;
+        }
+        "#,
+    );
+    assert_eq!(output, b"ok 2.5\n");
+}
+
+#[test]
+fn test_entry_arguments_need_a_64_bit_convention() {
+    let compile = |settings| {
+        Compiler::new(64, settings, crate::target::CallingConvention::X86_CDeclGcc)
+            .compile_to_object_file(
+                vec![node(IrOp::Function(
+                    "f".to_string(),
+                    vec![node(IrOp::Add(1))],
+                ))],
+                "test.hf",
+            )
+            .unwrap_err()
+    };
+    let err = compile(CompilerSettings {
+        entry_pointers: EntryPointers::Arguments,
+        ..Default::default()
+    });
+    assert!(matches!(
+        err.kind,
+        CompilerErrorKind::UnsupportedOp("`EntryPointers::Arguments`")
+    ));
+    let err = compile(CompilerSettings {
+        entry_points: vec!["f".to_string()],
+        ..Default::default()
+    });
+    assert!(matches!(
+        err.kind,
+        CompilerErrorKind::UnsupportedOp("`entry_points`")
+    ));
+}
+
+#[cfg(feature = "jit")]
+#[test]
+fn test_entry_pointers_from_arguments_on_filled_tape() {
+    let mut compiler = get_compiler_with(CompilerSettings {
+        entry_pointers: EntryPointers::Arguments,
+        entry_exit: EntryExit::Return,
+        optimization_level: 1,
+        ..Default::default()
+    });
+    // +[-->+<] never enters the loop if the host's cell was 255
+    let ir = vec![
+        node(IrOp::Add(1)),
+        node(IrOp::Condition(vec![
+            node(IrOp::Subtract(2)),
+            node(IrOp::MoveRight(1)),
+            node(IrOp::Add(1)),
+            node(IrOp::MoveLeft(1)),
+        ])),
+    ];
+    let summary = summarize_object(&compile_to_object_bytes(&mut compiler, ir));
+    assert!(summary.relocations.is_empty());
+    assert!(summary.symbols.contains(&("_start".to_string(), 0, false)));
+
+    // `_start` is called just like the entry of the JIT
+    let code = super::CallableCode {
+        code: summary.text,
+        entry: 0,
+        external_calls: Vec::new(),
+    };
+    let mut jit = super::jit::JitFunction::new(code, &[], 0, 0).unwrap();
+    let mut tape = [255, 0];
+    assert_eq!(unsafe { jit.call(&mut tape) }.unwrap(), 0);
+    assert_eq!(tape, [0, 0]);
+
+    // nor is a loop at the start dead
+    let clear = vec![node(IrOp::Condition(vec![node(IrOp::Subtract(1))]))];
+    compile_to_object_bytes(&mut compiler, clear);
+    assert!(compiler.warnings().is_empty());
+}
+
+#[test]
+fn test_static_entry_pointers() {
+    let settings = |static_tape, stack_overflow| CompilerSettings {
+        entry_pointers: EntryPointers::Static,
+        static_tape,
+        stack_overflow,
+        stack_size: 0x10,
+        ..Default::default()
+    };
+
+    let mut compiler = get_compiler_with(settings(Some(8), Some(StackOverflow::Trap)));
+    let summary = summarize_object(&compile_to_object_bytes(
+        &mut compiler,
+        vec![node(IrOp::Add(3))],
+    ));
+    assert_eq_hex!(
+        summary.text[..18],
+        [
+            &[0x4c, 0x8d, 0x05, 0x00, 0x00, 0x00, 0x00][..], // lea r8, [rip + tape]
+            &[0x4c, 0x8d, 0x0d, 0x00, 0x00, 0x00, 0x00],     // lea r9, [rip + stack - 1]
+            &[0x41, 0x80, 0x00, 0x03],                       // add byte ptr [r8], 3
+        ]
+        .concat()
+    );
+    assert_eq!(
+        summary.relocations,
+        [
+            (3, super::TAPE_SYMBOL.to_string()),
+            (10, super::STACK_SYMBOL.to_string())
+        ]
+    );
+
+    // nothing to take the pointers from
+    let compile = |static_tape, stack_overflow| {
+        get_compiler_with(settings(static_tape, stack_overflow))
+            .compile_to_object_file(vec![node(IrOp::Add(3))], "test.hf")
+            .unwrap_err()
+    };
+    let err = compile(None, Some(StackOverflow::Trap));
+    assert!(matches!(
+        err.kind,
+        CompilerErrorKind::RequiresStaticTape("_start")
+    ));
+    assert_eq!(err.span, Some(Span::from_location((0, 0))));
+    let err = compile(Some(8), None);
+    assert!(matches!(
+        err.kind,
+        CompilerErrorKind::RequiresStackRegion("_start")
+    ));
+}
+
+#[test]
+fn test_op_stats() {
+    let mut compiler = get_compiler();
+    compiler
+        .compile_to_bytecode(vec![
+            node(IrOp::Add(1)),
+            node(IrOp::Add(300)),
+            node(IrOp::Condition(vec![node(IrOp::MoveRight(1))])),
+        ])
+        .unwrap();
+    let stats = compiler.stats();
+
+    assert_eq!(stats.code_size, 20);
+    let op = |name| stats.ops[name];
+    assert_eq!(op("Add"), OpStats { count: 2, bytes: 8 });
+    // cmp, je and jmp
+    assert_eq!(op("Condition"), OpStats { count: 1, bytes: 8 });
+    assert_eq!(op("MoveRight"), OpStats { count: 1, bytes: 4 });
+    assert_eq!(stats.ops.len(), 3);
+}
+
+#[test]
+fn test_offset_for_span() {
+    let at = |op, column| IrNode {
+        node: op,
+        span: Span::from_location((0, column)),
+    };
+    let mut compiler = get_compiler();
+    let bytes = compiler
+        .compile_to_bytecode(vec![
+            at(IrOp::Add(1), 0),
+            at(IrOp::Nop, 1),
+            at(IrOp::Add(300), 2),
+            at(IrOp::Condition(vec![at(IrOp::MoveRight(1), 4)]), 3),
+        ])
+        .unwrap();
+    let stats = compiler.stats();
+    let offset = |column| stats.offset_for_span(&Span::from_location((0, column)));
+
+    assert_eq!(offset(0), Some(0));
+    // nothing to break on
+    assert_eq!(offset(1), None);
+    assert_eq!(offset(2), Some(4));
+    // the loop starts with its check, its body comes after the je
+    assert_eq!(offset(3), Some(8));
+    assert_eq!(offset(4), Some(14));
+    assert_eq!(offset(5), None);
+
+    let boundaries = iced_x86::Decoder::new(64, &bytes, iced_x86::DecoderOptions::NONE)
+        .into_iter()
+        .map(|instruction| instruction.ip())
+        .collect::<Vec<_>>();
+    for column in [0, 2, 3, 4] {
+        assert!(boundaries.contains(&offset(column).unwrap()));
+    }
+    assert_eq_hex!(bytes[14..18], [0x4d, 0x8d, 0x40, 0x01]); // lea r8, [r8 + 1]
+}
+
+#[test]
+fn test_compile_to_listing() {
+    let src = "++>\n[-]\n.";
+    let at = |op, location, length| IrNode {
+        node: op,
+        span: Span { location, length },
+    };
+    let ir = vec![
+        at(IrOp::Add(2), (0, 0), 2),
+        at(IrOp::MoveRight(1), (0, 2), 1),
+        at(
+            IrOp::Condition(vec![at(IrOp::Subtract(1), (1, 1), 1)]),
+            (1, 0),
+            3,
+        ),
+        at(IrOp::WriteByte, (2, 0), 1),
+    ];
+    let mut compiler = HfCompiler::new(
+        Target::native(),
+        CompilerSettings {
+            base_address: 0x1000,
+            ..Default::default()
+        },
+    );
+    let code = compiler.compile_to_bytecode(ir.clone()).unwrap();
+    let listing = compiler.compile_to_listing(ir, src).unwrap();
+
+    let mut bytes = Vec::new();
+    let mut ranges = Vec::new();
+    let mut sources = Vec::new();
+    for line in listing.lines() {
+        let (address, rest) = line.split_at(17);
+        let (hex, source) = rest[2..].split_at(rest[2..].len().min(23));
+        bytes.extend(
+            hex.split_whitespace()
+                .map(|byte| u8::from_str_radix(byte, 16).unwrap()),
+        );
+        if let Some((start, end)) = address.split_once('-') {
+            let range = (
+                u64::from_str_radix(start, 16).unwrap(),
+                u64::from_str_radix(end, 16).unwrap(),
+            );
+            ranges.push(range);
+            sources.push(source.split_whitespace().collect::<Vec<_>>());
+        }
+    }
+    assert_eq!(bytes, code);
+
+    // the loop gets a line before its body and one after
+    assert_eq!(
+        sources,
+        [
+            ["1:1-1:2", "++"],
+            ["1:3", ">"],
+            ["2:1-2:3", "[-]"],
+            ["2:2", "-"],
+            ["2:1-2:3", "[-]"],
+            ["3:1", "."],
+        ]
+    );
+    // every construct has code, laid out one after the other
+    assert_eq!(ranges[0].0, 0x1000);
+    assert_eq!(ranges.last().unwrap().1, 0x1000 + code.len() as u64);
+    for (i, &(start, end)) in ranges.iter().enumerate() {
+        assert!(start < end);
+        if let Some(&(next, _)) = ranges.get(i + 1) {
+            assert_eq!(end, next);
+        }
+    }
+}
+
+#[test]
+fn test_read_bytes() {
+    let mnemonics = |short_read, n| {
+        let bytes = get_compiler_with(CompilerSettings {
+            short_read,
+            ..Default::default()
+        })
+        .compile_to_bytecode(vec![node(IrOp::ReadBytes(n))])
+        .unwrap();
+        iced_x86::Decoder::new(64, &bytes, iced_x86::DecoderOptions::NONE)
+            .into_iter()
+            .map(|instruction| instruction.mnemonic())
+            .collect::<Vec<_>>()
+    };
+    let count = |mnemonics: &[iced_x86::Mnemonic], mnemonic| {
+        mnemonics.iter().filter(|&&other| other == mnemonic).count()
+    };
+
+    // a single read, however much it returns
+    let accept = mnemonics(ShortRead::Accept, 4);
+    assert_eq!(count(&accept, iced_x86::Mnemonic::Call), 1);
+    assert_eq!(count(&accept, iced_x86::Mnemonic::Jb), 0);
+    assert_eq!(count(&accept, iced_x86::Mnemonic::Cmova), 0);
+
+    // retrying loops back to the same call
+    let retry = mnemonics(ShortRead::Retry, 4);
+    assert_eq!(count(&retry, iced_x86::Mnemonic::Call), 1);
+    assert_eq!(count(&retry, iced_x86::Mnemonic::Jb), 1);
+
+    // 300 bytes don't fit in a cell, so the count saturates
+    let long = mnemonics(ShortRead::Accept, 300);
+    assert_eq!(count(&long, iced_x86::Mnemonic::Cmova), 1);
+
+    let error = get_compiler()
+        .compile_to_bytecode(vec![node(IrOp::ReadBytes(u32::MAX))])
+        .unwrap_err();
+    assert!(matches!(error.kind, CompilerErrorKind::IoTooLarge(..)));
+
+    // what is on the stack stays clear of the shadow space of `read`
+    let bytes = Compiler::new(
+        64,
+        CompilerSettings::default(),
+        crate::target::CallingConvention::X86_64_MicrosoftX64,
+    )
+    .compile_to_bytecode(vec![node(IrOp::ReadBytes(4))])
+    .unwrap();
+    let call = bytes.iter().position(|&b| b == 0xe8).unwrap();
+    assert_eq_hex!(
+        [&bytes[..=call], &bytes[call + 5..call + 11]].concat(),
+        vec![
+            0x49, 0x8d, 0x40, 0x04, // lea rax, [r8 + 4]
+            0x50, // push rax
+            0x41, 0x51, // push r9
+            0x41, 0x50, // push r8
+            0x48, 0x83, 0xec, 0x28, // sub rsp, 0x28
+            0xb9, 0x00, 0x00, 0x00, 0x00, // mov ecx, 0
+            0x4c, 0x89, 0xc2, // mov rdx, r8
+            0x4c, 0x8b, 0x44, 0x24, 0x38, // mov r8, [rsp + 0x38]
+            0x49, 0x29, 0xd0, // sub r8, rdx
+            0xe8, // call read
+            0x48, 0x83, 0xc4, 0x28, // add rsp, 0x28
+            0x41, 0x58, // pop r8
+        ]
+    );
+}
+
+#[test]
+fn test_unsupported_op() {
+    use crate::target::{Arch, CallingConvention};
+
+    let ir = vec![
+        node(IrOp::Add(1)),
+        IrNode {
+            node: IrOp::MemAlloc(16),
+            span: Span::from_location((0, 3)),
+        },
+    ];
+    let err = get_compiler().compile_to_bytecode(ir).unwrap_err();
+    assert!(matches!(
+        err.kind,
+        CompilerErrorKind::UnsupportedOp("MemAlloc")
+    ));
+    assert_eq!(err.span, Some(Span::from_location((0, 3))));
+
+    // 32-bit targets have no convention to pass the pointers of an external
+    let target = Target::new(Arch::X86, CallingConvention::X86_CDeclGcc);
+    let err = HfCompiler::new(target, CompilerSettings::default())
+        .compile_to_bytecode(vec![IrNode {
+            node: IrOp::ExternalFunctionCall(ExternalCall::new("putchar".to_string())),
+            span: Span::from_location((1, 2)),
+        }])
+        .unwrap_err();
+    assert!(matches!(
+        err.kind,
+        CompilerErrorKind::UnsupportedOp("ExternalFunctionCall")
+    ));
+    assert_eq!(err.span, Some(Span::from_location((1, 2))));
+}
+
+#[cfg(feature = "disasm")]
+#[test]
+fn test_compile_and_format() {
+    use super::compile_and_format;
+
+    let ir = || {
+        vec![
+            node(IrOp::Function(
+                "f".to_string(),
+                vec![node(IrOp::ExternalFunctionCall(ExternalCall {
+                    clobbers: Clobbers::NONE,
+                    ..ExternalCall::new("zeta".to_string())
+                }))],
+            )),
+            node(IrOp::Condition(vec![
+                node(IrOp::Subtract(1)),
+                node(IrOp::FunctionCall("f".to_string())),
+            ])),
+            node(IrOp::DebugMarker("done".to_string())),
+            node(IrOp::ExternalFunctionCall(ExternalCall {
+                clobbers: Clobbers::NONE,
+                ..ExternalCall::new("alpha".to_string())
+            })),
+            node(IrOp::IndirectCall {
+                table: vec!["f".to_string()],
+            }),
+        ]
+    };
+
+    let text = compile_and_format(ir(), CompilerSettings::default()).unwrap();
+    for _ in 0..8 {
+        assert_eq!(
+            compile_and_format(ir(), CompilerSettings::default()).unwrap(),
+            text
+        );
+    }
+    assert_eq!(
+        text,
+        "\
+; externals: alpha, zeta
+f:
+    sub rsp, 8
+    mov rdi, r8
+    mov rsi, r9
+    call zeta
+    add rsp, 8
+    ret
+.L0:
+    cmp byte ptr [r8], 0
+    je done
+    sub byte ptr [r8], 1
+    call f
+    jmp .L0
+done:
+    mov rdi, r8
+    mov rsi, r9
+    call alpha
+    movzx eax, byte ptr [r8]
+    cmp eax, 1
+    jae .L1
+    lea rcx, [.L1]
+    call qword ptr [rcx + rax*8]
+.L1:
+    dq f
+"
+    );
+}
+
+#[cfg(feature = "disasm")]
+#[test]
+fn test_ir_comments() {
+    use super::compile_and_format;
+
+    let settings = |ir_comments| CompilerSettings {
+        ir_comments,
+        ..Default::default()
+    };
+    let text = compile_and_format(compile_to_ir("+++[-]"), settings(true)).unwrap();
+    assert!(text.contains("    ; Add(3)\n    add byte ptr [r8], 3\n"));
+    assert!(text.contains("    ; Subtract(1)\n    sub byte ptr [r8], 1\n"));
+    // once for the code before the body of the loop, once for the code after
+    assert_eq!(text.matches("; Condition\n").count(), 2);
+
+    // the same code without the comments
+    let plain = compile_and_format(compile_to_ir("+++[-]"), settings(false)).unwrap();
+    assert_eq!(
+        text.lines()
+            .filter(|line| !line.trim_start().starts_with(';'))
+            .collect::<Vec<_>>(),
+        plain.lines().collect::<Vec<_>>()
+    );
+}
+
+#[cfg(feature = "btree-maps")]
+#[test]
+fn test_output_is_stable() {
+    let externals = ["zeta", "alpha", "mu"].map(String::from);
+    let compile = || {
+        let ir = vec![
+            node(IrOp::Function(
+                "g".to_string(),
+                externals
+                    .iter()
+                    .map(|name| node(IrOp::FunctionCall(name.clone())))
+                    .collect(),
+            )),
+            node(IrOp::Function("f".to_string(), vec![])),
+            node(IrOp::FunctionCall("g".to_string())),
+        ];
+        get_compiler()
+            .compile_to_object_file_with_externals(ir, "test.hf", &externals)
+            .unwrap()
+            .write()
+            .unwrap()
+    };
+
+    let bytes = compile();
+    for _ in 0..8 {
+        assert_eq!(compile(), bytes);
+    }
+    // the externals are added in order of their names
+    let file = object::File::parse(&*bytes).unwrap();
+    let undefined = file
+        .symbols()
+        .filter(|symbol| symbol.is_undefined())
+        .map(|symbol| symbol.name().unwrap().to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(undefined, ["alpha", "mu", "zeta"]);
+}
+
+#[test]
+fn test_large_moves() {
+    let bytes = get_compiler()
+        .compile_to_bytecode(vec![
+            node(IrOp::MoveRight(0x7FFF_FFFF)),
+            node(IrOp::MoveRight(0x1_0000_0000)),
+            node(IrOp::MoveLeft(0x8000_0000)),
+        ])
+        .unwrap();
+    assert_eq_hex!(
+        bytes,
+        [
+            0x4d, 0x8d, 0x80, 0xff, 0xff, 0xff, 0x7f, // lea r8, [r8 + 0x7fffffff]
+            0x48, 0xb8, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00,
+            0x00, // mov rax, 0x100000000
+            0x49, 0x01, 0xc0, // add r8, rax
+            0x48, 0xb8, 0x00, 0x00, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00, // mov rax, 0x80000000
+            0x49, 0x29, 0xc0, // sub r8, rax
+        ]
+    );
+
+    // the displacement of 16-bit code is only 16 bits
+    let mut compiler = Compiler::new(
+        16,
+        CompilerSettings::default(),
+        Target::native().calling_convention,
+    );
+    let err = compiler
+        .compile_to_bytecode(vec![node(IrOp::MoveRight(0x1_0000))])
+        .unwrap_err();
+    assert!(matches!(
+        err.kind,
+        CompilerErrorKind::MoveTooLarge {
+            count: 0x1_0000,
+            limit: 0x7FFF
+        }
+    ));
+}
+
+#[test]
+fn test_top_level_spans() {
+    let at = |op, location| IrNode {
+        node: op,
+        span: Span::from_location(location),
+    };
+    let ir = || {
+        vec![
+            at(
+                IrOp::Function("f".to_string(), vec![node(IrOp::Add(1))]),
+                (0, 0),
+            ),
+            at(IrOp::Add(1), (2, 3)),
+            at(IrOp::WriteBytes(0x1_0000_0000), (4, 1)),
+        ]
+    };
+
+    let err = get_compiler()
+        .compile_to_object_file(ir(), "test.hf")
+        .unwrap_err();
+    assert!(matches!(err.kind, CompilerErrorKind::IoTooLarge(_)));
+    assert_eq!(err.span, Some(Span::from_location((4, 1))));
+
+    // the code of `_start` itself is attributed to the first top-level node
+    let mut ir = ir();
+    ir.pop();
+    let plan = HfCompiler::new(Target::native(), CompilerSettings::default())
+        .plan(ir)
+        .unwrap();
+    let start = plan
+        .nodes
+        .iter()
+        .filter(|node| node.op == "Function")
+        .map(|node| node.span)
+        .collect::<Vec<_>>();
+    assert_eq!(start.last(), Some(&Span::from_location((2, 3))));
+}
+
+#[test]
+fn test_plan() {
+    let ir = || {
+        vec![
+            node(IrOp::Condition(vec![node(IrOp::Subtract(1))])),
+            // never runs
+            node(IrOp::Condition(vec![node(IrOp::Add(1))])),
+            node(IrOp::Function(
+                "f".to_string(),
+                vec![node(IrOp::WriteByte), node(IrOp::ReadByte)],
+            )),
+            node(IrOp::Add(2)),
+            node(IrOp::Condition(vec![
+                node(IrOp::FunctionCall("f".to_string())),
+                node(IrOp::WriteByte),
+                node(IrOp::Subtract(2)),
+            ])),
+            node(IrOp::ExternalFunctionCall(ExternalCall::new(
+                "putchar".to_string(),
+            ))),
+        ]
+    };
+    let mut compiler = HfCompiler::new(Target::native(), CompilerSettings::default());
+    let plan = compiler.plan(ir()).unwrap();
+    let bytes = compiler
+        .compile_to_object_file(ir(), "test.hf")
+        .unwrap()
+        .write()
+        .unwrap();
+    let summary = summarize_object(&bytes);
+
+    // every planned call is a relocation in the object file
+    let mut relocations = Map::<String, usize>::new();
+    for (_, name) in &summary.relocations {
+        *relocations.entry(name.clone()).or_default() += 1;
+    }
+    let mut relocations = relocations.into_iter().collect::<Vec<_>>();
+    relocations.sort();
+    assert_eq!(plan.external_calls, relocations);
+    assert_eq!(
+        plan.external_calls,
+        [
+            ("putchar".to_string(), 1),
+            ("read".to_string(), 1),
+            ("write".to_string(), 2)
+        ]
+    );
+
+    assert_eq!(plan.functions, ["f", "_start"]);
+    assert!(plan.max_code_size >= summary.text.len());
+    assert_eq!(
+        plan.max_code_size,
+        plan.nodes.iter().map(|node| node.max_size).sum::<usize>()
+    );
+    assert_eq!(plan.warnings, compiler.warnings());
+    assert_eq!(plan.warnings[0].kind, CompilerWarningKind::DeadLoop);
+
+    // the add is the only instruction of its node, and its size is exact
+    let add = plan.nodes.iter().find(|node| node.op == "Add").unwrap();
+    assert_eq!(add.instructions.len(), 1);
+    assert_eq!(add.max_size, 4);
+}
+
+#[test]
+fn test_add_from_cell() {
+    let bytes = get_compiler()
+        .compile_to_bytecode(vec![
+            node(IrOp::AddFromCell { src_offset: -1 }),
+            node(IrOp::AddFromCell { src_offset: 0x100 }),
+        ])
+        .unwrap();
+    assert_eq_hex!(
+        bytes,
+        [
+            &[0x41, 0x8a, 0x40, 0xff][..], // mov al, byte ptr [r8 - 1]
+            &[0x41, 0x00, 0x00],           // add byte ptr [r8], al
+            &[0x41, 0x8a, 0x80, 0x00, 0x01, 0x00, 0x00], // mov al, byte ptr [r8 + 0x100]
+            &[0x41, 0x00, 0x00],           // add byte ptr [r8], al
+        ]
+        .concat()
+    );
+}
+
+#[test]
+fn test_if_eq() {
+    let bytes = get_compiler()
+        .compile_to_bytecode(vec![node(IrOp::IfEq {
+            value: 7,
+            body: vec![node(IrOp::Add(1)), node(IrOp::WriteByte)],
+        })])
+        .unwrap();
+
+    let mut decoder = iced_x86::Decoder::new(64, &bytes, iced_x86::DecoderOptions::NONE);
+    let cmp = decoder.decode();
+    assert_eq!(&bytes[..4], [0x41, 0x80, 0x38, 0x07]); // cmp byte ptr [r8], 7
+    assert_eq!(cmp.mnemonic(), iced_x86::Mnemonic::Cmp);
+    let jne = decoder.decode();
+    assert_eq!(jne.mnemonic(), iced_x86::Mnemonic::Jne);
+    // the body comes next, and the jump skips all of it
+    assert_eq!(&bytes[6..10], [0x41, 0x80, 0x00, 0x01]); // add byte ptr [r8], 1
+    assert_eq!(jne.near_branch_target(), bytes.len() as u64);
+}
+
+#[test]
+fn test_deeply_nested_loops() {
+    extern crate std;
+    const DEPTH: usize = 1000;
+
+    // lowering recurses once per loop, which takes more than the default
+    // stack of a test thread in debug builds
+    let bytes = std::thread::Builder::new()
+        .stack_size(256 << 20)
+        .spawn(|| {
+            let mut ir = vec![node(IrOp::Add(1))];
+            for _ in 0..DEPTH {
+                ir = vec![node(IrOp::Condition(ir))];
+            }
+            get_compiler().compile_to_bytecode(ir).unwrap()
+        })
+        .unwrap()
+        .join()
+        .unwrap();
+
+    // each loop tests the cell once on the way in and jumps back unconditionally
+    let mut decoder = iced_x86::Decoder::new(64, &bytes, iced_x86::DecoderOptions::NONE);
+    let tests = decoder
+        .iter()
+        .filter(|instruction| instruction.mnemonic() == iced_x86::Mnemonic::Je)
+        .count();
+    assert_eq!(tests, DEPTH);
+}
+
+/// Follows the dispatch of the `Switch` at the start of `code`, loaded at
+/// `base`, with the cell at `value`, until it reaches one of `markers`, and
+/// returns its name.
+fn follow_switch(code: &[u8], base: u64, markers: &[(String, usize)], value: u8) -> String {
+    use iced_x86::{Mnemonic, OpKind};
+
+    let (mut eax, mut rcx, mut flag) = (0, 0, false);
+    let mut ip = base;
+    loop {
+        let offset = (ip - base) as usize;
+        if let Some((name, _)) = markers.iter().find(|(_, marker)| *marker == offset) {
+            return name.clone();
+        }
+        let instruction =
+            iced_x86::Decoder::with_ip(64, &code[offset..], ip, iced_x86::DecoderOptions::NONE)
+                .decode();
+        ip = instruction.next_ip();
+        match (instruction.mnemonic(), instruction.op0_kind()) {
+            (Mnemonic::Movzx, _) => eax = value as u64,
+            (Mnemonic::Sub, _) => eax = (eax as u32).wrapping_sub(instruction.immediate32()) as u64,
+            (Mnemonic::Cmp, OpKind::Register) => flag = eax >= instruction.immediate(1),
+            (Mnemonic::Cmp, _) => flag = value as u64 != instruction.immediate(1),
+            (Mnemonic::Lea, _) => rcx = instruction.ip_rel_memory_address(),
+            (Mnemonic::Jmp, OpKind::Memory) => {
+                let entry = (rcx - base) as usize + eax as usize * 8;
+                ip = u64::from_le_bytes(code[entry..entry + 8].try_into().unwrap());
+            }
+            // a position-independent table, see `CompilerSettings::pic`
+            (Mnemonic::Movsxd, _) => {
+                let entry = (rcx - base) as usize + eax as usize * 4;
+                eax = i32::from_le_bytes(code[entry..entry + 4].try_into().unwrap()) as u64;
+            }
+            (Mnemonic::Add, _) => eax = eax.wrapping_add(rcx),
+            (Mnemonic::Jmp, OpKind::Register) => ip = eax,
+            (Mnemonic::Jae | Mnemonic::Jne, _) if flag => ip = instruction.near_branch_target(),
+            (Mnemonic::Jmp, _) => ip = instruction.near_branch_target(),
+            (Mnemonic::Jae | Mnemonic::Jne, _) => {}
+            (mnemonic, _) => panic!("unexpected {mnemonic:?} at {offset:#x}"),
+        }
+    }
+}
+
+#[test]
+fn test_switch() {
+    let arm = |value, name: &str| (value, vec![node(IrOp::DebugMarker(name.to_string()))]);
+    let switch = |arms| {
+        vec![node(IrOp::Switch {
+            arms,
+            default: vec![node(IrOp::DebugMarker("default".to_string()))],
+        })]
+    };
+    let settings = CompilerSettings {
+        base_address: 0x1000,
+        ..Default::default()
+    };
+
+    // dense, with a hole at 4 and a second arm for 2 that never runs
+    let mut compiler = get_compiler_with(settings.clone());
+    let code = compiler
+        .compile_to_bytecode(switch(vec![
+            arm(1, "one"),
+            arm(2, "two"),
+            arm(3, "three"),
+            arm(5, "five"),
+            arm(2, "two again"),
+        ]))
+        .unwrap();
+    let markers = &compiler.stats().markers;
+    assert_eq_hex!(
+        code[..15],
+        [
+            &[0x41, 0x0f, 0xb6, 0x00][..],   // movzx eax, byte ptr [r8]
+            &[0x2d, 0x01, 0x00, 0x00, 0x00], // sub eax, 1
+            &[0x3d, 0x05, 0x00, 0x00, 0x00], // cmp eax, 5
+            &[0x73],                         // jae default
+        ]
+        .concat()
+    );
+    // one entry for each value from 1 to 5, at the end
+    assert_eq!(compiler.stats().absolute_references.len(), 5);
+    assert_eq!(
+        compiler.stats().absolute_references[0].offset,
+        code.len() - 5 * 8
+    );
+    for (value, name) in [
+        (0, "default"),
+        (1, "one"),
+        (2, "two"),
+        (3, "three"),
+        (4, "default"),
+        (5, "five"),
+        (6, "default"),
+        (0xff, "default"),
+    ] {
+        assert_eq!(follow_switch(&code, 0x1000, markers, value), name);
+    }
+
+    // sparse, compared one by one
+    let mut compiler = get_compiler_with(settings);
+    let code = compiler
+        .compile_to_bytecode(switch(vec![
+            arm(0x10, "low"),
+            arm(0x80, "middle"),
+            arm(3, "lowest"),
+            arm(0xff, "high"),
+        ]))
+        .unwrap();
+    let markers = &compiler.stats().markers;
+    assert_eq_hex!(
+        code[..5],
+        [
+            &[0x41, 0x80, 0x38, 0x10][..], // cmp byte ptr [r8], 0x10
+            &[0x75],                       // jne next
+        ]
+        .concat()
+    );
+    assert!(compiler.stats().is_position_independent());
+    for (value, name) in [
+        (0, "default"),
+        (3, "lowest"),
+        (0x10, "low"),
+        (0x80, "middle"),
+        (0x81, "default"),
+        (0xff, "high"),
+    ] {
+        assert_eq!(follow_switch(&code, 0x1000, markers, value), name);
+    }
+}
+
+#[test]
+fn test_switch_table_in_rodata() {
+    use object::{RelocationKind, RelocationTarget};
+
+    let arm = |value, name: &str| (value, vec![node(IrOp::DebugMarker(name.to_string()))]);
+    let mut compiler = get_compiler_with(CompilerSettings {
+        auto_entry: false,
+        ..Default::default()
+    });
+    let bytes = compile_to_object_bytes(
+        &mut compiler,
+        vec![node(IrOp::Function(
+            "dispatch".to_string(),
+            vec![node(IrOp::Switch {
+                arms: vec![
+                    arm(1, "one"),
+                    arm(2, "two"),
+                    arm(3, "three"),
+                    arm(5, "five"),
+                ],
+                default: vec![node(IrOp::DebugMarker("default".to_string()))],
+            })],
+        ))],
+    );
+    let file = object::File::parse(&*bytes).unwrap();
+    let text = file.section_by_name(".text").unwrap();
+    let rodata = file.section_by_name(".rodata").unwrap();
+    assert_eq!(rodata.kind(), object::SectionKind::ReadOnlyData);
+    assert_eq!(rodata.size(), 5 * 8);
+    // the code only refers to the table relative to itself
+    assert!(text
+        .relocations()
+        .all(|(_, relocation)| relocation.kind() == RelocationKind::Relative));
+
+    // link it like a linker would, with `.rodata` some way after `.text`
+    let base = 0x40_1000;
+    let rodata_start = (text.size() as usize).next_multiple_of(0x100) + 0x100;
+    let mut image = text.data().unwrap().to_vec();
+    image.resize(rodata_start, 0xcc);
+    image.extend_from_slice(rodata.data().unwrap());
+    let section_start = |index| match index {
+        index if index == text.index() => 0,
+        index if index == rodata.index() => rodata_start,
+        _ => panic!("relocation against another section"),
+    };
+    for section in [&text, &rodata] {
+        for (offset, relocation) in section.relocations() {
+            let RelocationTarget::Symbol(symbol) = relocation.target() else {
+                panic!("relocation against something other than a symbol");
+            };
+            let symbol = file.symbol_by_index(symbol).unwrap();
+            let target = base
+                + (section_start(symbol.section_index().unwrap()) as u64 + symbol.address())
+                    .wrapping_add(relocation.addend() as u64);
+            let place = section_start(section.index()) + offset as usize;
+            match relocation.kind() {
+                RelocationKind::Relative => {
+                    let value = target.wrapping_sub(base + place as u64) as i32;
+                    image[place..place + 4].copy_from_slice(&value.to_le_bytes());
+                }
+                RelocationKind::Absolute => {
+                    image[place..place + 8].copy_from_slice(&target.to_le_bytes());
+                }
+                kind => panic!("unexpected {kind:?}"),
+            }
+        }
+    }
+
+    let markers = &compiler.stats().markers;
+    for (value, name) in [(0, "default"), (1, "one"), (4, "default"), (5, "five")] {
+        assert_eq!(follow_switch(&image, base, markers, value), name);
+    }
+}
+
+#[test]
+fn test_pic_jump_tables() {
+    use object::{RelocationKind, RelocationTarget};
+
+    let arm = |value, name: &str| (value, vec![node(IrOp::DebugMarker(name.to_string()))]);
+    let switch = || {
+        node(IrOp::Switch {
+            arms: vec![
+                arm(1, "one"),
+                arm(2, "two"),
+                arm(3, "three"),
+                arm(5, "five"),
+            ],
+            default: vec![node(IrOp::DebugMarker("default".to_string()))],
+        })
+    };
+    let settings = CompilerSettings {
+        pic: true,
+        ..Default::default()
+    };
+    let cases = [
+        (0, "default"),
+        (1, "one"),
+        (3, "three"),
+        (4, "default"),
+        (5, "five"),
+        (0xff, "default"),
+    ];
+    // nowhere near where executables are usually loaded
+    let base = 0x7f3a_5c21_3000;
+
+    let mut compiler = get_compiler_with(CompilerSettings {
+        auto_entry: false,
+        ..settings.clone()
+    });
+    let bytes = compile_to_object_bytes(
+        &mut compiler,
+        vec![node(IrOp::Function("dispatch".to_string(), vec![switch()]))],
+    );
+    assert!(compiler.stats().is_position_independent());
+    let file = object::File::parse(&*bytes).unwrap();
+    let text = file.section_by_name(".text").unwrap();
+    let rodata = file.section_by_name(".rodata").unwrap();
+    // a 32-bit offset for each value from 1 to 5
+    assert_eq!(rodata.size(), 5 * 4);
+    for section in [&text, &rodata] {
+        assert!(section.relocations().all(|(_, relocation)| {
+            relocation.kind() == RelocationKind::Relative && relocation.size() == 32
+        }));
+    }
+
+    // link it with `.rodata` at an odd distance from `.text`
+    let rodata_start = text.size() as usize + 0x1234;
+    let mut image = text.data().unwrap().to_vec();
+    image.resize(rodata_start, 0xcc);
+    image.extend_from_slice(rodata.data().unwrap());
+    let section_start = |index| match index {
+        index if index == text.index() => 0,
+        index if index == rodata.index() => rodata_start,
+        _ => panic!("relocation against another section"),
+    };
+    for section in [&text, &rodata] {
+        for (offset, relocation) in section.relocations() {
+            let RelocationTarget::Symbol(symbol) = relocation.target() else {
+                panic!("relocation against something other than a symbol");
+            };
+            let symbol = file.symbol_by_index(symbol).unwrap();
+            let target = (section_start(symbol.section_index().unwrap()) as u64 + symbol.address())
+                .wrapping_add(relocation.addend() as u64);
+            let place = section_start(section.index()) + offset as usize;
+            let value = target.wrapping_sub(place as u64) as i32;
+            image[place..place + 4].copy_from_slice(&value.to_le_bytes());
+        }
+    }
+    let markers = &compiler.stats().markers;
+    for (value, name) in cases {
+        assert_eq!(follow_switch(&image, base, markers, value), name);
+    }
+
+    // bytecode runs wherever it is copied to, whatever its base address
+    let mut compiler = get_compiler_with(settings);
+    let code = compiler.compile_to_bytecode(vec![switch()]).unwrap();
+    assert!(compiler.stats().is_position_independent());
+    let markers = &compiler.stats().markers;
+    for (value, name) in cases {
+        assert_eq!(follow_switch(&code, base, markers, value), name);
+    }
+}
+
+#[test]
+fn test_move_loops() {
+    // +++[->+<]
+    let ir = vec![
+        node(IrOp::Add(3)),
+        node(IrOp::Condition(vec![
+            node(IrOp::Subtract(1)),
+            node(IrOp::MoveRight(1)),
+            node(IrOp::Add(1)),
+            node(IrOp::MoveLeft(1)),
+        ])),
+    ];
+    let bytes = get_compiler_with(CompilerSettings {
+        optimization_level: 2,
+        ..Default::default()
+    })
+    .compile_to_bytecode(ir)
+    .unwrap();
+
+    assert_eq_hex!(
+        bytes,
+        [
+            &[0x41, 0x80, 0x00, 0x03][..], // add byte ptr [r8], 3
+            &[0x41, 0x8a, 0x00],           // mov al, byte ptr [r8]
+            &[0x41, 0x00, 0x40, 0x01],     // add byte ptr [r8 + 1], al
+            &[0x41, 0xc6, 0x00, 0x00],     // mov byte ptr [r8], 0
+        ]
+        .concat()
+    );
+}
+
+#[test]
+fn test_vector_adds() {
+    // +>+>+ … over 32 cells, then back
+    let ir = || {
+        let mut ir = Vec::new();
+        for _ in 0..32 {
+            ir.push(node(IrOp::Add(3)));
+            ir.push(node(IrOp::MoveRight(1)));
+        }
+        ir.push(node(IrOp::MoveLeft(32)));
+        ir
+    };
+    let mnemonics = |sse2| {
+        let bytes = get_compiler_with(CompilerSettings {
+            optimization_level: 2,
+            sse2,
+            ..Default::default()
+        })
+        .compile_to_bytecode(ir())
+        .unwrap();
+        iced_x86::Decoder::new(64, &bytes, iced_x86::DecoderOptions::NONE)
+            .into_iter()
+            .map(|instruction| instruction.mnemonic())
+            .collect::<Vec<_>>()
+    };
+
+    use iced_x86::Mnemonic::*;
+    assert_eq!(
+        mnemonics(true),
+        [Mov, Movd, Pshufd, Movdqu, Paddb, Movdqu, Movdqu, Paddb, Movdqu]
+    );
+    // without SSE2, every cell is added to on its own
+    assert_eq!(mnemonics(false), [Add; 32]);
+}
+
+#[test]
+fn test_incremental_compilation() {
+    let at = |line, op| IrNode {
+        node: op,
+        span: Span::from_location((line, 0)),
+    };
+    // `line` moves every node, as an edit above them would
+    let program = |line: usize, body: usize| {
+        vec![
+            at(
+                line,
+                IrOp::Function("a".to_string(), vec![at(line + 1, IrOp::Add(1))]),
+            ),
+            at(
+                line + 2,
+                IrOp::Function("b".to_string(), vec![at(line + 3, IrOp::Add(body))]),
+            ),
+            at(
+                line + 4,
+                IrOp::Function(
+                    "c".to_string(),
+                    vec![at(line + 5, IrOp::FunctionCall("a".to_string()))],
+                ),
+            ),
+            at(line + 6, IrOp::FunctionCall("c".to_string())),
+            at(line + 7, IrOp::MoveRight(1)),
+            at(line + 8, IrOp::FunctionCall("b".to_string())),
+        ]
+    };
+    let mut compiler = get_compiler();
+
+    let code = compiler.compile_incremental(program(0, 2)).unwrap();
+    assert_eq_hex!(
+        code,
+        get_compiler().compile_to_bytecode(program(0, 2)).unwrap()
+    );
+    assert!(compiler.stats().reused_functions.is_empty());
+
+    let code = compiler.compile_incremental(program(10, 3)).unwrap();
+    assert_eq_hex!(
+        code,
+        get_compiler().compile_to_bytecode(program(10, 3)).unwrap()
+    );
+    assert_eq!(compiler.stats().reused_functions, ["a", "c"]);
+    // the spans of the reused code are those of the new program
+    assert!(compiler
+        .stats()
+        .spans
+        .iter()
+        .all(|(span, _)| span.location.0 >= 10));
+
+    // a function that is gone takes the code calling it along
+    let mut ir = program(0, 3);
+    ir.remove(0);
+    let err = compiler.compile_incremental(ir).unwrap_err();
+    assert!(matches!(err.kind, CompilerErrorKind::FunctionNotFound(ref name) if name == "a"));
+
+    // changing the settings drops the cache
+    compiler.compile_incremental(program(0, 3)).unwrap();
+    compiler.settings_mut().optimization_level = 0;
+    compiler.compile_incremental(program(0, 3)).unwrap();
+    assert!(compiler.stats().reused_functions.is_empty());
+}
+
+#[test]
+fn test_incremental_indirect_call() {
+    let program = |body: usize| {
+        vec![
+            node(IrOp::Function(
+                "zero".to_string(),
+                vec![node(IrOp::Add(body))],
+            )),
+            node(IrOp::Function("one".to_string(), vec![node(IrOp::Add(2))])),
+            node(IrOp::IndirectCall {
+                table: vec!["zero".to_string(), "one".to_string()],
+            }),
+        ]
+    };
+    let mut compiler = get_compiler_with(CompilerSettings {
+        base_address: 0x1000,
+        ..Default::default()
+    });
+    compiler.compile_incremental(program(1)).unwrap();
+    let code = compiler.compile_incremental(program(3)).unwrap();
+    assert_eq!(compiler.stats().reused_functions, ["one"]);
+
+    // the table follows the code of the `IndirectCall`
+    assert_eq_hex!(
+        code,
+        [
+            // zero:
+            &[0x48, 0x83, 0xec, 0x08][..],   // sub rsp, 8
+            &[0x41, 0x80, 0x00, 0x03],       // add byte ptr [r8], 3
+            &[0x48, 0x83, 0xc4, 0x08, 0xc3], // add rsp, 8; ret
+            // one:
+            &[0x48, 0x83, 0xec, 0x08],                   // sub rsp, 8
+            &[0x41, 0x80, 0x00, 0x02],                   // add byte ptr [r8], 2
+            &[0x48, 0x83, 0xc4, 0x08, 0xc3],             // add rsp, 8; ret
+            &[0x41, 0x0f, 0xb6, 0x00],                   // movzx eax, byte ptr [r8]
+            &[0x3d, 0x02, 0x00, 0x00, 0x00],             // cmp eax, 2
+            &[0x73, 0x0a],                               // jae skip
+            &[0x48, 0x8d, 0x0d, 0x03, 0x00, 0x00, 0x00], // lea rcx, [rip + table]
+            &[0xff, 0x14, 0xc1],                         // call qword ptr [rcx + rax * 8]
+            // skip: table:
+            &0x1000u64.to_le_bytes(),
+            &0x100du64.to_le_bytes(),
+        ]
+        .concat()
+    );
+    let references = &compiler.stats().absolute_references;
+    assert_eq!(
+        references.iter().map(|r| r.offset).collect::<Vec<_>>(),
+        [0x2f, 0x37]
+    );
+}
+
+#[test]
+fn test_is_pure() {
+    let is_pure = |ir| {
+        let mut compiler = get_compiler();
+        compiler.compile_to_bytecode(ir).unwrap();
+        compiler.is_pure()
+    };
+
+    // +[->+<]
+    assert!(is_pure(vec![
+        node(IrOp::Add(1)),
+        node(IrOp::Condition(vec![
+            node(IrOp::Subtract(1)),
+            node(IrOp::MoveRight(1)),
+            node(IrOp::Add(1)),
+            node(IrOp::MoveLeft(1)),
+        ])),
+    ]));
+    // calls to the program's own functions don't count
+    assert!(is_pure(vec![
+        node(IrOp::Function("f".to_string(), vec![node(IrOp::StackPush)])),
+        node(IrOp::ExternalFunctionCall(ExternalCall::new(
+            "f".to_string()
+        ))),
+    ]));
+    assert!(!is_pure(vec![node(IrOp::ExternalFunctionCall(
+        ExternalCall::new("getchar".to_string())
+    ))]));
+    assert!(!is_pure(vec![node(IrOp::WriteByte)]));
+
+    // the exit of `_start` is a system call
+    let mut compiler = get_compiler();
+    compile_to_object_bytes(&mut compiler, vec![node(IrOp::Add(1))]);
+    assert!(!compiler.is_pure());
+    compiler.settings_mut().entry_exit = EntryExit::Return;
+    compile_to_object_bytes(&mut compiler, vec![node(IrOp::Add(1))]);
+    assert!(compiler.is_pure());
+}
+
+#[test]
+fn test_scratch_register() {
+    let settings = CompilerSettings {
+        register_abi: RegisterAbi {
+            scratch: ScratchRegister::R11,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let bytes = get_compiler_with(settings.clone())
+        .compile_to_bytecode(vec![node(IrOp::StackPush), node(IrOp::StackPop)])
+        .unwrap();
+    assert_eq_hex!(
+        bytes,
+        [
+            &[0x4d, 0x8d, 0x49, 0x01][..], // lea r9, [r9 + 1]
+            &[0x45, 0x8a, 0x18],           // mov r11b, byte ptr [r8]
+            &[0x45, 0x88, 0x19],           // mov byte ptr [r9], r11b
+            &[0x45, 0x8a, 0x19],           // mov r11b, byte ptr [r9]
+            &[0x45, 0x88, 0x18],           // mov byte ptr [r8], r11b
+            &[0x4d, 0x8d, 0x49, 0xff],     // lea r9, [r9 - 1]
+        ]
+        .concat()
+    );
+
+    let bytes = get_compiler_with(settings.clone())
+        .compile_to_bytecode(vec![node(IrOp::MulConst(3))])
+        .unwrap();
+    assert_eq_hex!(
+        bytes,
+        [
+            &[0x45, 0x0f, 0xb6, 0x18][..], // movzx r11d, byte ptr [r8]
+            &[0x45, 0x6b, 0xdb, 0x03],     // imul r11d, r11d, 3
+            &[0x45, 0x88, 0x18],           // mov byte ptr [r8], r11b
+        ]
+        .concat()
+    );
+
+    // rsi is callee-saved on Windows
+    let mut compiler = Compiler::new(
+        64,
+        CompilerSettings {
+            register_abi: RegisterAbi {
+                scratch: ScratchRegister::Rsi,
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+        crate::target::CallingConvention::X86_64_MicrosoftX64,
+    );
+    let err = compiler
+        .compile_to_bytecode(vec![node(IrOp::Add(1)), node(IrOp::StackPush)])
+        .unwrap_err();
+    assert!(matches!(
+        err.kind,
+        CompilerErrorKind::CalleeSavedScratchRegister(ScratchRegister::Rsi)
+    ));
+    assert!(compiler
+        .compile_to_bytecode(vec![node(IrOp::Add(1))])
+        .is_ok());
+}
+
+#[test]
+fn test_register_abi() {
+    use crate::target::CallingConvention;
+    use iced_x86::Register;
+
+    let ir = || {
+        vec![node(IrOp::Function(
+            "f".to_string(),
+            vec![
+                node(IrOp::Condition(vec![
+                    node(IrOp::StackPush),
+                    node(IrOp::MoveRight(1)),
+                    node(IrOp::MulConst(3)),
+                    node(IrOp::StackPop),
+                    node(IrOp::Subtract(1)),
+                ])),
+                node(IrOp::WriteByte),
+                node(IrOp::ReadByte),
+                node(IrOp::ExternalFunctionCall(ExternalCall {
+                    store_result: true,
+                    clobbers: Clobbers::ALL,
+                    ..ExternalCall::new("ext".to_string())
+                })),
+            ],
+        ))]
+    };
+    let custom = RegisterAbi {
+        cell_pointer: PointerRegister::Rbx,
+        stack_pointer: PointerRegister::R12,
+        scratch: ScratchRegister::Rcx,
+        base: Some(PointerRegister::R15),
+    };
+    // every register the code names, other than the stack's
+    let registers = |code: &[u8]| {
+        let mut registers = Vec::new();
+        for instruction in iced_x86::Decoder::new(64, code, iced_x86::DecoderOptions::NONE) {
+            registers.extend(
+                (0..instruction.op_count())
+                    .map(|i| instruction.op_register(i))
+                    .chain([instruction.memory_base(), instruction.memory_index()])
+                    .map(full_register)
+                    .filter(|&register| register != Register::None),
+            );
+        }
+        registers
+    };
+
+    for (abi, cell, owned) in [
+        (RegisterAbi::default(), Register::R8, &[][..]),
+        (custom, Register::RBX, &[Register::RBX, Register::R12][..]),
+    ] {
+        assert!(abi.validate().is_ok());
+        // debug builds also check every compilation like this
+        let mut compiler = Compiler::new(
+            64,
+            CompilerSettings {
+                entry_points: vec!["f".to_string()],
+                register_abi: abi,
+                ..Default::default()
+            },
+            CallingConvention::X86_64_SystemVAMD64,
+        );
+        let summary = summarize_object(&compile_to_object_bytes(&mut compiler, ir()));
+        let calls = summary
+            .relocations
+            .iter()
+            .map(|&(offset, _)| offset as usize - 1)
+            .collect::<Vec<_>>();
+        assert!(verify_callee_saved(&summary.text, 64, owned).is_ok());
+        assert!(verify_cell_pointer(&summary.text, 64, cell, &calls).is_ok());
+        assert!(!registers(&summary.text).contains(&Register::R15));
+    }
+
+    // the custom ABI leaves r8 and r9 alone, the wrapper saves what it uses
+    let mut compiler = Compiler::new(
+        64,
+        CompilerSettings {
+            entry_points: vec!["f".to_string()],
+            register_abi: custom,
+            ..Default::default()
+        },
+        CallingConvention::X86_64_SystemVAMD64,
+    );
+    let code = summarize_object(&compile_to_object_bytes(&mut compiler, ir())).text;
+    let used = registers(&code);
+    assert!(used.contains(&Register::RBX) && used.contains(&Register::R12));
+    assert!(!used.contains(&Register::R8) && !used.contains(&Register::R9));
+    assert!(code.windows(3).any(|bytes| bytes == [0x53, 0x41, 0x54])); // push rbx; push r12
+    assert!(code.windows(3).any(|bytes| bytes == [0x41, 0x5c, 0x5b])); // pop r12; pop rbx
+
+    let bytes = get_compiler_with(CompilerSettings {
+        register_abi: custom,
+        ..Default::default()
+    })
+    .compile_to_bytecode(vec![node(IrOp::Add(1)), node(IrOp::StackPush)])
+    .unwrap();
+    assert_eq_hex!(
+        bytes,
+        [
+            &[0x80, 0x03, 0x01][..],         // add byte ptr [rbx], 1
+            &[0x4d, 0x8d, 0x64, 0x24, 0x01], // lea r12, [r12 + 1]
+            &[0x8a, 0x0b],                   // mov cl, byte ptr [rbx]
+            &[0x41, 0x88, 0x0c, 0x24],       // mov byte ptr [r12], cl
+        ]
+        .concat()
+    );
+
+    for (abi, conflict) in [
+        (
+            RegisterAbi {
+                stack_pointer: PointerRegister::R8,
+                ..Default::default()
+            },
+            ("cell pointer", "stack pointer"),
+        ),
+        (
+            RegisterAbi {
+                base: Some(PointerRegister::R9),
+                ..Default::default()
+            },
+            ("stack pointer", "base"),
+        ),
+    ] {
+        let err = get_compiler_with(CompilerSettings {
+            register_abi: abi,
+            ..Default::default()
+        })
+        .compile_to_bytecode(vec![node(IrOp::Add(1))])
+        .unwrap_err();
+        assert!(matches!(
+            err.kind,
+            CompilerErrorKind::RegisterConflict(_, first, second) if (first, second) == conflict
+        ));
+    }
+    let err = RegisterAbi {
+        cell_pointer: PointerRegister::Rbx,
+        base: Some(PointerRegister::R8),
+        ..Default::default()
+    }
+    .validate()
+    .unwrap_err();
+    assert!(matches!(
+        err.kind,
+        CompilerErrorKind::CallerSavedBaseRegister(PointerRegister::R8)
+    ));
+}
+
+#[test]
+fn test_debug_frame() {
+    let mut compiler = get_compiler_with(CompilerSettings {
+        debug_frame: true,
+        ..Default::default()
+    });
+    let bytes = compile_to_object_bytes(
+        &mut compiler,
+        vec![
+            node(IrOp::Function(
+                "f".to_string(),
+                vec![node(IrOp::ExternalFunctionCall(ExternalCall::new(
+                    "ext".to_string(),
+                )))],
+            )),
+            node(IrOp::FunctionCall("f".to_string())),
+        ],
+    );
+    let file = object::File::parse(&*bytes).unwrap();
+    let section = file.section_by_name(".debug_frame").unwrap();
+    let data = section.data().unwrap();
+
+    assert_eq_hex!(
+        data[..24],
+        [
+            0x14, 0x00, 0x00, 0x00, // length
+            0xff, 0xff, 0xff, 0xff, // CIE id
+            0x01, 0x00, 0x01, 0x78, 0x10, // version, augmentation, factors, ra
+            0x0c, 0x07, 0x08, // DW_CFA_def_cfa rsp, 8
+            0x90, 0x01, // DW_CFA_offset r16, cfa - 8
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // padding
+        ]
+    );
+    // f aligns the stack, and pushes r8 and r9 around the call
+    let f = &data[24..];
+    assert_eq_hex!(f[..4], [0x2c, 0x00, 0x00, 0x00]);
+    assert_eq_hex!(
+        f[24..48],
+        [
+            0x44, 0x0e, 0x10, // sub rsp, 8
+            0x42, 0x0e, 0x18, // push r8
+            0x42, 0x0e, 0x20, // push r9
+            0x50, 0x0e, 0x18, // lea rdi, lea rsi, call, pop r9
+            0x42, 0x0e, 0x10, // pop r8
+            0x44, 0x0e, 0x08, // add rsp, 8
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // padding
+        ]
+    );
+    // `_start` only calls f
+    let start = &data[24 + 48..];
+    assert_eq!(start.len(), 24);
+
+    // every FDE points at the CIE and the start of its function
+    let relocations = section
+        .relocations()
+        .map(|(offset, relocation)| (offset, relocation.addend()))
+        .collect::<Vec<_>>();
+    assert_eq!(relocations, [(28, 0), (32, 0), (76, 0), (80, 0x1f)]);
+}
+
+#[test]
+fn test_entry_offset() {
+    let ir = || {
+        vec![
+            node(IrOp::Add(1)),
+            node(IrOp::FunctionCall("f".to_string())),
+            node(IrOp::Function("f".to_string(), vec![node(IrOp::Add(2))])),
+        ]
+    };
+    let settings = CompilerSettings {
+        base_address: 0x7c00,
+        entry_offset: Some(0x200),
+        ..Default::default()
+    };
+    let mut compiler = HfCompiler::new(Target::native(), settings.clone());
+    let artifact = compiler.compile_to_artifact(ir()).unwrap();
+    assert_eq!(artifact.entry, 0x7e00);
+
+    // the function comes first, even though it is defined last
+    let code = artifact.code;
+    assert_eq_hex!(
+        code[..13],
+        [
+            &[0x48, 0x83, 0xec, 0x08][..], // f: sub rsp, 8
+            &[0x41, 0x80, 0x00, 0x02],     // add byte ptr [r8], 2
+            &[0x48, 0x83, 0xc4, 0x08],     // add rsp, 8
+            &[0xc3],                       // ret
+        ]
+        .concat()
+    );
+    assert!(code[13..0x200].iter().all(|&byte| byte == 0xcc));
+    assert_eq_hex!(
+        code[0x200..],
+        [
+            &[0x41, 0x80, 0x00, 0x01][..],   // add byte ptr [r8], 1
+            &[0xe8, 0xf7, 0xfd, 0xff, 0xff], // call f
+        ]
+        .concat()
+    );
+
+    let err = get_compiler_with(CompilerSettings {
+        entry_offset: Some(4),
+        ..settings
+    })
+    .compile_to_bytecode(ir())
+    .unwrap_err();
+    assert!(matches!(
+        err.kind,
+        CompilerErrorKind::EntryOffsetTooSmall {
+            offset: 4,
+            size: 13
+        }
+    ));
+}
+
+#[test]
+fn test_trace() {
+    extern crate std;
+    use alloc::sync::Arc;
+    use std::sync::Mutex;
+
+    let lines = Arc::new(Mutex::new(Vec::new()));
+    let sink = lines.clone();
+    let mut compiler = get_compiler_with(CompilerSettings {
+        trace: Some(TraceSink::new(move |line| {
+            sink.lock().unwrap().push(line.to_string())
+        })),
+        ..Default::default()
+    });
+    compiler
+        .compile_to_bytecode(vec![
+            node(IrOp::Add(1)),
+            node(IrOp::Condition(vec![node(IrOp::Subtract(1))])),
+        ])
+        .unwrap();
+    assert_eq!(
+        *lines.lock().unwrap(),
+        [
+            "Add 0:0 #0..#1: Add_rm8_imm8",
+            "  Subtract 0:0 #4..#5: Sub_rm8_imm8",
+            "Condition 0:0 #1..#7: 6 instructions",
+        ]
+    );
+}
+
+#[cfg(feature = "jit")]
+#[test]
+fn test_external_reads_cells() {
+    use core::sync::atomic::{AtomicU32, Ordering};
+
+    static READ: AtomicU32 = AtomicU32::new(0);
+    // takes the cell pointer by value, and the four cells from it on as a
+    // 32-bit integer
+    extern "C" fn read_u32(cell: *const u8, _stack: *const u8) {
+        let value = unsafe { (cell as *const u32).read_unaligned() };
+        READ.store(value, Ordering::Relaxed);
+    }
+
+    let mut ir = compile_to_ir("+>++>+++>++++<<<");
+    ir.push(node(IrOp::ExternalFunctionCall(ExternalCall {
+        clobbers: Clobbers::NONE,
+        ..ExternalCall::new("read_u32".to_string())
+    })));
+    let mut jit = HfCompiler::new(Target::native(), CompilerSettings::default())
+        .jit_compile(ir)
+        .unwrap();
+    jit.bind("read_u32", read_u32 as *const ()).unwrap();
+
+    let mut tape = [0; 4];
+    unsafe { jit.call(&mut tape) }.unwrap();
+    assert_eq!(tape, [1, 2, 3, 4]);
+    // one byte per cell, in the order of the cells, so little-endian
+    assert_eq!(READ.load(Ordering::Relaxed), u32::from_le_bytes(tape));
+    assert_eq!(READ.load(Ordering::Relaxed), 0x0403_0201);
+}
+
+#[cfg(feature = "jit")]
+#[test]
+fn test_jit_compile() {
+    extern "C" fn bump(cell: *mut *mut u8, _stack: *mut *mut u8) {
+        unsafe { **cell += 7 };
+    }
+
+    // dispatches through a jump table, which has to be relocated
+    let ir = vec![
+        node(IrOp::Switch {
+            arms: (0..4)
+                .map(|value| (value, vec![node(IrOp::Add(10 * value as usize))]))
+                .collect(),
+            default: vec![node(IrOp::Subtract(1))],
+        }),
+        node(IrOp::MoveRight(1)),
+        node(IrOp::ExternalFunctionCall(ExternalCall::new(
+            "bump".to_string(),
+        ))),
+    ];
+    let mut jit = HfCompiler::new(Target::native(), CompilerSettings::default())
+        .jit_compile(ir)
+        .unwrap();
+
+    let mut tape = [2, 5];
+    let err = unsafe { jit.call(&mut tape) }.unwrap_err();
+    assert!(matches!(err.kind, CompilerErrorKind::UnboundExternal(name) if name == "bump"));
+    assert!(jit.bind("putchar", bump as *const ()).is_err());
+    jit.bind("bump", bump as *const ()).unwrap();
+
+    assert_eq!(unsafe { jit.call(&mut tape) }.unwrap(), 1);
+    assert_eq!(tape, [22, 12]);
+    let mut other_tape = [3, 0, 0];
+    assert_eq!(unsafe { jit.call(&mut other_tape) }.unwrap(), 1);
+    assert_eq!(other_tape, [33, 7, 0]);
+    assert_eq!(tape, [22, 12]);
+}
+
+#[cfg(feature = "jit")]
+#[test]
+fn test_jit_external_called_with_stack_aligned() {
+    #[repr(align(16))]
+    struct Aligned(u8);
+
+    // a 16-byte aligned local ends up 8 bytes off if the caller's stack was,
+    // as the callee assumes it was aligned before the call
+    extern "C" fn check(cell: *mut u8, _stack: *mut u8) {
+        let local = Aligned(1);
+        let address = core::hint::black_box(&local) as *const Aligned as usize;
+        unsafe { *cell = local.0 + address.is_multiple_of(16) as u8 };
+    }
+
+    let check_call = || {
+        node(IrOp::ExternalFunctionCall(ExternalCall {
+            clobbers: Clobbers::NONE,
+            ..ExternalCall::new("check".to_string())
+        }))
+    };
+    // from top-level code and from within a function
+    let ir = vec![
+        node(IrOp::Function("f".to_string(), vec![check_call()])),
+        check_call(),
+        node(IrOp::MoveRight(1)),
+        node(IrOp::FunctionCall("f".to_string())),
+    ];
+    let mut jit = HfCompiler::new(Target::native(), CompilerSettings::default())
+        .jit_compile(ir)
+        .unwrap();
+    jit.bind("check", check as *const ()).unwrap();
+
+    let mut tape = [0; 2];
+    unsafe { jit.call(&mut tape) }.unwrap();
+    assert_eq!(tape, [2, 2]);
+}
+
+#[cfg(feature = "jit")]
+#[test]
+fn test_jit_compile_on_filled_tape() {
+    // [-]+ leaves 1 whatever the cell was
+    let ir = vec![
+        node(IrOp::Condition(vec![node(IrOp::Subtract(1))])),
+        node(IrOp::Add(1)),
+    ];
+    for optimization_level in 1..=2 {
+        let mut compiler = HfCompiler::new(
+            Target::native(),
+            CompilerSettings {
+                optimization_level,
+                ..Default::default()
+            },
+        );
+        let mut jit = compiler.jit_compile(ir.clone()).unwrap();
+        assert!(compiler.warnings().is_empty());
+        let mut tape = [5];
+        unsafe { jit.call(&mut tape) }.unwrap();
+        assert_eq!(tape, [1]);
+    }
+}
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+#[test]
+fn test_dynamic_executable() {
+    extern crate std;
+    use std::os::unix::fs::PermissionsExt;
+
+    // libc's `write` prints, the jump table of the `Switch` is relocated
+    let ir = vec![
+        node(IrOp::Function(
+            "newline".to_string(),
+            vec![node(IrOp::Add(10)), node(IrOp::WriteByte)],
+        )),
+        node(IrOp::Add(2)),
+        node(IrOp::Switch {
+            arms: (0..4)
+                .map(|value| (value, vec![node(IrOp::Add(70))]))
+                .collect(),
+            default: vec![],
+        }),
+        node(IrOp::WriteByte),
+        node(IrOp::Add(33)),
+        node(IrOp::WriteByte),
+        node(IrOp::MoveRight(1)),
+        node(IrOp::FunctionCall("newline".to_string())),
+    ];
+    let bytes = HfCompiler::new(Target::native(), CompilerSettings::default())
+        .compile_to_dynamic_executable(ir, &super::DynamicLinking::default())
+        .unwrap();
+
+    let path = std::env::temp_dir().join(format!("hf_dynamic_{}", std::process::id()));
+    std::fs::write(&path, bytes).unwrap();
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let output = std::process::Command::new(&path).output().unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert!(output.status.success(), "{:?}", output.status);
+    assert_eq!(output.stdout, b"Hi\n");
+}
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+#[test]
+fn test_dynamic_executable_calls_printf() {
+    extern crate std;
+    use std::os::unix::fs::PermissionsExt;
+
+    // printf is passed the format string on the tape. `write` returned 1, so al
+    // tells it there are vector registers to save, which it does with movaps,
+    // faulting unless the stack is aligned. It doesn't preserve the cell
+    // pointer, so nothing comes after it
+    let ir = vec![
+        node(IrOp::Add(72)),
+        node(IrOp::WriteByte),
+        node(IrOp::MoveRight(1)),
+        node(IrOp::Add(105)),
+        node(IrOp::MoveRight(1)),
+        node(IrOp::Add(10)),
+        node(IrOp::MoveLeft(2)),
+        node(IrOp::ExternalFunctionCall(ExternalCall {
+            clobbers: Clobbers::NONE,
+            ..ExternalCall::new("printf".to_string())
+        })),
+    ];
+    let bytes = HfCompiler::new(Target::native(), CompilerSettings::default())
+        .compile_to_dynamic_executable(ir, &super::DynamicLinking::default())
+        .unwrap();
+
+    let path = std::env::temp_dir().join(format!("hf_printf_{}", std::process::id()));
+    std::fs::write(&path, bytes).unwrap();
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let output = std::process::Command::new(&path).output().unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert!(output.status.success(), "{:?}", output.status);
+    assert_eq!(output.stdout, b"HHi\n");
+}
+
+#[test]
+fn test_append_checksum() {
+    // the usual table-driven CRC-32
+    let table = (0..256u32)
+        .map(|n| {
+            (0..8).fold(n, |c, _| {
+                if c & 1 == 1 {
+                    0xedb88320 ^ (c >> 1)
+                } else {
+                    c >> 1
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    let crc32 = |bytes: &[u8]| {
+        !bytes.iter().fold(!0u32, |crc, &byte| {
+            table[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8)
+        })
+    };
+    assert_eq!(crc32(b"123456789"), 0xcbf43926);
+
+    let ir = compile_to_ir("+++[>++<-]>.");
+    let code = get_compiler().compile_to_bytecode(ir.clone()).unwrap();
+    let image = get_compiler_with(CompilerSettings {
+        append_checksum: Some(ChecksumKind::Crc32),
+        ..Default::default()
+    })
+    .compile_to_bytecode(ir)
+    .unwrap();
+
+    let (image_code, trailer) = image.split_at(image.len() - 4);
+    assert_eq_hex!(image_code, code);
+    assert_eq!(
+        u32::from_le_bytes(trailer.try_into().unwrap()),
+        crc32(&code)
+    );
+
+    assert_eq_hex!(
+        ChecksumKind::Adler32.checksum(b"Wikipedia"),
+        0x11e60398u32.to_le_bytes()
+    );
+}
+
+#[test]
+fn test_coff_imports() {
+    use crate::target::{Arch, CallingConvention, Os};
+
+    let target = Target::new(
+        Arch::X86_64,
+        CallingConvention::from_arch_os(Arch::X86_64, Os::Windows),
+    );
+    let compile = |import_ordinals| {
+        let mut compiler = HfCompiler::new(
+            target.clone(),
+            CompilerSettings {
+                // only for the other compile paths
+                object_format: ObjectFormat::Elf,
+                external_calls: ExternalCalls::IndirectGot,
+                import_ordinals,
+                ..Default::default()
+            },
+        );
+        let ir = vec![
+            node(IrOp::ExternalFunctionCall(ExternalCall::new(
+                "MessageBoxA".to_string(),
+            ))),
+            node(IrOp::WriteByte),
+        ];
+        let output = compiler
+            .compile_to_coff_with_imports(ir, "test.hf")
+            .unwrap();
+        let elf = compiler
+            .compile_to_object_file(Vec::new(), "test.hf")
+            .unwrap();
+        assert_eq!(elf.format(), object::BinaryFormat::Elf);
+        (output.object.write().unwrap(), output.imports)
+    };
+
+    let (bytes, imports) = compile(Vec::new());
+    let file = object::File::parse(&*bytes).unwrap();
+    assert_eq!(file.format(), object::BinaryFormat::Coff);
+    assert_eq!(
+        imports,
+        vec![
+            Import {
+                name: "MessageBoxA".to_string(),
+                symbol: "__imp_MessageBoxA".to_string(),
+                lookup: ImportLookup::Name,
+            },
+            Import {
+                name: "write".to_string(),
+                symbol: "__imp_write".to_string(),
+                lookup: ImportLookup::Name,
+            },
+        ]
+    );
+    // every import is an undefined symbol of the object, and nothing else is
+    let mut undefined = summarize_object(&bytes)
+        .symbols
+        .into_iter()
+        .filter(|(_, _, undefined)| *undefined)
+        .map(|(name, _, _)| name)
+        .collect::<Vec<_>>();
+    undefined.sort();
+    let symbols = imports.iter().map(|import| import.symbol.clone());
+    assert_eq!(undefined, symbols.collect::<Vec<_>>());
+
+    let (_, imports) = compile(vec![("MessageBoxA".to_string(), 2039)]);
+    assert_eq!(imports[0].lookup, ImportLookup::Ordinal(2039));
+    assert_eq!(imports[1].lookup, ImportLookup::Name);
 }
@@ -0,0 +1,168 @@
+//! Generators for random but well-formed IR, for fuzzing the compiler.
+//!
+//! Everything generated here is something a front-end could produce, so
+//! compiling it is expected to succeed. A panic or an error is a bug.
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use arbitrary::{Arbitrary, Result, Unstructured};
+
+use crate::ir::{Clobbers, ExternalCall, IrNode, IrOp, Span};
+
+/// How deep `Condition`s are nested at most.
+const MAX_DEPTH: usize = 4;
+/// Longest body of a function or `Condition`.
+const MAX_BODY_LEN: usize = 16;
+/// Most functions a [`Program`] defines.
+const MAX_FUNCTIONS: usize = 4;
+/// Externals that calls are generated for.
+const EXTERNALS: &[&str] = &["putchar", "getchar", "malloc", "free"];
+
+impl<'a> Arbitrary<'a> for Span {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(Span {
+            location: (u.int_in_range(0..=0xffff)?, u.int_in_range(0..=0xff)?),
+            length: u.int_in_range(1..=0xff)?,
+        })
+    }
+}
+
+impl<'a> Arbitrary<'a> for Clobbers {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(Clobbers {
+            cell_pointer: u.arbitrary()?,
+            stack_pointer: u.arbitrary()?,
+        })
+    }
+}
+
+impl<'a> Arbitrary<'a> for ExternalCall {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(ExternalCall {
+            name: u.choose(EXTERNALS)?.to_string(),
+            store_result: u.arbitrary()?,
+            status_offset: match u.arbitrary()? {
+                true => Some(u.int_in_range(-0x100..=0x100)?),
+                false => None,
+            },
+            clobbers: u.arbitrary()?,
+        })
+    }
+}
+
+/// Generates any op that doesn't refer to a function, since a lone op can't
+/// know which functions exist. Use [`Program`] for IR with function calls.
+impl<'a> Arbitrary<'a> for IrOp {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        arbitrary_op(u, 0, &[])
+    }
+}
+
+impl<'a> Arbitrary<'a> for IrNode {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        arbitrary_node(u, 0, &[])
+    }
+}
+
+/// A whole program: a few functions, each of which may call the ones defined
+/// before it, followed by top-level code that may call any of them.
+///
+/// Functions never call themselves or functions defined after them, so the
+/// program can't recurse endlessly. Loops may still never terminate.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program(pub Vec<IrNode>);
+
+impl<'a> Arbitrary<'a> for Program {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let mut ir = Vec::new();
+        let mut functions = Vec::new();
+
+        for i in 0..u.int_in_range(0..=MAX_FUNCTIONS)? {
+            let body = arbitrary_body(u, 0, &functions)?;
+            let name = format!("f{i}");
+            ir.push(IrNode {
+                node: IrOp::Function(name.clone(), body),
+                span: u.arbitrary()?,
+            });
+            functions.push(name);
+        }
+        ir.extend(arbitrary_body(u, 0, &functions)?);
+
+        Ok(Program(ir))
+    }
+}
+
+fn arbitrary_body(
+    u: &mut Unstructured<'_>,
+    depth: usize,
+    functions: &[String],
+) -> Result<Vec<IrNode>> {
+    (0..u.int_in_range(0..=MAX_BODY_LEN)?)
+        .map(|_| arbitrary_node(u, depth, functions))
+        .collect()
+}
+
+fn arbitrary_node(u: &mut Unstructured<'_>, depth: usize, functions: &[String]) -> Result<IrNode> {
+    Ok(IrNode {
+        node: arbitrary_op(u, depth, functions)?,
+        span: u.arbitrary()?,
+    })
+}
+
+fn arbitrary_op(u: &mut Unstructured<'_>, depth: usize, functions: &[String]) -> Result<IrOp> {
+    Ok(match u.int_in_range(0..=22)? {
+        0 => IrOp::Add(u.int_in_range(0..=0x400)?),
+        1 => IrOp::Subtract(u.int_in_range(0..=0x400)?),
+        2 => IrOp::MoveRight(u.int_in_range(0..=0x100)?),
+        3 => IrOp::MoveLeft(u.int_in_range(0..=0x100)?),
+        4 => IrOp::StackPush,
+        5 => IrOp::StackPop,
+        6 => IrOp::ExternalFunctionCall(u.arbitrary()?),
+        7 => IrOp::MulConst(u.arbitrary()?),
+        8 => IrOp::WriteByte,
+        9 => IrOp::WriteBytes(u.int_in_range(1..=0x40)?),
+        10 => IrOp::ReadByte,
+        11 => IrOp::Nop,
+        12 if depth < MAX_DEPTH => IrOp::Condition(arbitrary_body(u, depth + 1, functions)?),
+        13 if !functions.is_empty() => IrOp::FunctionCall(u.choose(functions)?.clone()),
+        14 if !functions.is_empty() => IrOp::IndirectCall {
+            table: (0..u.int_in_range(1..=MAX_FUNCTIONS)?)
+                .map(|_| Ok(u.choose(functions)?.clone()))
+                .collect::<Result<_>>()?,
+        },
+        15 => IrOp::DebugMarker(format!("marker{}", u.int_in_range(0..=0xff)?)),
+        16 => IrOp::AddAtOffset {
+            offset: u.int_in_range(-0x100..=0x100)?,
+            amount: u.arbitrary()?,
+        },
+        17 => IrOp::ReadBytes(u.int_in_range(0..=0x40)?),
+        18 => IrOp::AddToCells {
+            offset: u.int_in_range(-0x100..=0x100)?,
+            count: u.int_in_range(0..=0x40)?,
+            amount: u.arbitrary()?,
+        },
+        19 => IrOp::AddFromCell {
+            src_offset: u.int_in_range(-0x100..=0x100)?,
+        },
+        20 => IrOp::MoveCell {
+            offset: u.int_in_range(-0x100..=0x100)?,
+        },
+        21 if depth < MAX_DEPTH => IrOp::IfEq {
+            value: u.arbitrary()?,
+            body: arbitrary_body(u, depth + 1, functions)?,
+        },
+        // values from a small range, so the arms are dense about as often as
+        // they are sparse
+        22 if depth < MAX_DEPTH => IrOp::Switch {
+            arms: (0..u.int_in_range(0..=8)?)
+                .map(|_| {
+                    let value = u.int_in_range(0..=0x10)?;
+                    Ok((value, arbitrary_body(u, depth + 1, functions)?))
+                })
+                .collect::<Result<_>>()?,
+            default: arbitrary_body(u, depth + 1, functions)?,
+        },
+        _ => IrOp::Nop,
+    })
+}
@@ -0,0 +1,652 @@
+//! A tree-walking interpreter for the IR.
+//!
+//! It is the reference for what a program is supposed to do, so the compiler
+//! and the optimisation passes can be tested against it: the same program has
+//! to leave the same tape and produce the same output either way.
+//!
+//! The semantics follow the x86 backend:
+//! - the cell pointer starts at the first cell of the tape, and may leave it
+//!   as long as no cell outside of it is accessed
+//! - arithmetic wraps around
+//! - `Condition` loops while the current cell isn't zero
+//! - a `Function` node only defines the function; its body runs when called.
+//!   Functions can be called from the body they are defined in, including
+//!   before the definition, and from anything nested in it
+//! - `ReadByte` leaves the cell unchanged at the end of the input
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use thiserror_no_std::Error;
+
+use crate::ir::{IrNode, IrOp, Span};
+use crate::Map;
+
+#[derive(Debug, Clone, PartialEq, Error)]
+pub struct InterpreterError {
+    pub kind: InterpreterErrorKind,
+    pub span: Span,
+}
+
+impl core::fmt::Display for InterpreterError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{}:{}: {}",
+            self.span.location.0 + 1,
+            self.span.location.1 + 1,
+            self.kind
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum InterpreterErrorKind {
+    #[error("cell {0} is outside of the tape")]
+    OutOfTape(isize),
+    #[error("popped from an empty stack")]
+    StackUnderflow,
+    #[error("function not found: '{0}'")]
+    FunctionNotFound(String),
+    #[error("{0} can't be interpreted")]
+    Unsupported(&'static str),
+    #[error("ran out of fuel")]
+    OutOfFuel,
+}
+
+/// Runs `nodes` on `tape` with `input` as standard input and returns what the
+/// program wrote to standard output.
+///
+/// # Panics
+///
+/// If the program fails, see [`try_interpret`].
+pub fn interpret(nodes: &[IrNode], tape: &mut [u8], input: &[u8]) -> Vec<u8> {
+    try_interpret(nodes, tape, input, None).unwrap_or_else(|e| panic!("{e}"))
+}
+
+/// Like [`interpret`], but returns an error if the program accesses a cell
+/// outside of `tape`, pops from an empty stack, calls an unknown function or
+/// contains an op without a meaning outside of compiled code, like external
+/// calls.
+///
+/// With `fuel` set, every executed node and every loop iteration consumes one
+/// unit, and the program is stopped with [`InterpreterErrorKind::OutOfFuel`]
+/// once none is left. That bounds programs that may never terminate.
+pub fn try_interpret(
+    nodes: &[IrNode],
+    tape: &mut [u8],
+    input: &[u8],
+    fuel: Option<u64>,
+) -> Result<Vec<u8>, InterpreterError> {
+    let mut machine = Machine {
+        tape,
+        pointer: 0,
+        stack: Vec::new(),
+        input,
+        output: Vec::new(),
+        fuel,
+        frames: Vec::new(),
+    };
+    machine.run_body(nodes, None)?;
+    Ok(machine.output)
+}
+
+/// The functions defined in a body that is running, and where to look for the
+/// ones it doesn't define.
+struct Frame<'a> {
+    functions: Map<&'a str, &'a [IrNode]>,
+    parent: Option<usize>,
+}
+
+struct Machine<'a, 't> {
+    tape: &'t mut [u8],
+    pointer: isize,
+    stack: Vec<u8>,
+    input: &'t [u8],
+    output: Vec<u8>,
+    fuel: Option<u64>,
+    /// Frames of the bodies that are running, innermost last.
+    frames: Vec<Frame<'a>>,
+}
+
+impl<'a> Machine<'a, '_> {
+    fn run_body(
+        &mut self,
+        nodes: &'a [IrNode],
+        parent: Option<usize>,
+    ) -> Result<(), InterpreterError> {
+        let frame = self.frames.len();
+        self.frames.push(Frame {
+            functions: nodes
+                .iter()
+                .filter_map(|node| match &node.node {
+                    IrOp::Function(name, body) => Some((name.as_str(), body.as_slice())),
+                    _ => None,
+                })
+                .collect(),
+            parent,
+        });
+        for node in nodes {
+            self.step(node, frame)?;
+        }
+        // frames pushed by the body are gone already, so this is its own
+        self.frames.truncate(frame);
+        Ok(())
+    }
+
+    /// Finds a function and the frame it was defined in.
+    fn find_function(&self, name: &str, mut frame: usize) -> Option<(&'a [IrNode], usize)> {
+        loop {
+            if let Some(body) = self.frames[frame].functions.get(name) {
+                return Some((body, frame));
+            }
+            frame = self.frames[frame].parent?;
+        }
+    }
+
+    fn burn_fuel(&mut self, span: Span) -> Result<(), InterpreterError> {
+        match &mut self.fuel {
+            Some(0) => Err(InterpreterError {
+                kind: InterpreterErrorKind::OutOfFuel,
+                span,
+            }),
+            Some(fuel) => {
+                *fuel -= 1;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// The `count` cells starting at the current one.
+    fn cells(&mut self, count: usize, span: Span) -> Result<&mut [u8], InterpreterError> {
+        let len = self.tape.len();
+        let range = usize::try_from(self.pointer)
+            .ok()
+            .and_then(|start| Some(start..start.checked_add(count)?))
+            .filter(|range| range.end <= len);
+        match range {
+            Some(range) => Ok(&mut self.tape[range]),
+            None => Err(InterpreterError {
+                // the first cell that is out of bounds
+                kind: InterpreterErrorKind::OutOfTape(match self.pointer {
+                    pointer if pointer < 0 => pointer,
+                    pointer => pointer.max(len as isize),
+                }),
+                span,
+            }),
+        }
+    }
+
+    fn cell(&mut self, span: Span) -> Result<&mut u8, InterpreterError> {
+        Ok(&mut self.cells(1, span)?[0])
+    }
+
+    /// Runs the function `name` as seen from `frame`.
+    fn call(&mut self, name: &str, frame: usize, span: Span) -> Result<(), InterpreterError> {
+        let (body, defined_in) =
+            self.find_function(name, frame)
+                .ok_or_else(|| InterpreterError {
+                    kind: InterpreterErrorKind::FunctionNotFound(name.to_string()),
+                    span,
+                })?;
+        self.run_body(body, Some(defined_in))
+    }
+
+    fn step(&mut self, node: &'a IrNode, frame: usize) -> Result<(), InterpreterError> {
+        let span = node.span;
+        self.burn_fuel(span)?;
+        match &node.node {
+            IrOp::Add(n) => {
+                let cell = self.cell(span)?;
+                *cell = cell.wrapping_add(*n as u8);
+            }
+            IrOp::Subtract(n) => {
+                let cell = self.cell(span)?;
+                *cell = cell.wrapping_sub(*n as u8);
+            }
+            IrOp::MoveRight(n) => self.pointer = self.pointer.wrapping_add(*n as isize),
+            IrOp::MoveLeft(n) => self.pointer = self.pointer.wrapping_sub(*n as isize),
+            IrOp::SeekCell(index) => self.pointer = *index as isize,
+            IrOp::AddAtOffset { offset, amount } => {
+                self.pointer = self.pointer.wrapping_add(*offset as isize);
+                let result = self
+                    .cell(span)
+                    .map(|cell| *cell = cell.wrapping_add(*amount));
+                self.pointer = self.pointer.wrapping_sub(*offset as isize);
+                result?;
+            }
+            IrOp::AddToCells {
+                offset,
+                count,
+                amount,
+            } => {
+                self.pointer = self.pointer.wrapping_add(*offset as isize);
+                let result = self.cells(*count as usize, span).map(|cells| {
+                    for cell in cells {
+                        *cell = cell.wrapping_add(*amount);
+                    }
+                });
+                self.pointer = self.pointer.wrapping_sub(*offset as isize);
+                result?;
+            }
+            IrOp::AddFromCell { src_offset } => {
+                self.pointer = self.pointer.wrapping_add(*src_offset as isize);
+                let value = self.cell(span).map(|cell| *cell);
+                self.pointer = self.pointer.wrapping_sub(*src_offset as isize);
+                let value = value?;
+                let cell = self.cell(span)?;
+                *cell = cell.wrapping_add(value);
+            }
+            IrOp::MoveCell { offset } => {
+                let value = *self.cell(span)?;
+                self.pointer = self.pointer.wrapping_add(*offset as isize);
+                let result = self.cell(span).map(|cell| *cell = cell.wrapping_add(value));
+                self.pointer = self.pointer.wrapping_sub(*offset as isize);
+                result?;
+                *self.cell(span)? = 0;
+            }
+            IrOp::StackPush => {
+                let value = *self.cell(span)?;
+                self.stack.push(value);
+            }
+            IrOp::StackPop => {
+                let value = self.stack.pop().ok_or(InterpreterError {
+                    kind: InterpreterErrorKind::StackUnderflow,
+                    span,
+                })?;
+                *self.cell(span)? = value;
+            }
+            IrOp::Function(_, _) => {}
+            IrOp::FunctionCall(name) => self.call(name, frame, span)?,
+            IrOp::ExternalFunctionCall(call) if self.find_function(&call.name, frame).is_some() => {
+                self.call(&call.name, frame, span)?
+            }
+            IrOp::IndirectCall { table } => {
+                let index = *self.cell(span)? as usize;
+                if let Some(name) = table.get(index) {
+                    self.call(name, frame, span)?;
+                }
+            }
+            IrOp::Condition(body) => {
+                while *self.cell(span)? != 0 {
+                    self.burn_fuel(span)?;
+                    self.run_body(body, Some(frame))?;
+                }
+            }
+            IrOp::IfEq { value, body } => {
+                if *self.cell(span)? == *value {
+                    self.run_body(body, Some(frame))?;
+                }
+            }
+            IrOp::Switch { arms, default } => {
+                let cell = *self.cell(span)?;
+                let body = arms
+                    .iter()
+                    .find(|(value, _)| *value == cell)
+                    .map_or(default, |(_, body)| body);
+                self.run_body(body, Some(frame))?;
+            }
+            IrOp::MulConst(k) => {
+                let cell = self.cell(span)?;
+                *cell = cell.wrapping_mul(*k);
+            }
+            IrOp::WriteByte => {
+                let value = *self.cell(span)?;
+                self.output.push(value);
+            }
+            IrOp::WriteBytes(n) => {
+                let cells = self.cells(*n, span)?.to_vec();
+                self.output.extend(cells);
+            }
+            IrOp::ReadByte => {
+                if let Some((&byte, rest)) = self.input.split_first() {
+                    *self.cell(span)? = byte;
+                    self.input = rest;
+                } else {
+                    // still has to be a valid cell
+                    self.cell(span)?;
+                }
+            }
+            IrOp::ReadBytes(n) => {
+                let n = *n as usize;
+                let (read, rest) = self.input.split_at(n.min(self.input.len()));
+                // the count goes into the cell after the ones read into
+                self.cells(n + 1, span)?[..read.len()].copy_from_slice(read);
+                self.input = rest;
+                self.pointer += n as isize;
+                *self.cell(span)? = read.len().min(u8::MAX as usize) as u8;
+            }
+            IrOp::Nop | IrOp::DebugMarker(_) => {}
+            IrOp::MemAlloc(_) | IrOp::ExternalFunctionCall(_) | IrOp::MoveToExternal(_) => {
+                return Err(InterpreterError {
+                    kind: InterpreterErrorKind::Unsupported(node.node.name()),
+                    span,
+                })
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::CompilerSettings;
+    use crate::ir::ExternalCall;
+    use crate::optimizer;
+
+    fn node(op: IrOp) -> IrNode {
+        IrNode {
+            node: op,
+            span: Span::from_location((0, 0)),
+        }
+    }
+
+    /// Prints "HI", then echoes one byte of input.
+    fn program() -> Vec<IrNode> {
+        vec![
+            node(IrOp::Function(
+                "print".into(),
+                vec![node(IrOp::WriteByte), node(IrOp::MoveRight(1))],
+            )),
+            // cell 0 = 8, loop: cell 1 += 9, cell 2 += 9
+            node(IrOp::Add(8)),
+            node(IrOp::Condition(vec![
+                node(IrOp::MoveRight(1)),
+                node(IrOp::Add(9)),
+                node(IrOp::MoveRight(1)),
+                node(IrOp::Add(9)),
+                node(IrOp::MoveLeft(2)),
+                node(IrOp::Subtract(1)),
+            ])),
+            node(IrOp::MoveRight(1)),
+            node(IrOp::FunctionCall("print".into())),
+            node(IrOp::Add(1)),
+            node(IrOp::StackPush),
+            // no-ops, for the optimiser to remove
+            node(IrOp::MoveRight(1)),
+            node(IrOp::MoveLeft(1)),
+            node(IrOp::Add(1)),
+            node(IrOp::Subtract(1)),
+            node(IrOp::MoveRight(1)),
+            node(IrOp::StackPop),
+            node(IrOp::WriteByte),
+            node(IrOp::ReadByte),
+            node(IrOp::WriteByte),
+        ]
+    }
+
+    #[test]
+    fn test_interpret() {
+        let mut tape = [0; 4];
+        let output = interpret(&program(), &mut tape, b"!");
+        assert_eq!(output, b"HI!");
+        assert_eq!(tape, [0, 72, 73, 33]);
+    }
+
+    #[test]
+    fn test_nested_functions() {
+        let ir = vec![
+            node(IrOp::FunctionCall("outer".into())),
+            node(IrOp::Function(
+                "outer".into(),
+                vec![
+                    node(IrOp::Function("inner".into(), vec![node(IrOp::Add(2))])),
+                    node(IrOp::FunctionCall("inner".into())),
+                    node(IrOp::FunctionCall("inner".into())),
+                ],
+            )),
+        ];
+        let mut tape = [0; 1];
+        interpret(&ir, &mut tape, &[]);
+        assert_eq!(tape, [4]);
+
+        let ir = vec![
+            node(IrOp::Function(
+                "outer".into(),
+                vec![node(IrOp::Function("inner".into(), vec![]))],
+            )),
+            node(IrOp::FunctionCall("inner".into())),
+        ];
+        assert_eq!(
+            try_interpret(&ir, &mut tape, &[], None).unwrap_err().kind,
+            InterpreterErrorKind::FunctionNotFound("inner".into())
+        );
+    }
+
+    #[test]
+    fn test_indirect_call() {
+        let program = |cell| {
+            vec![
+                node(IrOp::Function("zero".into(), vec![node(IrOp::Add(10))])),
+                node(IrOp::Function("one".into(), vec![node(IrOp::Add(20))])),
+                node(IrOp::Add(cell)),
+                node(IrOp::IndirectCall {
+                    table: vec!["zero".into(), "one".into()],
+                }),
+            ]
+        };
+        let run = |cell| {
+            let mut tape = [0; 1];
+            interpret(&program(cell), &mut tape, &[]);
+            tape[0]
+        };
+        assert_eq!(run(0), 10);
+        assert_eq!(run(1), 21);
+        // out of range, nothing is called
+        assert_eq!(run(2), 2);
+    }
+
+    #[test]
+    fn test_external_defined_by_program() {
+        let mut tape = [0; 1];
+        interpret(
+            &[
+                node(IrOp::Function("putchar".into(), vec![node(IrOp::Add(1))])),
+                node(IrOp::ExternalFunctionCall(ExternalCall::new(
+                    "putchar".into(),
+                ))),
+            ],
+            &mut tape,
+            &[],
+        );
+        assert_eq!(tape, [1]);
+    }
+
+    #[test]
+    fn test_seek_cell() {
+        let mut tape = [0; 4];
+        interpret(
+            &[
+                node(IrOp::MoveRight(1)),
+                node(IrOp::SeekCell(3)),
+                node(IrOp::Add(1)),
+                node(IrOp::SeekCell(0)),
+                node(IrOp::Add(2)),
+            ],
+            &mut tape,
+            &[],
+        );
+        assert_eq!(tape, [2, 0, 0, 1]);
+    }
+
+    #[test]
+    fn test_add_at_offset() {
+        let mut tape = [0; 3];
+        interpret(
+            &[
+                node(IrOp::MoveRight(1)),
+                node(IrOp::AddAtOffset {
+                    offset: 1,
+                    amount: 2,
+                }),
+                node(IrOp::AddAtOffset {
+                    offset: -1,
+                    amount: 0xff,
+                }),
+                node(IrOp::Add(3)),
+            ],
+            &mut tape,
+            &[],
+        );
+        assert_eq!(tape, [0xff, 3, 2]);
+    }
+
+    #[test]
+    fn test_add_from_cell() {
+        let mut tape = [3, 4, 5];
+        interpret(
+            &[
+                node(IrOp::MoveRight(1)),
+                node(IrOp::AddFromCell { src_offset: -1 }),
+                node(IrOp::AddFromCell { src_offset: 1 }),
+                // doubles the cell
+                node(IrOp::MoveRight(1)),
+                node(IrOp::AddFromCell { src_offset: 0 }),
+            ],
+            &mut tape,
+            &[],
+        );
+        assert_eq!(tape, [3, 12, 10]);
+
+        assert_eq!(
+            try_interpret(
+                &[node(IrOp::AddFromCell { src_offset: 3 })],
+                &mut [0; 2],
+                &[],
+                None
+            )
+            .unwrap_err()
+            .kind,
+            InterpreterErrorKind::OutOfTape(3)
+        );
+    }
+
+    #[test]
+    fn test_if_eq() {
+        let body = || {
+            vec![
+                node(IrOp::Add(1)),
+                // doesn't loop, even though the cell isn't zero
+                node(IrOp::WriteByte),
+            ]
+        };
+        let mut tape = [7, 0];
+        let output = interpret(
+            &[
+                node(IrOp::IfEq {
+                    value: 7,
+                    body: body(),
+                }),
+                node(IrOp::MoveRight(1)),
+                node(IrOp::IfEq {
+                    value: 7,
+                    body: body(),
+                }),
+            ],
+            &mut tape,
+            &[],
+        );
+        assert_eq!(output, [8]);
+        assert_eq!(tape, [8, 0]);
+    }
+
+    #[test]
+    fn test_switch() {
+        let switch = node(IrOp::Switch {
+            arms: vec![
+                (1, vec![node(IrOp::Add(10))]),
+                (2, vec![node(IrOp::Add(20))]),
+                // never runs, the first arm for a value wins
+                (1, vec![node(IrOp::Add(30))]),
+            ],
+            default: vec![node(IrOp::MulConst(0))],
+        });
+        let mut tape = [1, 2, 3];
+        interpret(
+            &[
+                switch.clone(),
+                node(IrOp::MoveRight(1)),
+                switch.clone(),
+                node(IrOp::MoveRight(1)),
+                switch,
+            ],
+            &mut tape,
+            &[],
+        );
+        assert_eq!(tape, [11, 22, 0]);
+    }
+
+    #[test]
+    fn test_read_bytes() {
+        let mut tape = [7; 5];
+        let output = interpret(
+            &[node(IrOp::ReadBytes(3)), node(IrOp::WriteByte)],
+            &mut tape,
+            b"ab",
+        );
+        // the third cell isn't read into and keeps its value
+        assert_eq!(tape, [b'a', b'b', 7, 2, 7]);
+        assert_eq!(output, [2]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let run = |ir: Vec<IrNode>| {
+            try_interpret(&ir, &mut [1; 2], &[], Some(100))
+                .unwrap_err()
+                .kind
+        };
+
+        assert_eq!(
+            run(vec![node(IrOp::MoveLeft(1)), node(IrOp::Add(1))]),
+            InterpreterErrorKind::OutOfTape(-1)
+        );
+        assert_eq!(
+            run(vec![node(IrOp::WriteBytes(3))]),
+            InterpreterErrorKind::OutOfTape(2)
+        );
+        assert_eq!(
+            run(vec![node(IrOp::StackPop)]),
+            InterpreterErrorKind::StackUnderflow
+        );
+        assert_eq!(
+            run(vec![node(IrOp::Condition(vec![]))]),
+            InterpreterErrorKind::OutOfFuel
+        );
+        assert_eq!(
+            run(vec![node(IrOp::ExternalFunctionCall(ExternalCall::new(
+                "putchar".into()
+            )))]),
+            InterpreterErrorKind::Unsupported("ExternalFunctionCall")
+        );
+
+        // leaving the tape is fine as long as nothing is accessed out there
+        let ir = [
+            node(IrOp::MoveLeft(5)),
+            node(IrOp::MoveRight(6)),
+            node(IrOp::Add(1)),
+        ];
+        let mut tape = [0; 2];
+        interpret(&ir, &mut tape, &[]);
+        assert_eq!(tape, [0, 1]);
+    }
+
+    #[test]
+    fn test_optimized_program_behaves_the_same() {
+        let settings = CompilerSettings {
+            optimization_level: 1,
+            ..Default::default()
+        };
+        let optimized = optimizer::optimize(program(), &settings);
+        assert_ne!(optimized, program());
+
+        let (mut tape, mut optimized_tape) = ([0; 4], [0; 4]);
+        assert_eq!(
+            interpret(&program(), &mut tape, b"!"),
+            interpret(&optimized, &mut optimized_tape, b"!")
+        );
+        assert_eq!(tape, optimized_tape);
+    }
+}
@@ -1,15 +1,17 @@
-// TODO: Implement optimisation passes
-
 use alloc::string::String;
 use alloc::vec::Vec;
 
-use hashbrown::HashMap;
+use crate::Map;
 
 use hf_parser_rust::ast::{AstNode, SyntaxNode};
 
-#[derive(Debug, Clone, PartialEq, Copy)]
+/// The region of the source code a node was produced from.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Copy)]
 pub struct Span {
+    /// The line and column of the first character, both counted from 0.
+    /// Columns count characters, not bytes.
     pub location: (usize, usize),
+    /// How many characters the span covers, not counting line breaks.
     pub length: usize, // We only need the length as we can calculate the rest
 }
 
@@ -27,9 +29,63 @@ impl Span {
             length: self.length + length,
         }
     }
+
+    /// Renders the span as `line:column` of its first character, or as
+    /// `line:column-line:column` of its first and last character if it covers
+    /// more than one, counting from 1 like editors do. Prefix it with the
+    /// filename for `file:line:column`.
+    ///
+    /// The span is clamped to `src`, so a span that doesn't fit the source it
+    /// is rendered against still renders, just not meaningfully.
+    pub fn render(&self, src: &str) -> String {
+        let lines = src
+            .lines()
+            .map(|line| line.chars().count())
+            .collect::<Vec<_>>();
+        let last_line = lines.len().saturating_sub(1);
+        let (mut line, mut column) = self.location;
+        if line > last_line {
+            (line, column) = (last_line, usize::MAX);
+        }
+        let line_length = |line: usize| lines.get(line).copied().unwrap_or(0);
+        column = column.min(line_length(line).saturating_sub(1));
+        let start = (line, column);
+
+        let mut remaining = self.length.saturating_sub(1);
+        while remaining > 0 {
+            let length = line_length(line);
+            if column + remaining < length {
+                column += remaining;
+                break;
+            }
+            // on to the first character of the next line that has any
+            match (line + 1..lines.len()).find(|&line| line_length(line) > 0) {
+                Some(next) => {
+                    remaining -= (length - column).clamp(1, remaining);
+                    (line, column) = (next, 0);
+                }
+                None => {
+                    column = length.saturating_sub(1);
+                    break;
+                }
+            }
+        }
+
+        if (line, column) == start {
+            format!("{}:{}", start.0 + 1, start.1 + 1)
+        } else {
+            format!(
+                "{}:{}-{}:{}",
+                start.0 + 1,
+                start.1 + 1,
+                line + 1,
+                column + 1
+            )
+        }
+    }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Hash)]
 pub enum IrOp {
     Add(usize),
     Subtract(usize),
@@ -40,11 +96,196 @@ pub enum IrOp {
     MemAlloc(usize),
     Function(String, Vec<IrNode>),
     FunctionCall(String),
-    ExternalFunctionCall(String),
+    /// Calls the function at the index given by the current cell in `table`,
+    /// for dispatch tables. Nothing is called if the cell is out of range.
+    IndirectCall {
+        table: Vec<String>,
+    },
+    /// Calls a function outside of the program. If the program defines a
+    /// function with the same name, which a `FunctionCall` could call, that
+    /// one is called like a `FunctionCall` instead, so programs can bring their
+    /// own implementation of externals and link without any.
+    ExternalFunctionCall(ExternalCall),
     Condition(Vec<IrNode>),
+    /// Multiplies the current cell by a constant, wrapping around on
+    /// overflow. For front-ends that know they are emitting `cell *= k`,
+    /// rather than a loop that has to be recognised as such.
+    MulConst(u8),
+    /// Writes the current cell to standard output.
+    WriteByte,
+    /// Writes this many cells, starting at the current one, to standard output
+    /// at once. The cell pointer doesn't move.
+    WriteBytes(usize),
+    /// Reads a byte from standard input into the current cell. At the end of
+    /// the input the cell is left unchanged.
+    ReadByte,
+    /// Reads up to this many bytes from standard input into as many cells,
+    /// starting at the current one, with a single `read` unless
+    /// [`CompilerSettings::short_read`] retries. Cells past the end of the
+    /// input are left unchanged. The cell pointer then moves past them, and
+    /// the number of bytes read is stored in the cell it ends up at, or 255 if
+    /// more were read.
+    ///
+    /// [`CompilerSettings::short_read`]: crate::compiler::CompilerSettings::short_read
+    ReadBytes(u32),
+    /// Emits no code. Left behind by optimisation passes in place of nodes
+    /// they eliminated, so the node's span still records which source region
+    /// produced no code.
+    Nop,
+    /// Emits no code, but marks its place in the code with a name, for finding
+    /// your way around a disassembly. Object files get a local symbol with the
+    /// name, see also [`CompilationStats::markers`]. Optimisation passes keep
+    /// markers, but may move them past the ops they fold.
+    ///
+    /// [`CompilationStats::markers`]: crate::compiler::CompilationStats::markers
+    DebugMarker(String),
+    /// Points the cell pointer at the external data symbol with this name,
+    /// which the linker resolves, e.g. to work on a buffer defined in C. Only
+    /// object files can refer to external data.
+    MoveToExternal(String),
+    /// Points the cell pointer at the cell with this index, wherever it is
+    /// now. Only object files with a [`CompilerSettings::static_tape`] know
+    /// where the tape starts, and the index has to be inside of it.
+    ///
+    /// [`CompilerSettings::static_tape`]: crate::compiler::CompilerSettings::static_tape
+    SeekCell(usize),
+    /// Adds `amount` to the cell `offset` cells away from the current one,
+    /// wrapping around on overflow, without moving the cell pointer. What a
+    /// move there, an `Add` or `Subtract`, and a move back are folded into.
+    AddAtOffset {
+        offset: i32,
+        amount: u8,
+    },
+    /// Adds `amount` to each of the `count` cells starting `offset` cells away
+    /// from the current one, like that many `AddAtOffset`s. What
+    /// [`crate::optimizer::VectorAdds`] fuses them into, for SSE2 to add 16
+    /// cells at a time.
+    AddToCells {
+        offset: i32,
+        count: u32,
+        amount: u8,
+    },
+    /// Adds the cell `src_offset` cells away from the current one to the
+    /// current one, wrapping around on overflow, without moving the cell
+    /// pointer. What copy and multiply loops like `[->+<]` do to each cell
+    /// they add to, as seen from that cell.
+    AddFromCell {
+        src_offset: i32,
+    },
+    /// Runs `body` once if the current cell equals `value`, and skips it
+    /// otherwise. Unlike a `Condition`, it doesn't loop, and the body may
+    /// leave the cell as it likes.
+    IfEq {
+        value: u8,
+        body: Vec<IrNode>,
+    },
+    /// Runs the body of the first arm whose value the current cell equals, or
+    /// `default` if there is none, once, like a chain of `IfEq`s that stops at
+    /// the first match. The x86 backend dispatches through a jump table when
+    /// the arm values are dense enough, and compares them one by one if not.
+    Switch {
+        arms: Vec<(u8, Vec<IrNode>)>,
+        default: Vec<IrNode>,
+    },
+    /// Adds the current cell to the cell `offset` cells away from it, wrapping
+    /// around on overflow, and clears the current one, without moving the
+    /// cell pointer. What [`crate::optimizer::MoveLoops`] turns loops like
+    /// `[->+<]` into.
+    MoveCell {
+        offset: i32,
+    },
+}
+
+/// A call to a function outside of the program, resolved by the linker.
+#[derive(Debug, Clone, PartialEq, Hash)]
+pub struct ExternalCall {
+    pub name: String,
+    /// Store the low byte of the function's return value into the current
+    /// cell once it returns, for externals like `getchar` that produce a value.
+    pub store_result: bool,
+    /// Store the low byte of the function's return value into the cell at
+    /// this offset from the current one, for system-call-style externals that
+    /// return an error code for the program to check. Works alongside
+    /// `store_result`, which gets the same byte. Like it, this has no effect
+    /// when the program defines the function itself.
+    pub status_offset: Option<i32>,
+    /// The parts of the program state the callee may overwrite. Anything it
+    /// doesn't clobber is not saved around the call.
+    pub clobbers: Clobbers,
+}
+
+impl ExternalCall {
+    pub fn new(name: String) -> Self {
+        Self {
+            name,
+            store_result: false,
+            status_offset: None,
+            clobbers: Clobbers::ALL,
+        }
+    }
+}
+
+/// The registers holding the program state that an external function is
+/// allowed to overwrite.
+///
+/// The callee receives the cell pointer and the stack pointer as its first two
+/// arguments. A pointer it may clobber is saved on the stack and passed by
+/// address, so the callee can also move it. A pointer the callee preserves is
+/// passed by value instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Clobbers {
+    pub cell_pointer: bool,
+    pub stack_pointer: bool,
+}
+
+impl Clobbers {
+    /// Everything the calling convention lets the callee clobber. This is the
+    /// safe default for externals we know nothing about.
+    pub const ALL: Self = Self {
+        cell_pointer: true,
+        stack_pointer: true,
+    };
+    /// For well-behaved leaf functions that preserve every register we use.
+    pub const NONE: Self = Self {
+        cell_pointer: false,
+        stack_pointer: false,
+    };
 }
 
 impl IrOp {
+    /// The name of the variant, used to describe the op in diagnostics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Add(_) => "Add",
+            Self::Subtract(_) => "Subtract",
+            Self::MoveRight(_) => "MoveRight",
+            Self::MoveLeft(_) => "MoveLeft",
+            Self::StackPush => "StackPush",
+            Self::StackPop => "StackPop",
+            Self::MemAlloc(_) => "MemAlloc",
+            Self::Function(_, _) => "Function",
+            Self::FunctionCall(_) => "FunctionCall",
+            Self::IndirectCall { .. } => "IndirectCall",
+            Self::ExternalFunctionCall(_) => "ExternalFunctionCall",
+            Self::Condition(_) => "Condition",
+            Self::MulConst(_) => "MulConst",
+            Self::WriteByte => "WriteByte",
+            Self::WriteBytes(_) => "WriteBytes",
+            Self::ReadByte => "ReadByte",
+            Self::ReadBytes(_) => "ReadBytes",
+            Self::Nop => "Nop",
+            Self::DebugMarker(_) => "DebugMarker",
+            Self::MoveToExternal(_) => "MoveToExternal",
+            Self::SeekCell(_) => "SeekCell",
+            Self::AddAtOffset { .. } => "AddAtOffset",
+            Self::AddToCells { .. } => "AddToCells",
+            Self::AddFromCell { .. } => "AddFromCell",
+            Self::IfEq { .. } => "IfEq",
+            Self::Switch { .. } => "Switch",
+            Self::MoveCell { .. } => "MoveCell",
+        }
+    }
+
     fn equals_extend(&mut self, op: &SyntaxNode) -> bool {
         match self {
             Self::Add(n) => {
@@ -92,7 +333,7 @@ impl IrOp {
     }
 }
 
-#[derive(Clone, PartialEq)]
+#[derive(Clone, PartialEq, Hash)]
 pub struct IrNode {
     pub node: IrOp,
     pub span: Span,
@@ -101,7 +342,10 @@ pub struct IrNode {
 impl core::fmt::Debug for IrNode {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         if f.alternate() && matches!(self.node, IrOp::Function(_, _))
-            || matches!(self.node, IrOp::Condition(_))
+            || matches!(
+                self.node,
+                IrOp::Condition(_) | IrOp::IfEq { .. } | IrOp::Switch { .. }
+            )
         {
             write!(
                 f,
@@ -157,7 +401,9 @@ impl IrBuilder {
                 SyntaxNode::MemAlloc(n) => IrOp::MemAlloc(n),
                 SyntaxNode::Function(name, args) => IrOp::Function(name, from_ast(args)),
                 SyntaxNode::FuncCall(name) => IrOp::FunctionCall(name),
-                SyntaxNode::ExternalFunctionCall(code) => IrOp::ExternalFunctionCall(code),
+                SyntaxNode::ExternalFunctionCall(code) => {
+                    IrOp::ExternalFunctionCall(ExternalCall::new(code))
+                }
                 SyntaxNode::Condition(conditions) => IrOp::Condition(from_ast(conditions)),
             },
             span: Span::from_location(ast.location),
@@ -191,9 +437,29 @@ pub fn from_ast(ast: Vec<AstNode>) -> Vec<IrNode> {
     ir_nodes
 }
 
+/// The deepest nesting of `Condition`s and `Function`s in `nodes`, each of
+/// which is one level, for planning or rejecting a compilation up front. An
+/// `IfEq` or `Switch` is no level of its own, but the nodes in its bodies
+/// are counted where they are.
+pub fn max_loop_depth(nodes: &[IrNode]) -> usize {
+    nodes
+        .iter()
+        .map(|node| match &node.node {
+            IrOp::Function(_, body) | IrOp::Condition(body) => 1 + max_loop_depth(body),
+            IrOp::IfEq { body, .. } => max_loop_depth(body),
+            IrOp::Switch { arms, default } => arms
+                .iter()
+                .map(|(_, body)| max_loop_depth(body))
+                .fold(max_loop_depth(default), usize::max),
+            _ => 0,
+        })
+        .max()
+        .unwrap_or(0)
+}
+
 fn fix_func_names(ir: &mut Vec<IrNode>) {
     let mut i = 1usize;
-    let mut name_map = HashMap::new();
+    let mut name_map = Map::new();
 
     for node in ir.iter_mut() {
         match &mut node.node {
@@ -216,6 +482,13 @@ fn fix_func_names(ir: &mut Vec<IrNode>) {
                     *name = new_name.clone();
                 }
             }
+            IrOp::IndirectCall { table } => {
+                for name in table {
+                    if let Some(new_name) = name_map.get(&*name) {
+                        *name = new_name.clone();
+                    }
+                }
+            }
             _ => {}
         }
     }
@@ -223,7 +496,7 @@ fn fix_func_names(ir: &mut Vec<IrNode>) {
 
 fn flatten_ir_impl(
     scope: Vec<String>,
-    scope_funcs: &HashMap<String, String>,
+    scope_funcs: &Map<String, String>,
     ir: Vec<IrNode>,
 ) -> (Vec<IrNode>, Vec<IrNode>) {
     let mut fns = Vec::new();
@@ -257,6 +530,16 @@ fn flatten_ir_impl(
                     span: node.span,
                 });
             }
+            IrOp::IndirectCall { table } => {
+                let table = table
+                    .into_iter()
+                    .map(|name| new_scope_funcs.get(&name).cloned().unwrap_or(name))
+                    .collect();
+                non_fn_ir.push(IrNode {
+                    node: IrOp::IndirectCall { table },
+                    span: node.span,
+                });
+            }
             _ => non_fn_ir.push(node),
         }
     }
@@ -265,7 +548,7 @@ fn flatten_ir_impl(
 }
 
 fn flatten_ir(ir: Vec<IrNode>) -> Vec<IrNode> {
-    let (mut fns, non_fn_ir) = flatten_ir_impl(Vec::new(), &HashMap::new(), ir);
+    let (mut fns, non_fn_ir) = flatten_ir_impl(Vec::new(), &Map::new(), ir);
     fns.extend(non_fn_ir);
     fns
 }
@@ -276,6 +559,24 @@ mod tests {
     use alloc::string::ToString;
     use hf_parser_rust::ast::{AstNode, SyntaxNode};
 
+    #[test]
+    fn test_span_render() {
+        let src = "+[\n->\n]";
+        let span = |location, length| Span { location, length };
+
+        // the first and last character
+        assert_eq!(span((0, 0), 1).render(src), "1:1");
+        assert_eq!(span((2, 0), 1).render(src), "3:1");
+        // across lines, line breaks don't count
+        assert_eq!(span((0, 0), 5).render(src), "1:1-3:1");
+        assert_eq!(span((0, 1), 2).render(src), "1:2-2:1");
+        // out of range
+        assert_eq!(span((0, 1), 100).render(src), "1:2-3:1");
+        assert_eq!(span((7, 7), 1).render(src), "3:1");
+        assert_eq!(span((1, 7), 1).render(src), "2:2");
+        assert_eq!(span((0, 0), 1).render(""), "1:1");
+    }
+
     #[test]
     fn test_from_ast_with_empty_vec() {
         let ast = Vec::new();
@@ -423,4 +724,32 @@ mod tests {
             },]
         );
     }
+
+    #[test]
+    fn test_max_loop_depth() {
+        let node = |node| IrNode {
+            node,
+            span: Span::from_location((0, 0)),
+        };
+        let innermost = node(IrOp::Condition(vec![node(IrOp::Subtract(1))]));
+        let middle = node(IrOp::Condition(vec![innermost]));
+        let ir = vec![
+            node(IrOp::Add(1)),
+            node(IrOp::Condition(vec![middle])),
+            node(IrOp::Function("f".to_string(), vec![])),
+        ];
+        assert_eq!(max_loop_depth(&ir), 3);
+        assert_eq!(max_loop_depth(&ir[2..]), 1);
+        assert_eq!(max_loop_depth(&[]), 0);
+
+        // neither is a level, but what is in them is
+        let ir = vec![node(IrOp::IfEq {
+            value: 1,
+            body: vec![node(IrOp::Switch {
+                arms: vec![(0, ir)],
+                default: vec![],
+            })],
+        })];
+        assert_eq!(max_loop_depth(&ir), 3);
+    }
 }
@@ -3,8 +3,26 @@
 extern crate alloc;
 
 pub mod compiler;
+#[cfg(feature = "arbitrary")]
+pub mod fuzz;
+pub mod interpreter;
 pub mod ir;
+pub mod optimizer;
 pub mod target;
 pub mod scope;
 
 pub use compiler::{CompilerError, CompilerErrorKind};
+
+/// The map used throughout the crate, including in public types like
+/// [`compiler::CompilationStats`].
+///
+/// With the `btree-maps` feature, or without the `hashbrown` one, this is a
+/// `BTreeMap`, which iterates in order of its keys. Object files then come out
+/// the same on every run, as the order of the symbols the compiler adds no
+/// longer depends on hash seeds.
+pub type Map<K, V> = MapImpl<K, V>;
+
+#[cfg(any(feature = "btree-maps", not(feature = "hashbrown")))]
+type MapImpl<K, V> = alloc::collections::BTreeMap<K, V>;
+#[cfg(all(feature = "hashbrown", not(feature = "btree-maps")))]
+type MapImpl<K, V> = hashbrown::HashMap<K, V>;
@@ -0,0 +1,184 @@
+use alloc::vec::Vec;
+
+use super::IrPass;
+use crate::compiler::CompilerSettings;
+use crate::ir::{IrNode, IrOp, Span};
+
+/// Coalesces writes of neighbouring cells into a single `WriteBytes`, so a run
+/// of output costs one `write` call instead of one per byte.
+///
+/// `.>.>.` becomes `WriteBytes(3)` followed by `MoveRight(2)`. Only writes
+/// separated by nothing but a move to the first cell not written yet are
+/// merged: the cells are then contiguous, and since nothing else happens in
+/// between, their values are final by the time the batched write runs.
+pub struct BatchWrites;
+
+impl IrPass for BatchWrites {
+    fn name(&self) -> &'static str {
+        "batch-writes"
+    }
+
+    fn run(&mut self, ir: Vec<IrNode>, _settings: &CompilerSettings) -> Vec<IrNode> {
+        batch(ir)
+    }
+}
+
+fn batch(ir: Vec<IrNode>) -> Vec<IrNode> {
+    let mut out = Vec::with_capacity(ir.len());
+    let mut ir = ir.into_iter().peekable();
+
+    while let Some(mut node) = ir.next() {
+        let Some(mut written) = write_count(&node.node) else {
+            match &mut node.node {
+                IrOp::Function(_, children)
+                | IrOp::Condition(children)
+                | IrOp::IfEq { body: children, .. } => {
+                    *children = batch(core::mem::take(children));
+                }
+                IrOp::Switch { arms, default } => {
+                    for body in arms.iter_mut().map(|(_, body)| body).chain([default]) {
+                        *body = batch(core::mem::take(body));
+                    }
+                }
+                _ => {}
+            }
+            out.push(node);
+            continue;
+        };
+
+        let mut span = node.span;
+        // where the cell pointer is, relative to the first written cell
+        let mut offset = 0;
+        while let Some(IrOp::MoveRight(n)) = ir.peek().map(|n| &n.node) {
+            if offset + n != written {
+                break;
+            }
+            let n = *n;
+            let move_node = ir.next().unwrap();
+            match ir.peek().and_then(|next| write_count(&next.node)) {
+                Some(count) => {
+                    let write_node = ir.next().unwrap();
+                    offset += n;
+                    written += count;
+                    span = span.extend(move_node.span.length + write_node.span.length);
+                }
+                None => {
+                    // not followed by a write, so it isn't part of the run
+                    push_run(&mut out, written, offset, span);
+                    node = move_node;
+                    written = 0;
+                    break;
+                }
+            }
+        }
+
+        if written == 0 {
+            out.push(node);
+        } else {
+            push_run(&mut out, written, offset, span);
+        }
+    }
+
+    out
+}
+
+fn write_count(op: &IrOp) -> Option<usize> {
+    match op {
+        IrOp::WriteByte => Some(1),
+        IrOp::WriteBytes(n) => Some(*n),
+        _ => None,
+    }
+}
+
+fn push_run(out: &mut Vec<IrNode>, written: usize, offset: usize, span: Span) {
+    out.push(IrNode {
+        node: if written == 1 {
+            IrOp::WriteByte
+        } else {
+            IrOp::WriteBytes(written)
+        },
+        span,
+    });
+    if offset > 0 {
+        out.push(IrNode {
+            node: IrOp::MoveRight(offset),
+            span,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::optimizer::{merged, node, run_pass};
+
+    #[test]
+    fn test_batches_neighbouring_writes() {
+        let ir = vec![
+            node(IrOp::WriteByte, 0),
+            node(IrOp::MoveRight(1), 1),
+            node(IrOp::WriteByte, 2),
+            node(IrOp::MoveRight(1), 3),
+            node(IrOp::WriteByte, 4),
+            node(IrOp::Add(1), 5),
+        ];
+        assert_eq!(
+            run_pass(BatchWrites, ir, false),
+            vec![
+                merged(IrOp::WriteBytes(3), 0, 5),
+                merged(IrOp::MoveRight(2), 0, 5),
+                node(IrOp::Add(1), 5),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_extends_batched_writes() {
+        let ir = vec![
+            node(IrOp::WriteBytes(2), 0),
+            node(IrOp::MoveRight(2), 1),
+            node(IrOp::WriteByte, 2),
+        ];
+        assert_eq!(
+            run_pass(BatchWrites, ir, false),
+            vec![
+                merged(IrOp::WriteBytes(3), 0, 3),
+                merged(IrOp::MoveRight(2), 0, 3),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_leaves_gaps_and_trailing_moves() {
+        let ir = vec![
+            node(IrOp::WriteByte, 0),
+            node(IrOp::MoveRight(2), 1),
+            node(IrOp::WriteByte, 2),
+            node(IrOp::MoveRight(1), 3),
+            node(
+                IrOp::Condition(vec![
+                    node(IrOp::WriteByte, 5),
+                    node(IrOp::MoveRight(1), 6),
+                    node(IrOp::WriteByte, 7),
+                ]),
+                4,
+            ),
+        ];
+        assert_eq!(
+            run_pass(BatchWrites, ir, false),
+            vec![
+                node(IrOp::WriteByte, 0),
+                node(IrOp::MoveRight(2), 1),
+                node(IrOp::WriteByte, 2),
+                node(IrOp::MoveRight(1), 3),
+                node(
+                    IrOp::Condition(vec![
+                        merged(IrOp::WriteBytes(2), 5, 3),
+                        merged(IrOp::MoveRight(1), 5, 3),
+                    ]),
+                    4
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,245 @@
+use alloc::vec::Vec;
+
+use super::{eliminated, IrPass};
+use crate::compiler::CompilerSettings;
+use crate::ir::{IrNode, IrOp};
+
+/// Folds runs of `Add`/`Subtract` and of `MoveRight`/`MoveLeft` into their net
+/// effect, removing them entirely when they cancel out.
+///
+/// `+++--` becomes `Add(1)`, `><` disappears. Cells are bytes, so arithmetic
+/// is folded modulo 256. Debug markers within a run don't end it, they are
+/// moved after the folded op instead.
+pub struct CancelOpposites;
+
+impl IrPass for CancelOpposites {
+    fn name(&self) -> &'static str {
+        "cancel-opposites"
+    }
+
+    fn run(&mut self, ir: Vec<IrNode>, settings: &CompilerSettings) -> Vec<IrNode> {
+        let mut out: Vec<IrNode> = Vec::with_capacity(ir.len());
+        // The run currently being folded: its first node and the net effect.
+        let mut run: Option<(IrNode, Net)> = None;
+        // Debug markers within the run.
+        let mut markers = Vec::new();
+
+        for mut node in ir {
+            match &mut node.node {
+                IrOp::Function(_, children)
+                | IrOp::Condition(children)
+                | IrOp::IfEq { body: children, .. } => {
+                    *children = self.run(core::mem::take(children), settings);
+                }
+                IrOp::Switch { arms, default } => {
+                    for body in arms.iter_mut().map(|(_, body)| body).chain([default]) {
+                        *body = self.run(core::mem::take(body), settings);
+                    }
+                }
+                _ => {}
+            }
+
+            if run.is_some() && matches!(node.node, IrOp::DebugMarker(_)) {
+                markers.push(node);
+                continue;
+            }
+
+            if let Some(net) = Net::of(&node.node) {
+                if let Some((first, acc)) = &mut run {
+                    if let Some(combined) = acc.combine(net) {
+                        *acc = combined;
+                        first.span = first.span.extend(node.span.length);
+                        continue;
+                    }
+                }
+                flush(&mut out, run.take(), settings);
+                out.append(&mut markers);
+                run = Some((node, net));
+                continue;
+            }
+
+            // Anything else ends the run, which is what keeps the runs on
+            // either side of a fence apart.
+            flush(&mut out, run.take(), settings);
+            out.append(&mut markers);
+            out.push(node);
+        }
+        flush(&mut out, run, settings);
+        out.append(&mut markers);
+
+        out
+    }
+}
+
+/// The net effect of a run of arithmetic or pointer moves.
+#[derive(Debug, Clone, Copy)]
+enum Net {
+    Arithmetic(u8),
+    Move(i64),
+}
+
+impl Net {
+    /// The effect of a single op, if it is arithmetic or a move that fits an
+    /// `i64`. Larger moves are left as they are.
+    fn of(op: &IrOp) -> Option<Self> {
+        match *op {
+            IrOp::Add(n) => Some(Self::Arithmetic(n as u8)),
+            IrOp::Subtract(n) => Some(Self::Arithmetic((n as u8).wrapping_neg())),
+            IrOp::MoveRight(n) => i64::try_from(n).ok().map(Self::Move),
+            IrOp::MoveLeft(n) => i64::try_from(n).ok().map(|n| Self::Move(-n)),
+            _ => None,
+        }
+    }
+
+    /// The effect of both, if they are of the same kind and a net move still
+    /// fits an `i64`.
+    fn combine(self, other: Self) -> Option<Self> {
+        match (self, other) {
+            (Self::Arithmetic(a), Self::Arithmetic(b)) => Some(Self::Arithmetic(a.wrapping_add(b))),
+            (Self::Move(a), Self::Move(b)) => a.checked_add(b).map(Self::Move),
+            _ => None,
+        }
+    }
+
+    fn into_op(self) -> Option<IrOp> {
+        match self {
+            Self::Arithmetic(0) | Self::Move(0) => None,
+            Self::Arithmetic(n) => Some(IrOp::Add(n as usize)),
+            Self::Move(n) if n > 0 => Some(IrOp::MoveRight(n as usize)),
+            Self::Move(n) => Some(IrOp::MoveLeft(n.unsigned_abs() as usize)),
+        }
+    }
+}
+
+fn flush(out: &mut Vec<IrNode>, run: Option<(IrNode, Net)>, settings: &CompilerSettings) {
+    let Some((first, net)) = run else {
+        return;
+    };
+    match net.into_op() {
+        Some(node) => out.push(IrNode {
+            node,
+            span: first.span,
+        }),
+        None => out.extend(eliminated(first.span, settings)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::ExternalCall;
+    use crate::optimizer::is_fence;
+    use crate::optimizer::{merged, node, run_pass};
+
+    #[test]
+    fn test_folds_to_net_effect() {
+        let ir = vec![
+            node(IrOp::Add(3), 0),
+            node(IrOp::Subtract(1), 3),
+            node(IrOp::MoveRight(1), 4),
+            node(IrOp::MoveLeft(3), 5),
+        ];
+        assert_eq!(
+            run_pass(CancelOpposites, ir, false),
+            vec![merged(IrOp::Add(2), 0, 2), merged(IrOp::MoveLeft(2), 4, 2)]
+        );
+    }
+
+    #[test]
+    fn test_subtract_wraps() {
+        let ir = vec![node(IrOp::Add(1), 0), node(IrOp::Subtract(3), 1)];
+        assert_eq!(
+            run_pass(CancelOpposites, ir, false),
+            vec![merged(IrOp::Add(254), 0, 2)]
+        );
+    }
+
+    #[test]
+    fn test_removes_cancelled_runs() {
+        let ir = vec![
+            node(
+                IrOp::Condition(vec![
+                    node(IrOp::MoveRight(2), 1),
+                    node(IrOp::MoveLeft(2), 3),
+                ]),
+                0,
+            ),
+            node(IrOp::Add(1), 6),
+            node(IrOp::Subtract(1), 7),
+        ];
+        assert_eq!(
+            run_pass(CancelOpposites, ir, false),
+            vec![node(IrOp::Condition(vec![]), 0)]
+        );
+    }
+
+    #[test]
+    fn test_leaves_overflowing_moves() {
+        let ir = vec![
+            node(IrOp::MoveRight(1 << 62), 0),
+            node(IrOp::MoveRight(1 << 62), 1),
+            node(IrOp::MoveLeft(usize::MAX), 2),
+            node(IrOp::MoveLeft(1), 3),
+        ];
+        assert_eq!(run_pass(CancelOpposites, ir.clone(), false), ir);
+    }
+
+    #[test]
+    fn test_folds_across_debug_markers() {
+        let ir = vec![
+            node(IrOp::Add(1), 0),
+            node(IrOp::DebugMarker("here".into()), 1),
+            node(IrOp::Add(1), 2),
+            node(IrOp::MoveRight(1), 3),
+        ];
+        assert_eq!(
+            run_pass(CancelOpposites, ir, false),
+            vec![
+                merged(IrOp::Add(2), 0, 2),
+                node(IrOp::DebugMarker("here".into()), 1),
+                node(IrOp::MoveRight(1), 3),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_no_folding_across_fences() {
+        let ir = vec![
+            node(IrOp::Add(1), 0),
+            node(
+                IrOp::ExternalFunctionCall(ExternalCall::new("putchar".into())),
+                1,
+            ),
+            node(IrOp::Add(2), 2),
+            node(IrOp::MoveRight(1), 3),
+            node(IrOp::ReadByte, 4),
+            node(IrOp::MoveLeft(1), 5),
+            node(IrOp::WriteByte, 6),
+            node(IrOp::Add(1), 7),
+        ];
+        assert!([1, 4, 6].iter().all(|&i| is_fence(&ir[i].node)));
+        assert_eq!(run_pass(CancelOpposites, ir.clone(), false), ir);
+    }
+
+    #[test]
+    fn test_preserve_spans() {
+        let ir = vec![
+            node(
+                IrOp::Condition(vec![
+                    node(IrOp::MoveRight(2), 1),
+                    node(IrOp::MoveLeft(2), 3),
+                ]),
+                0,
+            ),
+            node(IrOp::Add(1), 6),
+            node(IrOp::Subtract(1), 7),
+        ];
+        assert_eq!(
+            run_pass(CancelOpposites, ir, true),
+            vec![
+                node(IrOp::Condition(vec![merged(IrOp::Nop, 1, 2)]), 0),
+                merged(IrOp::Nop, 6, 2),
+            ]
+        );
+    }
+}
@@ -0,0 +1,124 @@
+use alloc::vec::Vec;
+
+use super::{arithmetic_step, IrPass};
+use crate::compiler::CompilerSettings;
+use crate::ir::{IrNode, IrOp};
+
+/// Replaces loops that count the cell down to zero with `MulConst(0)`, which
+/// is lowered to a single store.
+///
+/// `[-]` and `[+]` are the common ones, but any loop that only adds an odd
+/// amount to the cell reaches zero, since an odd step visits every value
+/// modulo 256. Loops with an even step may never end, so they are kept.
+pub struct ClearLoops;
+
+impl IrPass for ClearLoops {
+    fn name(&self) -> &'static str {
+        "clear-loops"
+    }
+
+    fn run(&mut self, ir: Vec<IrNode>, _settings: &CompilerSettings) -> Vec<IrNode> {
+        clear(ir)
+    }
+}
+
+fn clear(ir: Vec<IrNode>) -> Vec<IrNode> {
+    ir.into_iter()
+        .map(|mut node| {
+            match &mut node.node {
+                IrOp::Condition(children)
+                    if arithmetic_step(children).is_some_and(|step| step % 2 == 1) =>
+                {
+                    node.node = IrOp::MulConst(0);
+                }
+                IrOp::Function(_, children)
+                | IrOp::Condition(children)
+                | IrOp::IfEq { body: children, .. } => {
+                    *children = clear(core::mem::take(children));
+                }
+                IrOp::Switch { arms, default } => {
+                    for body in arms.iter_mut().map(|(_, body)| body).chain([default]) {
+                        *body = clear(core::mem::take(body));
+                    }
+                }
+                _ => {}
+            }
+            node
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::optimizer::{node, run_pass};
+
+    #[test]
+    fn test_clears_odd_steps() {
+        let ir = vec![
+            node(IrOp::Condition(vec![node(IrOp::Subtract(1), 1)]), 0),
+            node(IrOp::Condition(vec![node(IrOp::Add(1), 4)]), 3),
+            node(
+                IrOp::Condition(vec![node(IrOp::Add(4), 7), node(IrOp::Subtract(1), 8)]),
+                6,
+            ),
+        ];
+        assert_eq!(
+            run_pass(ClearLoops, ir, false),
+            [
+                node(IrOp::MulConst(0), 0),
+                node(IrOp::MulConst(0), 3),
+                node(IrOp::MulConst(0), 6),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_keeps_other_loops() {
+        let ir = vec![
+            // may never end
+            node(IrOp::Condition(vec![node(IrOp::Subtract(2), 1)]), 0),
+            node(
+                IrOp::Condition(vec![
+                    node(IrOp::Subtract(1), 4),
+                    node(IrOp::MoveRight(1), 5),
+                ]),
+                3,
+            ),
+        ];
+        assert_eq!(run_pass(ClearLoops, ir.clone(), false), ir);
+    }
+
+    #[test]
+    fn test_clears_nested_loops() {
+        let ir = vec![node(
+            IrOp::Function(
+                "f".into(),
+                vec![node(
+                    IrOp::Condition(vec![
+                        node(IrOp::MoveRight(1), 2),
+                        node(IrOp::Condition(vec![node(IrOp::Subtract(1), 4)]), 3),
+                    ]),
+                    1,
+                )],
+            ),
+            0,
+        )];
+        assert_eq!(
+            run_pass(ClearLoops, ir, false),
+            [node(
+                IrOp::Function(
+                    "f".into(),
+                    vec![node(
+                        IrOp::Condition(vec![
+                            node(IrOp::MoveRight(1), 2),
+                            node(IrOp::MulConst(0), 3),
+                        ]),
+                        1,
+                    )],
+                ),
+                0,
+            )]
+        );
+    }
+}
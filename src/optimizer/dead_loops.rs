@@ -0,0 +1,178 @@
+use alloc::vec::Vec;
+
+use super::{cell_value_after, eliminated, IrPass};
+use crate::compiler::CompilerSettings;
+use crate::ir::{IrNode, IrOp, Span};
+
+/// Removes loops that are never entered, because their cell is known to be
+/// zero when they are reached.
+///
+/// `[-][+]` becomes `[-]`: a loop always leaves its cell at zero, so the second
+/// one can't run. Nothing is assumed about the tape the program starts on, as
+/// the host may fill it, so loops at the very start of the program are kept.
+/// The value of the cell is only tracked until the next pointer move, call or
+/// read.
+pub struct DeadLoops;
+
+impl IrPass for DeadLoops {
+    fn name(&self) -> &'static str {
+        "dead-loops"
+    }
+
+    fn run(&mut self, ir: Vec<IrNode>, settings: &CompilerSettings) -> Vec<IrNode> {
+        remove_dead_loops(ir, None, settings)
+    }
+}
+
+fn remove_dead_loops(
+    ir: Vec<IrNode>,
+    mut value: Option<u8>,
+    settings: &CompilerSettings,
+) -> Vec<IrNode> {
+    let mut out = Vec::with_capacity(ir.len());
+
+    for mut node in ir {
+        match &mut node.node {
+            IrOp::Condition(_) if value == Some(0) => {
+                out.extend(eliminated(node.span, settings));
+                continue;
+            }
+            // nothing is known about the cell when a function is called or a
+            // loop comes around again
+            IrOp::Function(_, children) | IrOp::Condition(children) => {
+                *children = remove_dead_loops(core::mem::take(children), None, settings);
+            }
+            IrOp::IfEq { value, body } => {
+                *body = remove_dead_loops(core::mem::take(body), Some(*value), settings);
+            }
+            IrOp::Switch { arms, default } => {
+                for (value, body) in arms {
+                    *body = remove_dead_loops(core::mem::take(body), Some(*value), settings);
+                }
+                *default = remove_dead_loops(core::mem::take(default), None, settings);
+            }
+            _ => {}
+        }
+        value = cell_value_after(value, &node.node);
+        out.push(node);
+    }
+
+    out
+}
+
+/// Collects the spans of the loops [`DeadLoops`] removes from `ir`, given the
+/// value of the cell before it, without changing anything. Returns the value
+/// of the cell after `ir`.
+pub(crate) fn find_dead_loops(
+    ir: &[IrNode],
+    mut value: Option<u8>,
+    found: &mut Vec<Span>,
+) -> Option<u8> {
+    for node in ir {
+        match &node.node {
+            IrOp::Condition(_) if value == Some(0) => {
+                found.push(node.span);
+                continue;
+            }
+            IrOp::Function(_, children) | IrOp::Condition(children) => {
+                find_dead_loops(children, None, found);
+            }
+            IrOp::IfEq { value, body } => {
+                find_dead_loops(body, Some(*value), found);
+            }
+            IrOp::Switch { arms, default } => {
+                for (value, body) in arms {
+                    find_dead_loops(body, Some(*value), found);
+                }
+                find_dead_loops(default, None, found);
+            }
+            _ => {}
+        }
+        value = cell_value_after(value, &node.node);
+    }
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::interpret;
+    use crate::optimizer::{node, run_pass};
+
+    fn clear(column: usize) -> IrNode {
+        node(
+            IrOp::Condition(vec![node(IrOp::Subtract(1), column + 1)]),
+            column,
+        )
+    }
+
+    #[test]
+    fn test_removes_loop_after_loop() {
+        let ir = vec![
+            node(IrOp::MoveRight(1), 0),
+            clear(1),
+            node(IrOp::Condition(vec![node(IrOp::Add(1), 5)]), 4),
+        ];
+        assert_eq!(run_pass(DeadLoops, ir.clone(), false), ir[..2]);
+        assert_eq!(
+            run_pass(DeadLoops, ir.clone(), true),
+            [ir[0].clone(), ir[1].clone(), node(IrOp::Nop, 4)]
+        );
+    }
+
+    #[test]
+    fn test_keeps_loops_that_may_run() {
+        let ir = vec![
+            node(IrOp::Add(1), 0),
+            clear(1),
+            node(IrOp::Add(2), 4),
+            clear(5),
+            node(IrOp::MoveRight(1), 8),
+            clear(9),
+            node(IrOp::ReadByte, 12),
+            clear(13),
+            node(IrOp::Function("f".into(), vec![clear(17)]), 16),
+        ];
+        assert_eq!(run_pass(DeadLoops, ir.clone(), false), ir);
+    }
+
+    #[test]
+    fn test_removes_second_of_two_loops() {
+        // [-][+]
+        let ir = vec![
+            clear(0),
+            node(IrOp::Condition(vec![node(IrOp::Add(1), 4)]), 3),
+        ];
+        assert_eq!(run_pass(DeadLoops, ir.clone(), false), ir[..1]);
+    }
+
+    #[test]
+    fn test_removes_loop_after_multiplying_by_zero() {
+        // whatever the cell was, it is zero after multiplying it by 0
+        let ir = vec![
+            node(IrOp::ReadByte, 0),
+            node(IrOp::MulConst(0), 1),
+            node(IrOp::Condition(vec![node(IrOp::Add(1), 3)]), 2),
+        ];
+        assert_eq!(run_pass(DeadLoops, ir.clone(), false), ir[..2]);
+
+        let ir = vec![
+            node(IrOp::ReadByte, 0),
+            node(IrOp::MulConst(3), 1),
+            node(IrOp::Condition(vec![node(IrOp::Add(1), 3)]), 2),
+        ];
+        assert_eq!(run_pass(DeadLoops, ir.clone(), false), ir);
+    }
+
+    #[test]
+    fn test_keeps_loop_at_start() {
+        // [-]+ on a tape the host filled
+        let ir = vec![clear(0), node(IrOp::Add(1), 3)];
+        let optimized = run_pass(DeadLoops, ir.clone(), false);
+        assert_eq!(optimized, ir);
+
+        let mut tape = [5];
+        interpret(&optimized, &mut tape, &[]);
+        assert_eq!(tape, [1]);
+    }
+}
@@ -0,0 +1,192 @@
+//! Optimisation passes over the IR.
+//!
+//! Passes run on the flattened IR produced by [`crate::ir::from_ast`], right
+//! before it is lowered to machine code. Which passes run is decided by
+//! [`CompilerSettings::optimization_level`]; level 0 leaves the IR untouched.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+use crate::compiler::CompilerSettings;
+use crate::ir::{IrNode, IrOp, Span};
+
+mod batch_writes;
+mod cancel;
+mod clear_loops;
+mod dead_loops;
+mod move_loops;
+mod regions;
+mod vector_adds;
+
+pub use batch_writes::BatchWrites;
+pub use cancel::CancelOpposites;
+pub use clear_loops::ClearLoops;
+pub(crate) use dead_loops::find_dead_loops;
+pub use dead_loops::DeadLoops;
+pub use move_loops::MoveLoops;
+pub use regions::Regions;
+pub use vector_adds::VectorAdds;
+
+/// A transformation of the IR that must not change the observable behaviour
+/// of the program.
+///
+/// # Fences
+///
+/// Ops for which [`is_fence`] holds have side effects, and may read or modify
+/// any cell, the cell pointer and the stack. Passes must not move other ops
+/// across them, or fold ops on either side of one into a single op: anything
+/// accumulated, like a net `Add` or a pending move, has to be emitted before
+/// the fence, and folding starts over after it.
+pub trait IrPass {
+    /// A short, human readable name of the pass.
+    fn name(&self) -> &'static str;
+
+    /// Runs the pass over a list of nodes. Passes are responsible for walking
+    /// into the bodies of `Function` and `Condition` nodes themselves.
+    fn run(&mut self, ir: Vec<IrNode>, settings: &CompilerSettings) -> Vec<IrNode>;
+}
+
+/// The highest optimisation level that enables anything, levels above it
+/// run the same passes.
+pub const MAX_OPTIMIZATION_LEVEL: u8 = 2;
+
+/// The passes run at a given optimisation level, in order.
+///
+/// Level 2 adds passes that trade something away for smaller code, like the
+/// iteration counts of [`CompilerSettings::profile`] for the loops they
+/// replace, and passes that depend on target features, like
+/// [`VectorAdds`].
+pub fn passes(optimization_level: u8) -> Vec<Box<dyn IrPass>> {
+    let mut passes: Vec<Box<dyn IrPass>> = Vec::new();
+    if optimization_level >= 1 {
+        passes.push(Box::new(CancelOpposites));
+        passes.push(Box::new(Regions));
+        passes.push(Box::new(DeadLoops));
+        passes.push(Box::new(BatchWrites));
+    }
+    if optimization_level >= 2 {
+        passes.push(Box::new(ClearLoops));
+        passes.push(Box::new(MoveLoops));
+        passes.push(Box::new(VectorAdds));
+    }
+    passes
+}
+
+/// Runs every pass enabled by `settings` over the IR.
+pub fn optimize(mut ir: Vec<IrNode>, settings: &CompilerSettings) -> Vec<IrNode> {
+    for mut pass in passes(settings.optimization_level) {
+        ir = pass.run(ir, settings);
+    }
+    ir
+}
+
+/// Whether `op` is an ordering fence for passes, see [`IrPass`].
+///
+/// I/O and calls are, external or not, since a function can do anything the
+/// program can. `Condition` isn't: its body is optimised on its own, and the
+/// loop as a whole stays in place.
+pub fn is_fence(op: &IrOp) -> bool {
+    matches!(
+        op,
+        IrOp::ExternalFunctionCall(_)
+            | IrOp::FunctionCall(_)
+            | IrOp::IndirectCall { .. }
+            | IrOp::WriteByte
+            | IrOp::WriteBytes(_)
+            | IrOp::ReadByte
+            | IrOp::ReadBytes(_)
+    )
+}
+
+/// The value of the current cell after `op`, given its value before, as far
+/// as it can be known at compile time.
+///
+/// After a loop the cell is always zero. Anything that moves the cell pointer,
+/// or may change the cell in unknown ways, like calls and reads, loses track.
+pub(crate) fn cell_value_after(value: Option<u8>, op: &IrOp) -> Option<u8> {
+    match *op {
+        IrOp::Add(n) => value.map(|v| v.wrapping_add(n as u8)),
+        IrOp::Subtract(n) => value.map(|v| v.wrapping_sub(n as u8)),
+        // zero whatever the cell was
+        IrOp::MulConst(0) => Some(0),
+        IrOp::MulConst(k) => value.map(|v| v.wrapping_mul(k)),
+        IrOp::AddAtOffset { offset: 0, amount } => value.map(|v| v.wrapping_add(amount)),
+        IrOp::AddAtOffset { .. } => value,
+        IrOp::AddToCells {
+            offset,
+            count,
+            amount,
+        } if offset <= 0 && i64::from(offset) + i64::from(count) > 0 => {
+            value.map(|v| v.wrapping_add(amount))
+        }
+        IrOp::AddToCells { .. } => value,
+        IrOp::AddFromCell { src_offset: 0 } => value.map(|v| v.wrapping_add(v)),
+        IrOp::MoveCell { .. } => Some(0),
+        IrOp::Condition(_) => Some(0),
+        // the body doesn't run
+        IrOp::IfEq { value: k, .. } if value.is_some_and(|v| v != k) => value,
+        IrOp::StackPush
+        | IrOp::WriteByte
+        | IrOp::WriteBytes(_)
+        | IrOp::Nop
+        | IrOp::DebugMarker(_) => value,
+        _ => None,
+    }
+}
+
+/// The net amount a loop body adds to the cell, if all it does is `Add`s and
+/// `Subtract`s.
+pub(crate) fn arithmetic_step(body: &[IrNode]) -> Option<u8> {
+    body.iter().try_fold(0u8, |step, node| match node.node {
+        IrOp::Add(n) => Some(step.wrapping_add(n as u8)),
+        IrOp::Subtract(n) => Some(step.wrapping_sub(n as u8)),
+        IrOp::Nop => Some(step),
+        _ => None,
+    })
+}
+
+/// What a pass should leave behind for a node it eliminated: a
+/// [`IrOp::Nop`] carrying its span when [`CompilerSettings::preserve_spans`]
+/// is set, nothing otherwise.
+pub(crate) fn eliminated(span: Span, settings: &CompilerSettings) -> Option<IrNode> {
+    settings.preserve_spans.then_some(IrNode {
+        node: IrOp::Nop,
+        span,
+    })
+}
+
+/// A node for `op` at `column` of the first line, for the tests of the passes.
+#[cfg(test)]
+pub(crate) fn node(op: IrOp, column: usize) -> IrNode {
+    IrNode {
+        node: op,
+        span: Span::from_location((0, column)),
+    }
+}
+
+/// A node for `op` merged from the `length` columns starting at `column`.
+#[cfg(test)]
+pub(crate) fn merged(op: IrOp, column: usize, length: usize) -> IrNode {
+    IrNode {
+        node: op,
+        span: Span {
+            location: (0, column),
+            length,
+        },
+    }
+}
+
+/// Runs `pass` over `ir` with the default settings, but for
+/// [`CompilerSettings::preserve_spans`].
+#[cfg(test)]
+pub(crate) fn run_pass(
+    mut pass: impl IrPass,
+    ir: Vec<IrNode>,
+    preserve_spans: bool,
+) -> Vec<IrNode> {
+    let settings = CompilerSettings {
+        preserve_spans,
+        ..Default::default()
+    };
+    pass.run(ir, &settings)
+}
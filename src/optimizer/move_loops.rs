@@ -0,0 +1,179 @@
+use alloc::vec::Vec;
+
+use super::IrPass;
+use crate::compiler::CompilerSettings;
+use crate::ir::{IrNode, IrOp};
+
+/// Replaces loops that move the value of the cell to another one, like
+/// `[->+<]`, with [`IrOp::MoveCell`], which adds it there all at once and
+/// clears the cell.
+///
+/// Such a loop counts the cell down by one and adds one to the other cell on
+/// every iteration, so it runs as many times as the cell's value. Bodies are
+/// expected to be folded by [`super::Regions`] already, into the decrement and
+/// an `AddAtOffset`, in either order.
+pub struct MoveLoops;
+
+impl IrPass for MoveLoops {
+    fn name(&self) -> &'static str {
+        "move-loops"
+    }
+
+    fn run(&mut self, ir: Vec<IrNode>, _settings: &CompilerSettings) -> Vec<IrNode> {
+        replace(ir)
+    }
+}
+
+fn replace(ir: Vec<IrNode>) -> Vec<IrNode> {
+    ir.into_iter()
+        .map(|mut node| {
+            match &mut node.node {
+                IrOp::Condition(children) => match moved_to(children) {
+                    Some(offset) => node.node = IrOp::MoveCell { offset },
+                    None => *children = replace(core::mem::take(children)),
+                },
+                IrOp::Function(_, children) | IrOp::IfEq { body: children, .. } => {
+                    *children = replace(core::mem::take(children));
+                }
+                IrOp::Switch { arms, default } => {
+                    for body in arms.iter_mut().map(|(_, body)| body).chain([default]) {
+                        *body = replace(core::mem::take(body));
+                    }
+                }
+                _ => {}
+            }
+            node
+        })
+        .collect()
+}
+
+/// The offset of the cell a loop with `body` moves the value of the cell to,
+/// if that is all it does.
+fn moved_to(body: &[IrNode]) -> Option<i32> {
+    let mut ops = body
+        .iter()
+        .map(|node| &node.node)
+        .filter(|op| **op != IrOp::Nop);
+    let (first, second) = (ops.next()?, ops.next()?);
+    if ops.next().is_some() {
+        return None;
+    }
+    let is_decrement = |op: &IrOp| matches!(op, IrOp::Add(0xff) | IrOp::Subtract(1));
+    match (first, second) {
+        (&IrOp::AddAtOffset { offset, amount: 1 }, decrement)
+        | (decrement, &IrOp::AddAtOffset { offset, amount: 1 })
+            if offset != 0 && is_decrement(decrement) =>
+        {
+            Some(offset)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::interpret;
+    use crate::optimizer::optimize;
+    use crate::optimizer::{node, run_pass};
+
+    #[test]
+    fn test_replaces_move_loops() {
+        let ir = vec![
+            // [->+<]
+            node(
+                IrOp::Condition(vec![
+                    node(IrOp::Add(0xff), 1),
+                    node(
+                        IrOp::AddAtOffset {
+                            offset: 1,
+                            amount: 1,
+                        },
+                        3,
+                    ),
+                ]),
+                0,
+            ),
+            // [<<+>>-], with spans preserved
+            node(
+                IrOp::Condition(vec![
+                    node(
+                        IrOp::AddAtOffset {
+                            offset: -2,
+                            amount: 1,
+                        },
+                        9,
+                    ),
+                    node(IrOp::Subtract(1), 13),
+                    node(IrOp::Nop, 8),
+                ]),
+                7,
+            ),
+        ];
+        assert_eq!(
+            run_pass(MoveLoops, ir, false),
+            [
+                node(IrOp::MoveCell { offset: 1 }, 0),
+                node(IrOp::MoveCell { offset: -2 }, 7),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_keeps_other_loops() {
+        let add = |amount| node(IrOp::AddAtOffset { offset: 1, amount }, 2);
+        let ir = vec![
+            // a multiplication
+            node(IrOp::Condition(vec![node(IrOp::Add(0xff), 1), add(2)]), 0),
+            // may never end
+            node(IrOp::Condition(vec![node(IrOp::Subtract(2), 1), add(1)]), 0),
+            // a copy to two cells
+            node(
+                IrOp::Condition(vec![
+                    node(IrOp::Add(0xff), 1),
+                    add(1),
+                    node(
+                        IrOp::AddAtOffset {
+                            offset: 2,
+                            amount: 1,
+                        },
+                        3,
+                    ),
+                ]),
+                0,
+            ),
+        ];
+        assert_eq!(run_pass(MoveLoops, ir.clone(), false), ir);
+    }
+
+    #[test]
+    fn test_behaves_like_the_loop() {
+        // ++++[->>+<<]>>.
+        let ir = vec![
+            node(IrOp::Add(4), 0),
+            node(
+                IrOp::Condition(vec![
+                    node(IrOp::Subtract(1), 5),
+                    node(IrOp::MoveRight(2), 6),
+                    node(IrOp::Add(1), 8),
+                    node(IrOp::MoveLeft(2), 9),
+                ]),
+                4,
+            ),
+            node(IrOp::MoveRight(2), 12),
+            node(IrOp::WriteByte, 14),
+        ];
+        let settings = CompilerSettings {
+            optimization_level: 2,
+            ..Default::default()
+        };
+        let optimized = optimize(ir.clone(), &settings);
+        assert_eq!(optimized[1].node, IrOp::MoveCell { offset: 2 });
+
+        let mut tape = [0; 3];
+        let output = interpret(&ir, &mut tape, &[]);
+        let mut optimized_tape = [0; 3];
+        assert_eq!(interpret(&optimized, &mut optimized_tape, &[]), output);
+        assert_eq!(optimized_tape, tape);
+    }
+}
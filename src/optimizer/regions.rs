@@ -0,0 +1,335 @@
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+use super::{eliminated, IrPass};
+use crate::compiler::CompilerSettings;
+use crate::ir::{IrNode, IrOp};
+
+/// Folds every straight-line region of pointer moves and arithmetic into the
+/// net amount added to each cell it touches, plus at most one move.
+///
+/// Cells are addressed by their offset from where the region starts, so
+/// `>+>++<<-` becomes `Add(0xff)`, `AddAtOffset { offset: 1, amount: 1 }`
+/// and `AddAtOffset { offset: 2, amount: 2 }`, without moving the cell
+/// pointer at all. When the region does move it, the cell it ends up on is
+/// added to after the move, as a plain `Add`. Like [`super::CancelOpposites`],
+/// debug markers within a region don't end it, they are moved after it.
+///
+/// Each cell gets at most one add, `AddAtOffset`s the region started out with
+/// included, so none of the adds left depends on another and there is no
+/// chain through memory for the CPU to wait on.
+pub struct Regions;
+
+impl IrPass for Regions {
+    fn name(&self) -> &'static str {
+        "regions"
+    }
+
+    fn run(&mut self, ir: Vec<IrNode>, settings: &CompilerSettings) -> Vec<IrNode> {
+        let mut out = Vec::with_capacity(ir.len());
+        let mut region = Vec::new();
+
+        for mut node in ir {
+            match &mut node.node {
+                IrOp::Function(_, children)
+                | IrOp::Condition(children)
+                | IrOp::IfEq { body: children, .. } => {
+                    *children = self.run(core::mem::take(children), settings);
+                }
+                IrOp::Switch { arms, default } => {
+                    for body in arms.iter_mut().map(|(_, body)| body).chain([default]) {
+                        *body = self.run(core::mem::take(body), settings);
+                    }
+                }
+                _ => {}
+            }
+
+            if is_foldable(&node.node) {
+                region.push(node);
+                continue;
+            }
+            flush(&mut out, core::mem::take(&mut region), settings);
+            out.push(node);
+        }
+        flush(&mut out, region, settings);
+
+        out
+    }
+}
+
+/// Whether `op` can be part of a region.
+fn is_foldable(op: &IrOp) -> bool {
+    matches!(
+        op,
+        IrOp::Add(_)
+            | IrOp::Subtract(_)
+            | IrOp::MoveRight(_)
+            | IrOp::MoveLeft(_)
+            | IrOp::AddAtOffset { .. }
+            | IrOp::Nop
+            | IrOp::DebugMarker(_)
+    )
+}
+
+/// The net effect of a region: what it adds to each cell, and where it leaves
+/// the cell pointer, both relative to where it starts.
+#[derive(Default)]
+struct Net {
+    /// The amount added at each offset, and the first node adding to it.
+    deltas: BTreeMap<i64, (u8, usize)>,
+    /// How far the region moves, and the first node moving.
+    moved: (i64, Option<usize>),
+}
+
+impl Net {
+    /// The net effect of `region`, if every offset fits into an
+    /// `AddAtOffset` and the move into a single `MoveRight` or `MoveLeft`.
+    fn of(region: &[IrNode]) -> Option<Self> {
+        let mut net = Self::default();
+        for (index, node) in region.iter().enumerate() {
+            let position = net.moved.0;
+            let (offset, amount) = match node.node {
+                IrOp::Add(n) => (0, n as u8),
+                IrOp::Subtract(n) => (0, (n as u8).wrapping_neg()),
+                IrOp::AddAtOffset { offset, amount } => (offset.into(), amount),
+                IrOp::MoveRight(n) | IrOp::MoveLeft(n) => {
+                    let n = i64::try_from(n).ok()?;
+                    let n = if matches!(node.node, IrOp::MoveLeft(_)) {
+                        -n
+                    } else {
+                        n
+                    };
+                    net.moved.0 = position.checked_add(n)?;
+                    net.moved.1.get_or_insert(index);
+                    continue;
+                }
+                _ => continue,
+            };
+            let (delta, _) = net
+                .deltas
+                .entry(position.checked_add(offset)?)
+                .or_insert((0, index));
+            *delta = delta.wrapping_add(amount);
+        }
+
+        let fits = |offset: &i64| i32::try_from(*offset).is_ok();
+        (net.deltas.keys().all(fits) && fits(&net.moved.0)).then_some(net)
+    }
+}
+
+fn flush(out: &mut Vec<IrNode>, region: Vec<IrNode>, settings: &CompilerSettings) {
+    let Some(net) = Net::of(&region) else {
+        out.extend(region);
+        return;
+    };
+    // which nodes lend their span to a folded op
+    let mut kept = Vec::new();
+    let mut emit = |node: IrOp, index: usize| {
+        kept.push(index);
+        out.push(IrNode {
+            node,
+            span: region[index].span,
+        });
+    };
+
+    let (moved, first_move) = net.moved;
+    for (&offset, &(amount, index)) in &net.deltas {
+        if amount == 0 || (offset == moved && moved != 0) {
+            continue;
+        }
+        match offset {
+            0 => emit(IrOp::Add(amount as usize), index),
+            // checked to fit by `Net::of`
+            offset => emit(
+                IrOp::AddAtOffset {
+                    offset: offset as i32,
+                    amount,
+                },
+                index,
+            ),
+        }
+    }
+    if let Some(index) = first_move.filter(|_| moved != 0) {
+        let distance = moved.unsigned_abs() as usize;
+        match moved > 0 {
+            true => emit(IrOp::MoveRight(distance), index),
+            false => emit(IrOp::MoveLeft(distance), index),
+        }
+        if let Some(&(amount @ 1.., index)) = net.deltas.get(&moved) {
+            emit(IrOp::Add(amount as usize), index);
+        }
+    }
+
+    for (index, node) in region.into_iter().enumerate() {
+        match node.node {
+            IrOp::DebugMarker(_) | IrOp::Nop => out.push(node),
+            _ if kept.contains(&index) => {}
+            _ => out.extend(eliminated(node.span, settings)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::interpret;
+    use crate::optimizer::{node, run_pass};
+
+    #[test]
+    fn test_folds_regions() {
+        let ir = vec![node(
+            IrOp::Condition(vec![
+                node(IrOp::Subtract(1), 1),
+                node(IrOp::MoveRight(1), 2),
+                node(IrOp::Add(1), 3),
+                node(IrOp::MoveLeft(3), 4),
+                node(IrOp::Subtract(3), 5),
+                node(IrOp::MoveRight(2), 6),
+            ]),
+            0,
+        )];
+        assert_eq!(
+            run_pass(Regions, ir.clone(), false),
+            [node(
+                IrOp::Condition(vec![
+                    node(
+                        IrOp::AddAtOffset {
+                            offset: -2,
+                            amount: 0xfd
+                        },
+                        5
+                    ),
+                    node(IrOp::Add(0xff), 1),
+                    node(
+                        IrOp::AddAtOffset {
+                            offset: 1,
+                            amount: 1
+                        },
+                        3
+                    ),
+                ]),
+                0,
+            )]
+        );
+
+        let IrOp::Condition(body) = &run_pass(Regions, ir, true)[0].node else {
+            panic!("expected a Condition");
+        };
+        assert_eq!(
+            body[3..],
+            [node(IrOp::Nop, 2), node(IrOp::Nop, 4), node(IrOp::Nop, 6)]
+        );
+    }
+
+    #[test]
+    fn test_adds_after_the_move() {
+        let ir = vec![
+            node(IrOp::Add(1), 0),
+            node(IrOp::MoveRight(2), 1),
+            node(IrOp::Add(2), 2),
+            node(IrOp::MoveLeft(1), 3),
+            node(IrOp::Add(3), 4),
+            node(IrOp::DebugMarker("m".into()), 5),
+            // ends the region
+            node(IrOp::WriteByte, 6),
+            node(IrOp::MoveLeft(1), 7),
+            node(IrOp::MoveRight(1), 8),
+        ];
+        assert_eq!(
+            run_pass(Regions, ir, false),
+            [
+                node(IrOp::Add(1), 0),
+                node(
+                    IrOp::AddAtOffset {
+                        offset: 2,
+                        amount: 2
+                    },
+                    2
+                ),
+                node(IrOp::MoveRight(1), 1),
+                node(IrOp::Add(3), 4),
+                node(IrOp::DebugMarker("m".into()), 5),
+                node(IrOp::WriteByte, 6),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_one_add_per_cell() {
+        let add_at = |offset, column| node(IrOp::AddAtOffset { offset, amount: 1 }, column);
+        let ir = vec![
+            add_at(1, 0),
+            add_at(2, 1),
+            add_at(1, 2),
+            node(IrOp::Add(1), 3),
+            add_at(2, 4),
+        ];
+        assert_eq!(
+            run_pass(Regions, ir, false),
+            [
+                node(IrOp::Add(1), 3),
+                node(
+                    IrOp::AddAtOffset {
+                        offset: 1,
+                        amount: 2
+                    },
+                    0
+                ),
+                node(
+                    IrOp::AddAtOffset {
+                        offset: 2,
+                        amount: 2
+                    },
+                    1
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_keeps_regions_too_far_to_fold() {
+        let ir = vec![
+            node(IrOp::MoveRight(i32::MAX as usize), 0),
+            node(IrOp::MoveRight(1), 1),
+            node(IrOp::Add(1), 2),
+        ];
+        assert_eq!(run_pass(Regions, ir.clone(), false), ir);
+    }
+
+    #[test]
+    fn test_behaves_the_same() {
+        let ir = vec![
+            node(IrOp::MoveRight(4), 0),
+            node(IrOp::Add(7), 1),
+            node(IrOp::MoveLeft(2), 2),
+            node(IrOp::Subtract(3), 3),
+            node(
+                IrOp::AddAtOffset {
+                    offset: -1,
+                    amount: 5,
+                },
+                4,
+            ),
+            node(IrOp::MoveRight(3), 5),
+            node(IrOp::Add(200), 6),
+            node(IrOp::MoveLeft(1), 7),
+            node(IrOp::Subtract(9), 8),
+            node(IrOp::MoveLeft(4), 9),
+            node(IrOp::Add(100), 10),
+            node(IrOp::MoveRight(2), 11),
+            node(IrOp::Add(100), 12),
+            node(IrOp::WriteByte, 13),
+            node(IrOp::MoveRight(1), 14),
+            node(IrOp::Subtract(1), 15),
+            node(IrOp::WriteByte, 16),
+        ];
+        let folded = run_pass(Regions, ir.clone(), false);
+        assert!(folded.len() < ir.len());
+
+        let mut tape = [0; 8];
+        let output = interpret(&ir, &mut tape, &[]);
+        let mut folded_tape = [0; 8];
+        assert_eq!(interpret(&folded, &mut folded_tape, &[]), output);
+        assert_eq!(folded_tape, tape);
+    }
+}
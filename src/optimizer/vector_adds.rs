@@ -0,0 +1,182 @@
+use alloc::vec::Vec;
+
+use super::{eliminated, IrPass};
+use crate::compiler::CompilerSettings;
+use crate::ir::{IrNode, IrOp};
+
+/// How many cells an SSE2 register holds, and so the shortest run worth
+/// fusing.
+const VECTOR_CELLS: usize = 16;
+
+/// Fuses runs of adds of the same amount to at least 16 consecutive cells
+/// into an [`IrOp::AddToCells`], which is lowered to SSE2 `paddb`s on 16
+/// cells at a time.
+///
+/// [`super::Regions`] emits the adds of a region in order of their offsets,
+/// so a uniform add over many cells, like `+>+>+…<<<`, ends up as such a run.
+/// Only runs with [`CompilerSettings::sse2`].
+pub struct VectorAdds;
+
+impl IrPass for VectorAdds {
+    fn name(&self) -> &'static str {
+        "vector-adds"
+    }
+
+    fn run(&mut self, ir: Vec<IrNode>, settings: &CompilerSettings) -> Vec<IrNode> {
+        if !settings.sse2 {
+            return ir;
+        }
+
+        let mut out = Vec::with_capacity(ir.len());
+        let mut run: Vec<IrNode> = Vec::new();
+        for mut node in ir {
+            match &mut node.node {
+                IrOp::Function(_, children)
+                | IrOp::Condition(children)
+                | IrOp::IfEq { body: children, .. } => {
+                    *children = self.run(core::mem::take(children), settings);
+                }
+                IrOp::Switch { arms, default } => {
+                    for body in arms.iter_mut().map(|(_, body)| body).chain([default]) {
+                        *body = self.run(core::mem::take(body), settings);
+                    }
+                }
+                _ => {}
+            }
+
+            let Some((offset, amount)) = cell_add(&node.node) else {
+                flush(&mut out, core::mem::take(&mut run), settings);
+                out.push(node);
+                continue;
+            };
+            let continues = run
+                .last()
+                .and_then(|last| cell_add(&last.node))
+                .is_some_and(|(last_offset, last_amount)| {
+                    last_amount == amount && last_offset.checked_add(1) == Some(offset)
+                });
+            if !continues {
+                flush(&mut out, core::mem::take(&mut run), settings);
+            }
+            run.push(node);
+        }
+        flush(&mut out, run, settings);
+
+        out
+    }
+}
+
+/// The offset of the cell `op` adds to, and the amount it adds.
+fn cell_add(op: &IrOp) -> Option<(i32, u8)> {
+    match *op {
+        IrOp::Add(n) => Some((0, n as u8)),
+        IrOp::AddAtOffset { offset, amount } => Some((offset, amount)),
+        _ => None,
+    }
+}
+
+fn flush(out: &mut Vec<IrNode>, run: Vec<IrNode>, settings: &CompilerSettings) {
+    if run.len() < VECTOR_CELLS {
+        out.extend(run);
+        return;
+    }
+    let (offset, amount) = cell_add(&run[0].node).expect("runs only hold adds");
+    out.push(IrNode {
+        node: IrOp::AddToCells {
+            offset,
+            count: run.len() as u32,
+            amount,
+        },
+        span: run[0].span,
+    });
+    out.extend(
+        run[1..]
+            .iter()
+            .filter_map(|node| eliminated(node.span, settings)),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::interpret;
+    use crate::optimizer::node;
+    use crate::optimizer::optimize;
+
+    fn adds(offsets: core::ops::Range<i32>, amount: u8) -> Vec<IrNode> {
+        offsets
+            .map(|offset| node(IrOp::AddAtOffset { offset, amount }, offset as usize))
+            .collect()
+    }
+
+    fn run(ir: Vec<IrNode>, sse2: bool) -> Vec<IrNode> {
+        let settings = CompilerSettings {
+            sse2,
+            ..Default::default()
+        };
+        VectorAdds.run(ir, &settings)
+    }
+
+    #[test]
+    fn test_fuses_runs() {
+        let mut ir = adds(1..17, 3);
+        // a different amount ends the run
+        ir.extend(adds(17..20, 4));
+        assert_eq!(
+            run(ir.clone(), true)[..],
+            [
+                &[node(
+                    IrOp::AddToCells {
+                        offset: 1,
+                        count: 16,
+                        amount: 3
+                    },
+                    1
+                )][..],
+                &ir[16..],
+            ]
+            .concat()
+        );
+        assert_eq!(run(ir.clone(), false), ir);
+    }
+
+    #[test]
+    fn test_keeps_short_and_gapped_runs() {
+        let mut ir = adds(0..15, 1);
+        ir.extend(adds(16..31, 1));
+        assert_eq!(run(ir.clone(), true), ir);
+    }
+
+    #[test]
+    fn test_behaves_like_scalar_adds() {
+        // +>+>+ … over 32 cells, then back
+        let mut ir = Vec::new();
+        for column in 0..32 {
+            ir.push(node(IrOp::Add(7), 2 * column));
+            ir.push(node(IrOp::MoveRight(1), 2 * column + 1));
+        }
+        ir.push(node(IrOp::MoveLeft(32), 64));
+        ir.push(node(IrOp::WriteBytes(33), 65));
+
+        let settings = CompilerSettings {
+            optimization_level: 2,
+            sse2: true,
+            ..Default::default()
+        };
+        let optimized = optimize(ir.clone(), &settings);
+        assert_eq!(
+            optimized[0].node,
+            IrOp::AddToCells {
+                offset: 0,
+                count: 32,
+                amount: 7
+            }
+        );
+
+        let mut tape = [1; 40];
+        let output = interpret(&ir, &mut tape, &[]);
+        let mut optimized_tape = [1; 40];
+        assert_eq!(interpret(&optimized, &mut optimized_tape, &[]), output);
+        assert_eq!(optimized_tape, tape);
+    }
+}
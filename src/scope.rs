@@ -1,5 +1,5 @@
 use alloc::{string::{String, ToString}, vec::Vec};
-use hashbrown::HashMap;
+use crate::Map;
 use iced_x86::code_asm::CodeLabel;
 use thiserror_no_std::Error;
 
@@ -7,7 +7,7 @@ use thiserror_no_std::Error;
 struct Scope {
     name: String,
     unnamed_scope_counter: usize,
-    functions: HashMap<String, CodeLabel>,
+    functions: Map<String, CodeLabel>,
 }
 
 impl Scope {
@@ -15,7 +15,7 @@ impl Scope {
         Self {
             name,
             unnamed_scope_counter: 0,
-            functions: HashMap::new(),
+            functions: Map::new(),
         }
     }
 }
@@ -26,11 +26,12 @@ pub struct ScopeManager {
     scopes: Vec<Scope>,
 }
 
-fn merge_scopes(dest: &mut Scope, mut src: Scope) {
+fn merge_scopes(dest: &mut Scope, src: Scope) {
+    let name = src.name;
     dest.functions.extend(
         src.functions
-            .drain()
-            .map(|(k, v)| (format!("{}{{{}", src.name, k), v)),
+            .into_iter()
+            .map(|(k, v)| (format!("{}{{{}", name, k), v)),
     );
 }
 
@@ -42,7 +43,7 @@ impl ScopeManager {
         }
     }
 
-    pub fn get_global_functions(&self) -> &HashMap<String, CodeLabel> {
+    pub fn get_global_functions(&self) -> &Map<String, CodeLabel> {
         if !self.scopes.is_empty() {
             unreachable!("global functions should only be accessed when no scopes are active")
         }
@@ -53,6 +54,16 @@ impl ScopeManager {
         self.scopes.push(Scope::new(name));
     }
 
+    /// Pushes a scope for a loop or another block without a name of its own.
+    /// It is named after its number among the unnamed scopes of the current
+    /// one, like `;2`. Together with the names of the scopes it is nested in,
+    /// which functions are prefixed with as they are merged outwards, that is
+    /// unique, and the name stays short however deep blocks are nested.
+    pub fn push_unnamed_scope(&mut self) {
+        let name = format!(";{}", self.next_unnamed_scope_number());
+        self.push_scope(name);
+    }
+
     pub fn pop_scope(&mut self) {
         if self.scopes.len() > 1 {
             let popped = self.scopes.pop().unwrap();
@@ -82,6 +93,28 @@ impl ScopeManager {
         self.scopes.last().map(|s| s.name.clone())
     }
 
+    /// Lists the functions of every scope, from the global one in, with each
+    /// scope indented under the one it is nested in, for finding out why a
+    /// function can't be found. Functions of scopes that were already popped
+    /// are listed under their qualified name in the enclosing scope, like
+    /// `outer{hello`.
+    pub fn dump(&self) -> String {
+        let mut dump = String::new();
+        let scopes = core::iter::once(&self.global_scope).chain(&self.scopes);
+        for (depth, scope) in scopes.enumerate() {
+            let indent = "  ".repeat(depth);
+            let name = if depth == 0 { "<global>" } else { scope.name.as_str() };
+            dump.push_str(&format!("{}{}\n", indent, name));
+            // sorted, as the order of a map may change from run to run
+            let mut functions = scope.functions.iter().collect::<Vec<_>>();
+            functions.sort_by(|a, b| a.0.cmp(b.0));
+            for (name, label) in functions {
+                dump.push_str(&format!("{}  fn {}: {:?}\n", indent, name, label));
+            }
+        }
+        dump
+    }
+
     pub fn next_unnamed_scope_number(&mut self) -> usize {
         if let Some(scope) = self.scopes.last_mut() {
             scope.unnamed_scope_counter += 1;
@@ -144,4 +177,55 @@ mod tests {
 
         assert_eq!(scope_manager.next_unnamed_scope_number(), 3);
     }
+
+    #[test]
+    fn test_dump() {
+        let mut code_asm = CodeAssembler::new(64).unwrap();
+        let (outer, inner) = (code_asm.create_label(), code_asm.create_label());
+
+        let mut scope_manager = ScopeManager::new();
+        scope_manager.push_fn(("outer".to_string(), outer));
+        scope_manager.push_scope("outer".to_string());
+        scope_manager.push_unnamed_scope();
+        scope_manager.push_fn(("inner".to_string(), inner));
+
+        let expected = format!(
+            "<global>\n  fn outer: {:?}\n  outer\n    ;1\n      fn inner: {:?}\n",
+            outer, inner
+        );
+        assert_eq!(scope_manager.dump(), expected);
+
+        // popped scopes are merged into the enclosing one
+        scope_manager.pop_scope();
+        scope_manager.pop_scope();
+        let expected = format!(
+            "<global>\n  fn outer: {:?}\n  fn outer{{;1{{inner: {:?}\n",
+            outer, inner
+        );
+        assert_eq!(scope_manager.dump(), expected);
+    }
+
+    #[test]
+    fn test_unnamed_scopes() {
+        let mut code_asm = CodeAssembler::new(64).unwrap();
+        let label = code_asm.create_label();
+
+        let mut scope_manager = ScopeManager::new();
+        scope_manager.push_scope("outer".to_string());
+        scope_manager.push_unnamed_scope();
+        scope_manager.pop_scope();
+        // the name doesn't grow with the depth
+        for _ in 0..1000 {
+            scope_manager.push_unnamed_scope();
+        }
+        assert_eq!(scope_manager.get_top_scope_name(), Some(";1".to_string()));
+        scope_manager.push_fn(("hello".to_string(), label));
+        for _ in 0..1000 {
+            scope_manager.pop_scope();
+        }
+
+        // the second unnamed scope of `outer`, not the first
+        let name = format!("{}hello", ";1{".repeat(999));
+        assert!(scope_manager.get_fn(&format!(";2{{{name}")).is_some());
+    }
 }